@@ -1,22 +1,39 @@
 use crate::cursor::Cursor;
+use crate::motion::MotionRange;
+use crate::normal::Operator;
+use crate::search::{self, Match};
+use crate::syntax::HighlightCache;
+use crate::text;
+use crate::undo::{undo_file_path, EditOp, UndoTravel, UndoTree};
+use crate::visual::Block;
 use anyhow::Result;
+use regex::Regex;
 use ropey::Rope;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
-use std::path::Path;
-#[derive(Clone)]
-pub struct BufferState {
-    pub content: Rope,
-    pub cursor: Cursor,
-}
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
 pub struct Buffer {
     pub content: Rope,
     pub cursor: Cursor,
     pub file_path: Option<String>,
     pub modified: bool,
-    pub undo_stack: Vec<BufferState>,
-    pub redo_stack: Vec<BufferState>,
+    /// Change number of the text last written, so undoing back to it
+    /// clears `modified`.
+    saved_change: usize,
+    pub undo_tree: UndoTree,
+    pub undo_dir: Option<PathBuf>,
     pub line_numbers: bool,
+    /// Number lines by their distance from the cursor line. Together with
+    /// `line_numbers` the cursor line shows its own number.
+    pub relative_numbers: bool,
     pub syntax_highlighting: bool,
+    pub highlight_cache: HighlightCache,
+    /// Positions set with `m{a-z}`, as `(row, col)`. They follow lines
+    /// inserted or deleted above them.
+    pub marks: HashMap<char, (usize, usize)>,
+    /// Lines a running `:global` has still to visit.
+    pub global_lines: GlobalLines,
 }
 impl Buffer {
     pub fn new() -> Self {
@@ -25,33 +42,49 @@ impl Buffer {
             cursor: Cursor::new(),
             file_path: None,
             modified: false,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
+            saved_change: 0,
+            undo_tree: UndoTree::new(),
+            undo_dir: None,
             line_numbers: true,
+            relative_numbers: false,
             syntax_highlighting: true,
+            highlight_cache: HighlightCache::new(),
+            marks: HashMap::new(),
+            global_lines: GlobalLines::default(),
         }
     }
-    pub fn from_file(path: &str) -> Result<Self> {
+    pub fn from_file(path: &str, undo_dir: Option<PathBuf>) -> Result<Self> {
         let content = if Path::new(path).exists() {
             Rope::from_str(&fs::read_to_string(path)?)
         } else {
             Rope::new()
         };
+        let undo_tree = undo_dir
+            .as_ref()
+            .and_then(|dir| UndoTree::read_from(&undo_file_path(dir, path), &content))
+            .unwrap_or_else(UndoTree::new);
         Ok(Self {
             content,
             cursor: Cursor::new(),
             file_path: Some(path.to_string()),
             modified: false,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
+            saved_change: undo_tree.current(),
+            undo_tree,
+            undo_dir,
             line_numbers: true,
+            relative_numbers: false,
             syntax_highlighting: true,
+            highlight_cache: HighlightCache::new(),
+            marks: HashMap::new(),
+            global_lines: GlobalLines::default(),
         })
     }
     pub fn save(&mut self) -> Result<()> {
-        if let Some(path) = &self.file_path {
-            fs::write(path, self.content.to_string())?;
+        if let Some(path) = self.file_path.clone() {
+            fs::write(&path, self.content.to_string())?;
             self.modified = false;
+            self.saved_change = self.undo_tree.change_number();
+            self.write_undo_file(&path)?;
         }
         Ok(())
     }
@@ -59,125 +92,248 @@ impl Buffer {
         fs::write(path, self.content.to_string())?;
         self.file_path = Some(path.to_string());
         self.modified = false;
+        self.saved_change = self.undo_tree.change_number();
+        self.write_undo_file(path)?;
+        Ok(())
+    }
+    fn write_undo_file(&mut self, path: &str) -> Result<()> {
+        if let Some(dir) = &self.undo_dir {
+            let undo_path = undo_file_path(dir, path);
+            self.undo_tree.write_to(&undo_path, &self.content)?;
+        }
         Ok(())
     }
     pub fn save_state(&mut self) {
-        let state = BufferState {
-            content: self.content.clone(),
-            cursor: self.cursor.clone(),
-        };
-        self.undo_stack.push(state);
-        self.redo_stack.clear();
-        if self.undo_stack.len() > 1000 {
-            self.undo_stack.remove(0);
+        self.undo_tree.checkpoint();
+    }
+    pub fn begin_undo_group(&mut self) {
+        self.undo_tree.begin_group();
+    }
+    pub fn end_undo_group(&mut self) {
+        self.undo_tree.end_group();
+    }
+    pub fn undo(&mut self) -> bool {
+        let pos = self.undo_tree.undo(&mut self.content);
+        self.after_history_jump(pos)
+    }
+    pub fn redo(&mut self) -> bool {
+        let pos = self.undo_tree.redo(&mut self.content);
+        self.after_history_jump(pos)
+    }
+    pub fn undo_goto(&mut self, change: usize) -> bool {
+        let pos = self.undo_tree.goto(change, &mut self.content);
+        self.after_history_jump(pos)
+    }
+    pub fn earlier(&mut self, travel: UndoTravel) -> bool {
+        let pos = self.undo_tree.earlier(travel, &mut self.content);
+        self.after_history_jump(pos)
+    }
+    pub fn later(&mut self, travel: UndoTravel) -> bool {
+        let pos = self.undo_tree.later(travel, &mut self.content);
+        self.after_history_jump(pos)
+    }
+    fn after_history_jump(&mut self, pos: Option<usize>) -> bool {
+        match pos {
+            Some(pos) => {
+                self.set_cursor_char_index(pos);
+                self.invalidate_highlight(0);
+                // Lines taken away by the jump pull marks past the end back
+                // onto the last line, as deleting them would.
+                let last = text::line_count(&self.content) - 1;
+                for (row, _) in self.marks.values_mut() {
+                    *row = (*row).min(last);
+                }
+                self.global_lines.truncate_after(last);
+                self.modified = self.undo_tree.current() != self.saved_change;
+                true
+            }
+            None => false,
         }
     }
-    pub fn undo(&mut self) {
-        if let Some(state) = self.undo_stack.pop() {
-            let current_state = BufferState {
-                content: self.content.clone(),
-                cursor: self.cursor.clone(),
-            };
-            self.redo_stack.push(current_state);
-            self.content = state.content;
-            self.cursor = state.cursor;
+    pub fn insert_text(&mut self, pos: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let line = self.content.char_to_line(pos);
+        // Text put in front of a whole line pushes that line down too.
+        let before_line = pos == self.content.line_to_char(line);
+        self.content.insert(pos, text);
+        let added = text.matches('\n').count();
+        let moves = |row: usize| row > line || (before_line && row == line);
+        for (row, _) in self.marks.values_mut() {
+            if moves(*row) {
+                *row += added;
+            }
         }
+        self.global_lines.inserted(moves, added);
+        self.undo_tree.record(EditOp::Insert {
+            pos,
+            text: text.to_string(),
+        });
+        self.invalidate_highlight(line);
+        self.modified = true;
     }
-    pub fn redo(&mut self) {
-        if let Some(state) = self.redo_stack.pop() {
-            let current_state = BufferState {
-                content: self.content.clone(),
-                cursor: self.cursor.clone(),
-            };
-            self.undo_stack.push(current_state);
-            self.content = state.content;
-            self.cursor = state.cursor;
+    pub fn remove_text(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
         }
+        let line = self.content.char_to_line(range.start);
+        let last = self.content.char_to_line(range.end);
+        let whole_lines = range.start == self.content.line_to_char(line)
+            && range.end == self.content.line_to_char(last);
+        let text = self.content.slice(range.clone()).to_string();
+        self.content.remove(range.clone());
+        let removed = last - line;
+        for (row, _) in self.marks.values_mut() {
+            if *row > last {
+                *row -= removed;
+            } else if *row > line {
+                *row = line;
+            }
+        }
+        self.global_lines.removed(line, last, whole_lines);
+        self.undo_tree.record(EditOp::Delete {
+            pos: range.start,
+            text,
+        });
+        self.invalidate_highlight(line);
+        self.modified = true;
     }
     pub fn insert_char(&mut self, c: char) {
         self.save_state();
         let pos = self.cursor_to_char_index();
-        self.content.insert_char(pos, c);
+        self.insert_text(pos, &c.to_string());
         self.cursor.col += 1;
-        self.modified = true;
+        self.cursor.update_desired_col(&self.content);
     }
     pub fn insert_newline(&mut self) {
         self.save_state();
         let pos = self.cursor_to_char_index();
-        self.content.insert_char(pos, '\n');
+        self.insert_text(pos, "\n");
         self.cursor.row += 1;
         self.cursor.col = 0;
-        self.modified = true;
+        self.cursor.desired_col = 0;
     }
     pub fn backspace(&mut self) {
         if self.cursor.col > 0 {
             self.save_state();
-            self.cursor.col -= 1;
-            let pos = self.cursor_to_char_index();
-            self.content.remove(pos..pos + 1);
-            self.modified = true;
+            let end = self.cursor_to_char_index();
+            let line = text::line_text(&self.content, self.cursor.row);
+            self.cursor.col = text::prev_grapheme(&line, self.cursor.col);
+            let start = self.cursor_to_char_index();
+            self.remove_text(start..end);
         } else if self.cursor.row > 0 {
             self.save_state();
+            let end = self.content.line_to_char(self.cursor.row);
             let prev_line_len = self.get_line_length(self.cursor.row - 1);
             self.cursor.row -= 1;
             self.cursor.col = prev_line_len;
-            let pos = self.cursor_to_char_index();
-            self.content.remove(pos..pos + 1);
-            self.modified = true;
+            let start = self.cursor_to_char_index();
+            self.remove_text(start..end);
         }
+        self.cursor.update_desired_col(&self.content);
     }
     pub fn delete_char(&mut self) {
         self.save_state();
         let pos = self.cursor_to_char_index();
-        if pos < self.content.len_chars() {
-            self.content.remove(pos..pos + 1);
-            self.modified = true;
-        }
-    }
-    pub fn delete_line(&mut self) {
-        self.save_state();
-        let line_start = self.content.line_to_char(self.cursor.row);
-        let line_end = if self.cursor.row + 1 < self.content.len_lines() {
+        let line_len = self.get_line_length(self.cursor.row);
+        let end = if self.cursor.col < line_len {
+            let line = text::line_text(&self.content, self.cursor.row);
+            pos + text::next_grapheme(&line, self.cursor.col) - self.cursor.col
+        } else if self.cursor.row + 1 < self.content.len_lines() {
             self.content.line_to_char(self.cursor.row + 1)
         } else {
             self.content.len_chars()
         };
-        self.content.remove(line_start..line_end);
-        self.cursor.col = 0;
-        self.modified = true;
-    }
-    pub fn get_current_line(&self) -> String {
-        if self.cursor.row < self.content.len_lines() {
-            self.content.line(self.cursor.row).to_string()
-        } else {
-            String::new()
-        }
+        self.remove_text(pos..end);
     }
     pub fn paste_after(&mut self, text: &str) {
         self.save_state();
-        self.cursor.col += 1;
+        let line = text::line_text(&self.content, self.cursor.row);
+        self.cursor.col = text::next_grapheme(&line, self.cursor.col).min(line.chars().count());
         let pos = self.cursor_to_char_index();
-        self.content.insert(pos, text);
-        self.modified = true;
+        self.put_chars(pos, text);
     }
     pub fn paste_before(&mut self, text: &str) {
         self.save_state();
         let pos = self.cursor_to_char_index();
-        self.content.insert(pos, text);
-        self.modified = true;
+        self.put_chars(pos, text);
     }
-    pub fn get_line_length(&self, line: usize) -> usize {
-        if line < self.content.len_lines() {
-            let line_content = self.content.line(line);
-            let line_str = line_content.to_string();
-            if line_str.ends_with('\n') {
-                line_str.len() - 1
+    /// Inserts charwise text, leaving the cursor on its last char, or on its
+    /// first char when it spans lines.
+    fn put_chars(&mut self, pos: usize, text: &str) {
+        self.insert_text(pos, text);
+        let len = text.chars().count();
+        if text.contains('\n') || len == 0 {
+            self.set_cursor_char_index(pos);
+        } else {
+            self.set_cursor_char_index(pos + len - 1);
+        }
+    }
+    /// Puts whole lines below the cursor line, or above it when `after` is
+    /// false, and moves to the first non-blank of the first new line.
+    pub fn paste_lines(&mut self, text: &str, after: bool) {
+        self.save_state();
+        let row = if after {
+            self.cursor.row + 1
+        } else {
+            self.cursor.row
+        };
+        if row >= self.content.len_lines() {
+            // Below a last line that has no line ending.
+            let body = text.strip_suffix('\n').unwrap_or(text);
+            self.insert_text(self.content.len_chars(), &format!("\n{}", body));
+        } else {
+            self.insert_text(self.content.line_to_char(row), text);
+        }
+        self.cursor.row = row;
+        self.cursor.move_line_first_non_whitespace(&self.content);
+    }
+    /// Puts a block so each of its lines lands on a successive buffer line at
+    /// the cursor's display column, padding short lines with spaces.
+    pub fn paste_block(&mut self, text: &str, after: bool, count: usize) {
+        self.save_state();
+        let line = text::line_text(&self.content, self.cursor.row);
+        let col = if after && !line.is_empty() {
+            text::next_grapheme(&line, self.cursor.col).min(line.chars().count())
+        } else {
+            self.cursor.col
+        };
+        let display = text::display_col(&line, col);
+        let pieces: Vec<&str> = text.split('\n').collect();
+        let width = pieces
+            .iter()
+            .map(|piece| text::display_col(piece, piece.chars().count()))
+            .max()
+            .unwrap_or(0);
+        for (i, piece) in pieces.iter().enumerate() {
+            let row = self.cursor.row + i;
+            if row >= self.content.len_lines() {
+                self.insert_text(self.content.len_chars(), "\n");
+            }
+            let line = text::line_text(&self.content, row);
+            let line_width = text::display_col(&line, line.chars().count());
+            let line_start = self.content.line_to_char(row);
+            let at = if line_width < display {
+                line.chars().count()
             } else {
-                line_str.len()
+                text::col_for_display(&line, display)
+            };
+            let piece_width = text::display_col(piece, piece.chars().count());
+            let padded = format!("{}{}", piece, " ".repeat(width - piece_width));
+            let mut insert = " ".repeat(display.saturating_sub(line_width));
+            insert.push_str(&padded.repeat(count));
+            if at >= line.chars().count() {
+                insert.truncate(insert.trim_end_matches(' ').len());
             }
-        } else {
-            0
+            self.insert_text(line_start + at, &insert);
         }
+        self.cursor.col =
+            text::col_for_display(&text::line_text(&self.content, self.cursor.row), display);
+        self.cursor.update_desired_col(&self.content);
+    }
+    pub fn get_line_length(&self, line: usize) -> usize {
+        text::line_len(&self.content, line)
     }
     pub fn get_line(&self, line_num: usize) -> Option<String> {
         if line_num < self.content.len_lines() {
@@ -186,109 +342,401 @@ impl Buffer {
             None
         }
     }
+    pub fn invalidate_highlight(&mut self, line: usize) {
+        self.highlight_cache.invalidate_from(line);
+    }
     pub fn line_count(&self) -> usize {
         self.content.len_lines()
     }
     fn cursor_to_char_index(&self) -> usize {
         let line_start = self.content.line_to_char(self.cursor.row);
-        line_start + self.cursor.col
+        line_start + self.cursor.col.min(self.get_line_length(self.cursor.row))
     }
-    pub fn delete_to_line_end(&mut self) {
-        self.save_state();
-        let line_start = self.content.line_to_char(self.cursor.row);
-        let line_end_pos = line_start + self.cursor.col;
-        let actual_line_end = if self.cursor.row + 1 < self.content.len_lines() {
-            self.content.line_to_char(self.cursor.row + 1) - 1
-        } else {
-            self.content.len_chars()
-        };
-        if line_end_pos < actual_line_end {
-            self.content.remove(line_end_pos..actual_line_end);
-            self.modified = true;
+    pub fn set_cursor_char_index(&mut self, pos: usize) {
+        let pos = pos.min(self.content.len_chars());
+        let row = self.content.char_to_line(pos);
+        self.cursor.row = row;
+        self.cursor.col = pos - self.content.line_to_char(row);
+        self.cursor.update_desired_col(&self.content);
+    }
+    /// Moves to the next match of `regex` after the cursor, or the previous
+    /// one when `forward` is unset, wrapping around the buffer.
+    pub fn search(&mut self, regex: &Regex, forward: bool) -> Option<Match> {
+        let found = search::find(&self.content, regex, self.cursor_to_char_index(), forward)?;
+        self.set_cursor_char_index(found.range.start);
+        Some(found)
+    }
+    /// Text covered by `range`. Linewise text always ends with a newline.
+    pub fn range_text(&self, range: &MotionRange) -> String {
+        let mut text = self.content.slice(range.start..range.end).to_string();
+        if range.linewise && !text.ends_with('\n') {
+            text.push('\n');
         }
+        text
     }
-    pub fn get_text_to_line_end(&self) -> String {
-        let line_start = self.content.line_to_char(self.cursor.row);
-        let line_end_pos = line_start + self.cursor.col;
-        let actual_line_end = if self.cursor.row + 1 < self.content.len_lines() {
-            self.content.line_to_char(self.cursor.row + 1) - 1
+    pub fn delete_range(&mut self, range: &MotionRange) -> String {
+        let text = self.range_text(range);
+        let mut start = range.start;
+        if range.linewise && range.end == self.content.len_chars() && start > 0 {
+            // Deleting the last lines also removes the line ending before them.
+            let ends_with_newline =
+                range.end > range.start && self.content.char(range.end - 1) == '\n';
+            if !ends_with_newline {
+                start -= 1;
+            }
+        }
+        self.remove_text(start..range.end);
+        if range.linewise {
+            let len = self.content.len_chars();
+            // Past a trailing line ending, land on the last real line.
+            let row = if start >= len && start > 0 {
+                self.content.char_to_line(len - 1)
+            } else {
+                self.content.char_to_line(start)
+            };
+            self.cursor.row = row.min(self.line_count().saturating_sub(1));
+            self.cursor.move_line_first_non_whitespace(&self.content);
         } else {
-            self.content.len_chars()
-        };
-        if line_end_pos < actual_line_end {
-            self.content
-                .slice(line_end_pos..actual_line_end)
-                .to_string()
+            self.cursor.set_char_index(&self.content, start);
+        }
+        text
+    }
+    /// Empties the lines in `range` for `cc`, keeping the first line's
+    /// indentation and one line to insert into.
+    pub fn clear_lines(&mut self, range: &MotionRange) -> String {
+        let text = self.range_text(range);
+        let first = range.first_line(&self.content);
+        let last = range.last_line(&self.content);
+        let indent = leading_whitespace(&text::line_text(&self.content, first));
+        let start = self.content.line_to_char(first) + indent.chars().count();
+        let end = self.content.line_to_char(last) + self.get_line_length(last);
+        self.remove_text(start..end.max(start));
+        self.cursor.set_char_index(&self.content, start);
+        text
+    }
+    pub fn shift_lines(&mut self, first: usize, last: usize, right: bool) {
+        for row in first..=last.min(self.line_count().saturating_sub(1)) {
+            let line = text::line_text(&self.content, row);
+            if line.is_empty() {
+                continue;
+            }
+            let line_start = self.content.line_to_char(row);
+            if right {
+                self.insert_text(line_start, &" ".repeat(text::SHIFT_WIDTH));
+            } else {
+                let mut removed = 0;
+                let mut width = 0;
+                for c in line.chars() {
+                    if width >= text::SHIFT_WIDTH || !(c == ' ' || c == '\t') {
+                        break;
+                    }
+                    width += if c == '\t' { text::SHIFT_WIDTH } else { 1 };
+                    removed += 1;
+                }
+                self.remove_text(line_start..line_start + removed);
+            }
+        }
+        self.cursor.row = first;
+        self.cursor.move_line_first_non_whitespace(&self.content);
+    }
+    pub fn change_case(&mut self, range: &MotionRange, operator: Operator) {
+        let original = self.content.slice(range.start..range.end).to_string();
+        let changed: String = original
+            .chars()
+            .map(|c| match operator {
+                Operator::Lowercase => c.to_lowercase().collect::<String>(),
+                Operator::Uppercase => c.to_uppercase().collect(),
+                _ if c.is_uppercase() => c.to_lowercase().collect(),
+                _ => c.to_uppercase().collect(),
+            })
+            .collect();
+        if changed != original {
+            self.remove_text(range.start..range.end);
+            self.insert_text(range.start, &changed);
+        }
+        if range.linewise {
+            self.cursor.row = range.first_line(&self.content);
+            self.cursor.move_line_first_non_whitespace(&self.content);
         } else {
-            String::new()
+            self.cursor.set_char_index(&self.content, range.start);
         }
     }
-    pub fn find_next(&mut self, pattern: &str) {
-        let content_str = self.content.to_string();
-        let current_pos = self.cursor_to_char_index();
-        if let Some(pos) = content_str[current_pos + 1..].find(pattern) {
-            let absolute_pos = current_pos + 1 + pos;
-            let line = self.content.char_to_line(absolute_pos);
-            let line_start = self.content.line_to_char(line);
-            let col = absolute_pos - line_start;
-            self.cursor.row = line;
-            self.cursor.col = col;
-            self.cursor.desired_col = col;
+    /// Replaces every char in `range` except line endings with `c`.
+    pub fn replace_chars(&mut self, range: &MotionRange, c: char) {
+        let original = self.content.slice(range.start..range.end).to_string();
+        let replaced: String = original
+            .chars()
+            .map(|ch| if ch == '\n' || ch == '\r' { ch } else { c })
+            .collect();
+        if replaced != original {
+            self.remove_text(range.start..range.end);
+            self.insert_text(range.start, &replaced);
         }
     }
-    pub fn find_previous(&mut self, pattern: &str) {
-        let content_str = self.content.to_string();
-        let current_pos = self.cursor_to_char_index();
-        if current_pos > 0 {
-            if let Some(pos) = content_str[..current_pos].rfind(pattern) {
-                let line = self.content.char_to_line(pos);
-                let line_start = self.content.line_to_char(line);
-                let col = pos - line_start;
-                self.cursor.row = line;
-                self.cursor.col = col;
-                self.cursor.desired_col = col;
+    /// Puts a copy of lines `first..=last` below line `dest`, counted from
+    /// 1 with 0 meaning above the first line, for `:t`.
+    pub fn copy_lines(&mut self, first: usize, last: usize, dest: usize) {
+        let text = self.range_text(&MotionRange::lines(&self.content, first, last));
+        self.put_lines_below(dest, &text);
+    }
+    /// Moves lines `first..=last` below line `dest` as `copy_lines` counts
+    /// it, for `:m`. `dest` must not fall inside the lines moved.
+    pub fn move_lines(&mut self, first: usize, last: usize, dest: usize) {
+        let range = MotionRange::lines(&self.content, first, last);
+        let text = self.range_text(&range);
+        self.delete_range(&range);
+        let dest = if dest > last {
+            dest - (last - first + 1)
+        } else {
+            dest
+        };
+        self.put_lines_below(dest, &text);
+    }
+    /// Leaves the cursor on the last line put.
+    fn put_lines_below(&mut self, dest: usize, text: &str) {
+        self.cursor.row = dest.saturating_sub(1);
+        self.paste_lines(text, dest > 0);
+        self.cursor.row += text.matches('\n').count().saturating_sub(1);
+        self.cursor.move_line_first_non_whitespace(&self.content);
+    }
+    /// Joins lines `first` through `last` into one, like `J`: leading blanks
+    /// of each joined line become a single space, or none before `)` or after
+    /// a line that already ends in a blank.
+    pub fn join_lines(&mut self, first: usize, last: usize) {
+        let len = self.content.len_chars();
+        let mut lines = self.content.len_lines();
+        if len > 0 && self.content.char(len - 1) == '\n' {
+            lines -= 1;
+        }
+        for _ in first..last.min(lines.saturating_sub(1)) {
+            let line = text::line_text(&self.content, first);
+            let next = text::line_text(&self.content, first + 1);
+            let next_indent = leading_whitespace(&next).chars().count();
+            let join = self.content.line_to_char(first) + line.chars().count();
+            let next_start = self.content.line_to_char(first + 1);
+            let rest = next.trim_start();
+            let separator = if line.is_empty()
+                || line.ends_with([' ', '\t'])
+                || rest.is_empty()
+                || rest.starts_with(')')
+            {
+                ""
+            } else {
+                " "
+            };
+            self.remove_text(join..next_start + next_indent);
+            self.insert_text(join, separator);
+            self.cursor.row = first;
+            self.cursor.col = line.chars().count();
+        }
+        self.cursor.update_desired_col(&self.content);
+    }
+    /// Rewraps the paragraphs in lines `first` through `last` to `width`
+    /// columns for `gq`, keeping each paragraph's first-line indent.
+    pub fn format_lines(&mut self, first: usize, last: usize, width: usize) {
+        let last = last.min(self.line_count().saturating_sub(1));
+        let mut formatted: Vec<String> = Vec::new();
+        let mut paragraph: Vec<String> = Vec::new();
+        let flush = |paragraph: &mut Vec<String>, formatted: &mut Vec<String>| {
+            let Some(head) = paragraph.first() else {
+                return;
+            };
+            let indent = leading_whitespace(head);
+            let indent_width = text::display_col(&indent, indent.chars().count());
+            let mut current = indent.clone();
+            let mut current_width = indent_width;
+            for word in paragraph.iter().flat_map(|line| line.split_whitespace()) {
+                let word_width = text::display_col(word, word.chars().count());
+                if current_width > indent_width && current_width + 1 + word_width > width {
+                    formatted.push(std::mem::replace(&mut current, indent.clone()));
+                    current_width = indent_width;
+                }
+                if current_width > indent_width {
+                    current.push(' ');
+                    current_width += 1;
+                }
+                current.push_str(word);
+                current_width += word_width;
+            }
+            formatted.push(current);
+            paragraph.clear();
+        };
+        for row in first..=last {
+            let line = text::line_text(&self.content, row);
+            if line.trim().is_empty() {
+                flush(&mut paragraph, &mut formatted);
+                formatted.push(String::new());
+            } else {
+                paragraph.push(line);
             }
         }
+        flush(&mut paragraph, &mut formatted);
+        let start = self.content.line_to_char(first);
+        let end = self.content.line_to_char(last) + self.get_line_length(last);
+        let replacement = formatted.join("\n");
+        if self.content.slice(start..end) != replacement.as_str() {
+            self.remove_text(start..end);
+            self.insert_text(start, &replacement);
+        }
+        self.cursor.row = first + formatted.len().saturating_sub(1);
+        self.cursor.move_line_first_non_whitespace(&self.content);
     }
-    pub fn delete_word(&mut self) {
-        self.save_state();
-        let cursor_pos = self.cursor_to_char_index();
-        let content_str = self.content.to_string();
-        let chars: Vec<char> = content_str.chars().collect();
-        let mut end_pos = cursor_pos;
-        while end_pos < chars.len() && chars[end_pos].is_alphanumeric() {
-            end_pos += 1;
+    /// The lines of `block` joined with newlines, as stored in a blockwise
+    /// register.
+    pub fn block_text(&self, block: &Block) -> String {
+        (block.first_row..=block.last_row)
+            .map(|row| {
+                let (start, end) = block.cols(&self.content, row);
+                text::line_text(&self.content, row)
+                    .chars()
+                    .skip(start)
+                    .take(end - start)
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    pub fn delete_block(&mut self, block: &Block) {
+        for row in block.first_row..=block.last_row {
+            let (start, end) = block.cols(&self.content, row);
+            let line_start = self.content.line_to_char(row);
+            self.remove_text(line_start + start..line_start + end);
         }
-        while end_pos < chars.len() && chars[end_pos].is_whitespace() {
-            end_pos += 1;
+        self.cursor.row = block.first_row;
+        self.cursor.col = block.cols(&self.content, block.first_row).0;
+        self.cursor.clamp_to_buffer(&self.content);
+    }
+    /// Inserts `text` on each of `rows` at display column `display`, or at
+    /// the line end when it is `None`, for block `I` and `A`. Lines too short
+    /// to reach the column are padded with spaces when `pad` is set and
+    /// skipped otherwise.
+    pub fn insert_block_text(
+        &mut self,
+        rows: RangeInclusive<usize>,
+        display: Option<usize>,
+        text: &str,
+        pad: bool,
+    ) {
+        for row in rows {
+            let line = text::line_text(&self.content, row);
+            let len = line.chars().count();
+            let width = text::display_col(&line, len);
+            let line_start = self.content.line_to_char(row);
+            match display {
+                None => self.insert_text(line_start + len, text),
+                Some(display) if width < display => {
+                    if pad {
+                        let padding = " ".repeat(display - width);
+                        self.insert_text(line_start + len, &format!("{}{}", padding, text));
+                    }
+                }
+                Some(display) => {
+                    let col = text::col_for_display(&line, display);
+                    self.insert_text(line_start + col, text);
+                }
+            }
         }
-        if cursor_pos < end_pos {
-            self.content.remove(cursor_pos..end_pos);
-            self.modified = true;
+    }
+    /// Re-indents lines from the nearest non-blank line above, one level
+    /// deeper after an opening bracket and one shallower before a closing one.
+    pub fn reindent_lines(&mut self, first: usize, last: usize) {
+        let last = last.min(self.line_count().saturating_sub(1));
+        for row in first..=last {
+            let line = text::line_text(&self.content, row);
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() {
+                continue;
+            }
+            let previous = (0..row)
+                .rev()
+                .map(|r| text::line_text(&self.content, r))
+                .find(|l| !l.trim().is_empty());
+            let mut indent = previous.as_deref().map_or(0, |prev| {
+                let width = text::display_col(prev, leading_whitespace(prev).chars().count());
+                let opens = prev.trim_end().ends_with(['{', '(', '[']);
+                width + if opens { text::SHIFT_WIDTH } else { 0 }
+            });
+            if trimmed.starts_with(['}', ')', ']']) {
+                indent = indent.saturating_sub(text::SHIFT_WIDTH);
+            }
+            let current = leading_whitespace(&line);
+            let wanted = " ".repeat(indent);
+            if current != wanted {
+                let line_start = self.content.line_to_char(row);
+                self.remove_text(line_start..line_start + current.chars().count());
+                self.insert_text(line_start, &wanted);
+            }
         }
+        self.cursor.row = first;
+        self.cursor.move_line_first_non_whitespace(&self.content);
     }
-    pub fn delete_to_line_start(&mut self) {
-        self.save_state();
-        let line_start = self.content.line_to_char(self.cursor.row);
-        let cursor_pos = line_start + self.cursor.col;
-        if cursor_pos > line_start {
-            self.content.remove(line_start..cursor_pos);
-            self.cursor.col = 0;
-            self.modified = true;
+}
+fn leading_whitespace(line: &str) -> String {
+    line.chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect()
+}
+/// Lines a running `:global` has still to visit, in order. They follow
+/// edits like marks, and lines deleted or joined into another one are
+/// dropped. Rows are kept less `shift`, so that an edit above all of them,
+/// as when the command deletes the line it runs on, moves them at once.
+#[derive(Debug, Default)]
+pub struct GlobalLines {
+    rows: VecDeque<usize>,
+    shift: isize,
+}
+impl GlobalLines {
+    pub fn new(rows: Vec<usize>) -> Self {
+        Self {
+            rows: rows.into(),
+            shift: 0,
         }
     }
-    pub fn yank_word(&self) -> String {
-        let cursor_pos = self.cursor_to_char_index();
-        let content_str = self.content.to_string();
-        let chars: Vec<char> = content_str.chars().collect();
-        let mut end_pos = cursor_pos;
-        while end_pos < chars.len() && chars[end_pos].is_alphanumeric() {
-            end_pos += 1;
+    /// Takes the next line to visit.
+    pub fn pop_front(&mut self) -> Option<usize> {
+        let row = self.rows.pop_front()?;
+        Some(self.row(row))
+    }
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.shift = 0;
+    }
+    fn row(&self, kept: usize) -> usize {
+        (kept as isize + self.shift) as usize
+    }
+    /// Drops the lines after `last`, gone after an undo.
+    fn truncate_after(&mut self, last: usize) {
+        let len = self.rows.partition_point(|&kept| self.row(kept) <= last);
+        self.rows.truncate(len);
+    }
+    /// Moves the lines `moves` says down by the `added` lines inserted
+    /// above them, which are always the last ones.
+    fn inserted(&mut self, moves: impl Fn(usize) -> bool, added: usize) {
+        let first = self.rows.partition_point(|&kept| !moves(self.row(kept)));
+        if first == 0 {
+            self.shift += added as isize;
+        } else {
+            self.rows.range_mut(first..).for_each(|kept| *kept += added);
         }
-        if cursor_pos < end_pos {
-            chars[cursor_pos..end_pos].iter().collect()
+    }
+    /// Follows the removal of text from `line` to `last`: lines in between
+    /// are dropped, as is `line` when `whole_lines` went, and the later
+    /// ones move up.
+    fn removed(&mut self, line: usize, last: usize, whole_lines: bool) {
+        let kept = self.rows.partition_point(|&kept| {
+            let row = self.row(kept);
+            row < line || (row == line && !whole_lines)
+        });
+        let dropped = self.rows.partition_point(|&kept| {
+            let row = self.row(kept);
+            row < last || (row == last && !whole_lines)
+        });
+        self.rows.drain(kept..dropped);
+        let removed = last - line;
+        if kept == 0 {
+            self.shift -= removed as isize;
         } else {
-            String::new()
+            self.rows.range_mut(kept..).for_each(|row| *row -= removed);
         }
     }
 }
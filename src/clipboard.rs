@@ -0,0 +1,202 @@
+use crate::registers::{Register, RegisterKind};
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crossterm::{execute, Command};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command as Process, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// OSC 52 escape that asks the terminal to put `text` on the clipboard
+/// (`"+`) or the primary selection (`"*`). Works over SSH, since the
+/// terminal on the user's machine does the copying.
+pub struct SetClipboard<'a> {
+    pub selection: char,
+    pub text: &'a str,
+}
+impl Command for SetClipboard<'_> {
+    fn write_ansi(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        let target = if self.selection == '*' { 'p' } else { 'c' };
+        write!(f, "\x1b]52;{};{}\x07", target, STANDARD.encode(self.text))
+    }
+    #[cfg(windows)]
+    fn execute_winapi(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+/// The `"+` and `"*` registers. Writes go out as OSC 52 on `output` and,
+/// when configured, to an external copy command. Reads use the external
+/// paste command, falling back to what was last written.
+pub struct Clipboard {
+    output: Box<dyn Write>,
+    last: HashMap<char, Register>,
+    /// Copy commands run in the background and report failures here.
+    failures: (Sender<String>, Receiver<String>),
+}
+impl Clipboard {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Self {
+            output,
+            last: HashMap::new(),
+            failures: mpsc::channel(),
+        }
+    }
+    pub fn is_clipboard(name: char) -> bool {
+        matches!(name, '+' | '*')
+    }
+    pub fn write(
+        &mut self,
+        selection: char,
+        register: &Register,
+        copy_command: Option<&str>,
+    ) -> Result<()> {
+        self.last.insert(selection, register.clone());
+        execute!(
+            self.output,
+            SetClipboard {
+                selection,
+                text: &register.text,
+            }
+        )?;
+        if let Some(command) = copy_command {
+            let mut child = shell(command).stdin(Stdio::piped()).spawn()?;
+            let stdin = child.stdin.take();
+            let text = register.text.clone();
+            let command = command.to_string();
+            let failures = self.failures.0.clone();
+            // A slow copy command must not hold up typing, so it is fed
+            // and reaped on its own thread.
+            thread::spawn(move || {
+                let written = match stdin {
+                    // Dropping stdin at the end of the arm closes it.
+                    Some(mut stdin) => stdin.write_all(text.as_bytes()),
+                    None => Ok(()),
+                };
+                let failure = match child.wait() {
+                    Ok(status) if !status.success() => {
+                        Some(format!("{} failed with {}", command, status))
+                    }
+                    Ok(_) => written.err().map(|err| err.to_string()),
+                    Err(err) => Some(err.to_string()),
+                };
+                if let Some(failure) = failure {
+                    let _ = failures.send(failure);
+                }
+            });
+        }
+        Ok(())
+    }
+    /// A copy command that failed since the last call.
+    pub fn take_failure(&self) -> Option<String> {
+        self.failures.1.try_recv().ok()
+    }
+    pub fn read(&self, selection: char, paste_command: Option<&str>) -> Option<Register> {
+        let last = self.last.get(&selection);
+        let Some(command) = paste_command else {
+            return last.cloned();
+        };
+        let output = shell(command).stdin(Stdio::null()).output().ok()?;
+        if !output.status.success() {
+            return last.cloned();
+        }
+        let text = String::from_utf8_lossy(&output.stdout).into_owned();
+        // Keep the type of our own yank while the clipboard still holds it.
+        match last {
+            Some(register) if register.text == text => Some(register.clone()),
+            _ if text.ends_with('\n') => Some(Register::new(text, RegisterKind::Linewise)),
+            _ => Some(Register::charwise(text)),
+        }
+    }
+    /// Contents written during this session, for `:registers`.
+    pub fn last(&self, selection: char) -> Option<&Register> {
+        self.last.get(&selection)
+    }
+}
+fn shell(command: &str) -> Process {
+    let mut process = Process::new("sh");
+    process.arg("-c").arg(command).stderr(Stdio::null());
+    process
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    /// Output the test can still read after handing it to the clipboard.
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    /// Polls until `check` holds, failing after a few seconds.
+    fn eventually<T>(mut check: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(value) = check() {
+                return value;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_write_emits_osc52() {
+        let output = Captured::default();
+        let mut clipboard = Clipboard::new(Box::new(output.clone()));
+        let register = Register::charwise("hello".to_string());
+        clipboard.write('+', &register, None).unwrap();
+        assert_eq!(output.0.borrow().as_slice(), b"\x1b]52;c;aGVsbG8=\x07");
+        output.0.borrow_mut().clear();
+        clipboard.write('*', &register, None).unwrap();
+        assert_eq!(output.0.borrow().as_slice(), b"\x1b]52;p;aGVsbG8=\x07");
+        assert_eq!(clipboard.last('+').map(|r| r.text.as_str()), Some("hello"));
+    }
+
+    #[test]
+    fn test_copy_command_runs_in_background() {
+        let path = std::env::temp_dir().join(format!("vimrust-clip-{}", std::process::id()));
+        let command = format!("cat > {}", path.display());
+        let mut clipboard = Clipboard::new(Box::new(Captured::default()));
+        let register = Register::charwise("copied".to_string());
+        clipboard.write('+', &register, Some(&command)).unwrap();
+        let text = eventually(|| {
+            std::fs::read_to_string(&path)
+                .ok()
+                .filter(|text| !text.is_empty())
+        });
+        assert_eq!(text, "copied");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_copy_command_is_reported() {
+        let mut clipboard = Clipboard::new(Box::new(Captured::default()));
+        let register = Register::charwise("x".to_string());
+        clipboard.write('+', &register, Some("exit 3")).unwrap();
+        let failure = eventually(|| clipboard.take_failure());
+        assert!(failure.starts_with("exit 3 failed"), "{}", failure);
+    }
+
+    #[test]
+    fn test_read_falls_back_to_last_write() {
+        let mut clipboard = Clipboard::new(Box::new(Captured::default()));
+        assert!(clipboard.read('+', None).is_none());
+        let register = Register::new("line\n".to_string(), RegisterKind::Linewise);
+        clipboard.write('+', &register, None).unwrap();
+        let read = clipboard.read('+', Some("printf 'line\\n'")).unwrap();
+        assert_eq!(read.kind, RegisterKind::Linewise);
+        assert_eq!(clipboard.read('+', Some("exit 1")).unwrap().text, "line\n");
+    }
+}
@@ -0,0 +1,218 @@
+use crate::motion::char_class;
+use std::ops::Range;
+
+/// Text typed after `:`, `/` or `?` (which stays as its first char) and the
+/// cursor in it, as a byte offset.
+#[derive(Debug, Clone, Default)]
+pub struct CommandLine {
+    pub text: String,
+    pub cursor: usize,
+    /// Completions shown for the word before the cursor.
+    pub wildmenu: Option<Wildmenu>,
+}
+/// Candidates for the word at `start..` that Tab and Shift-Tab cycle
+/// through, and the word as it was typed.
+#[derive(Debug, Clone)]
+pub struct Wildmenu {
+    pub start: usize,
+    pub typed: String,
+    pub items: Vec<String>,
+    /// `None` while the typed word is shown again after the last item.
+    pub selected: Option<usize>,
+}
+impl CommandLine {
+    /// Starts a new command line with `text`, the cursor after it.
+    pub fn start(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.cursor = text.len();
+        self.wildmenu = None;
+    }
+    pub fn clear(&mut self) {
+        self.start("");
+    }
+    /// Everything after the first char.
+    pub fn content(&self) -> &str {
+        self.text.get(1..).unwrap_or("")
+    }
+    /// Replaces everything after the first char, as history does.
+    pub fn set_content(&mut self, content: &str) {
+        self.text.truncate(1);
+        self.text.push_str(content);
+        self.cursor = self.text.len();
+    }
+    pub fn insert(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+    /// Deletes the char before the cursor. False when there is none.
+    pub fn backspace(&mut self) -> bool {
+        let Some(c) = self.text[1..self.cursor].chars().next_back() else {
+            return false;
+        };
+        self.cursor -= c.len_utf8();
+        self.text.remove(self.cursor);
+        true
+    }
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+    pub fn move_left(&mut self) {
+        if let Some(c) = self.text[1..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+    pub fn move_right(&mut self) {
+        if let Some(c) = self.text[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+    pub fn move_home(&mut self) {
+        self.cursor = 1.min(self.text.len());
+    }
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+    /// `Ctrl-w`: deletes the blanks before the cursor and then the word or
+    /// run of punctuation before them.
+    pub fn delete_word(&mut self) {
+        let before = &self.text[1..self.cursor];
+        let trimmed = before.trim_end();
+        let class = trimmed.chars().next_back().map(|c| char_class(c, false));
+        let start = trimmed
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| Some(char_class(c, false)) == class)
+            .last()
+            .map_or(trimmed.len(), |(i, _)| i);
+        self.replace(1 + start..self.cursor, "");
+    }
+    /// `Ctrl-u`: deletes everything before the cursor.
+    pub fn delete_to_start(&mut self) {
+        self.replace(1.min(self.cursor)..self.cursor, "");
+    }
+    /// Replaces `range` of the text and puts the cursor after `text`.
+    pub fn replace(&mut self, range: Range<usize>, text: &str) {
+        self.text.replace_range(range.clone(), text);
+        self.cursor = range.start + text.len();
+    }
+    /// Shows `items` as completions of the word starting at byte `start`
+    /// and puts in the first one, or the last going backwards. A single
+    /// item is put in without showing the menu.
+    pub fn complete(&mut self, start: usize, items: Vec<String>, forward: bool) {
+        match items.len() {
+            0 => {}
+            1 => self.replace(start..self.cursor, &items[0]),
+            _ => {
+                self.wildmenu = Some(Wildmenu {
+                    start,
+                    typed: self.text[start..self.cursor].to_string(),
+                    items,
+                    selected: None,
+                });
+                self.cycle(forward);
+            }
+        }
+    }
+    /// Puts in the next or previous completion; past either end the word
+    /// as typed comes back.
+    pub fn cycle(&mut self, forward: bool) {
+        let Some(menu) = &mut self.wildmenu else {
+            return;
+        };
+        let last = menu.items.len() - 1;
+        menu.selected = match (menu.selected, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(last),
+            (Some(i), true) if i < last => Some(i + 1),
+            (Some(i), false) if i > 0 => Some(i - 1),
+            _ => None,
+        };
+        let text = match menu.selected {
+            Some(i) => menu.items[i].clone(),
+            None => menu.typed.clone(),
+        };
+        let start = menu.start;
+        self.replace(start..self.cursor, &text);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str) -> CommandLine {
+        let mut line = CommandLine::default();
+        line.start(text);
+        line
+    }
+    fn items(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_editing_never_touches_the_first_char() {
+        let mut line = typed(":aé");
+        line.move_left();
+        assert_eq!(line.cursor, 2);
+        line.insert("b");
+        assert_eq!((line.text.as_str(), line.cursor), (":abé", 3));
+        line.move_right();
+        line.move_right();
+        assert_eq!(line.cursor, line.text.len());
+        assert!(line.backspace());
+        assert_eq!(line.text, ":ab");
+        line.move_home();
+        line.move_left();
+        assert_eq!(line.cursor, 1);
+        assert!(!line.backspace());
+        line.delete();
+        assert_eq!(line.text, ":b");
+        line.move_end();
+        line.delete_to_start();
+        assert_eq!((line.text.as_str(), line.cursor), (":", 1));
+        line.set_content("s/a/b");
+        assert_eq!((line.content(), line.cursor), ("s/a/b", 6));
+    }
+
+    #[test]
+    fn test_delete_word_takes_one_class_of_chars() {
+        let mut line = typed(":e src/main.rs  ");
+        line.delete_word();
+        assert_eq!(line.text, ":e src/main.");
+        line.delete_word();
+        assert_eq!(line.text, ":e src/main");
+        line.delete_word();
+        line.delete_word();
+        assert_eq!(line.text, ":e src");
+        line.delete_word();
+        line.delete_word();
+        assert_eq!(line.text, ":");
+        line.delete_word();
+        assert_eq!((line.text.as_str(), line.cursor), (":", 1));
+    }
+
+    #[test]
+    fn test_completions_cycle_back_to_what_was_typed() {
+        let mut line = typed(":set nu");
+        line.complete(5, items(&["number", "nuw"]), true);
+        assert_eq!(line.text, ":set number");
+        line.cycle(true);
+        assert_eq!(line.text, ":set nuw");
+        line.cycle(true);
+        assert_eq!(line.text, ":set nu");
+        assert_eq!(line.wildmenu.as_ref().unwrap().selected, None);
+        line.cycle(false);
+        assert_eq!(line.text, ":set nuw");
+        // Shift-Tab starts from the last one, and one item needs no menu.
+        let mut line = typed(":b");
+        line.complete(1, items(&["bnext", "buffer"]), false);
+        assert_eq!(line.text, ":buffer");
+        let mut line = typed(":wri");
+        line.complete(1, items(&["write"]), true);
+        assert_eq!(
+            (line.text.as_str(), line.wildmenu.is_none()),
+            (":write", true)
+        );
+    }
+}
@@ -1,169 +1,1175 @@
 use crate::buffer::Buffer;
-use anyhow::Result;
-use std::collections::HashMap;
+use crate::editor::Editor;
+use crate::ex::{self, LineRange};
+use crate::motion::MotionRange;
+use crate::normal::Operator;
+use crate::options::{BOOLEAN_OPTIONS, OPTION_NAMES};
+use crate::substitute;
+use crate::text;
+use crate::undo::UndoTravel;
+use crate::window::Split;
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
 
-#[allow(dead_code)]
+/// What a command takes besides its name.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Accepts {
+    pub range: bool,
+    pub bang: bool,
+    /// A register name as the first argument, as in `:d a`.
+    pub register: bool,
+    /// A count after any register, as in `:d 3`.
+    pub count: bool,
+    /// Any other text after the name.
+    pub args: bool,
+}
+/// A command line split up as far as the command's `Accepts` allows.
+#[derive(Debug, Clone)]
+pub struct CommandArgs<'a> {
+    /// Lines given before the name, `None` when there were none.
+    pub range: Option<LineRange>,
+    /// `!` right after the name.
+    pub bang: bool,
+    pub register: Option<char>,
+    pub count: Option<usize>,
+    /// The rest of the line, after the blanks following the name.
+    pub args: &'a str,
+}
+impl CommandArgs<'_> {
+    /// The range, or the cursor line without one. A count makes it that
+    /// many lines starting at the last line of the range.
+    pub fn lines(&self, buffer: &Buffer) -> LineRange {
+        let range = self.range.unwrap_or(LineRange {
+            start: buffer.cursor.row,
+            end: buffer.cursor.row,
+        });
+        match self.count {
+            Some(count) => {
+                let last = text::line_count(&buffer.content).saturating_sub(1);
+                LineRange {
+                    start: range.end,
+                    end: range.end.saturating_add(count - 1).min(last),
+                }
+            }
+            None => range,
+        }
+    }
+    /// The range, or every line without one.
+    pub fn lines_or_all(&self, buffer: &Buffer) -> LineRange {
+        self.range.unwrap_or(LineRange {
+            start: 0,
+            end: text::line_count(&buffer.content).saturating_sub(1),
+        })
+    }
+}
+/// A `:` command. Errors are shown in the message line and stop any macro
+/// or `:global` that ran the command.
+pub trait Command {
+    fn accepts(&self) -> Accepts {
+        Accepts::default()
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()>;
+    /// What the argument word `word` could be completed to with Tab.
+    fn complete(&self, _editor: &Editor, _word: &str) -> Vec<String> {
+        Vec::new()
+    }
+}
+struct Entry {
+    name: &'static str,
+    min: usize,
+    command: Box<dyn Command>,
+}
+/// Every `:` command by name. Names can be shortened down to a minimum
+/// length set when the command is registered, as `:wri` for `:write`.
 pub struct CommandHandler {
-    commands: HashMap<String, Box<dyn Command>>,
+    commands: Vec<Entry>,
 }
-#[allow(dead_code)]
 impl CommandHandler {
     pub fn new() -> Self {
-        let mut commands: HashMap<String, Box<dyn Command>> = HashMap::new();
-        commands.insert("w".to_string(), Box::new(WriteCommand));
-        commands.insert("write".to_string(), Box::new(WriteCommand));
-        commands.insert("q".to_string(), Box::new(QuitCommand));
-        commands.insert("quit".to_string(), Box::new(QuitCommand));
-        commands.insert("wq".to_string(), Box::new(WriteQuitCommand));
-        commands.insert("x".to_string(), Box::new(WriteQuitCommand));
-        commands.insert("e".to_string(), Box::new(EditCommand));
-        commands.insert("edit".to_string(), Box::new(EditCommand));
-        commands.insert("s".to_string(), Box::new(SubstituteCommand));
-        commands.insert("substitute".to_string(), Box::new(SubstituteCommand));
-        commands.insert("bn".to_string(), Box::new(NextBufferCommand));
-        commands.insert("bp".to_string(), Box::new(PrevBufferCommand));
-        commands.insert("bd".to_string(), Box::new(DeleteBufferCommand));
-        commands.insert("set".to_string(), Box::new(SetCommand));
-        Self { commands }
-    }
-    pub fn execute(&self, command: &str, buffer: &mut Buffer) -> Result<CommandResult> {
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        if parts.is_empty() {
-            return Ok(CommandResult::None);
-        }
-        let cmd_name = parts[0];
-        let args = &parts[1..];
-        if let Some(cmd) = self.commands.get(cmd_name) {
-            cmd.execute(args, buffer)
-        } else {
-            Err(anyhow::anyhow!("Unknown command: {}", cmd_name))
-        }
+        let mut handler = Self {
+            commands: Vec::new(),
+        };
+        handler.register("substitute", 1, SubstituteCommand);
+        handler.register("global", 1, GlobalCommand { invert: false });
+        handler.register("vglobal", 1, GlobalCommand { invert: true });
+        handler.register("delete", 1, DeleteCommand);
+        handler.register("yank", 1, YankCommand);
+        handler.register("move", 1, MoveCommand);
+        handler.register("t", 1, CopyCommand);
+        handler.register("copy", 2, CopyCommand);
+        handler.register(">", 1, ShiftCommand { right: true });
+        handler.register("<", 1, ShiftCommand { right: false });
+        handler.register("join", 1, JoinCommand);
+        handler.register("normal", 4, NormalCommand);
+        handler.register("mark", 2, MarkCommand);
+        handler.register("k", 1, MarkCommand);
+        handler.register("write", 1, WriteCommand);
+        handler.register("wq", 2, WriteQuitCommand);
+        handler.register("xit", 1, WriteQuitCommand);
+        handler.register("quit", 1, QuitCommand);
+        handler.register("edit", 1, EditCommand);
+        handler.register("split", 2, SplitCommand(Split::Horizontal));
+        handler.register("vsplit", 2, SplitCommand(Split::Vertical));
+        handler.register("new", 3, NewWindowCommand(Split::Horizontal));
+        handler.register("vnew", 3, NewWindowCommand(Split::Vertical));
+        handler.register("only", 2, OnlyCommand);
+        handler.register("close", 3, CloseCommand);
+        handler.register("tabnext", 4, TabNextCommand { forward: true });
+        handler.register("tabprevious", 4, TabNextCommand { forward: false });
+        handler.register("tabnew", 6, TabEditCommand);
+        handler.register("tabedit", 4, TabEditCommand);
+        handler.register("tabclose", 4, TabCloseCommand);
+        handler.register("tabmove", 4, TabMoveCommand);
+        handler.register("bnext", 2, NextBufferCommand);
+        handler.register("bprevious", 2, PrevBufferCommand);
+        handler.register("bdelete", 2, DeleteBufferCommand);
+        handler.register("buffer", 1, BufferCommand);
+        handler.register("registers", 3, RegistersCommand);
+        handler.register("display", 2, RegistersCommand);
+        handler.register("undolist", 5, UndoListCommand);
+        handler.register("earlier", 2, UndoTravelCommand { later: false });
+        handler.register("later", 3, UndoTravelCommand { later: true });
+        handler.register("set", 2, SetCommand);
+        handler.register("nohlsearch", 3, NoHighlightCommand);
+        handler.register("colorscheme", 4, ColorschemeCommand);
+        handler
+    }
+    /// Adds `command` as `:{name}`, which can be shortened to `min` chars.
+    /// An abbreviation of several names picks the one registered first.
+    pub fn register(&mut self, name: &'static str, min: usize, command: impl Command + 'static) {
+        self.commands.push(Entry {
+            name,
+            min,
+            command: Box::new(command),
+        });
     }
-    pub fn get_completions(&self, partial: &str) -> Vec<String> {
+    fn find(&self, name: &str) -> Option<&Entry> {
         self.commands
-            .keys()
-            .filter(|cmd| cmd.starts_with(partial))
-            .cloned()
-            .collect()
+            .iter()
+            .find(|entry| entry.name == name)
+            .or_else(|| {
+                self.commands
+                    .iter()
+                    .find(|entry| name.len() >= entry.min && entry.name.starts_with(name))
+            })
+    }
+    /// Parses and runs a command line typed after `:`. A range alone moves
+    /// to its last line.
+    pub fn execute(&self, editor: &mut Editor, line: &str) -> Result<()> {
+        let (range, rest) = editor
+            .addresses()
+            .parse_range(line)
+            .map_err(|e| anyhow!(e))?;
+        let rest = rest.trim_start();
+        let (name, after) = self.split_name(rest);
+        if name.is_empty() {
+            if !rest.is_empty() {
+                return Err(anyhow!("E492: Not an editor command: {}", line.trim()));
+            }
+            if let Some(range) = range {
+                let buffer = editor.get_current_buffer_mut();
+                buffer.cursor.row = range.end;
+                buffer
+                    .cursor
+                    .move_line_first_non_whitespace(&buffer.content);
+            }
+            return Ok(());
+        }
+        let entry = self
+            .find(name)
+            .ok_or_else(|| anyhow!("E492: Not an editor command: {}", line.trim()))?;
+        let accepts = entry.command.accepts();
+        if range.is_some() && !accepts.range {
+            return Err(anyhow!("E481: No range allowed"));
+        }
+        let (bang, after) = match after.strip_prefix('!') {
+            Some(_) if !accepts.bang => return Err(anyhow!("E477: No ! allowed")),
+            Some(after) => (true, after),
+            None => (false, after),
+        };
+        let mut args = after.trim_start();
+        let mut register = None;
+        if accepts.register {
+            register = args.chars().next().filter(|c| !c.is_ascii_digit());
+            if let Some(name) = register {
+                args = args[name.len_utf8()..].trim_start();
+            }
+        }
+        let mut count = None;
+        if accepts.count {
+            let (parsed, rest) = split_count(args)?;
+            count = parsed;
+            args = rest.trim_start();
+        }
+        if !accepts.args && !args.trim().is_empty() {
+            return Err(anyhow!("E488: Trailing characters: {}", args.trim()));
+        }
+        entry.command.execute(
+            editor,
+            &CommandArgs {
+                range,
+                bang,
+                register,
+                count,
+                args,
+            },
+        )
+    }
+    /// Completions for the end of `line`, a command line typed so far
+    /// without its `:`, and the byte offset of the word they replace:
+    /// command names while the name is typed, else what the command
+    /// completes its last argument word to.
+    pub fn get_completions(&self, editor: &Editor, line: &str) -> (usize, Vec<String>) {
+        let Ok((_, rest)) = editor.addresses().parse_range(line) else {
+            return (line.len(), Vec::new());
+        };
+        let rest = rest.trim_start();
+        let name_start = line.len() - rest.len();
+        let (name, after) = self.split_name(rest);
+        if after.is_empty() {
+            let mut names: Vec<String> = self
+                .commands
+                .iter()
+                .map(|entry| entry.name)
+                .filter(|entry| entry.starts_with(name) && entry.starts_with(char::is_alphabetic))
+                .map(str::to_string)
+                .collect();
+            names.sort();
+            names.dedup();
+            return (name_start, names);
+        }
+        let args = after.strip_prefix('!').unwrap_or(after);
+        let (Some(entry), true) = (self.find(name), args.starts_with(char::is_whitespace)) else {
+            return (line.len(), Vec::new());
+        };
+        let word_start = line.len() - line.rsplit(char::is_whitespace).next().unwrap_or("").len();
+        let items = entry.command.complete(editor, &line[word_start..]);
+        (word_start, items)
+    }
+    /// Splits the command name off `text`: a run of letters, or one of the
+    /// commands named by a symbol. `:ka` is `:k a` unless `ka` is a name.
+    fn split_name<'a>(&self, text: &'a str) -> (&'a str, &'a str) {
+        if text.starts_with(['>', '<']) {
+            return text.split_at(1);
+        }
+        let letters = text.len()
+            - text
+                .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+                .len();
+        let (name, rest) = text.split_at(letters);
+        if name.len() == 2 && name.starts_with('k') && self.find(name).is_none() {
+            return text.split_at(1);
+        }
+        (name, rest)
     }
 }
-#[allow(dead_code)]
-#[derive(Debug)]
-pub enum CommandResult {
-    None,
-    Quit,
-    Message(String),
-    Error(String),
+/// Splits a count off the front of `text`, which must be above zero.
+fn split_count(text: &str) -> Result<(Option<usize>, &str)> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 {
+        return Ok((None, text));
+    }
+    match text[..digits].parse() {
+        Ok(0) | Err(_) => Err(anyhow!("E939: Positive count required")),
+        Ok(count) => Ok((Some(count), &text[digits..])),
+    }
 }
-#[allow(dead_code)]
-pub trait Command {
-    fn execute(&self, args: &[&str], buffer: &mut Buffer) -> Result<CommandResult>;
+/// Line below which `:m` and `:t` put lines, counted from 1 with 0 for
+/// above the first line.
+fn destination(editor: &Editor, text: &str) -> Result<usize> {
+    let buffer = editor.get_current_buffer();
+    let (line, rest) = editor
+        .addresses()
+        .parse_address(text, buffer.cursor.row + 1)
+        .map_err(|e| anyhow!(e))?;
+    if !rest.trim().is_empty() {
+        return Err(anyhow!("E488: Trailing characters: {}", rest.trim()));
+    }
+    let line = line.ok_or_else(|| anyhow!("E14: Invalid address"))?;
+    if line > text::line_count(&buffer.content) {
+        return Err(anyhow!("E16: Invalid range"));
+    }
+    Ok(line)
+}
+struct SubstituteCommand;
+impl Command for SubstituteCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let parsed = substitute::parse(args.args).map_err(|e| anyhow!(e))?;
+        let range = args.lines(editor.get_current_buffer());
+        editor.execute_substitute(range, parsed)
+    }
+}
+/// `:g` and `:v`; `:g!` is `:v`.
+struct GlobalCommand {
+    invert: bool,
+}
+impl Command for GlobalCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            bang: !self.invert,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let parsed = ex::GlobalCommand::parse(args.args, self.invert || args.bang)
+            .map_err(|e| anyhow!(e))?;
+        let range = args.lines_or_all(editor.get_current_buffer());
+        editor.execute_global(range, parsed)
+    }
+}
+struct DeleteCommand;
+impl Command for DeleteCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            register: true,
+            count: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let buffer = editor.get_current_buffer();
+        let lines = args.lines(buffer);
+        let range = MotionRange::lines(&buffer.content, lines.start, lines.end);
+        editor.apply_operator(Operator::Delete, range, args.register, false);
+        Ok(())
+    }
+}
+struct YankCommand;
+impl Command for YankCommand {
+    fn accepts(&self) -> Accepts {
+        DeleteCommand.accepts()
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let buffer = editor.get_current_buffer();
+        let lines = args.lines(buffer);
+        let range = MotionRange::lines(&buffer.content, lines.start, lines.end);
+        // Unlike `y`, `:y` leaves the cursor where it is.
+        let cursor = buffer.cursor.clone();
+        editor.apply_operator(Operator::Yank, range, args.register, false);
+        editor.get_current_buffer_mut().cursor = cursor;
+        Ok(())
+    }
+}
+struct MoveCommand;
+impl Command for MoveCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let dest = destination(editor, args.args)?;
+        let buffer = editor.get_current_buffer_mut();
+        let range = args.lines(buffer);
+        if dest > range.start && dest <= range.end {
+            return Err(anyhow!("E134: Cannot move a range of lines into itself"));
+        }
+        buffer.begin_undo_group();
+        buffer.move_lines(range.start, range.end, dest);
+        buffer.end_undo_group();
+        Ok(())
+    }
+}
+/// `:t` and `:co`.
+struct CopyCommand;
+impl Command for CopyCommand {
+    fn accepts(&self) -> Accepts {
+        MoveCommand.accepts()
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let dest = destination(editor, args.args)?;
+        let buffer = editor.get_current_buffer_mut();
+        let range = args.lines(buffer);
+        buffer.begin_undo_group();
+        buffer.copy_lines(range.start, range.end, dest);
+        buffer.end_undo_group();
+        Ok(())
+    }
+}
+/// `:>` and `:<`, shifting once more for every extra `>` or `<`.
+struct ShiftCommand {
+    right: bool,
+}
+impl Command for ShiftCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let symbol = if self.right { '>' } else { '<' };
+        let extra = args.args.trim_start_matches(symbol);
+        let times = 1 + args.args.len() - extra.len();
+        let (count, rest) = split_count(extra.trim_start())?;
+        if !rest.trim().is_empty() {
+            return Err(anyhow!("E488: Trailing characters: {}", rest.trim()));
+        }
+        let buffer = editor.get_current_buffer_mut();
+        let lines = CommandArgs {
+            count,
+            ..args.clone()
+        }
+        .lines(buffer);
+        buffer.begin_undo_group();
+        for _ in 0..times {
+            buffer.shift_lines(lines.start, lines.end, self.right);
+        }
+        buffer.end_undo_group();
+        buffer.cursor.row = lines.end;
+        buffer
+            .cursor
+            .move_line_first_non_whitespace(&buffer.content);
+        Ok(())
+    }
+}
+struct JoinCommand;
+impl Command for JoinCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            count: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let buffer = editor.get_current_buffer_mut();
+        let range = args.lines(buffer);
+        // `:j` on one line joins it with the next, as does `:j 2`.
+        let (first, last) = match args.count {
+            Some(count) => (range.start, range.start.saturating_add(count.max(2) - 1)),
+            None => (range.start, range.end.max(range.start + 1)),
+        };
+        let last_line = text::line_count(&buffer.content).saturating_sub(1);
+        if first < last_line {
+            buffer.save_state();
+            buffer.join_lines(first, last.min(last_line));
+        }
+        Ok(())
+    }
+}
+struct NormalCommand;
+impl Command for NormalCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            bang: true,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let range = args.lines(editor.get_current_buffer());
+        editor.execute_normal_keys(range, args.args)
+    }
 }
+/// `:mark {a-z}` and `:k{a-z}`, on the last line of the range.
+struct MarkCommand;
+impl Command for MarkCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let mut chars = args.args.trim().chars();
+        let mark = match (chars.next(), chars.next()) {
+            (Some(mark), None) if mark.is_ascii_lowercase() => mark,
+            (None, _) => return Err(anyhow!("E471: Argument required")),
+            _ => {
+                return Err(anyhow!(
+                    "E191: Argument must be a letter or forward/backward quote"
+                ))
+            }
+        };
+        let buffer = editor.get_current_buffer_mut();
+        let row = args.lines(buffer).end;
+        buffer.marks.insert(mark, (row, 0));
+        Ok(())
+    }
+}
+/// `:w`, `:w {file}` (which renames the buffer), and with a range or `>>`
+/// `:[range]w[!] [>>] [file]` to write just those lines.
 struct WriteCommand;
 impl Command for WriteCommand {
-    fn execute(&self, args: &[&str], buffer: &mut Buffer) -> Result<CommandResult> {
-        if args.is_empty() {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            range: true,
+            bang: true,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let (append, file) = match args.args.strip_prefix(">>") {
+            Some(file) => (true, file.trim()),
+            None => (false, args.args.trim()),
+        };
+        if args.range.is_none() && !append {
+            let buffer = editor.get_current_buffer_mut();
+            let message = if file.is_empty() {
+                buffer.save()?;
+                "File saved".to_string()
+            } else {
+                buffer.save_as(file)?;
+                format!("File saved as {}", file)
+            };
+            editor.set_message(message);
+            return Ok(());
+        }
+        let range = args.lines_or_all(editor.get_current_buffer());
+        editor.write_lines(range, file, append, args.bang)
+    }
+    fn complete(&self, _editor: &Editor, word: &str) -> Vec<String> {
+        complete_path(word)
+    }
+}
+/// `:wq` and `:x`.
+struct WriteQuitCommand;
+impl Command for WriteQuitCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            bang: true,
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let buffer = editor.get_current_buffer_mut();
+        if args.args.is_empty() {
             buffer.save()?;
-            Ok(CommandResult::Message("File saved".to_string()))
         } else {
-            buffer.save_as(args[0])?;
-            Ok(CommandResult::Message(format!("File saved as {}", args[0])))
+            buffer.save_as(args.args.trim())?;
         }
+        quit_window(editor, args.bang)
     }
 }
 struct QuitCommand;
 impl Command for QuitCommand {
-    fn execute(&self, _args: &[&str], buffer: &mut Buffer) -> Result<CommandResult> {
-        if buffer.modified {
-            Err(anyhow::anyhow!(
-                "No write since last change (use :q! to force quit)"
-            ))
-        } else {
-            Ok(CommandResult::Quit)
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            bang: true,
+            ..Accepts::default()
         }
     }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        quit_window(editor, args.bang)
+    }
 }
-struct WriteQuitCommand;
-impl Command for WriteQuitCommand {
-    fn execute(&self, args: &[&str], buffer: &mut Buffer) -> Result<CommandResult> {
-        if args.is_empty() {
-            buffer.save()?;
-        } else {
-            buffer.save_as(args[0])?;
+/// Closes the current window, or its tab page from the last window in it,
+/// or leaves the editor from the last one of all. Unless `force` is set,
+/// leaving fails while any buffer has changes not written.
+fn quit_window(editor: &mut Editor, force: bool) -> Result<()> {
+    if editor.window_count() > 1 {
+        editor.close_window()
+    } else if editor.tab_count() > 1 {
+        editor.close_tab()
+    } else {
+        if !force {
+            if editor.get_current_buffer().modified {
+                return Err(anyhow!(
+                    "E37: No write since last change (add ! to override)"
+                ));
+            }
+            if let Some(name) = editor.modified_buffer() {
+                return Err(anyhow!(
+                    "E162: No write since last change for buffer \"{}\"",
+                    name
+                ));
+            }
         }
-        Ok(CommandResult::Quit)
+        editor.quit();
+        Ok(())
     }
 }
 struct EditCommand;
 impl Command for EditCommand {
-    fn execute(&self, args: &[&str], _buffer: &mut Buffer) -> Result<CommandResult> {
-        if args.is_empty() {
-            Err(anyhow::anyhow!("No file specified"))
-        } else {
-            Ok(CommandResult::Message(format!("Opening {}", args[0])))
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
         }
     }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let filename = args.args.trim();
+        if filename.is_empty() {
+            return Err(anyhow!("E32: No file name"));
+        }
+        editor.open_file(filename)?;
+        editor.set_message(format!("Opened {}", filename));
+        Ok(())
+    }
+    fn complete(&self, _editor: &Editor, word: &str) -> Vec<String> {
+        complete_path(word)
+    }
 }
-struct SubstituteCommand;
-impl Command for SubstituteCommand {
-    fn execute(&self, args: &[&str], _buffer: &mut Buffer) -> Result<CommandResult> {
-        if args.is_empty() {
-            Err(anyhow::anyhow!("Substitute requires pattern"))
-        } else {
-            Ok(CommandResult::Message(
-                "Substitute not implemented yet".to_string(),
-            ))
+/// `:sp [file]` and `:vs [file]`: a second window on the current buffer,
+/// or on `file`.
+struct SplitCommand(Split);
+impl Command for SplitCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        editor.split_window(self.0)?;
+        let filename = args.args.trim();
+        if !filename.is_empty() {
+            editor.open_file(filename)?;
+        }
+        Ok(())
+    }
+    fn complete(&self, _editor: &Editor, word: &str) -> Vec<String> {
+        complete_path(word)
+    }
+}
+/// `:new` and `:vnew`: a new window on an empty buffer.
+struct NewWindowCommand(Split);
+impl Command for NewWindowCommand {
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.split_window(self.0)?;
+        editor.new_buffer();
+        Ok(())
+    }
+}
+struct OnlyCommand;
+impl Command for OnlyCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            bang: true,
+            ..Accepts::default()
         }
     }
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.only_window();
+        Ok(())
+    }
+}
+struct CloseCommand;
+impl Command for CloseCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            bang: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.close_window()
+    }
+}
+/// `:tabnext` and `:tabprevious`, going to tab page N with a count.
+struct TabNextCommand {
+    forward: bool,
+}
+impl Command for TabNextCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            count: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        match (self.forward, args.count) {
+            (true, Some(n)) => editor.select_tab(n - 1),
+            (forward, count) => editor.cycle_tab(forward, count.unwrap_or(1)),
+        }
+        Ok(())
+    }
+}
+/// `:tabnew` and `:tabedit [file]`.
+struct TabEditCommand;
+impl Command for TabEditCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let filename = args.args.trim();
+        editor.new_tab((!filename.is_empty()).then_some(filename))
+    }
+    fn complete(&self, _editor: &Editor, word: &str) -> Vec<String> {
+        complete_path(word)
+    }
+}
+struct TabCloseCommand;
+impl Command for TabCloseCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            bang: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.close_tab()
+    }
+}
+/// `:tabmove N` puts the tab page after tab page N (0 makes it first,
+/// nothing makes it last), and `:tabmove +N` / `:tabmove -N` moves it by N.
+struct TabMoveCommand;
+impl Command for TabMoveCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let arg = args.args.trim();
+        let current = editor.current_tab();
+        let last = editor.tab_count() - 1;
+        let invalid = || anyhow!("E474: Invalid argument");
+        let index = if arg.is_empty() {
+            last
+        } else if let Some(amount) = arg.strip_prefix('+') {
+            let amount: usize = if amount.is_empty() {
+                Ok(1)
+            } else {
+                amount.parse()
+            }
+            .map_err(|_| invalid())?;
+            current
+                .checked_add(amount)
+                .filter(|&i| i <= last)
+                .ok_or_else(invalid)?
+        } else if let Some(amount) = arg.strip_prefix('-') {
+            let amount: usize = if amount.is_empty() {
+                Ok(1)
+            } else {
+                amount.parse()
+            }
+            .map_err(|_| invalid())?;
+            current.checked_sub(amount).ok_or_else(invalid)?
+        } else {
+            // Counted before the move, so tabs after this one shift back.
+            let after: usize = arg.parse().map_err(|_| invalid())?;
+            let after = after.min(last + 1);
+            if after > current {
+                after - 1
+            } else {
+                after
+            }
+        };
+        editor.move_tab(index);
+        Ok(())
+    }
 }
 struct NextBufferCommand;
 impl Command for NextBufferCommand {
-    fn execute(&self, _args: &[&str], _buffer: &mut Buffer) -> Result<CommandResult> {
-        Ok(CommandResult::Message("Next buffer".to_string()))
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.switch_buffer(true);
+        Ok(())
     }
 }
 struct PrevBufferCommand;
 impl Command for PrevBufferCommand {
-    fn execute(&self, _args: &[&str], _buffer: &mut Buffer) -> Result<CommandResult> {
-        Ok(CommandResult::Message("Previous buffer".to_string()))
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.switch_buffer(false);
+        Ok(())
+    }
+}
+/// `:b {N}` goes to buffer N, and `:b {name}` to the one whose name is or
+/// uniquely contains `name`.
+struct BufferCommand;
+impl Command for BufferCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let arg = args.args.trim();
+        if arg.is_empty() {
+            return Ok(());
+        }
+        let names = editor.buffer_names();
+        let index = if let Ok(number) = arg.parse::<usize>() {
+            if number == 0 || number > names.len() {
+                return Err(anyhow!("E86: Buffer {} does not exist", number));
+            }
+            number - 1
+        } else if let Some(index) = names.iter().position(|name| name == arg) {
+            index
+        } else {
+            let matches: Vec<usize> = (0..names.len())
+                .filter(|&i| names[i].contains(arg))
+                .collect();
+            match matches[..] {
+                [index] => index,
+                [] => return Err(anyhow!("E94: No matching buffer for {}", arg)),
+                _ => return Err(anyhow!("E93: More than one match for {}", arg)),
+            }
+        };
+        editor.select_buffer(index);
+        Ok(())
+    }
+    fn complete(&self, editor: &Editor, word: &str) -> Vec<String> {
+        let mut names: Vec<String> = editor
+            .buffer_names()
+            .into_iter()
+            .filter(|name| name.contains(word))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
     }
 }
 struct DeleteBufferCommand;
 impl Command for DeleteBufferCommand {
-    fn execute(&self, _args: &[&str], _buffer: &mut Buffer) -> Result<CommandResult> {
-        Ok(CommandResult::Message("Delete buffer".to_string()))
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.close_buffer();
+        Ok(())
+    }
+}
+/// `:registers` and `:display`, limited to the names given.
+struct RegistersCommand;
+impl Command for RegistersCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let listing = editor.format_registers(args.args.trim());
+        editor.set_message(listing);
+        Ok(())
+    }
+}
+struct UndoListCommand;
+impl Command for UndoListCommand {
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        let listing = format_undo_list(editor.get_current_buffer());
+        editor.set_message(listing);
+        Ok(())
+    }
+}
+/// `:earlier` and `:later` with a count, time or file-write argument.
+struct UndoTravelCommand {
+    later: bool,
+}
+impl Command for UndoTravelCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let travel = UndoTravel::parse(args.args)
+            .ok_or_else(|| anyhow!("Invalid argument: {}", args.args.trim()))?;
+        let buffer = editor.get_current_buffer_mut();
+        let moved = if self.later {
+            buffer.later(travel)
+        } else {
+            buffer.earlier(travel)
+        };
+        if !moved {
+            editor.set_message("Already at that change".to_string());
+        }
+        Ok(())
     }
 }
 struct SetCommand;
 impl Command for SetCommand {
-    fn execute(&self, args: &[&str], buffer: &mut Buffer) -> Result<CommandResult> {
-        if args.is_empty() {
-            return Ok(CommandResult::Message(
-                "Available settings: number, syntax".to_string(),
-            ));
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
         }
-        match args[0] {
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let buffer = editor.get_current_buffer_mut();
+        let message = match args.args.trim() {
             "number" | "nu" => {
                 buffer.line_numbers = true;
-                Ok(CommandResult::Message("Line numbers enabled".to_string()))
+                "Line numbers enabled".to_string()
             }
             "nonumber" | "nonu" => {
                 buffer.line_numbers = false;
-                Ok(CommandResult::Message("Line numbers disabled".to_string()))
+                "Line numbers disabled".to_string()
+            }
+            "relativenumber" | "rnu" => {
+                buffer.relative_numbers = true;
+                "Relative line numbers enabled".to_string()
+            }
+            "norelativenumber" | "nornu" => {
+                buffer.relative_numbers = false;
+                "Relative line numbers disabled".to_string()
             }
             "syntax" => {
                 buffer.syntax_highlighting = true;
-                Ok(CommandResult::Message(
-                    "Syntax highlighting enabled".to_string(),
-                ))
+                "Syntax highlighting enabled".to_string()
             }
             "nosyntax" => {
                 buffer.syntax_highlighting = false;
-                Ok(CommandResult::Message(
-                    "Syntax highlighting disabled".to_string(),
-                ))
+                "Syntax highlighting disabled".to_string()
+            }
+            setting => {
+                // A trailing `\ ` keeps its space, as in `:set showbreak=>\ `.
+                let raw = args.args.trim_start();
+                let setting = match raw.get(..setting.len() + 1) {
+                    Some(kept) if setting.ends_with('\\') => kept,
+                    _ => setting,
+                };
+                editor.set_option(setting).map_err(|e| anyhow!(e))?
             }
-            _ => Err(anyhow::anyhow!("Unknown setting: {}", args[0])),
+        };
+        editor.set_message(message);
+        Ok(())
+    }
+    fn complete(&self, _editor: &Editor, word: &str) -> Vec<String> {
+        let buffer_options = ["number", "relativenumber", "syntax"];
+        let booleans = BOOLEAN_OPTIONS.iter().chain(&buffer_options);
+        let mut names: Vec<String> = OPTION_NAMES
+            .iter()
+            .chain(&buffer_options)
+            .map(|name| name.to_string())
+            .chain(booleans.map(|name| format!("no{}", name)))
+            .filter(|name| name.starts_with(word))
+            .collect();
+        names.sort();
+        names
+    }
+}
+struct NoHighlightCommand;
+impl Command for NoHighlightCommand {
+    fn execute(&self, editor: &mut Editor, _args: &CommandArgs) -> Result<()> {
+        editor.clear_search_highlight();
+        Ok(())
+    }
+}
+/// `:colorscheme {name}` switches the syntax colours; without a name it
+/// shows the current one.
+struct ColorschemeCommand;
+impl Command for ColorschemeCommand {
+    fn accepts(&self) -> Accepts {
+        Accepts {
+            args: true,
+            ..Accepts::default()
+        }
+    }
+    fn execute(&self, editor: &mut Editor, args: &CommandArgs) -> Result<()> {
+        let name = args.args.trim();
+        if name.is_empty() {
+            let current = editor.ui().current_theme().to_string();
+            editor.set_message(current);
+        } else if !editor.ui_mut().set_theme(name) {
+            return Err(anyhow!("E185: Cannot find color scheme '{}'", name));
         }
+        Ok(())
+    }
+    fn complete(&self, editor: &Editor, word: &str) -> Vec<String> {
+        let mut themes = editor.ui().themes();
+        themes.retain(|theme| theme.starts_with(word));
+        themes
+    }
+}
+/// Files and directories whose path starts with `word`, directories ending
+/// in `/`. Hidden ones only come up when the name typed starts with `.`.
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(slash) => word.split_at(slash + 1),
+        None => ("", word),
+    };
+    let search_dir = match (dir.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ if dir.is_empty() => PathBuf::from("."),
+        _ => PathBuf::from(dir),
+    };
+    let Ok(entries) = fs::read_dir(search_dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, slash))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+fn format_undo_list(buffer: &Buffer) -> String {
+    let leaves = buffer.undo_tree.leaves();
+    if leaves.is_empty() {
+        return "Nothing to undo".to_string();
+    }
+    let now = SystemTime::now();
+    let mut lines = vec!["number changes  when".to_string()];
+    for (change, depth, time) in leaves {
+        let seconds = now.duration_since(time).unwrap_or_default().as_secs();
+        lines.push(format!(
+            "{:>6} {:>7}  {} seconds ago",
+            change, depth, seconds
+        ));
+    }
+    lines.join("\n")
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    fn editor_with(text: &str) -> Editor {
+        let mut editor = Editor::new().unwrap();
+        editor.get_current_buffer_mut().content = Rope::from_str(text);
+        editor
+    }
+    fn text_of(editor: &Editor) -> String {
+        editor.get_current_buffer().content.to_string()
+    }
+    fn name_of(line: &str) -> &'static str {
+        let handler = CommandHandler::new();
+        handler.find(line).map_or("", |entry| entry.name)
+    }
+    fn error_of(editor: &mut Editor, line: &str) -> String {
+        editor.execute_colon_command(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_abbreviations_pick_the_first_registered_name() {
+        assert_eq!(name_of("s"), "substitute");
+        assert_eq!(name_of("b"), "buffer");
+        assert_eq!(name_of("bn"), "bnext");
+        assert_eq!(name_of("v"), "vglobal");
+        assert_eq!(name_of("vs"), "vsplit");
+        assert_eq!(name_of("t"), "t");
+        assert_eq!(name_of("co"), "copy");
+        assert_eq!(name_of("tabn"), "tabnext");
+        // Shorter than the minimum, or not a prefix at all.
+        assert_eq!(name_of("tab"), "");
+        assert_eq!(name_of("writex"), "");
+        let handler = CommandHandler::new();
+        assert_eq!(handler.split_name("ka"), ("k", "a"));
+        assert_eq!(handler.split_name(">>"), (">", ">"));
+        assert_eq!(handler.split_name("d3"), ("d", "3"));
+    }
+
+    #[test]
+    fn test_register_and_count_after_the_name() {
+        let mut editor = editor_with("1\n2\n3\n4\n5\n");
+        editor.execute_colon_command("2d a 3").unwrap();
+        assert_eq!(text_of(&editor), "1\n5\n");
+        editor.execute_colon_command("normal \"aP").unwrap();
+        assert_eq!(text_of(&editor), "1\n2\n3\n4\n5\n");
+        // A digit is a count, never a register.
+        let mut editor = editor_with("1\n2\n3\n");
+        editor.execute_colon_command("d2").unwrap();
+        assert_eq!(text_of(&editor), "3\n");
+        let mut editor = editor_with("1\n2\n3\n");
+        editor
+            .execute_colon_command("d 18446744073709551615")
+            .unwrap();
+        assert_eq!(text_of(&editor), "");
+        let mut editor = editor_with("1\n2\n3\n");
+        editor
+            .execute_colon_command("j 18446744073709551615")
+            .unwrap();
+        assert_eq!(text_of(&editor), "1 2 3\n");
+    }
+
+    #[test]
+    fn test_what_a_command_does_not_accept_is_an_error() {
+        let mut editor = editor_with("1\n2\n");
+        assert_eq!(error_of(&mut editor, "2q"), "E481: No range allowed");
+        assert_eq!(error_of(&mut editor, "d!"), "E477: No ! allowed");
+        assert_eq!(error_of(&mut editor, "q x"), "E488: Trailing characters: x");
+        assert_eq!(
+            error_of(&mut editor, "d 0"),
+            "E939: Positive count required"
+        );
+        assert_eq!(
+            error_of(&mut editor, "d a 3x"),
+            "E488: Trailing characters: x"
+        );
+        assert_eq!(
+            error_of(&mut editor, "frob"),
+            "E492: Not an editor command: frob"
+        );
+        assert_eq!(text_of(&editor), "1\n2\n");
+    }
+
+    #[test]
+    fn test_quit_refuses_while_any_buffer_is_modified() {
+        let mut editor = Editor::new().unwrap();
+        editor.get_current_buffer_mut().insert_text(0, "x");
+        editor.new_buffer();
+        let quit = editor.execute_colon_command("q").unwrap_err();
+        assert_eq!(
+            quit.to_string(),
+            "E162: No write since last change for buffer \"[No Name]\""
+        );
+        editor.get_current_buffer_mut().insert_text(0, "y");
+        let quit = editor.execute_colon_command("q").unwrap_err();
+        assert!(quit.to_string().starts_with("E37:"));
+        // Closing one of two windows leaves the buffer open, so it is allowed.
+        editor.execute_colon_command("split").unwrap();
+        editor.execute_colon_command("q").unwrap();
+        assert_eq!(editor.window_count(), 1);
+        editor.execute_colon_command("q!").unwrap();
+    }
+
+    #[test]
+    fn test_completions_of_names_and_arguments() {
+        let editor = editor_with("a\nb\n");
+        let handler = CommandHandler::new();
+        assert_eq!(
+            handler.get_completions(&editor, "tabn"),
+            (0, vec!["tabnew".to_string(), "tabnext".to_string()])
+        );
+        // After a range, and only for names made of letters.
+        assert_eq!(handler.get_completions(&editor, "1,2j").1, ["join"]);
+        assert!(handler
+            .get_completions(&editor, "")
+            .1
+            .iter()
+            .all(|name| name != ">"));
+        let (start, names) = handler.get_completions(&editor, "set nohls");
+        assert_eq!((start, names), (4, vec!["nohlsearch".to_string()]));
+        // Without a blank after it, the name itself is completed.
+        assert_eq!(handler.get_completions(&editor, "set").1, ["set"]);
+        assert!(handler.get_completions(&editor, "d a").1.is_empty());
+    }
+
+    #[test]
+    fn test_path_completion_marks_directories() {
+        let dir = std::env::temp_dir().join(format!("complete-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("file.rs"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        let word = format!("{}/", dir.display());
+        let names: Vec<String> = complete_path(&word)
+            .into_iter()
+            .map(|path| path[word.len()..].to_string())
+            .collect();
+        assert_eq!(names, ["file.rs", "sub/"]);
+        assert_eq!(complete_path(&format!("{}.", word)).len(), 1);
+        let _ = fs::remove_dir_all(&dir);
     }
 }
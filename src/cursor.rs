@@ -1,3 +1,6 @@
+use crate::motion::{self, Motion, MotionKind, MotionRange};
+use crate::text;
+use crate::wrap::Wrapping;
 use ropey::Rope;
 #[derive(Clone, Debug)]
 pub struct Cursor {
@@ -13,86 +16,50 @@ impl Cursor {
             desired_col: 0,
         }
     }
-    pub fn move_left(&mut self) {
+    /// Remembers the current display column so vertical moves keep it.
+    pub fn update_desired_col(&mut self, content: &Rope) {
+        let line = text::line_text(content, self.row);
+        self.desired_col = text::display_col(&line, self.col);
+    }
+    pub fn move_left(&mut self, content: &Rope) {
         if self.col > 0 {
-            self.col -= 1;
-            self.desired_col = self.col;
+            let line = text::line_text(content, self.row);
+            self.col = text::prev_grapheme(&line, self.col);
+            self.update_desired_col(content);
         }
     }
     pub fn move_right(&mut self, content: &Rope) {
         let line_len = self.get_line_length(content, self.row);
         if self.col < line_len {
-            self.col += 1;
-            self.desired_col = self.col;
+            let line = text::line_text(content, self.row);
+            self.col = text::next_grapheme(&line, self.col).min(line_len);
+            self.update_desired_col(content);
         }
     }
-    pub fn move_up(&mut self) {
+    pub fn move_up(&mut self, content: &Rope) {
         if self.row > 0 {
             self.row -= 1;
-            self.col = self.desired_col;
+            self.restore_desired_col(content);
         }
     }
     pub fn move_down(&mut self, content: &Rope) {
         if self.row + 1 < content.len_lines() {
             self.row += 1;
-            let line_len = self.get_line_length(content, self.row);
-            self.col = self.desired_col.min(line_len);
-        }
-    }
-    pub fn move_word_forward(&mut self, content: &Rope) {
-        let line = content.line(self.row).to_string();
-        let chars: Vec<char> = line.chars().collect();
-        if self.col < chars.len() {
-            while self.col < chars.len() && chars[self.col].is_alphanumeric() {
-                self.col += 1;
-            }
-            while self.col < chars.len() && chars[self.col].is_whitespace() {
-                self.col += 1;
-            }
-            self.desired_col = self.col;
-        }
-    }
-    pub fn move_word_backward(&mut self, content: &Rope) {
-        if self.col > 0 {
-            let line = content.line(self.row).to_string();
-            let chars: Vec<char> = line.chars().collect();
-            self.col -= 1;
-            while self.col > 0 && chars[self.col].is_whitespace() {
-                self.col -= 1;
-            }
-            while self.col > 0 && chars[self.col - 1].is_alphanumeric() {
-                self.col -= 1;
-            }
-            self.desired_col = self.col;
+            self.restore_desired_col(content);
         }
     }
-    pub fn move_word_end(&mut self, content: &Rope) {
-        let line = content.line(self.row).to_string();
-        let chars: Vec<char> = line.chars().collect();
-        if self.col < chars.len() {
-            while self.col < chars.len() && chars[self.col].is_alphanumeric() {
-                self.col += 1;
-            }
-            while self.col < chars.len() && chars[self.col].is_whitespace() {
-                self.col += 1;
-            }
-            while self.col < chars.len() && chars[self.col].is_alphanumeric() {
-                self.col += 1;
-            }
-            if self.col > 0 && self.col <= chars.len() {
-                self.col -= 1;
-            }
-            self.desired_col = self.col;
-        }
+    fn restore_desired_col(&mut self, content: &Rope) {
+        let line = text::line_text(content, self.row);
+        self.col = text::col_for_display(&line, self.desired_col);
     }
     pub fn move_line_first_non_whitespace(&mut self, content: &Rope) {
-        let line = content.line(self.row).to_string();
+        let line = text::line_text(content, self.row);
         let chars: Vec<char> = line.chars().collect();
         self.col = 0;
         while self.col < chars.len() && chars[self.col].is_whitespace() {
             self.col += 1;
         }
-        self.desired_col = self.col;
+        self.update_desired_col(content);
     }
     pub fn move_line_start(&mut self) {
         self.col = 0;
@@ -101,43 +68,426 @@ impl Cursor {
     pub fn move_line_end(&mut self, content: &Rope) {
         let line_len = self.get_line_length(content, self.row);
         self.col = line_len;
-        self.desired_col = self.col;
+        self.update_desired_col(content);
     }
+    #[allow(dead_code)]
     pub fn move_to_start(&mut self) {
         self.row = 0;
         self.col = 0;
         self.desired_col = 0;
     }
+    #[allow(dead_code)]
     pub fn move_to_end(&mut self, content: &Rope) {
         self.row = content.len_lines().saturating_sub(1);
         let line_len = self.get_line_length(content, self.row);
         self.col = line_len;
-        self.desired_col = self.col;
+        self.update_desired_col(content);
+    }
+    pub fn char_index(&self, content: &Rope) -> usize {
+        if self.row >= content.len_lines() {
+            return content.len_chars();
+        }
+        content.line_to_char(self.row) + self.col.min(self.get_line_length(content, self.row))
+    }
+    pub fn set_char_index(&mut self, content: &Rope, pos: usize) {
+        let pos = pos.min(content.len_chars());
+        self.row = content.char_to_line(pos);
+        self.col = pos - content.line_to_char(self.row);
+        self.update_desired_col(content);
+    }
+    /// Moves the cursor by `motion` repeated `count` times (or to line
+    /// `count` for `gg`/`G`). Returns false when the motion fails, e.g. an
+    /// `f` target that is not on the line. `wrapping` is how the window
+    /// shows lines, for the screen row motions.
+    pub fn apply_motion(
+        &mut self,
+        content: &Rope,
+        motion: Motion,
+        count: Option<usize>,
+        wrapping: &Wrapping,
+    ) -> bool {
+        let times = count.unwrap_or(1).max(1);
+        let before = (self.row, self.col);
+        match motion {
+            // Repeated motions stop once the cursor stops moving, so a huge
+            // count does not spin.
+            Motion::Left => {
+                for _ in 0..times.min(self.col) {
+                    self.move_left(content);
+                }
+            }
+            Motion::Right => {
+                for _ in 0..times {
+                    let col = self.col;
+                    self.move_right(content);
+                    if self.col == col {
+                        break;
+                    }
+                }
+            }
+            Motion::Up => {
+                if self.row == 0 {
+                    return false;
+                }
+                self.move_to_line(content, self.row.saturating_sub(times));
+            }
+            Motion::Down => {
+                if self.row + 1 >= content.len_lines() {
+                    return false;
+                }
+                self.move_to_line(content, self.row.saturating_add(times));
+            }
+            Motion::WordForward
+            | Motion::WordBackward
+            | Motion::WordEnd
+            | Motion::BigWordForward
+            | Motion::BigWordBackward
+            | Motion::BigWordEnd => {
+                let mut pos = self.char_index(content);
+                for _ in 0..times {
+                    let next = match motion {
+                        Motion::WordForward => motion::word_forward(content, pos, false),
+                        Motion::WordBackward => motion::word_backward(content, pos, false),
+                        Motion::WordEnd => motion::word_end(content, pos, false),
+                        Motion::BigWordForward => motion::word_forward(content, pos, true),
+                        Motion::BigWordBackward => motion::word_backward(content, pos, true),
+                        _ => motion::word_end(content, pos, true),
+                    };
+                    if next == pos {
+                        break;
+                    }
+                    pos = next;
+                }
+                self.set_char_index(content, pos);
+            }
+            Motion::LineStart => self.move_line_start(),
+            Motion::FirstNonBlank => self.move_line_first_non_whitespace(content),
+            Motion::LineEnd => {
+                let row =
+                    (self.row.saturating_add(times - 1)).min(content.len_lines().saturating_sub(1));
+                self.row = row;
+                self.move_line_end(content);
+                return true;
+            }
+            Motion::CurrentLine => {
+                let row = self.row.saturating_add(times - 1);
+                if row >= content.len_lines() {
+                    return false;
+                }
+                self.row = row;
+                self.move_line_first_non_whitespace(content);
+                return true;
+            }
+            Motion::FileStart | Motion::FileEnd => {
+                let last = content.len_lines().saturating_sub(1);
+                self.row = match (motion, count) {
+                    (_, Some(line)) => line.saturating_sub(1).min(last),
+                    (Motion::FileStart, None) => 0,
+                    _ => last,
+                };
+                self.move_line_first_non_whitespace(content);
+                return true;
+            }
+            Motion::ParagraphForward | Motion::ParagraphBackward => {
+                let last = content.len_lines().saturating_sub(1);
+                let is_blank = |row: usize| text::line_len(content, row) == 0;
+                let mut row = self.row;
+                for _ in 0..times {
+                    if (motion == Motion::ParagraphForward && row == last)
+                        || (motion == Motion::ParagraphBackward && row == 0)
+                    {
+                        break;
+                    }
+                    if motion == Motion::ParagraphForward {
+                        while row < last && is_blank(row) {
+                            row += 1;
+                        }
+                        while row < last && !is_blank(row) {
+                            row += 1;
+                        }
+                    } else {
+                        while row > 0 && is_blank(row) {
+                            row -= 1;
+                        }
+                        while row > 0 && !is_blank(row) {
+                            row -= 1;
+                        }
+                    }
+                }
+                self.row = row;
+                if row == last && motion == Motion::ParagraphForward {
+                    self.move_line_end(content);
+                } else {
+                    self.move_line_start();
+                }
+            }
+            Motion::FindChar { ch, forward, till } => {
+                let chars: Vec<char> = text::line_text(content, self.row).chars().collect();
+                let mut col = self.col;
+                for _ in 0..times {
+                    // A `t` target right beside the cursor counts, so `ct)`
+                    // on the last char before `)` changes just that char.
+                    let found = if forward {
+                        (col + 1..chars.len()).find(|&i| chars[i] == ch)
+                    } else {
+                        (0..col).rev().find(|&i| chars[i] == ch)
+                    };
+                    match found {
+                        Some(i) => col = i,
+                        None => return false,
+                    }
+                }
+                self.col = match (till, forward) {
+                    (true, true) => col - 1,
+                    (true, false) => col + 1,
+                    _ => col,
+                };
+                self.update_desired_col(content);
+                return true;
+            }
+            Motion::MatchBracket => {
+                match motion::match_bracket(content, self.char_index(content)) {
+                    Some(pos) => self.set_char_index(content, pos),
+                    None => return false,
+                }
+            }
+            Motion::DisplayDown | Motion::DisplayUp if !wrapping.wraps() => {
+                let motion = if motion == Motion::DisplayDown {
+                    Motion::Down
+                } else {
+                    Motion::Up
+                };
+                return self.apply_motion(content, motion, count, wrapping);
+            }
+            Motion::DisplayDown | Motion::DisplayUp => {
+                for _ in 0..times {
+                    if !self.move_screen_row(content, wrapping, motion == Motion::DisplayDown) {
+                        break;
+                    }
+                }
+            }
+            Motion::DisplayLineStart => {
+                let line = text::line_text(content, self.row);
+                let start = if wrapping.wraps() {
+                    let rows = wrapping.rows(&line);
+                    rows[Wrapping::row_of(&rows, text::display_col(&line, self.col))].start
+                } else {
+                    wrapping.left_col
+                };
+                self.col = text::col_for_display(&line, start);
+                self.update_desired_col(content);
+            }
+            Motion::DisplayLineEnd => {
+                for _ in 1..times {
+                    if !self.move_screen_row(content, wrapping, true) {
+                        break;
+                    }
+                }
+                let line = text::line_text(content, self.row);
+                let end = if wrapping.wraps() {
+                    let rows = wrapping.rows(&line);
+                    rows[Wrapping::row_of(&rows, text::display_col(&line, self.col))].end
+                } else if wrapping.width > 0 {
+                    wrapping.left_col + wrapping.width
+                } else {
+                    usize::MAX
+                };
+                let last = line.chars().count().saturating_sub(1);
+                self.col = text::col_for_display(&line, end.saturating_sub(1)).min(last);
+                self.update_desired_col(content);
+                return true;
+            }
+        }
+        (self.row, self.col) != before
+    }
+    /// Moves to the screen row below or above, keeping the column on
+    /// screen as far as the row reaches. False at the end of the buffer.
+    fn move_screen_row(&mut self, content: &Rope, wrapping: &Wrapping, down: bool) -> bool {
+        let line = text::line_text(content, self.row);
+        let rows = wrapping.rows(&line);
+        let display = text::display_col(&line, self.col);
+        let index = Wrapping::row_of(&rows, display);
+        let x = rows[index].indent + display - rows[index].start;
+        let (row, index) = match down {
+            true if index + 1 < rows.len() => (self.row, index + 1),
+            true if self.row + 1 < content.len_lines() => (self.row + 1, 0),
+            false if index > 0 => (self.row, index - 1),
+            false if self.row > 0 => {
+                let above = text::line_text(content, self.row - 1);
+                (self.row - 1, wrapping.rows(&above).len() - 1)
+            }
+            _ => return false,
+        };
+        self.row = row;
+        let line = text::line_text(content, row);
+        let rows = wrapping.rows(&line);
+        let target = rows[index];
+        let mut display = target.start + x.saturating_sub(target.indent);
+        if index + 1 < rows.len() {
+            // Past the end of a wrapped row is the start of the next one.
+            display = display.min(target.end.saturating_sub(1));
+        }
+        self.col = text::col_for_display(&line, display);
+        self.update_desired_col(content);
+        true
+    }
+    /// Range an operator acts on when combined with `motion`, from the cursor
+    /// to where the motion lands.
+    pub fn motion_range(
+        &self,
+        content: &Rope,
+        motion: Motion,
+        count: Option<usize>,
+        wrapping: &Wrapping,
+    ) -> Option<MotionRange> {
+        let mut target = self.clone();
+        if !target.apply_motion(content, motion, count, wrapping) {
+            return None;
+        }
+        let from = self.char_index(content);
+        let to = target.char_index(content);
+        let (start, end) = (from.min(to), from.max(to));
+        match motion.kind() {
+            MotionKind::Linewise => Some(MotionRange::lines(
+                content,
+                self.row.min(target.row),
+                self.row.max(target.row),
+            )),
+            MotionKind::Inclusive => {
+                let end_row = content.char_to_line(end);
+                let line = text::line_text(content, end_row);
+                let col = end - content.line_to_char(end_row);
+                let end = end + text::next_grapheme(&line, col).min(line.chars().count()) - col;
+                Some(MotionRange {
+                    start,
+                    end,
+                    linewise: false,
+                })
+            }
+            MotionKind::Exclusive => {
+                let mut end = end;
+                // Like vim, `dw` on the last word of a line stops at the line end.
+                if matches!(motion, Motion::WordForward | Motion::BigWordForward)
+                    && target.row > self.row
+                {
+                    let last_row = target.row - 1;
+                    end = content.line_to_char(last_row) + text::line_len(content, last_row);
+                    end = end.max(start);
+                }
+                Some(MotionRange {
+                    start,
+                    end,
+                    linewise: false,
+                })
+            }
+        }
     }
-    #[allow(dead_code)]
     pub fn move_to_line(&mut self, content: &Rope, line: usize) {
         self.row = line.min(content.len_lines().saturating_sub(1));
-        let line_len = self.get_line_length(content, self.row);
-        self.col = self.desired_col.min(line_len);
+        self.restore_desired_col(content);
     }
     fn get_line_length(&self, content: &Rope, line: usize) -> usize {
-        if line < content.len_lines() {
-            let line_content = content.line(line);
-            let line_str = line_content.to_string();
-            if line_str.ends_with('\n') {
-                line_str.len().saturating_sub(1)
-            } else {
-                line_str.len()
-            }
-        } else {
-            0
-        }
+        text::line_len(content, line)
     }
-    #[allow(dead_code)]
     pub fn clamp_to_buffer(&mut self, content: &Rope) {
         self.row = self.row.min(content.len_lines().saturating_sub(1));
-        let line_len = self.get_line_length(content, self.row);
-        self.col = self.col.min(line_len);
-        self.desired_col = self.col;
+        let line = text::line_text(content, self.row);
+        self.col = text::grapheme_start(&line, self.col.min(line.chars().count()));
+        self.update_desired_col(content);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrap::WrapOptions;
+
+    fn no_wrap() -> Wrapping {
+        Wrapping {
+            width: 0,
+            left_col: 0,
+            options: WrapOptions {
+                wrap: false,
+                linebreak: false,
+                showbreak: String::new(),
+                breakindent: false,
+                sidescrolloff: 0,
+            },
+        }
+    }
+    fn find(line: &str, col: usize, ch: char, forward: bool, till: bool, count: usize) -> usize {
+        let content = Rope::from_str(line);
+        let mut cursor = Cursor::new();
+        cursor.col = col;
+        let motion = Motion::FindChar { ch, forward, till };
+        cursor.apply_motion(&content, motion, Some(count), &no_wrap());
+        cursor.col
+    }
+
+    #[test]
+    fn test_moves_by_grapheme_and_keeps_display_column() {
+        let content = Rope::from_str("ae\u{301}b\n日本語\n");
+        let mut cursor = Cursor::new();
+        cursor.move_right(&content);
+        cursor.move_right(&content);
+        assert_eq!(cursor.col, 3);
+        cursor.move_left(&content);
+        assert_eq!(cursor.col, 1);
+        // Display column 1 falls inside `日`, so the cursor lands on it and
+        // comes back to column 1 on the line above.
+        cursor.move_down(&content);
+        assert_eq!((cursor.row, cursor.col), (1, 0));
+        cursor.move_up(&content);
+        assert_eq!((cursor.row, cursor.col), (0, 1));
+        cursor.move_down(&content);
+        cursor.move_right(&content);
+        cursor.move_up(&content);
+        assert_eq!(cursor.col, 3);
+    }
+
+    #[test]
+    fn test_huge_count_stops_at_the_end() {
+        let content = Rope::from_str("one two\nthree\n");
+        for motion in [Motion::Right, Motion::WordForward, Motion::ParagraphForward] {
+            let mut cursor = Cursor::new();
+            cursor.apply_motion(&content, motion, Some(999_999_999), &no_wrap());
+            assert!(cursor.row <= 2, "{:?}", motion);
+        }
+        let mut cursor = Cursor::new();
+        cursor.row = 1;
+        cursor.apply_motion(&content, Motion::Down, Some(usize::MAX), &no_wrap());
+        assert_eq!(cursor.row, 2);
+    }
+
+    #[test]
+    fn test_till_target_beside_cursor() {
+        assert_eq!(find("(a)", 1, ')', true, true, 1), 1);
+        assert_eq!(find("(a)", 1, '(', false, true, 1), 1);
+    }
+
+    #[test]
+    fn test_till_with_count_takes_adjacent_matches() {
+        assert_eq!(find("a xx", 0, 'x', true, true, 2), 2);
+        assert_eq!(find("xx a", 3, 'x', false, true, 2), 1);
+    }
+
+    #[test]
+    fn test_find_char() {
+        assert_eq!(find("a,b,c", 0, ',', true, false, 2), 3);
+        assert_eq!(find("a,b,c", 4, ',', false, false, 1), 3);
+        assert_eq!(find("a,b,c", 0, ';', true, false, 1), 0);
+    }
+
+    #[test]
+    fn test_change_till_covers_one_char() {
+        let content = Rope::from_str("(a)");
+        let mut cursor = Cursor::new();
+        cursor.col = 1;
+        let motion = Motion::FindChar {
+            ch: ')',
+            forward: true,
+            till: true,
+        };
+        let range = cursor.motion_range(&content, motion, None, &no_wrap());
+        assert_eq!(range.map(|range| (range.start, range.end)), Some((1, 2)));
     }
 }
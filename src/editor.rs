@@ -1,49 +1,138 @@
-use crate::buffer::Buffer;
+use crate::buffer::{Buffer, GlobalLines};
+use crate::clipboard::Clipboard;
+use crate::cmdline::CommandLine;
 use crate::commands::CommandHandler;
+use crate::cursor::Cursor;
+use crate::ex::{self, Addresses, LineRange};
+use crate::history::History;
+use crate::macros;
 use crate::modes::EditorMode;
-use crate::ui::UI;
-use anyhow::Result;
+use crate::motion::{char_class, Motion, MotionRange};
+use crate::normal::{NormalCommand, NormalParser, Operator, ParseResult, RepeatableChange, Target};
+use crate::options::Options;
+use crate::registers::{Register, RegisterKind, Registers};
+use crate::search::{self, LastSearch, MatchCount, SearchOffset};
+use crate::substitute::{self, Found, Replacement, SubstituteCommand, Substitution};
+use crate::text;
+use crate::textobject::TextObject;
+use crate::ui::{self, Highlights, Prompt, TabLabel, TabLine, UI};
+use crate::undo::data_dir;
+use crate::visual::{Block, BlockInsert, Selection, VisualMode};
+use crate::window::{Split, WindowLayout};
+use crate::wrap::Wrapping;
+use anyhow::{anyhow, Result};
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
 use std::time::Duration;
 pub struct Editor {
     buffers: Vec<Buffer>,
     current_buffer: usize,
     mode: EditorMode,
     ui: UI,
-    #[allow(dead_code)]
-    command_handler: CommandHandler,
+    /// Tab pages, each with its own windows. The current window's buffer
+    /// and cursor are kept in `current_buffer` and that buffer's cursor
+    /// while it is current.
+    tabs: Vec<WindowLayout>,
+    current_tab: usize,
+    command_handler: Rc<CommandHandler>,
     running: bool,
-    clipboard: Vec<String>,
-    search_pattern: Option<String>,
-    #[allow(dead_code)]
-    last_command: Option<String>,
-    command_line: String,
+    last_search: Option<LastSearch>,
+    search_history: History,
+    command_history: History,
+    /// Entry recalled with Up/Down and the text typed before recalling.
+    history_index: Option<usize>,
+    history_prefix: String,
+    /// Cursor position an offset search left and the match it came from,
+    /// so that `n` continues from the match.
+    search_anchor: Option<(usize, usize)>,
+    /// Cleared by `:nohlsearch` until the next search.
+    highlight_search: bool,
+    /// Cursor from before `/` or `?`, restored if the search is abandoned.
+    search_origin: Option<Cursor>,
+    /// Match the cursor is on while a pattern is being typed.
+    current_match: Option<Range<usize>>,
+    search_count: Option<MatchCount>,
+    /// Replacement string of the last `:s`, for `~`.
+    last_replacement: String,
+    /// A `:s///c` waiting for y/n/a/q/l about `Found`.
+    confirm: Option<(Substitution, Found)>,
+    /// Set while `:global` runs its command on each line.
+    in_global: bool,
+    /// Substitutions and lines changed by the `:s` commands a `:global`
+    /// runs, reported once when it ends.
+    global_substituted: (usize, usize),
+    last_change: Option<RepeatableChange>,
+    insert_change: Option<RepeatableChange>,
+    command_line: CommandLine,
+    /// Set by `Ctrl-r` on the command line until the register name.
+    command_register_pending: bool,
     message: Option<String>,
-    pending_command: Option<char>,
-    visual_start: Option<(usize, usize)>,
+    normal_parser: NormalParser,
+    visual_object: Option<bool>,
+    visual: Option<Selection>,
+    last_visual: Option<Selection>,
+    block_insert: Option<BlockInsert>,
+    options: Options,
+    registers: Registers,
+    clipboard: Clipboard,
+    /// Register being recorded into and the keys typed so far.
+    recording: Option<(char, String)>,
+    /// Keys queued by macro playback, processed before reading new input.
+    typeahead: VecDeque<KeyEvent>,
+    last_macro: Option<char>,
 }
 impl Editor {
     pub fn new() -> Result<Self> {
         let ui = UI::new()?;
-        let command_handler = CommandHandler::new();
+        let command_handler = Rc::new(CommandHandler::new());
         Ok(Self {
             buffers: vec![Buffer::new()],
             current_buffer: 0,
             mode: EditorMode::Normal,
             ui,
+            tabs: vec![WindowLayout::new(0)],
+            current_tab: 0,
             command_handler,
             running: true,
-            clipboard: Vec::new(),
-            search_pattern: None,
-            last_command: None,
-            command_line: String::new(),
+            last_search: None,
+            search_history: History::load(Some(data_dir().join("search_history"))),
+            command_history: History::load(Some(data_dir().join("command_history"))),
+            history_index: None,
+            history_prefix: String::new(),
+            search_anchor: None,
+            highlight_search: true,
+            search_origin: None,
+            current_match: None,
+            search_count: None,
+            last_replacement: String::new(),
+            confirm: None,
+            in_global: false,
+            global_substituted: (0, 0),
+            last_change: None,
+            insert_change: None,
+            command_line: CommandLine::default(),
+            command_register_pending: false,
             message: None,
-            pending_command: None,
-            visual_start: None,
+            normal_parser: NormalParser::new(),
+            visual_object: None,
+            visual: None,
+            last_visual: None,
+            block_insert: None,
+            options: Options::new(),
+            registers: Registers::new(),
+            clipboard: Clipboard::new(Box::new(io::stdout())),
+            recording: None,
+            typeahead: VecDeque::new(),
+            last_macro: None,
         })
     }
     pub fn open_file(&mut self, path: &str) -> Result<()> {
-        let buffer = Buffer::from_file(path)?;
+        let buffer = Buffer::from_file(path, self.options.undo_dir())?;
         self.buffers.push(buffer);
         self.current_buffer = self.buffers.len() - 1;
         Ok(())
@@ -51,6 +140,9 @@ impl Editor {
     pub fn run(&mut self) -> Result<()> {
         self.ui.enter_alternate_screen()?;
         while self.running {
+            if let Some(failure) = self.clipboard.take_failure() {
+                self.message = Some(format!("Clipboard error: {}", failure));
+            }
             self.render()?;
             self.handle_input()?;
         }
@@ -58,161 +150,768 @@ impl Editor {
         Ok(())
     }
     fn render(&mut self) -> Result<()> {
+        self.store_window();
+        let tabline = TabLine {
+            labels: self.tab_labels(),
+            current: self.current_tab,
+        };
+        let selection = self.selection();
+        let search = self.highlighted_pattern();
+        let highlights = Highlights {
+            selection: selection.as_ref(),
+            search: search.as_ref(),
+            current_match: self.current_match.clone(),
+        };
         let buffer = &self.buffers[self.current_buffer];
-        self.ui
-            .render(buffer, &self.mode, &self.command_line, &self.message)?;
+        // The count only describes the match it was taken on.
+        let cursor = buffer.cursor.char_index(&buffer.content);
+        let search_count = self.search_count.filter(|count| count.pos == cursor);
+        let prompt = Prompt {
+            mode: &self.mode,
+            command_line: &self.command_line,
+            message: &self.message,
+            search_count,
+        };
+        self.ui.render(
+            &mut self.buffers,
+            &mut self.tabs[self.current_tab],
+            &highlights,
+            &prompt,
+            &tabline,
+            &self.options.wrap_options(),
+        )?;
         Ok(())
     }
+    /// Pattern to highlight with `hlsearch`: the one being typed when
+    /// `incsearch` is on, else the last search.
+    fn highlighted_pattern(&self) -> Option<Regex> {
+        if !self.options.hlsearch {
+            return None;
+        }
+        let pattern = match self.search_origin {
+            Some(_) if self.options.incsearch => self.typed_pattern(),
+            _ if self.highlight_search => {
+                self.last_search.as_ref().map(|last| last.pattern.as_str())
+            }
+            _ => None,
+        }
+        .filter(|pattern| !pattern.is_empty())?;
+        search::compile(pattern, self.options.ignorecase, self.options.smartcase).ok()
+    }
     fn handle_input(&mut self) -> Result<()> {
         if event::poll(Duration::from_millis(100))? {
-            if let Event::Key(key_event) = event::read()? {
-                self.process_key_event(key_event)?;
+            match event::read()? {
+                Event::Key(key_event) => self.handle_key(key_event)?,
+                // Windows are laid out again from the new size on the
+                // next draw, each scrolled to keep its cursor in view.
+                Event::Resize(..) => self.ui.resize()?,
+                _ => {}
             }
         }
         Ok(())
     }
+    /// Handles a key typed by the user, recording it for a macro, and then
+    /// whatever keys it queued.
+    fn handle_key(&mut self, key_event: KeyEvent) -> Result<()> {
+        if let Some((_, keys)) = &mut self.recording {
+            keys.extend(macros::encode_key(key_event));
+        }
+        self.process_key_event(key_event)?;
+        while let Some(key) = self.typeahead.pop_front() {
+            self.process_key_event(key)?;
+        }
+        Ok(())
+    }
     fn process_key_event(&mut self, key_event: KeyEvent) -> Result<()> {
+        if self.message.as_ref().is_some_and(|msg| msg.contains('\n')) {
+            self.message = None;
+        }
+        if self.confirm.is_some() {
+            self.handle_confirm_key(key_event);
+            return Ok(());
+        }
         match &self.mode {
             EditorMode::Normal => self.handle_normal_mode(key_event),
             EditorMode::Insert => self.handle_insert_mode(key_event),
-            EditorMode::Visual => self.handle_visual_mode(key_event),
+            EditorMode::Visual(_) => self.handle_visual_mode(key_event),
             EditorMode::Command => self.handle_command_mode(key_event),
         }
     }
     fn handle_normal_mode(&mut self, key_event: KeyEvent) -> Result<()> {
-        if let Some(pending) = self.pending_command {
-            self.pending_command = None;
-            return self.handle_pending_command(pending, key_event);
+        if key_event.code == KeyCode::Char('q')
+            && self.recording.is_some()
+            && !self.normal_parser.is_pending()
+        {
+            self.stop_recording();
+            return Ok(());
+        }
+        match self.normal_parser.feed(key_event, false) {
+            ParseResult::Complete(command) => self.execute_normal_command(command),
+            ParseResult::Pending | ParseResult::Invalid => Ok(()),
+        }
+    }
+    fn execute_normal_command(&mut self, command: NormalCommand) -> Result<()> {
+        if let NormalCommand::Action {
+            prefix: None,
+            key,
+            count,
+            ..
+        } = &command
+        {
+            if key.code == KeyCode::Char('.') {
+                return self.repeat_last_change(*count);
+            }
+        }
+        let repeatable = command.is_change();
+        self.run_normal_command(command.clone())?;
+        if self.mode == EditorMode::Insert {
+            self.insert_change = Some(RepeatableChange {
+                command,
+                inserted: Vec::new(),
+            });
+        } else if repeatable {
+            self.last_change = Some(RepeatableChange {
+                command,
+                inserted: Vec::new(),
+            });
+        }
+        Ok(())
+    }
+    /// Replays the last change for `.`. A new count replaces the count of an
+    /// operator or paste, and repeats an insertion that many times.
+    fn repeat_last_change(&mut self, count: Option<usize>) -> Result<()> {
+        let Some(change) = self.last_change.clone() else {
+            return Ok(());
+        };
+        let (command, times) = change.command.with_repeat_count(count);
+        for _ in 0..times {
+            self.execute_normal_command(command.clone())?;
+            if self.mode == EditorMode::Insert {
+                for key in &change.inserted {
+                    self.handle_insert_mode(*key)?;
+                }
+                self.handle_insert_mode(KeyEvent::from(KeyCode::Esc))?;
+            }
+        }
+        Ok(())
+    }
+    fn run_normal_command(&mut self, command: NormalCommand) -> Result<()> {
+        match command {
+            NormalCommand::Move { motion, count } => {
+                let wrapping = self.wrapping();
+                let buffer = &mut self.buffers[self.current_buffer];
+                if !buffer
+                    .cursor
+                    .apply_motion(&buffer.content, motion, count, &wrapping)
+                {
+                    self.typeahead.clear();
+                }
+                Ok(())
+            }
+            NormalCommand::Operate {
+                operator,
+                target,
+                count,
+                register,
+            } => {
+                self.execute_operator(operator, target, count, register);
+                Ok(())
+            }
+            NormalCommand::Action {
+                prefix: None,
+                key,
+                count,
+                arg: Some(arg),
+                ..
+            } => {
+                match key.code {
+                    KeyCode::Char('q') => self.start_recording(arg),
+                    KeyCode::Char('@') => self.play_macro(arg, count.unwrap_or(1)),
+                    KeyCode::Char('m') => self.set_mark(arg),
+                    KeyCode::Char(c @ ('\'' | '`')) => self.jump_to_mark(arg, c == '\''),
+                    _ => {}
+                }
+                Ok(())
+            }
+            NormalCommand::Action {
+                prefix: Some('w'),
+                key,
+                count,
+                ..
+            } => self.window_command(key, count),
+            NormalCommand::Action {
+                prefix: Some('z'),
+                key,
+                count,
+                ..
+            } => {
+                self.scroll_sideways(key, count);
+                Ok(())
+            }
+            NormalCommand::Action {
+                prefix: Some('g'),
+                key,
+                count,
+                ..
+            } if matches!(key.code, KeyCode::Char('t' | 'T')) => {
+                self.goto_tab(key.code == KeyCode::Char('t'), count);
+                Ok(())
+            }
+            NormalCommand::Action {
+                prefix,
+                key,
+                count,
+                register,
+                ..
+            } => self.execute_action(prefix, key, count.unwrap_or(1), register),
+        }
+    }
+    /// `m{a-z}` remembers the cursor position in the buffer.
+    fn set_mark(&mut self, mark: char) {
+        if mark.is_ascii_lowercase() {
+            let buffer = &mut self.buffers[self.current_buffer];
+            let cursor = &buffer.cursor;
+            buffer.marks.insert(mark, (cursor.row, cursor.col));
         }
+    }
+    /// `'{a-z}` goes to the first non-blank of the mark's line, `` `{a-z} ``
+    /// to the exact position.
+    fn jump_to_mark(&mut self, mark: char, linewise: bool) {
         let buffer = &mut self.buffers[self.current_buffer];
-        match key_event.code {
-            KeyCode::Char('q') => self.running = false,
-            KeyCode::Char('i') => self.mode = EditorMode::Insert,
-            KeyCode::Char('I') => {
-                buffer.cursor.move_line_start();
-                self.mode = EditorMode::Insert;
+        let Some(&(row, col)) = buffer.marks.get(&mark) else {
+            self.message = Some("E20: Mark not set".to_string());
+            self.typeahead.clear();
+            return;
+        };
+        buffer.cursor.row = row.min(text::line_count(&buffer.content) - 1);
+        if linewise {
+            buffer
+                .cursor
+                .move_line_first_non_whitespace(&buffer.content);
+        } else {
+            buffer.cursor.col = col;
+            buffer.cursor.clamp_to_buffer(&buffer.content);
+        }
+    }
+    fn start_recording(&mut self, register: char) {
+        if Registers::is_named(register) || register.is_ascii_digit() || register == '"' {
+            self.recording = Some((register, String::new()));
+            self.message = Some(format!("recording @{}", register));
+        }
+    }
+    fn stop_recording(&mut self) {
+        if let Some((register, mut keys)) = self.recording.take() {
+            if keys.ends_with('q') {
+                keys.pop();
             }
-            KeyCode::Char('o') => {
-                buffer.cursor.move_line_end(&buffer.content);
-                buffer.insert_newline();
-                self.mode = EditorMode::Insert;
+            self.registers.record(register, keys);
+            self.message = None;
+        }
+    }
+    /// Queues the keys stored in `register` ahead of any pending typeahead.
+    /// `@@` replays the last executed macro.
+    fn play_macro(&mut self, register: char, count: usize) {
+        let register = if register == '@' {
+            match self.last_macro {
+                Some(register) => register,
+                None => {
+                    self.message = Some("E748: No previously used register".to_string());
+                    return;
+                }
             }
-            KeyCode::Char('O') => {
-                buffer.cursor.move_line_start();
-                buffer.insert_newline();
-                buffer.cursor.move_up();
-                self.mode = EditorMode::Insert;
+        } else {
+            register
+        };
+        let Some(stored) = self.read_register(register) else {
+            return;
+        };
+        // `@:` repeats the last command line.
+        let text = if register == ':' {
+            format!(":{}\r", stored.text)
+        } else {
+            stored.text
+        };
+        let keys = macros::decode_keys(&text);
+        self.last_macro = Some(register);
+        for _ in 0..count {
+            for key in keys.iter().rev() {
+                self.typeahead.push_front(*key);
             }
-            KeyCode::Char('v') => {
-                self.mode = EditorMode::Visual;
-                self.visual_start = Some((buffer.cursor.row, buffer.cursor.col));
+        }
+    }
+    fn execute_operator(
+        &mut self,
+        operator: Operator,
+        target: Target,
+        count: Option<usize>,
+        register: Option<char>,
+    ) {
+        let wrapping = self.wrapping();
+        let buffer = &mut self.buffers[self.current_buffer];
+        let range = match target {
+            Target::Selection { linewise } => {
+                return self.execute_visual_operator(operator, linewise, count, register);
             }
-            KeyCode::Char(':') => {
-                self.mode = EditorMode::Command;
-                self.command_line = ":".to_string();
+            Target::Lines => {
+                let first = buffer.cursor.row;
+                let mut lines = count.unwrap_or(1).max(1);
+                if operator == Operator::Join {
+                    // `J` and `2J` both join two lines.
+                    lines = lines.max(2);
+                }
+                Some(MotionRange::lines(
+                    &buffer.content,
+                    first,
+                    first + lines - 1,
+                ))
             }
-            KeyCode::Char('/') => {
-                self.mode = EditorMode::Command;
-                self.command_line = "/".to_string();
+            Target::Motion(motion) => {
+                let (motion, count) = change_word_motion(buffer, operator, motion, count);
+                buffer
+                    .cursor
+                    .motion_range(&buffer.content, motion, count, &wrapping)
             }
-            KeyCode::Char('?') => {
-                self.mode = EditorMode::Command;
-                self.command_line = "?".to_string();
+            Target::TextObject(object) => {
+                let pos = buffer.cursor.char_index(&buffer.content);
+                object.select(&buffer.content, pos, count.unwrap_or(1))
             }
-            KeyCode::Up => buffer.cursor.move_up(),
-            KeyCode::Down => buffer.cursor.move_down(&buffer.content),
-            KeyCode::Left => buffer.cursor.move_left(),
-            KeyCode::Right => buffer.cursor.move_right(&buffer.content),
-            KeyCode::Char('a') => {
-                buffer.cursor.move_right(&buffer.content);
-                self.mode = EditorMode::Insert;
+        };
+        // `3rx` needs three chars to replace.
+        let range = range.filter(|range| match operator {
+            Operator::Replace(_) => range.end - range.start >= count.unwrap_or(1),
+            _ => true,
+        });
+        let Some(range) = range else {
+            self.typeahead.clear();
+            return;
+        };
+        // Like vim, these motions always fill "1 even within a line.
+        let numbered = matches!(
+            target,
+            Target::Motion(
+                Motion::MatchBracket | Motion::ParagraphForward | Motion::ParagraphBackward
+            )
+        );
+        self.apply_operator(operator, range, register, numbered);
+        if let Operator::Replace(_) = operator {
+            let buffer = &mut self.buffers[self.current_buffer];
+            buffer
+                .cursor
+                .set_char_index(&buffer.content, range.end.saturating_sub(1));
+        }
+    }
+    /// Runs `operator` over `range`; shared by normal-mode motions and text
+    /// objects and by char and line selections in Visual mode.
+    pub fn apply_operator(
+        &mut self,
+        operator: Operator,
+        range: MotionRange,
+        register: Option<char>,
+        numbered: bool,
+    ) {
+        if !self.register_accepts(operator, register) {
+            return;
+        }
+        let buffer = &self.buffers[self.current_buffer];
+        let kind = if range.linewise {
+            RegisterKind::Linewise
+        } else {
+            RegisterKind::Charwise
+        };
+        match operator {
+            Operator::Yank => {
+                let text = buffer.range_text(&range);
+                self.store_yank(register, Register::new(text, kind));
             }
-            KeyCode::Char('A') => {
-                buffer.cursor.move_line_end(&buffer.content);
+            Operator::Delete | Operator::Change => {
+                let text = buffer.range_text(&range);
+                self.store_delete(register, Register::new(text, kind), numbered);
+            }
+            _ => {}
+        }
+        let buffer = &mut self.buffers[self.current_buffer];
+        let first_line = range.first_line(&buffer.content);
+        let last_line = range.last_line(&buffer.content);
+        match operator {
+            Operator::Yank => {
+                if range.linewise {
+                    buffer.cursor.move_to_line(&buffer.content, first_line);
+                } else {
+                    buffer.cursor.set_char_index(&buffer.content, range.start);
+                }
+            }
+            Operator::Delete => {
+                buffer.save_state();
+                buffer.delete_range(&range);
+            }
+            Operator::Change => {
+                buffer.begin_undo_group();
+                if range.linewise {
+                    buffer.clear_lines(&range);
+                } else {
+                    buffer.delete_range(&range);
+                }
                 self.mode = EditorMode::Insert;
             }
-            KeyCode::Char('w') => buffer.cursor.move_word_forward(&buffer.content),
-            KeyCode::Char('b') => buffer.cursor.move_word_backward(&buffer.content),
-            KeyCode::Char('e') => buffer.cursor.move_word_end(&buffer.content),
-            KeyCode::Char('0') => buffer.cursor.move_line_start(),
-            KeyCode::Char('$') => buffer.cursor.move_line_end(&buffer.content),
-            KeyCode::Char('^') => buffer
-                .cursor
-                .move_line_first_non_whitespace(&buffer.content),
-            KeyCode::Char('G') => buffer.cursor.move_to_end(&buffer.content),
-            KeyCode::Char('g') => {
-                buffer.cursor.move_to_start();
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                buffer.save_state();
+                buffer.shift_lines(first_line, last_line, operator == Operator::ShiftRight);
             }
-            KeyCode::Char('x') => {
-                buffer.delete_char();
+            Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+                buffer.save_state();
+                buffer.change_case(&range, operator);
             }
-            KeyCode::Char('X') => {
-                buffer.backspace();
+            Operator::Reindent => {
+                buffer.save_state();
+                buffer.reindent_lines(first_line, last_line);
             }
-            KeyCode::Delete => {
-                buffer.delete_char();
+            Operator::Join => {
+                buffer.save_state();
+                buffer.join_lines(first_line, last_line.max(first_line + 1));
             }
-            KeyCode::Char('d') => {
-                self.pending_command = Some('d');
+            Operator::Format => {
+                buffer.save_state();
+                buffer.format_lines(first_line, last_line, text::TEXT_WIDTH);
             }
-            KeyCode::Char('D') => {
-                buffer.delete_to_line_end();
+            Operator::Replace(c) => {
+                buffer.save_state();
+                buffer.replace_chars(&range, c);
+                buffer.cursor.set_char_index(&buffer.content, range.start);
             }
-            KeyCode::Char('y') => {
-                self.pending_command = Some('y');
+        }
+    }
+    /// Applies `operator` to the Visual selection and leaves Visual mode. A
+    /// count repeats shifts.
+    fn execute_visual_operator(
+        &mut self,
+        operator: Operator,
+        linewise: bool,
+        count: Option<usize>,
+        register: Option<char>,
+    ) {
+        let Some(mut selection) = self.selection() else {
+            return;
+        };
+        self.exit_visual();
+        match (linewise, selection.mode) {
+            (false, _) => {}
+            (true, VisualMode::Block) => selection.to_eol = true,
+            (true, _) => selection.mode = VisualMode::Line,
+        }
+        let content = &self.buffers[self.current_buffer].content;
+        if selection.mode == VisualMode::Block {
+            let block = selection.block(content);
+            self.apply_block_operator(operator, &selection, block, count, register);
+            return;
+        }
+        let range = selection.range(content);
+        self.apply_operator(operator, range, register, false);
+        if matches!(operator, Operator::ShiftRight | Operator::ShiftLeft) {
+            let buffer = &mut self.buffers[self.current_buffer];
+            for _ in 1..count.unwrap_or(1) {
+                buffer.shift_lines(
+                    selection.first_row(),
+                    selection.last_row(),
+                    operator == Operator::ShiftRight,
+                );
             }
-            KeyCode::Char('Y') => {
-                let text = buffer.get_text_to_line_end();
-                self.clipboard = vec![text];
+        }
+    }
+    fn apply_block_operator(
+        &mut self,
+        operator: Operator,
+        selection: &Selection,
+        block: Block,
+        count: Option<usize>,
+        register: Option<char>,
+    ) {
+        if !self.register_accepts(operator, register) {
+            return;
+        }
+        let buffer = &self.buffers[self.current_buffer];
+        let text = Register::new(buffer.block_text(&block), RegisterKind::Blockwise);
+        match operator {
+            Operator::Yank => self.store_yank(register, text),
+            Operator::Delete | Operator::Change => self.store_delete(register, text, false),
+            _ => {}
+        }
+        let buffer = &mut self.buffers[self.current_buffer];
+        let (first, last) = (block.first_row, block.last_row);
+        match operator {
+            Operator::Yank => {}
+            Operator::Delete => {
+                buffer.save_state();
+                buffer.delete_block(&block);
+            }
+            Operator::Change => {
+                buffer.begin_undo_group();
+                buffer.delete_block(&block);
+                let selection = Selection {
+                    to_eol: false,
+                    ..*selection
+                };
+                self.start_block_insert(&selection, false);
+                return;
             }
-            KeyCode::Char('p') => {
-                if !self.clipboard.is_empty() {
-                    buffer.paste_after(&self.clipboard[0]);
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                buffer.save_state();
+                for _ in 0..count.unwrap_or(1) {
+                    buffer.shift_lines(first, last, operator == Operator::ShiftRight);
                 }
             }
-            KeyCode::Char('P') => {
-                if !self.clipboard.is_empty() {
-                    buffer.paste_before(&self.clipboard[0]);
+            Operator::Reindent => {
+                buffer.save_state();
+                buffer.reindent_lines(first, last);
+            }
+            Operator::Join => {
+                buffer.save_state();
+                buffer.join_lines(first, last.max(first + 1));
+            }
+            Operator::Format => {
+                buffer.save_state();
+                buffer.format_lines(first, last, text::TEXT_WIDTH);
+            }
+            Operator::Lowercase
+            | Operator::Uppercase
+            | Operator::ToggleCase
+            | Operator::Replace(_) => {
+                buffer.save_state();
+                for row in first..=last {
+                    let (start, end) = block.cols(&buffer.content, row);
+                    let line_start = buffer.content.line_to_char(row);
+                    let range = MotionRange {
+                        start: line_start + start,
+                        end: line_start + end,
+                        linewise: false,
+                    };
+                    match operator {
+                        Operator::Replace(c) => buffer.replace_chars(&range, c),
+                        _ => buffer.change_case(&range, operator),
+                    }
                 }
             }
-            KeyCode::Char('u') => {
-                buffer.undo();
+        }
+        if !matches!(
+            operator,
+            Operator::Delete | Operator::Join | Operator::Format
+        ) {
+            buffer.cursor.row = first;
+            buffer.cursor.col = block.cols(&buffer.content, first).0;
+            buffer.cursor.clamp_to_buffer(&buffer.content);
+        }
+    }
+    /// Refuses a yank or delete into a register that can only be read,
+    /// leaving the text and `""` alone.
+    fn register_accepts(&mut self, operator: Operator, register: Option<char>) -> bool {
+        let stores = matches!(
+            operator,
+            Operator::Yank | Operator::Delete | Operator::Change
+        );
+        match register {
+            Some(name) if stores && !Registers::is_writable(name) => {
+                self.message = Some(format!("E354: Invalid register name: '{}'", name));
+                self.typeahead.clear();
+                false
             }
-            KeyCode::Char('r') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
-                buffer.redo();
+            _ => true,
+        }
+    }
+    /// Records a yank in the registers, and on the system clipboard when
+    /// the target is `"+`/`"*` (directly or through `clipboard=unnamed...`).
+    /// Going to the clipboard through `clipboard` still fills `"0`, like a
+    /// yank naming no register; deletes likewise fill `"1`-`"9` and `"-`.
+    fn store_yank(&mut self, register: Option<char>, text: Register) {
+        self.registers.yank(register, text.clone());
+        self.copy_to_clipboard(register.or(self.options.default_register()), &text);
+    }
+    fn store_delete(&mut self, register: Option<char>, text: Register, numbered: bool) {
+        self.registers.delete(register, text.clone(), numbered);
+        self.copy_to_clipboard(register.or(self.options.default_register()), &text);
+    }
+    fn copy_to_clipboard(&mut self, register: Option<char>, text: &Register) {
+        let Some(selection) = register.filter(|&r| Clipboard::is_clipboard(r)) else {
+            return;
+        };
+        let copy_command = self.options.clipcopy.as_deref();
+        if let Err(err) = self.clipboard.write(selection, text, copy_command) {
+            self.message = Some(format!("Clipboard error: {}", err));
+        }
+    }
+    fn execute_action(
+        &mut self,
+        prefix: Option<char>,
+        key_event: KeyEvent,
+        count: usize,
+        register: Option<char>,
+    ) -> Result<()> {
+        let buffer = &mut self.buffers[self.current_buffer];
+        match (prefix, key_event.code) {
+            (Some('g'), KeyCode::Char('-')) => {
+                for _ in 0..count {
+                    let change = buffer.undo_tree.current();
+                    if change == 0 || !buffer.undo_goto(change - 1) {
+                        self.message = Some("Already at oldest change".to_string());
+                        break;
+                    }
+                }
             }
-            KeyCode::Char('n') => {
-                if let Some(pattern) = &self.search_pattern {
-                    buffer.find_next(pattern);
+            (Some('g'), KeyCode::Char('+')) => {
+                for _ in 0..count {
+                    let moved = buffer.undo_goto(buffer.undo_tree.current() + 1);
+                    if !moved {
+                        self.message = Some("Already at newest change".to_string());
+                        break;
+                    }
                 }
             }
-            KeyCode::Char('N') => {
-                if let Some(pattern) = &self.search_pattern {
-                    buffer.find_previous(pattern);
+            (Some('g'), KeyCode::Char('v')) => self.reselect_visual(),
+            (Some('g'), KeyCode::Char(c @ ('*' | '#'))) => self.search_word(c == '*', false, count),
+            (Some(_), _) => {}
+            (None, KeyCode::Char('i')) => {
+                buffer.begin_undo_group();
+                self.mode = EditorMode::Insert;
+            }
+            (None, KeyCode::Char('I')) => {
+                buffer.begin_undo_group();
+                buffer
+                    .cursor
+                    .move_line_first_non_whitespace(&buffer.content);
+                self.mode = EditorMode::Insert;
+            }
+            (None, KeyCode::Char('o')) => {
+                buffer.begin_undo_group();
+                buffer.cursor.move_line_end(&buffer.content);
+                buffer.insert_newline();
+                self.mode = EditorMode::Insert;
+            }
+            (None, KeyCode::Char('O')) => {
+                buffer.begin_undo_group();
+                buffer.cursor.move_line_start();
+                buffer.insert_newline();
+                buffer.cursor.move_up(&buffer.content);
+                self.mode = EditorMode::Insert;
+            }
+            (None, KeyCode::Char('v')) if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.start_visual(VisualMode::Block)
+            }
+            (None, KeyCode::Char('v')) => self.start_visual(VisualMode::Char),
+            (None, KeyCode::Char('V')) => self.start_visual(VisualMode::Line),
+            (None, KeyCode::Char(':')) => {
+                self.mode = EditorMode::Command;
+                self.command_line.start(":");
+            }
+            (None, KeyCode::Char(c @ ('/' | '?'))) => {
+                self.search_origin = Some(buffer.cursor.clone());
+                self.mode = EditorMode::Command;
+                self.command_line.start(&c.to_string());
+            }
+            (None, KeyCode::Char('a')) => {
+                buffer.begin_undo_group();
+                buffer.cursor.move_right(&buffer.content);
+                self.mode = EditorMode::Insert;
+            }
+            (None, KeyCode::Char('A')) => {
+                buffer.begin_undo_group();
+                buffer.cursor.move_line_end(&buffer.content);
+                self.mode = EditorMode::Insert;
+            }
+            (None, KeyCode::Delete) => {
+                buffer.delete_char();
+            }
+            (None, KeyCode::Char(key @ ('p' | 'P'))) => {
+                let name = register.or(self.options.default_register()).unwrap_or('"');
+                return self.put(name, key == 'p', count);
+            }
+            (None, KeyCode::Char('u')) => {
+                for _ in 0..count {
+                    let moved = buffer.undo();
+                    if !moved {
+                        self.message = Some("Already at oldest change".to_string());
+                        break;
+                    }
                 }
             }
+            (None, KeyCode::Char('r')) if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                for _ in 0..count {
+                    let moved = buffer.redo();
+                    if !moved {
+                        self.message = Some("Already at newest change".to_string());
+                        break;
+                    }
+                }
+            }
+            (None, KeyCode::Char('n')) => self.search_next(false, count),
+            (None, KeyCode::Char('N')) => self.search_next(true, count),
+            (None, KeyCode::Char(c @ ('*' | '#'))) => self.search_word(c == '*', true, count),
             _ => {}
         }
         Ok(())
     }
+    /// Puts register `name` `count` times, after the cursor for `p` and
+    /// before it for `P`, as lines, a block or plain text by register type.
+    fn put(&mut self, name: char, after: bool, count: usize) -> Result<()> {
+        let register = match self.read_register(name) {
+            Some(register) if !register.text.is_empty() => register,
+            _ => {
+                self.message = Some(format!("E353: Nothing in register {}", name));
+                self.typeahead.clear();
+                return Ok(());
+            }
+        };
+        let buffer = &mut self.buffers[self.current_buffer];
+        match register.kind {
+            RegisterKind::Linewise => buffer.paste_lines(&register.text.repeat(count), after),
+            RegisterKind::Blockwise => buffer.paste_block(&register.text, after, count),
+            RegisterKind::Charwise if after => buffer.paste_after(&register.text.repeat(count)),
+            RegisterKind::Charwise => buffer.paste_before(&register.text.repeat(count)),
+        }
+        Ok(())
+    }
+    fn read_register(&self, name: char) -> Option<Register> {
+        if name == '%' {
+            let file_path = self.buffers[self.current_buffer].file_path.clone();
+            return file_path.map(Register::charwise);
+        }
+        if Clipboard::is_clipboard(name) {
+            return self.clipboard.read(name, self.options.clippaste.as_deref());
+        }
+        self.registers.get(name)
+    }
     fn handle_insert_mode(&mut self, key_event: KeyEvent) -> Result<()> {
+        match (&mut self.insert_change, key_event.code) {
+            (Some(_), KeyCode::Esc) => {
+                self.last_change = self.insert_change.take();
+                if let Some(change) = &self.last_change {
+                    self.registers
+                        .set_last_inserted(inserted_text(&change.inserted));
+                }
+            }
+            (Some(change), _) => change.inserted.push(key_event),
+            (None, _) => {}
+        }
+        if key_event.code == KeyCode::Esc {
+            self.finish_block_insert();
+        }
         let buffer = &mut self.buffers[self.current_buffer];
         match key_event.code {
-            KeyCode::Esc => self.mode = EditorMode::Normal,
+            KeyCode::Esc => {
+                buffer.end_undo_group();
+                self.mode = EditorMode::Normal;
+            }
             KeyCode::Char(c) => buffer.insert_char(c),
             KeyCode::Enter => buffer.insert_newline(),
             KeyCode::Backspace => buffer.backspace(),
             KeyCode::Delete => buffer.delete_char(),
             KeyCode::Tab => buffer.insert_char('\t'),
-            KeyCode::Up => buffer.cursor.move_up(),
+            KeyCode::Up => buffer.cursor.move_up(&buffer.content),
             KeyCode::Down => buffer.cursor.move_down(&buffer.content),
-            KeyCode::Left => buffer.cursor.move_left(),
+            KeyCode::Left => buffer.cursor.move_left(&buffer.content),
             KeyCode::Right => buffer.cursor.move_right(&buffer.content),
             KeyCode::Home => buffer.cursor.move_line_start(),
             KeyCode::End => buffer.cursor.move_line_end(&buffer.content),
             KeyCode::PageUp => {
                 for _ in 0..10 {
-                    buffer.cursor.move_up();
+                    buffer.cursor.move_up(&buffer.content);
                 }
             }
             KeyCode::PageDown => {
@@ -225,267 +924,1606 @@ impl Editor {
         Ok(())
     }
     fn handle_visual_mode(&mut self, key_event: KeyEvent) -> Result<()> {
-        let buffer = &mut self.buffers[self.current_buffer];
-        match key_event.code {
-            KeyCode::Esc => {
-                self.mode = EditorMode::Normal;
-                self.visual_start = None;
-            }
-            KeyCode::Up => buffer.cursor.move_up(),
-            KeyCode::Down => buffer.cursor.move_down(&buffer.content),
-            KeyCode::Left => buffer.cursor.move_left(),
-            KeyCode::Right => buffer.cursor.move_right(&buffer.content),
-            KeyCode::Char('w') => buffer.cursor.move_word_forward(&buffer.content),
-            KeyCode::Char('b') => buffer.cursor.move_word_backward(&buffer.content),
-            KeyCode::Char('e') => buffer.cursor.move_word_end(&buffer.content),
-            KeyCode::Char('0') => buffer.cursor.move_line_start(),
-            KeyCode::Char('$') => buffer.cursor.move_line_end(&buffer.content),
-            KeyCode::Char('y') => {
-                if let Some(text) = self.get_visual_selection() {
-                    self.clipboard = vec![text];
+        if let Some(inner) = self.visual_object.take() {
+            if let KeyCode::Char(c) = key_event.code {
+                if let Some(object) = TextObject::from_char(c, inner) {
+                    self.select_text_object(object);
                 }
-                self.mode = EditorMode::Normal;
-                self.visual_start = None;
             }
-            KeyCode::Char('x') | KeyCode::Delete => {
-                if let Some(text) = self.get_visual_selection() {
-                    self.clipboard = vec![text];
-                    self.delete_visual_selection();
+            return Ok(());
+        }
+        let Some(mut selection) = self.selection() else {
+            self.mode = EditorMode::Normal;
+            return Ok(());
+        };
+        if !self.normal_parser.is_pending() {
+            let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+            let buffer = &mut self.buffers[self.current_buffer];
+            match key_event.code {
+                KeyCode::Esc => self.exit_visual(),
+                KeyCode::Char('v') if ctrl => self.toggle_visual(VisualMode::Block),
+                KeyCode::Char('v') => self.toggle_visual(VisualMode::Char),
+                KeyCode::Char('V') => self.toggle_visual(VisualMode::Line),
+                KeyCode::Char('O') if selection.mode == VisualMode::Block => {
+                    // Move to the other corner on the same line.
+                    let (_, col) = selection.anchor;
+                    selection.anchor.1 = selection.cursor.1;
+                    buffer.cursor.col = col;
+                    buffer.cursor.update_desired_col(&buffer.content);
+                    self.visual = Some(selection);
                 }
-                self.mode = EditorMode::Normal;
-                self.visual_start = None;
+                KeyCode::Char('o' | 'O') => {
+                    // Swap the ends: the cursor jumps to where the anchor was.
+                    let (row, col) = selection.anchor;
+                    selection.anchor = selection.cursor;
+                    buffer.cursor.row = row;
+                    buffer.cursor.col = col;
+                    buffer.cursor.update_desired_col(&buffer.content);
+                    self.visual = Some(selection);
+                }
+                KeyCode::Char('i') if selection.mode != VisualMode::Block => {
+                    self.visual_object = Some(true)
+                }
+                KeyCode::Char('a') if selection.mode != VisualMode::Block => {
+                    self.visual_object = Some(false)
+                }
+                KeyCode::Char(key @ ('I' | 'A')) if selection.mode == VisualMode::Block => {
+                    self.exit_visual();
+                    self.buffers[self.current_buffer].begin_undo_group();
+                    self.start_block_insert(&selection, key == 'A');
+                }
+                KeyCode::Char(':') => {
+                    self.exit_visual();
+                    self.mode = EditorMode::Command;
+                    self.command_line.start(":'<,'>");
+                }
+                _ => return self.feed_visual_key(key_event),
             }
-            _ => {}
+            return Ok(());
         }
-        Ok(())
+        self.feed_visual_key(key_event)
     }
-    fn get_visual_selection(&self) -> Option<String> {
-        if let Some((start_row, start_col)) = self.visual_start {
-            let buffer = &self.buffers[self.current_buffer];
-            let end_row = buffer.cursor.row;
-            let end_col = buffer.cursor.col;
-            let (start_row, start_col, end_row, end_col) =
-                if start_row < end_row || (start_row == end_row && start_col <= end_col) {
-                    (start_row, start_col, end_row, end_col)
-                } else {
-                    (end_row, end_col, start_row, start_col)
-                };
-            let content = &buffer.content;
-            let start_char = content.line_to_char(start_row) + start_col;
-            let end_char = content.line_to_char(end_row) + end_col + 1;
-            if start_char < end_char && end_char <= content.len_chars() {
-                Some(content.slice(start_char..end_char).to_string())
-            } else {
-                None
+    /// Runs keys that Visual mode shares with Normal mode: motions extend the
+    /// selection and operators apply to it.
+    fn feed_visual_key(&mut self, key_event: KeyEvent) -> Result<()> {
+        let command = match self.normal_parser.feed(key_event, true) {
+            ParseResult::Complete(command) => command,
+            ParseResult::Pending | ParseResult::Invalid => return Ok(()),
+        };
+        match command {
+            NormalCommand::Move { motion, .. } => {
+                self.run_normal_command(command)?;
+                if let Some(visual) = &mut self.visual {
+                    match motion {
+                        Motion::LineEnd => visual.to_eol = true,
+                        Motion::Up | Motion::Down | Motion::DisplayUp | Motion::DisplayDown => {}
+                        _ => visual.to_eol = false,
+                    }
+                }
+                Ok(())
             }
+            NormalCommand::Operate { .. } => self.run_normal_command(command),
+            NormalCommand::Action {
+                prefix: None,
+                key,
+                count,
+                register,
+                ..
+            } => match key.code {
+                KeyCode::Char(c @ ('p' | 'P')) => {
+                    self.put_over_selection(register, count.unwrap_or(1), c == 'P');
+                    Ok(())
+                }
+                KeyCode::Delete => {
+                    self.execute_visual_operator(Operator::Delete, false, count, register);
+                    Ok(())
+                }
+                KeyCode::Char('n' | 'N' | '/' | '?' | '*' | '#' | 'm' | '\'' | '`') => {
+                    self.run_normal_command(command)
+                }
+                _ => Ok(()),
+            },
+            NormalCommand::Action {
+                prefix: Some('g'),
+                key:
+                    KeyEvent {
+                        code: KeyCode::Char('*' | '#'),
+                        ..
+                    },
+                ..
+            } => self.run_normal_command(command),
+            NormalCommand::Action { .. } => Ok(()),
+        }
+    }
+    /// Visual `p` and `P`: replaces the selection with a register. `p` puts
+    /// the replaced text in the unnamed register, `P` leaves it alone.
+    fn put_over_selection(&mut self, register: Option<char>, count: usize, keep: bool) {
+        let name = register.or(self.options.default_register()).unwrap_or('"');
+        let Some(put) = self.read_register(name).filter(|r| !r.text.is_empty()) else {
+            self.message = Some(format!("E353: Nothing in register {}", name));
+            self.exit_visual();
+            return;
+        };
+        let Some(selection) = self.selection() else {
+            return;
+        };
+        self.exit_visual();
+        let replaced = self.visual_text(&selection);
+        if !keep {
+            self.store_delete(None, replaced, false);
+        }
+        let buffer = &mut self.buffers[self.current_buffer];
+        buffer.begin_undo_group();
+        let first_row = selection.first_row();
+        if selection.mode == VisualMode::Block {
+            buffer.delete_block(&selection.block(&buffer.content));
         } else {
-            None
+            let range = selection.range(&buffer.content);
+            buffer.delete_range(&range);
         }
+        let text = put.text.repeat(count);
+        match (selection.mode, put.kind) {
+            (VisualMode::Line, RegisterKind::Linewise) => {
+                let after = buffer.cursor.row < first_row;
+                buffer.paste_lines(&text, after);
+            }
+            (VisualMode::Line, _) => {
+                let after = buffer.cursor.row < first_row;
+                let mut lines = text;
+                if !lines.ends_with('\n') {
+                    lines.push('\n');
+                }
+                buffer.paste_lines(&lines, after);
+            }
+            (_, RegisterKind::Linewise) => {
+                // Put the lines between the two halves of the split line.
+                let pos = buffer.cursor.char_index(&buffer.content);
+                buffer.insert_text(pos, &format!("\n{}", text));
+                buffer.cursor.row += 1;
+                buffer
+                    .cursor
+                    .move_line_first_non_whitespace(&buffer.content);
+            }
+            (_, RegisterKind::Blockwise) => buffer.paste_block(&put.text, false, count),
+            (_, RegisterKind::Charwise) => buffer.paste_before(&text),
+        }
+        buffer.end_undo_group();
     }
-    fn delete_visual_selection(&mut self) {
-        if let Some((start_row, start_col)) = self.visual_start {
-            let buffer = &mut self.buffers[self.current_buffer];
-            let end_row = buffer.cursor.row;
-            let end_col = buffer.cursor.col;
-            let (start_row, start_col, end_row, end_col) =
-                if start_row < end_row || (start_row == end_row && start_col <= end_col) {
-                    (start_row, start_col, end_row, end_col)
+    /// The active selection, with its cursor end taken from the buffer.
+    fn selection(&self) -> Option<Selection> {
+        let cursor = &self.buffers[self.current_buffer].cursor;
+        self.visual.map(|selection| Selection {
+            cursor: (cursor.row, cursor.col),
+            ..selection
+        })
+    }
+    fn start_visual(&mut self, mode: VisualMode) {
+        let cursor = &self.buffers[self.current_buffer].cursor;
+        self.visual = Some(Selection::new(mode, cursor.row, cursor.col));
+        self.mode = EditorMode::Visual(mode);
+    }
+    /// `v`, `V` and `Ctrl-v` switch to their mode, or leave it when already
+    /// there.
+    fn toggle_visual(&mut self, mode: VisualMode) {
+        if self.mode == EditorMode::Visual(mode) {
+            self.exit_visual();
+        } else if let Some(visual) = &mut self.visual {
+            visual.mode = mode;
+            self.mode = EditorMode::Visual(mode);
+        }
+    }
+    fn exit_visual(&mut self) {
+        self.last_visual = self.selection();
+        self.visual = None;
+        self.visual_object = None;
+        self.mode = EditorMode::Normal;
+    }
+    /// `gv`: selects the previous visual area again.
+    fn reselect_visual(&mut self) {
+        let Some(selection) = self.last_visual else {
+            return;
+        };
+        let buffer = &mut self.buffers[self.current_buffer];
+        let last_row = buffer.line_count().saturating_sub(1);
+        let clamp = |(row, col): (usize, usize)| {
+            let row = row.min(last_row);
+            (row, col.min(text::line_len(&buffer.content, row)))
+        };
+        let (row, col) = clamp(selection.cursor);
+        buffer.cursor.row = row;
+        buffer.cursor.col = col;
+        buffer.cursor.update_desired_col(&buffer.content);
+        self.visual = Some(Selection {
+            anchor: clamp(selection.anchor),
+            ..selection
+        });
+        self.mode = EditorMode::Visual(selection.mode);
+    }
+    /// Selected text as a register of the matching type.
+    fn visual_text(&self, selection: &Selection) -> Register {
+        let buffer = &self.buffers[self.current_buffer];
+        match selection.mode {
+            VisualMode::Block => Register::new(
+                buffer.block_text(&selection.block(&buffer.content)),
+                RegisterKind::Blockwise,
+            ),
+            VisualMode::Line => Register::new(
+                buffer.range_text(&selection.range(&buffer.content)),
+                RegisterKind::Linewise,
+            ),
+            VisualMode::Char => {
+                Register::charwise(buffer.range_text(&selection.range(&buffer.content)))
+            }
+        }
+    }
+    /// Block `I` inserts before the block on every line; `A` appends after
+    /// it, padding short lines, or at each line end after `$`. The caller
+    /// opens the undo group that Insert mode closes.
+    fn start_block_insert(&mut self, selection: &Selection, append: bool) {
+        let buffer = &mut self.buffers[self.current_buffer];
+        let block = selection.block(&buffer.content);
+        let row = block.first_row;
+        let display = match (append, block.right) {
+            (false, _) => Some(block.left),
+            (true, Some(right)) => Some(right),
+            (true, None) => None,
+        };
+        let line = text::line_text(&buffer.content, row);
+        let len = line.chars().count();
+        let col = match display {
+            None => len,
+            Some(display) => {
+                let width = text::display_col(&line, len);
+                if append && width < display {
+                    let line_start = buffer.content.line_to_char(row);
+                    buffer.insert_text(line_start + len, &" ".repeat(display - width));
+                }
+                if append {
+                    block.cols(&buffer.content, row).1
                 } else {
-                    (end_row, end_col, start_row, start_col)
-                };
-            buffer.save_state();
-            let start_char = buffer.content.line_to_char(start_row) + start_col;
-            let end_char = buffer.content.line_to_char(end_row) + end_col + 1;
-            if start_char < end_char && end_char <= buffer.content.len_chars() {
-                buffer.content.remove(start_char..end_char);
-                buffer.cursor.row = start_row;
-                buffer.cursor.col = start_col;
-                buffer.modified = true;
+                    block.cols(&buffer.content, row).0
+                }
+            }
+        };
+        buffer.cursor.row = row;
+        buffer.cursor.col = col;
+        buffer.cursor.update_desired_col(&buffer.content);
+        self.block_insert = Some(BlockInsert {
+            row,
+            col,
+            line_len: text::line_len(&buffer.content, row),
+            other_rows: row + 1..=block.last_row,
+            display,
+            pad: append,
+        });
+        self.mode = EditorMode::Insert;
+    }
+    /// Copies the text typed on the first line of a block insert to the
+    /// other lines of the block.
+    fn finish_block_insert(&mut self) {
+        let Some(insert) = self.block_insert.take() else {
+            return;
+        };
+        let buffer = &mut self.buffers[self.current_buffer];
+        let line_len = text::line_len(&buffer.content, insert.row);
+        if buffer.cursor.row != insert.row || line_len <= insert.line_len {
+            return;
+        }
+        let typed: String = text::line_text(&buffer.content, insert.row)
+            .chars()
+            .skip(insert.col)
+            .take(line_len - insert.line_len)
+            .collect();
+        buffer.insert_block_text(insert.other_rows, insert.display, &typed, insert.pad);
+        buffer.cursor.row = insert.row;
+        buffer.cursor.col = insert.col;
+        buffer.cursor.update_desired_col(&buffer.content);
+    }
+    /// Selects `object` around the cursor, or the next larger one (outer
+    /// bracket, more words) when the selection already covers it.
+    fn select_text_object(&mut self, object: TextObject) {
+        let Some(selection) = self.selection() else {
+            return;
+        };
+        let (start_row, start_col) = selection.anchor;
+        let buffer = &mut self.buffers[self.current_buffer];
+        let content = &buffer.content;
+        let anchor = content.line_to_char(start_row) + start_col;
+        let cursor = buffer.cursor.char_index(content);
+        let (sel_start, sel_end) = (anchor.min(cursor), anchor.max(cursor) + 1);
+        let single_char = sel_end - sel_start <= 1;
+        let range = (1..=100)
+            .map_while(|count| object.select(content, cursor.min(anchor), count))
+            .find(|range| {
+                single_char
+                    || (range.start <= sel_start
+                        && range.end >= sel_end
+                        && (range.start, range.end) != (sel_start, sel_end))
+            });
+        if let Some(range) = range {
+            if range.end > range.start {
+                let start_row = content.char_to_line(range.start);
+                let anchor = (start_row, range.start - content.line_to_char(start_row));
+                buffer.cursor.set_char_index(&buffer.content, range.end - 1);
+                if let Some(visual) = &mut self.visual {
+                    visual.anchor = anchor;
+                }
             }
         }
     }
     fn handle_command_mode(&mut self, key_event: KeyEvent) -> Result<()> {
-        match key_event.code {
-            KeyCode::Esc => {
-                self.mode = EditorMode::Normal;
-                self.command_line.clear();
+        let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        if std::mem::take(&mut self.command_register_pending) {
+            if let KeyCode::Char(name) = key_event.code {
+                self.insert_register_in_command_line(name);
             }
+            return Ok(());
+        }
+        // Any key but Tab keeps the completion it lands on.
+        if !matches!(key_event.code, KeyCode::Tab | KeyCode::BackTab) {
+            self.command_line.wildmenu = None;
+        }
+        match key_event.code {
+            KeyCode::Esc => self.leave_command_mode(),
+            KeyCode::Char('c') if ctrl => self.leave_command_mode(),
             KeyCode::Enter => {
+                // Search from where `/` was typed, not from the preview.
+                if let Some(origin) = self.search_origin.take() {
+                    self.buffers[self.current_buffer].cursor = origin;
+                }
                 self.execute_command()?;
-                self.mode = EditorMode::Normal;
-                self.command_line.clear();
+                self.leave_command_mode();
             }
             KeyCode::Backspace => {
-                if self.command_line.len() > 1 {
-                    self.command_line.pop();
-                } else {
-                    self.mode = EditorMode::Normal;
-                    self.command_line.clear();
+                if self.command_line.content().is_empty() {
+                    self.leave_command_mode();
+                    return Ok(());
                 }
+                self.command_line.backspace();
+                self.command_line_edited();
             }
-            KeyCode::Char(c) => {
-                self.command_line.push(c);
+            KeyCode::Delete => {
+                self.command_line.delete();
+                self.command_line_edited();
+            }
+            KeyCode::Left => self.command_line.move_left(),
+            KeyCode::Right => self.command_line.move_right(),
+            KeyCode::Home => self.command_line.move_home(),
+            KeyCode::End => self.command_line.move_end(),
+            KeyCode::Char('b') if ctrl => self.command_line.move_home(),
+            KeyCode::Char('e') if ctrl => self.command_line.move_end(),
+            KeyCode::Char('w') if ctrl => {
+                self.command_line.delete_word();
+                self.command_line_edited();
+            }
+            KeyCode::Char('u') if ctrl => {
+                self.command_line.delete_to_start();
+                self.command_line_edited();
+            }
+            KeyCode::Char('r') if ctrl => self.command_register_pending = true,
+            KeyCode::Char(c) if !ctrl => {
+                let mut buf = [0; 4];
+                self.command_line.insert(c.encode_utf8(&mut buf));
+                self.command_line_edited();
+            }
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.complete_command_line(key_event.code == KeyCode::Tab)
+            }
+            KeyCode::Up | KeyCode::Down => {
+                self.recall_history(key_event.code == KeyCode::Up);
+                self.update_incsearch();
             }
             _ => {}
         }
         Ok(())
     }
+    /// After the text changes, history recall starts over from it.
+    fn command_line_edited(&mut self) {
+        self.history_index = None;
+        self.update_incsearch();
+    }
+    /// `Ctrl-r {reg}` puts a register in the command line, with its lines
+    /// joined by spaces.
+    fn insert_register_in_command_line(&mut self, name: char) {
+        let Some(register) = self.read_register(name) else {
+            return;
+        };
+        let text = register.text.strip_suffix('\n').unwrap_or(&register.text);
+        self.command_line.insert(&text.replace('\n', " "));
+        self.command_line_edited();
+    }
+    /// Tab and Shift-Tab on a `:` line: the first press shows the
+    /// completions of the word before the cursor, later ones cycle through
+    /// them.
+    fn complete_command_line(&mut self, forward: bool) {
+        if self.command_line.wildmenu.is_some() {
+            self.command_line.cycle(forward);
+            return;
+        }
+        if !self.command_line.text.starts_with(':') {
+            return;
+        }
+        let typed = self.command_line.text[1..self.command_line.cursor].to_string();
+        let handler = Rc::clone(&self.command_handler);
+        let (start, items) = handler.get_completions(self, &typed);
+        self.command_line.complete(1 + start, items, forward);
+        self.history_index = None;
+    }
+    /// Replaces the line being typed with an older or newer one from the
+    /// command or search history, among those starting with what was typed
+    /// before recalling.
+    fn recall_history(&mut self, older: bool) {
+        if self.history_index.is_none() {
+            self.history_prefix = self.command_line.content().to_string();
+        }
+        let history = if self.command_line.text.starts_with(':') {
+            &self.command_history
+        } else {
+            &self.search_history
+        };
+        let prefix = &self.history_prefix;
+        let index = match self.history_index {
+            _ if older => history.older(self.history_index, prefix),
+            Some(index) => history.newer(index, prefix),
+            None => return,
+        };
+        if older && index.is_none() {
+            return;
+        }
+        self.history_index = index;
+        let text = match index {
+            Some(index) => history.get(index).to_string(),
+            None => self.history_prefix.clone(),
+        };
+        self.command_line.set_content(&text);
+    }
+    /// Pattern part of the search being typed, without its offset.
+    fn typed_pattern(&self) -> Option<&str> {
+        let delimiter = self.command_line.text.chars().next()?;
+        search::parse_search(self.command_line.content(), delimiter)
+            .ok()
+            .map(|(pattern, _)| pattern)
+    }
+    /// Moves the cursor to the first match of the pattern typed so far,
+    /// starting over from where the search began.
+    fn update_incsearch(&mut self) {
+        let Some(origin) = self.search_origin.clone() else {
+            return;
+        };
+        let buffer = &mut self.buffers[self.current_buffer];
+        buffer.cursor = origin;
+        self.current_match = None;
+        let forward = self.command_line.text.starts_with('/');
+        let pattern = self.typed_pattern().unwrap_or_default();
+        if !self.options.incsearch || pattern.is_empty() {
+            return;
+        }
+        if let Ok(regex) = search::compile(pattern, self.options.ignorecase, self.options.smartcase)
+        {
+            let buffer = &mut self.buffers[self.current_buffer];
+            self.current_match = buffer.search(&regex, forward).map(|found| found.range);
+        }
+    }
+    /// Returns to Visual mode after a search started there, else to Normal.
+    /// An abandoned search puts the cursor back where it was.
+    fn leave_command_mode(&mut self) {
+        if let Some(origin) = self.search_origin.take() {
+            self.buffers[self.current_buffer].cursor = origin;
+        }
+        self.current_match = None;
+        self.history_index = None;
+        self.command_register_pending = false;
+        self.mode = match self.visual {
+            Some(selection) => EditorMode::Visual(selection.mode),
+            None => EditorMode::Normal,
+        };
+        self.command_line.clear();
+    }
     fn execute_command(&mut self) -> Result<()> {
-        let cmd = self.command_line.clone();
+        let cmd = self.command_line.text.clone();
         if let Some(command) = cmd.strip_prefix(':') {
-            self.execute_colon_command(command)?;
-        } else if let Some(pattern) = cmd.strip_prefix('/') {
-            self.search_forward(pattern);
-        } else if let Some(pattern) = cmd.strip_prefix('?') {
-            self.search_backward(pattern);
+            self.command_history.add(command);
+            let result = self.execute_colon_command(command);
+            if !command.is_empty() {
+                self.registers.set_last_command(command.to_string());
+            }
+            if let Err(error) = result {
+                self.message = Some(error.to_string());
+                self.typeahead.clear();
+            }
+        } else if let Some(text) = cmd.strip_prefix('/') {
+            self.search(text, true);
+        } else if let Some(text) = cmd.strip_prefix('?') {
+            self.search(text, false);
         }
         Ok(())
     }
-    fn execute_colon_command(&mut self, command: &str) -> Result<()> {
+    /// Runs a command line typed after `:` through the command handler.
+    pub fn execute_colon_command(&mut self, command: &str) -> Result<()> {
+        let handler = Rc::clone(&self.command_handler);
+        handler.execute(self, command)
+    }
+    /// What line addresses in an Ex range refer to in the current buffer.
+    pub fn addresses(&self) -> Addresses<'_> {
+        let buffer = &self.buffers[self.current_buffer];
+        Addresses {
+            content: &buffer.content,
+            cursor_row: buffer.cursor.row,
+            visual: self
+                .last_visual
+                .map(|selection| (selection.first_row(), selection.last_row())),
+            marks: &buffer.marks,
+            last_pattern: self.last_search.as_ref().map(|last| last.pattern.as_str()),
+            ignorecase: self.options.ignorecase,
+            smartcase: self.options.smartcase,
+        }
+    }
+    /// `:[range]w [>>] [file]` for part of the buffer. Writing part of the
+    /// buffer over its own file needs `force`, as does replacing another
+    /// file that exists.
+    pub fn write_lines(
+        &mut self,
+        range: LineRange,
+        file: &str,
+        append: bool,
+        force: bool,
+    ) -> Result<()> {
         let buffer = &mut self.buffers[self.current_buffer];
-        match command {
-            "w" | "write" => {
-                buffer.save()?;
-                self.message = Some("File saved".to_string());
-            }
-            "q" | "quit" => {
-                if buffer.modified {
-                    self.message =
-                        Some("No write since last change (use :q! to force quit)".to_string());
-                } else {
-                    self.running = false;
-                }
+        let whole = range.start == 0 && range.end + 1 >= text::line_count(&buffer.content);
+        if file.is_empty() && whole && !append {
+            buffer.save()?;
+            self.message = Some("File saved".to_string());
+            return Ok(());
+        }
+        let path = match (file, &buffer.file_path) {
+            ("", Some(path)) => path.clone(),
+            ("", None) => return Err(anyhow!("E32: No file name")),
+            (file, _) => file.to_string(),
+        };
+        if !force && !append {
+            if file.is_empty() {
+                return Err(anyhow!("E140: Use ! to write partial buffer"));
             }
-            "q!" => {
-                self.running = false;
+            if std::path::Path::new(&path).exists() {
+                return Err(anyhow!("E13: File exists (add ! to override)"));
             }
-            "wq" | "x" => {
-                buffer.save()?;
-                self.running = false;
+        }
+        let lines = MotionRange::lines(&buffer.content, range.start, range.end);
+        let text = buffer.range_text(&lines);
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)?;
+        file.write_all(text.as_bytes())?;
+        self.message = Some(format!(
+            "\"{}\" {}L, {}B {}",
+            path,
+            range.end - range.start + 1,
+            text.len(),
+            if append { "appended" } else { "written" }
+        ));
+        Ok(())
+    }
+    /// `:[range]normal {keys}`: runs `keys` in Normal mode with the cursor
+    /// at the start of each line, ending any Insert or Visual mode they
+    /// leave open. Lines the keys add or delete shift the ones still to go.
+    pub fn execute_normal_keys(&mut self, range: LineRange, keys: &str) -> Result<()> {
+        let keys = macros::decode_keys(keys);
+        let (mut row, mut end) = (range.start, range.end);
+        loop {
+            let buffer = &mut self.buffers[self.current_buffer];
+            let before = text::line_count(&buffer.content);
+            if row > end || row >= before {
+                break;
             }
-            cmd if cmd.starts_with("w ") => {
-                let filename = &cmd[2..];
-                buffer.save_as(filename)?;
-                self.message = Some(format!("File saved as {}", filename));
+            buffer.cursor.row = row;
+            buffer.cursor.move_line_start();
+            self.mode = EditorMode::Normal;
+            for key in &keys {
+                self.process_key_event(*key)?;
             }
-            cmd if cmd.starts_with("e ") => {
-                let filename = &cmd[2..];
-                let new_buffer = Buffer::from_file(filename)?;
-                self.buffers.push(new_buffer);
-                self.current_buffer = self.buffers.len() - 1;
-                self.message = Some(format!("Opened {}", filename));
+            self.normal_parser.reset();
+            if self.confirm.is_some() || self.mode != EditorMode::Normal {
+                self.process_key_event(KeyEvent::from(KeyCode::Esc))?;
             }
-            "bn" => {
-                if self.current_buffer + 1 < self.buffers.len() {
-                    self.current_buffer += 1;
-                }
+            let after = text::line_count(&self.buffers[self.current_buffer].content);
+            row = (row + 1 + after).saturating_sub(before);
+            end = (end + after).saturating_sub(before);
+        }
+        self.command_line.clear();
+        Ok(())
+    }
+    /// Like vim, a pattern used by `:s` or `:g` becomes the one `n`
+    /// searches for.
+    fn remember_pattern(&mut self, pattern: &str) {
+        self.registers.set_last_search(pattern.to_string());
+        self.last_search = Some(LastSearch {
+            pattern: pattern.to_string(),
+            forward: self.last_search.as_ref().is_none_or(|last| last.forward),
+            offset: SearchOffset::Start(0),
+        });
+        self.highlight_search = true;
+    }
+    /// `:[range]g/pattern/command`: marks the matching lines (or the others
+    /// for `:v`) first, then runs `command` on each one still there, undone
+    /// in one step. Stops at the first error.
+    pub fn execute_global(&mut self, range: LineRange, global: ex::GlobalCommand) -> Result<()> {
+        if self.in_global {
+            return Err(anyhow!("E147: Cannot do :global recursive"));
+        }
+        let pattern = match (global.pattern.as_str(), &self.last_search) {
+            ("", Some(last)) => last.pattern.clone(),
+            ("", None) => return Err(anyhow!("E35: No previous regular expression")),
+            (pattern, _) => pattern.to_string(),
+        };
+        let regex = search::compile(&pattern, self.options.ignorecase, self.options.smartcase)
+            .map_err(|e| anyhow!(e))?;
+        self.remember_pattern(&pattern);
+        let buffer = &mut self.buffers[self.current_buffer];
+        let lines: Vec<usize> = (range.start..=range.end)
+            .filter(|&row| regex.is_match(&text::line_text(&buffer.content, row)) != global.invert)
+            .collect();
+        if lines.is_empty() {
+            self.message = Some(if global.invert {
+                format!("Pattern found in every line: {}", pattern)
+            } else {
+                format!("Pattern not found: {}", pattern)
+            });
+            self.typeahead.clear();
+            return Ok(());
+        }
+        if global.command.is_empty() {
+            let listed: Vec<String> = lines
+                .iter()
+                .map(|&row| text::line_text(&buffer.content, row))
+                .collect();
+            self.message = Some(listed.join("\n"));
+            return Ok(());
+        }
+        buffer.global_lines = GlobalLines::new(lines);
+        buffer.begin_undo_group();
+        self.in_global = true;
+        self.global_substituted = (0, 0);
+        self.message = None;
+        let mut result = Ok(());
+        while let Some(row) = self.buffers[self.current_buffer].global_lines.pop_front() {
+            let buffer = &mut self.buffers[self.current_buffer];
+            buffer.cursor.row = row;
+            buffer.cursor.move_line_start();
+            result = self.execute_colon_command(&global.command);
+            if result.is_err() {
+                break;
             }
-            "bp" => {
-                if self.current_buffer > 0 {
-                    self.current_buffer -= 1;
+        }
+        self.in_global = false;
+        if let (substitutions @ 1.., lines) = self.global_substituted {
+            self.message = Some(substitute::report(substitutions, lines));
+        }
+        let buffer = &mut self.buffers[self.current_buffer];
+        buffer.global_lines.clear();
+        buffer.end_undo_group();
+        buffer.cursor.clamp_to_buffer(&buffer.content);
+        result
+    }
+    /// `:[range]s/pattern/replacement/[flags]`, undone in one step. With
+    /// the `c` flag each match waits for `handle_confirm_key`.
+    pub fn execute_substitute(
+        &mut self,
+        range: LineRange,
+        command: SubstituteCommand,
+    ) -> Result<()> {
+        let pattern = match (command.pattern.as_str(), &self.last_search) {
+            ("", Some(last)) => last.pattern.clone(),
+            ("", None) => return Err(anyhow!("E35: No previous regular expression")),
+            (pattern, _) => pattern.to_string(),
+        };
+        let (ignorecase, smartcase) = match command.flags.ignore_case {
+            Some(ignore_case) => (ignore_case, false),
+            None => (self.options.ignorecase, self.options.smartcase),
+        };
+        let regex = search::compile(&pattern, ignorecase, smartcase).map_err(|e| anyhow!(e))?;
+        self.remember_pattern(&pattern);
+        let source = substitute::expand_tilde(&command.replacement, &self.last_replacement);
+        let replacement = Replacement::parse(&source);
+        self.last_replacement = source;
+        let mut substitution = Substitution::new(
+            regex,
+            replacement,
+            command.flags,
+            pattern,
+            (range.start, range.end),
+        );
+        let buffer = &mut self.buffers[self.current_buffer];
+        buffer.begin_undo_group();
+        if command.flags.confirm {
+            self.confirm_next(substitution);
+            return Ok(());
+        }
+        while let Some(found) = substitution.next_match(&buffer.content) {
+            substitution.replace(buffer, &found);
+        }
+        self.finish_substitute(substitution);
+        Ok(())
+    }
+    /// Shows the next match of a `:s///c` and asks what to do with it.
+    fn confirm_next(&mut self, mut substitution: Substitution) {
+        let buffer = &mut self.buffers[self.current_buffer];
+        let Some(found) = substitution.next_match(&buffer.content) else {
+            self.finish_substitute(substitution);
+            return;
+        };
+        let range = found.range(&buffer.content);
+        buffer.set_cursor_char_index(range.start);
+        self.current_match = Some(range);
+        self.message = Some(format!("replace with {} (y/n/a/q/l)?", found.replacement));
+        self.confirm = Some((substitution, found));
+    }
+    /// Answers the `:s///c` prompt: `y` replaces, `n` skips, `a` replaces
+    /// this and every later match, `l` replaces this one and stops, and
+    /// `q` or Esc stops.
+    fn handle_confirm_key(&mut self, key_event: KeyEvent) {
+        let Some((mut substitution, found)) = self.confirm.take() else {
+            return;
+        };
+        let buffer = &mut self.buffers[self.current_buffer];
+        match key_event.code {
+            KeyCode::Char('y') => {
+                substitution.replace(buffer, &found);
+                self.confirm_next(substitution);
+            }
+            KeyCode::Char('n') => {
+                substitution.skip(&found);
+                self.confirm_next(substitution);
+            }
+            KeyCode::Char('a') => {
+                substitution.replace(buffer, &found);
+                while let Some(found) = substitution.next_match(&buffer.content) {
+                    substitution.replace(buffer, &found);
                 }
+                self.finish_substitute(substitution);
             }
-            "bd" => {
-                if self.buffers.len() > 1 {
-                    self.buffers.remove(self.current_buffer);
-                    if self.current_buffer >= self.buffers.len() {
-                        self.current_buffer = self.buffers.len() - 1;
-                    }
+            KeyCode::Char('l') => {
+                substitution.replace(buffer, &found);
+                self.finish_substitute(substitution);
+            }
+            KeyCode::Char('q') | KeyCode::Esc => self.finish_substitute(substitution),
+            _ => self.confirm = Some((substitution, found)),
+        }
+    }
+    /// Closes the undo step of a substitution, puts the cursor on the last
+    /// line changed and reports how much changed, or under `:global` adds
+    /// it to the total reported at the end.
+    fn finish_substitute(&mut self, substitution: Substitution) {
+        self.current_match = None;
+        let buffer = &mut self.buffers[self.current_buffer];
+        buffer.end_undo_group();
+        match substitution.last_row {
+            Some(row) => {
+                buffer.cursor.row = row;
+                buffer
+                    .cursor
+                    .move_line_first_non_whitespace(&buffer.content);
+                if self.in_global {
+                    self.global_substituted.0 += substitution.substitutions;
+                    self.global_substituted.1 += substitution.lines;
+                } else {
+                    self.message = Some(substitution.report());
                 }
             }
-            cmd if cmd.starts_with("s/") => {
-                self.execute_substitute(cmd)?;
+            None if substitution.flags.no_error || self.in_global => self.message = None,
+            None if substitution.flags.confirm => self.message = None,
+            None => {
+                self.message = Some(format!("E486: Pattern not found: {}", substitution.pattern));
+                self.typeahead.clear();
             }
-            "set number" | "set nu" => {
-                self.buffers[self.current_buffer].line_numbers = true;
-                self.message = Some("Line numbers enabled".to_string());
+        }
+    }
+    /// Lists registers for `:registers`, limited to `names` when given.
+    pub fn format_registers(&self, names: &str) -> String {
+        let mut entries = self.registers.entries();
+        let at = entries
+            .iter()
+            .position(|(name, _)| matches!(name, '.' | ':' | '/'))
+            .unwrap_or(entries.len());
+        for selection in ['+', '*'] {
+            if let Some(register) = self.clipboard.last(selection) {
+                entries.insert(at, (selection, register.clone()));
             }
-            "set nonumber" | "set nonu" => {
-                self.buffers[self.current_buffer].line_numbers = false;
-                self.message = Some("Line numbers disabled".to_string());
+        }
+        if let Some(file) = self.read_register('%') {
+            let at = entries
+                .iter()
+                .position(|(name, _)| *name == '/')
+                .unwrap_or(entries.len());
+            entries.insert(at, ('%', file));
+        }
+        let mut lines = vec!["Type Name Content".to_string()];
+        for (name, register) in entries {
+            if !names.trim().is_empty() && !names.contains(name) {
+                continue;
+            }
+            let content: String = register
+                .text
+                .chars()
+                .map(|c| match c {
+                    '\u{E000}'..='\u{E0FF}' => format!("<{:?}>", macros::decode_key(c).code),
+                    c if c.is_control() => format!("^{}", (c as u8 ^ 0x40) as char),
+                    c => c.to_string(),
+                })
+                .collect::<String>()
+                .chars()
+                .take(REGISTER_PREVIEW_WIDTH)
+                .collect();
+            lines.push(format!(
+                "  {}  \"{}   {}",
+                register.type_char(),
+                name,
+                content
+            ));
+        }
+        lines.join("\n")
+    }
+    /// Runs a search typed after `/` or `?`, such as `foo/e+1`. An empty
+    /// pattern searches for the last one again.
+    fn search(&mut self, text: &str, forward: bool) {
+        self.search_history.add(text);
+        let delimiter = if forward { '/' } else { '?' };
+        let (pattern, offset) = match search::parse_search(text, delimiter) {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                self.message = Some(error);
+                self.typeahead.clear();
+                return;
             }
-            "set syntax" => {
-                self.buffers[self.current_buffer].syntax_highlighting = true;
-                self.message = Some("Syntax highlighting enabled".to_string());
+        };
+        let pattern = match (pattern, &self.last_search) {
+            ("", Some(last)) => last.pattern.clone(),
+            _ => pattern.to_string(),
+        };
+        self.start_search(pattern, forward, offset, 1);
+    }
+    /// `*` and `#`, and `g*` and `g#` when `whole_word` is unset: searches
+    /// for the keyword under or after the cursor.
+    fn search_word(&mut self, forward: bool, whole_word: bool, count: usize) {
+        let buffer = &mut self.buffers[self.current_buffer];
+        let line: Vec<char> = text::line_text(&buffer.content, buffer.cursor.row)
+            .chars()
+            .collect();
+        // A keyword from the cursor on, else any run of non-blanks.
+        let start = (buffer.cursor.col..line.len())
+            .find(|&i| char_class(line[i], false) == 1)
+            .or_else(|| (buffer.cursor.col..line.len()).find(|&i| char_class(line[i], false) != 0));
+        let Some(mut start) = start else {
+            self.message = Some("E348: No string under cursor".to_string());
+            self.typeahead.clear();
+            return;
+        };
+        let class = char_class(line[start], false);
+        while start > 0 && char_class(line[start - 1], false) == class {
+            start -= 1;
+        }
+        let mut end = start;
+        while end < line.len() && char_class(line[end], false) == class {
+            end += 1;
+        }
+        let word: String = line[start..end].iter().collect();
+        let pattern = if whole_word && class == 1 {
+            format!("\\<{}\\>", search::escape(&word))
+        } else {
+            search::escape(&word)
+        };
+        // From the start of the word, so `#` skips the word itself.
+        buffer.cursor.col = start;
+        self.search_history.add(&pattern);
+        self.start_search(pattern, forward, SearchOffset::Start(0), count);
+    }
+    fn start_search(&mut self, pattern: String, forward: bool, offset: SearchOffset, count: usize) {
+        self.registers.set_last_search(pattern.clone());
+        self.last_search = Some(LastSearch {
+            pattern,
+            forward,
+            offset,
+        });
+        self.search_anchor = None;
+        self.search_next(false, count);
+    }
+    /// Moves to the `count`th match of the last search, in its direction
+    /// or the opposite one for `N`, reporting a wrap around the buffer or a
+    /// pattern that is not found.
+    fn search_next(&mut self, reverse: bool, count: usize) {
+        let Some(last) = self.last_search.clone() else {
+            self.message = Some("E35: No previous regular expression".to_string());
+            self.typeahead.clear();
+            return;
+        };
+        let forward = last.forward != reverse;
+        let regex = match search::compile(
+            &last.pattern,
+            self.options.ignorecase,
+            self.options.smartcase,
+        ) {
+            Ok(regex) => regex,
+            Err(error) => {
+                self.message = Some(error);
+                self.typeahead.clear();
+                return;
             }
-            "set nosyntax" => {
-                self.buffers[self.current_buffer].syntax_highlighting = false;
-                self.message = Some("Syntax highlighting disabled".to_string());
+        };
+        let buffer = &mut self.buffers[self.current_buffer];
+        let cursor = buffer.cursor.char_index(&buffer.content);
+        let mut from = match self.search_anchor {
+            Some((pos, start)) if pos == cursor => start,
+            _ => cursor,
+        };
+        let mut wrapped = false;
+        for _ in 0..count {
+            match search::find(&buffer.content, &regex, from, forward) {
+                Some(found) => {
+                    wrapped |= found.wrapped;
+                    from = found.range.start;
+                    let target = last.offset.apply(&buffer.content, &found.range);
+                    buffer.set_cursor_char_index(target);
+                }
+                None => {
+                    self.message = Some(format!("E486: Pattern not found: {}", last.pattern));
+                    self.typeahead.clear();
+                    return;
+                }
             }
-            _ => {
-                self.message = Some(format!("Unknown command: {}", command));
+        }
+        let target = buffer.cursor.char_index(&buffer.content);
+        self.search_anchor = Some((target, from));
+        self.search_count = Some(MatchCount {
+            pos: target,
+            ..search::count(&buffer.content, &regex, from)
+        });
+        self.highlight_search = true;
+        if wrapped {
+            self.message = Some(
+                if forward {
+                    "search hit BOTTOM, continuing at TOP"
+                } else {
+                    "search hit TOP, continuing at BOTTOM"
+                }
+                .to_string(),
+            );
+        }
+    }
+    pub fn get_current_buffer(&self) -> &Buffer {
+        &self.buffers[self.current_buffer]
+    }
+    pub fn get_current_buffer_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.current_buffer]
+    }
+    /// Applies one `:set` argument to the global options, giving every
+    /// buffer the new undo file setting.
+    pub fn set_option(&mut self, arg: &str) -> Result<String, String> {
+        let message = self.options.set(arg)?;
+        let undo_dir = self.options.undo_dir();
+        for buffer in &mut self.buffers {
+            buffer.undo_dir = undo_dir.clone();
+        }
+        Ok(message)
+    }
+    pub fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+    pub fn quit(&mut self) {
+        self.running = false;
+    }
+    /// `:nohlsearch`: hides search matches until the next search.
+    pub fn clear_search_highlight(&mut self) {
+        self.highlight_search = false;
+    }
+    /// Goes to the next buffer, or the previous one, stopping at the ends.
+    pub fn switch_buffer(&mut self, forward: bool) {
+        if forward && self.current_buffer + 1 < self.buffers.len() {
+            self.current_buffer += 1;
+        } else if !forward && self.current_buffer > 0 {
+            self.current_buffer -= 1;
+        }
+    }
+    /// File name of each buffer, `[No Name]` for those without one.
+    pub fn buffer_names(&self) -> Vec<String> {
+        self.buffers
+            .iter()
+            .map(|buffer| {
+                buffer
+                    .file_path
+                    .clone()
+                    .unwrap_or_else(|| "[No Name]".to_string())
+            })
+            .collect()
+    }
+    /// Name of a buffer with changes not written, if there is one.
+    pub fn modified_buffer(&self) -> Option<String> {
+        let index = self.buffers.iter().position(|buffer| buffer.modified)?;
+        self.buffer_names().into_iter().nth(index)
+    }
+    pub fn select_buffer(&mut self, index: usize) {
+        self.current_buffer = index;
+    }
+    pub fn ui(&self) -> &UI {
+        &self.ui
+    }
+    pub fn ui_mut(&mut self) -> &mut UI {
+        &mut self.ui
+    }
+    /// Closes the current buffer unless it is the only one.
+    /// Windows showing it switch to the buffer that takes its place.
+    pub fn close_buffer(&mut self) {
+        if self.buffers.len() > 1 {
+            self.store_window();
+            let removed = self.current_buffer;
+            self.buffers.remove(removed);
+            let replacement = removed.min(self.buffers.len() - 1);
+            for layout in &mut self.tabs {
+                layout.buffer_removed(removed, replacement);
             }
+            self.load_window();
         }
+    }
+    /// Opens an empty buffer in the current window.
+    pub fn new_buffer(&mut self) {
+        let mut buffer = Buffer::new();
+        buffer.undo_dir = self.options.undo_dir();
+        self.buffers.push(buffer);
+        self.current_buffer = self.buffers.len() - 1;
+    }
+    pub fn window_count(&self) -> usize {
+        self.layout().count()
+    }
+    /// Splits the current window, the new one becoming current.
+    pub fn split_window(&mut self, split: Split) -> Result<()> {
+        self.store_window();
+        self.layout_mut().split(split).map_err(|e| anyhow!(e))
+    }
+    /// Closes the current window; its buffer stays loaded.
+    pub fn close_window(&mut self) -> Result<()> {
+        self.store_window();
+        self.layout_mut().close().map_err(|e| anyhow!(e))?;
+        self.load_window();
         Ok(())
     }
-    fn execute_substitute(&mut self, command: &str) -> Result<()> {
-        let parts: Vec<&str> = command.split('/').collect();
-        if parts.len() >= 3 {
-            let pattern = parts[1];
-            let replacement = parts[2];
-            let flags = if parts.len() > 3 { parts[3] } else { "" };
-            let buffer = &mut self.buffers[self.current_buffer];
-            let content = buffer.content.to_string();
-            let new_content = if flags.contains('g') {
-                content.replace(pattern, replacement)
-            } else {
-                content.replacen(pattern, replacement, 1)
-            };
-            buffer.content = ropey::Rope::from_str(&new_content);
-            buffer.modified = true;
-            self.message = Some("Substitution complete".to_string());
-        } else {
-            self.message = Some("Invalid substitute format".to_string());
+    pub fn only_window(&mut self) {
+        self.layout_mut().only();
+    }
+    fn layout(&self) -> &WindowLayout {
+        &self.tabs[self.current_tab]
+    }
+    fn layout_mut(&mut self) -> &mut WindowLayout {
+        &mut self.tabs[self.current_tab]
+    }
+    pub fn tab_count(&self) -> usize {
+        self.tabs.len()
+    }
+    /// `:tabnew` and `:tabedit`: a tab page after the current one with a
+    /// single window on `file`, or on a new empty buffer.
+    pub fn new_tab(&mut self, file: Option<&str>) -> Result<()> {
+        self.store_window();
+        match file {
+            Some(file) => self.open_file(file)?,
+            None => self.new_buffer(),
         }
+        self.current_tab += 1;
+        self.tabs
+            .insert(self.current_tab, WindowLayout::new(self.current_buffer));
+        self.load_window();
         Ok(())
     }
-    fn search_forward(&mut self, pattern: &str) {
-        self.search_pattern = Some(pattern.to_string());
-        let buffer = &mut self.buffers[self.current_buffer];
-        buffer.find_next(pattern);
+    /// Closes the current tab page and its windows, going to the next one.
+    pub fn close_tab(&mut self) -> Result<()> {
+        if self.tabs.len() == 1 {
+            return Err(anyhow!("E784: Cannot close last tab page"));
+        }
+        self.tabs.remove(self.current_tab);
+        self.current_tab = self.current_tab.min(self.tabs.len() - 1);
+        self.load_window();
+        Ok(())
+    }
+    /// Goes to tab page `index`, counting from 0.
+    pub fn select_tab(&mut self, index: usize) {
+        self.store_window();
+        self.current_tab = index.min(self.tabs.len() - 1);
+        self.load_window();
+    }
+    /// Goes `count` tab pages forward or back, wrapping around.
+    pub fn cycle_tab(&mut self, forward: bool, count: usize) {
+        let len = self.tabs.len();
+        let step = count % len;
+        let index = if forward {
+            (self.current_tab + step) % len
+        } else {
+            (self.current_tab + len - step) % len
+        };
+        self.select_tab(index);
+    }
+    pub fn current_tab(&self) -> usize {
+        self.current_tab
     }
-    fn search_backward(&mut self, pattern: &str) {
-        self.search_pattern = Some(pattern.to_string());
+    /// Moves the current tab page to position `index`, counting from 0.
+    pub fn move_tab(&mut self, index: usize) {
+        let layout = self.tabs.remove(self.current_tab);
+        self.current_tab = index.min(self.tabs.len());
+        self.tabs.insert(self.current_tab, layout);
+    }
+    /// `gt`, `{n}gt` and `{n}gT`.
+    fn goto_tab(&mut self, forward: bool, count: Option<usize>) {
+        match (forward, count) {
+            (true, Some(n)) => self.select_tab(n.max(1) - 1),
+            (true, None) => self.cycle_tab(true, 1),
+            (false, count) => self.cycle_tab(false, count.unwrap_or(1)),
+        }
+    }
+    /// Label for each tab page: its current window's file name, marked
+    /// when a buffer shown in the tab has unsaved changes.
+    fn tab_labels(&self) -> Vec<TabLabel> {
+        self.tabs
+            .iter()
+            .map(|layout| {
+                let buffer = &self.buffers[layout.current().buffer];
+                let name = buffer
+                    .file_path
+                    .as_deref()
+                    .map(|path| {
+                        Path::new(path)
+                            .file_name()
+                            .map_or(path.to_string(), |name| name.to_string_lossy().into_owned())
+                    })
+                    .unwrap_or_else(|| "[No Name]".to_string());
+                let modified = layout
+                    .windows()
+                    .iter()
+                    .any(|window| self.buffers[window.buffer].modified);
+                TabLabel { name, modified }
+            })
+            .collect()
+    }
+    /// Saves the current buffer and cursor into the current window.
+    fn store_window(&mut self) {
+        let cursor = self.buffers[self.current_buffer].cursor.clone();
+        let current_buffer = self.current_buffer;
+        let window = self.layout_mut().current_mut();
+        window.buffer = current_buffer;
+        window.cursor = cursor;
+    }
+    /// Makes the current window's buffer and cursor the live ones.
+    fn load_window(&mut self) {
+        let window = self.tabs[self.current_tab].current();
+        self.current_buffer = window.buffer;
         let buffer = &mut self.buffers[self.current_buffer];
-        buffer.find_previous(pattern);
+        buffer.cursor = window.cursor.clone();
+        buffer.cursor.clamp_to_buffer(&buffer.content);
+    }
+    /// How the current window shows lines, for motions by screen row.
+    fn wrapping(&self) -> Wrapping {
+        let layout = self.layout();
+        let gutter = ui::gutter_width(&self.buffers[self.current_buffer]);
+        let width = layout
+            .current_area()
+            .map_or(0, |area| (area.text.width as usize).saturating_sub(gutter));
+        Wrapping {
+            width,
+            left_col: layout.current().left_col,
+            options: self.options.wrap_options(),
+        }
     }
-    fn handle_pending_command(&mut self, pending: char, key_event: KeyEvent) -> Result<()> {
+    /// `zh`, `zl`, `zs` and `ze`: scrolls the current window sideways while
+    /// lines do not wrap, moving the cursor to stay on screen.
+    fn scroll_sideways(&mut self, key: KeyEvent, count: Option<usize>) {
+        let wrapping = self.wrapping();
+        if wrapping.options.wrap || wrapping.width == 0 {
+            return;
+        }
         let buffer = &mut self.buffers[self.current_buffer];
-        match (pending, key_event.code) {
-            ('d', KeyCode::Char('d')) => {
-                buffer.delete_line();
-            }
-            ('d', KeyCode::Char('w')) => {
-                buffer.delete_word();
-            }
-            ('d', KeyCode::Char('$')) => {
-                buffer.delete_to_line_end();
-            }
-            ('d', KeyCode::Char('0')) => {
-                buffer.delete_to_line_start();
+        let line = text::line_text(&buffer.content, buffer.cursor.row);
+        let display = text::display_col(&line, buffer.cursor.col);
+        let margin = wrapping.side_margin();
+        let count = count.unwrap_or(1);
+        let left_col = match key.code {
+            KeyCode::Char('h') | KeyCode::Left => wrapping.left_col.saturating_sub(count),
+            KeyCode::Char('l') | KeyCode::Right => wrapping.left_col + count,
+            KeyCode::Char('s') => display.saturating_sub(margin),
+            KeyCode::Char('e') => (display + margin + 1).saturating_sub(wrapping.width),
+            _ => return,
+        };
+        let first = if left_col == 0 { 0 } else { left_col + margin };
+        let last = (left_col + wrapping.width).saturating_sub(margin + 1);
+        if display < first || display > last {
+            let target = display.clamp(first, last.max(first));
+            buffer.cursor.col = text::col_for_display(&line, target);
+            buffer.cursor.update_desired_col(&buffer.content);
+        }
+        self.layout_mut().current_mut().left_col = left_col;
+    }
+    fn focus_window(&mut self, id: usize) {
+        self.store_window();
+        self.layout_mut().focus(id);
+        self.load_window();
+    }
+    /// `Ctrl-w` followed by `key`, with the count typed before `Ctrl-w`.
+    fn window_command(&mut self, key: KeyEvent, count: Option<usize>) -> Result<()> {
+        let amount = count.unwrap_or(1) as i32;
+        match key.code {
+            KeyCode::Char('s' | 'S') => self.split_window(Split::Horizontal)?,
+            KeyCode::Char('v') => self.split_window(Split::Vertical)?,
+            KeyCode::Char('n') => {
+                self.split_window(Split::Horizontal)?;
+                self.new_buffer();
             }
-            ('y', KeyCode::Char('y')) => {
-                let line = buffer.get_current_line();
-                self.clipboard = vec![line];
+            KeyCode::Char('c') => self.close_window()?,
+            KeyCode::Char('q') => self.execute_colon_command("quit")?,
+            KeyCode::Char('o') => self.only_window(),
+            KeyCode::Char(c @ ('w' | 'W')) => {
+                self.store_window();
+                self.layout_mut().cycle(c == 'w', count);
+                self.load_window();
             }
-            ('y', KeyCode::Char('w')) => {
-                let word = buffer.yank_word();
-                self.clipboard = vec![word];
+            KeyCode::Char(c @ ('h' | 'j' | 'k' | 'l')) => {
+                for _ in 0..count.unwrap_or(1) {
+                    self.store_window();
+                    let Some(id) = self.layout().neighbor(c) else {
+                        break;
+                    };
+                    self.focus_window(id);
+                }
             }
-            ('y', KeyCode::Char('$')) => {
-                let text = buffer.get_text_to_line_end();
-                self.clipboard = vec![text];
+            KeyCode::Left => return self.window_command(KeyEvent::from(KeyCode::Char('h')), count),
+            KeyCode::Down => return self.window_command(KeyEvent::from(KeyCode::Char('j')), count),
+            KeyCode::Up => return self.window_command(KeyEvent::from(KeyCode::Char('k')), count),
+            KeyCode::Right => {
+                return self.window_command(KeyEvent::from(KeyCode::Char('l')), count)
             }
+            KeyCode::Char('=') => self.layout_mut().equalize(),
+            KeyCode::Char('+') => self.layout_mut().resize(Split::Horizontal, amount),
+            KeyCode::Char('-') => self.layout_mut().resize(Split::Horizontal, -amount),
+            KeyCode::Char('>') => self.layout_mut().resize(Split::Vertical, amount),
+            KeyCode::Char('<') => self.layout_mut().resize(Split::Vertical, -amount),
             _ => {}
         }
         Ok(())
     }
-    #[allow(dead_code)]
-    pub fn get_current_buffer(&self) -> &Buffer {
-        &self.buffers[self.current_buffer]
+}
+const REGISTER_PREVIEW_WIDTH: usize = 64;
+/// Text typed in an insert session, for the `".` register.
+fn inserted_text(keys: &[KeyEvent]) -> String {
+    let mut text = String::new();
+    for key in keys {
+        match key.code {
+            KeyCode::Char(c) => text.push(c),
+            KeyCode::Enter => text.push('\n'),
+            KeyCode::Tab => text.push('\t'),
+            KeyCode::Backspace => {
+                text.pop();
+            }
+            _ => {}
+        }
     }
-    #[allow(dead_code)]
-    pub fn get_current_buffer_mut(&mut self) -> &mut Buffer {
-        &mut self.buffers[self.current_buffer]
+    text
+}
+/// `cw` changes to the end of the word like `ce`, and only the char under
+/// the cursor when it is the last char of a word.
+fn change_word_motion(
+    buffer: &Buffer,
+    operator: Operator,
+    motion: Motion,
+    count: Option<usize>,
+) -> (Motion, Option<usize>) {
+    let big_word = match (operator, motion) {
+        (Operator::Change, Motion::WordForward) => false,
+        (Operator::Change, Motion::BigWordForward) => true,
+        _ => return (motion, count),
+    };
+    let content = &buffer.content;
+    let pos = buffer.cursor.char_index(content);
+    if pos >= content.len_chars() || content.char(pos).is_whitespace() {
+        return (motion, count);
+    }
+    let class = char_class(content.char(pos), big_word);
+    let at_word_end =
+        pos + 1 >= content.len_chars() || char_class(content.char(pos + 1), big_word) != class;
+    let end_motion = if big_word {
+        Motion::BigWordEnd
+    } else {
+        Motion::WordEnd
+    };
+    match (at_word_end, count.unwrap_or(1)) {
+        (true, 1) => (Motion::Right, None),
+        (true, n) => (end_motion, Some(n - 1)),
+        (false, n) => (end_motion, Some(n)),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    fn editor_with(text: &str) -> Editor {
+        let mut editor = Editor::new().unwrap();
+        editor.get_current_buffer_mut().content = Rope::from_str(text);
+        // Keep the user's saved history out of the tests, and theirs out of it.
+        editor.search_history = History::load(None);
+        editor.command_history = History::load(None);
+        editor
+    }
+    fn text_of(editor: &Editor) -> String {
+        editor.get_current_buffer().content.to_string()
+    }
+    /// Types `keys`, written as a macro register stores them.
+    fn type_keys(editor: &mut Editor, keys: &str) {
+        for key in macros::decode_keys(keys) {
+            editor.handle_key(key).unwrap();
+        }
+    }
+    fn typed(text: &str, keys: &str) -> String {
+        let mut editor = editor_with(text);
+        type_keys(&mut editor, keys);
+        text_of(&editor)
+    }
+
+    #[test]
+    fn test_global_delete_on_a_large_buffer() {
+        let text: String = (0..30_000)
+            .map(|i| {
+                if i % 3 == 0 {
+                    format!("DEBUG {}\n", i)
+                } else {
+                    format!("line {}\n", i)
+                }
+            })
+            .collect();
+        let mut editor = editor_with(&text);
+        editor.execute_colon_command("g/DEBUG/d").unwrap();
+        let content = &editor.get_current_buffer().content;
+        assert_eq!(text::line_count(content), 20_000);
+        assert_eq!(text::line_text(content, 0), "line 1");
+        assert_eq!(text::line_text(content, 19_999), "line 29999");
+        assert!(!content.to_string().contains("DEBUG"));
+    }
+
+    #[test]
+    fn test_global_follows_lines_moved_by_its_command() {
+        let mut editor = editor_with("a\nb\nc\n");
+        editor.execute_colon_command("g/^/m0").unwrap();
+        assert_eq!(text_of(&editor), "c\nb\na\n");
+        let mut editor = editor_with("x1\ny\nx2\n");
+        editor.execute_colon_command("g/x/t$").unwrap();
+        assert_eq!(text_of(&editor), "x1\ny\nx2\nx1\nx2\n");
+        // A matched line joined into the one before is not visited.
+        let mut editor = editor_with("a1\na2\nb\na3\nc\n");
+        editor.execute_colon_command("g/a/j").unwrap();
+        assert_eq!(text_of(&editor), "a1 a2\nb\na3 c\n");
+    }
+
+    #[test]
+    fn test_dot_repeats_operators_with_their_count() {
+        assert_eq!(typed("a b c d e\n", "dw."), "c d e\n");
+        assert_eq!(typed("a b c d e\n", "2dw."), "e\n");
+        // A count given to `.` replaces the one the change had.
+        assert_eq!(typed("a b c d e\n", "2dw3."), "\n");
+        assert_eq!(typed("abcdef\n", "2x."), "ef\n");
+        assert_eq!(typed("a\nb\nc\n", "dd."), "c\n");
+        assert_eq!(typed("a\nb\n", ">>j."), "    a\n    b\n");
+    }
+
+    #[test]
+    fn test_dot_repeats_inserted_text() {
+        assert_eq!(typed("one two\n", "ciwx\u{1b}w."), "x x\n");
+        assert_eq!(typed("a\nb\n", "A;\u{1b}j."), "a;\nb;\n");
+        assert_eq!(typed("a\nb\n", "ox\u{1b}j."), "a\nx\nb\nx\n");
+        assert_eq!(typed("ab\n", "ix\u{1b}3."), "xxxxab\n");
+    }
+
+    #[test]
+    fn test_dot_skips_yanks_and_motions() {
+        assert_eq!(typed("a b c d\n", "dwyw."), "c d\n");
+        assert_eq!(typed("ab\n", "ylp."), "aaab\n");
+        assert_eq!(typed("abc\n", "."), "abc\n");
+    }
+
+    #[test]
+    fn test_recorded_macro_plays_back() {
+        let text = "a\nb\nc\nd\n";
+        assert_eq!(typed(text, "qaA!\u{1b}jq@a"), "a!\nb!\nc\nd\n");
+        assert_eq!(typed(text, "qaA!\u{1b}jq2@a"), "a!\nb!\nc!\nd\n");
+        assert_eq!(typed(text, "qaA!\u{1b}jq@a@@"), "a!\nb!\nc!\nd\n");
+        // `qA` adds to the end of register `a`.
+        assert_eq!(typed(text, "qaA!\u{1b}qqAjq@a@@"), "a!\nb!\nc!\nd\n");
+    }
+
+    #[test]
+    fn test_macro_is_register_text() {
+        let mut editor = editor_with("x\n");
+        type_keys(&mut editor, "qqI-\u{1b}q");
+        assert_eq!(editor.registers.get('q').unwrap().text, "I-\u{1b}");
+        // A yanked line runs as keys too.
+        let mut editor = editor_with("A.\u{1b}\nx\n");
+        type_keys(&mut editor, "\"ay$j@a");
+        assert_eq!(text_of(&editor), "A.\u{1b}\nx.\n");
+        type_keys(&mut editor, "@z");
+        assert_eq!(editor.message.as_deref(), None);
+    }
+
+    #[test]
+    fn test_linewise_visual_takes_whole_lines() {
+        assert_eq!(typed("a\nb\nc\n", "lVjd"), "c\n");
+        assert_eq!(typed("ab\ncd\nef\n", "Vjy2jp"), "ab\ncd\nef\nab\ncd\n");
+        // `o` swaps ends without changing the rows covered.
+        assert_eq!(typed("a\nb\nc\nd\n", "jVjokd"), "d\n");
+    }
+
+    #[test]
+    fn test_block_visual_works_on_columns() {
+        let text = "abcd\nefgh\nijkl\n";
+        assert_eq!(typed(text, "l\u{16}jld"), "ad\neh\nijkl\n");
+        assert_eq!(typed(text, "l\u{16}2jI-\u{1b}"), "a-bcd\ne-fgh\ni-jkl\n");
+        assert_eq!(typed(text, "\u{16}j$A;\u{1b}"), "abcd;\nefgh;\nijkl\n");
+        // Short lines are skipped by a block that starts past their end.
+        assert_eq!(typed("abcd\nx\nabcd\n", "ll\u{16}2jd"), "abd\nx\nabd\n");
+    }
+
+    #[test]
+    fn test_gv_selects_the_last_area_again() {
+        assert_eq!(typed("a\nb\nc\n", "Vj\u{1b}Ggvd"), "c\n");
+        assert_eq!(typed("abcd\nefgh\n", "\u{16}jl\u{1b}$gvd"), "cd\ngh\n");
+    }
+
+    #[test]
+    fn test_visual_operators_act_on_the_selection() {
+        assert_eq!(typed("abc\ndef\n", "vjd"), "ef\n");
+        assert_eq!(typed("abc\ndef\n", "lvjc-\u{1b}"), "a-f\n");
+        assert_eq!(typed("a\nb\nc\n", "Vj>"), "    a\n    b\nc\n");
+        assert_eq!(typed("abc def\n", "veU"), "ABC def\n");
+        assert_eq!(typed("abc def\n", "v$~"), "ABC DEF\n");
+        assert_eq!(typed("abcd\n", "lvlr-"), "a--d\n");
+        assert_eq!(typed("a\nb\nc\n", "vjJ"), "a b\nc\n");
+        // Motions and text objects extend the selection before the operator.
+        assert_eq!(typed("one two three\n", "wviwd"), "one  three\n");
+        assert_eq!(typed("a b c d\n", "v2wd"), " d\n");
+    }
+
+    #[test]
+    fn test_visual_line_keys_widen_to_whole_lines() {
+        assert_eq!(typed("ab\ncd\nef\n", "lvD"), "cd\nef\n");
+        assert_eq!(typed("ab\ncd\nef\n", "lvjX"), "ef\n");
+        assert_eq!(typed("ab\ncd\n", "lvYjp"), "ab\ncd\nab\n");
+        // In a block they run to the end of every line instead.
+        assert_eq!(typed("abcd\nefgh\n", "l\u{16}jD"), "a\ne\n");
+    }
+
+    #[test]
+    fn test_incsearch_moves_to_the_match_being_typed() {
+        let mut editor = editor_with("one\ntwo\nthree two\n");
+        type_keys(&mut editor, "/t");
+        let cursor = |editor: &Editor| {
+            let cursor = &editor.get_current_buffer().cursor;
+            (cursor.row, cursor.col)
+        };
+        assert_eq!(cursor(&editor), (1, 0));
+        type_keys(&mut editor, "h");
+        assert_eq!(cursor(&editor), (2, 0));
+        assert_eq!(editor.highlighted_pattern().unwrap().as_str(), "th");
+        // Esc gives the cursor back, and nothing was searched for.
+        type_keys(&mut editor, "\u{1b}");
+        assert_eq!(cursor(&editor), (0, 0));
+        assert!(editor.highlighted_pattern().is_none());
+        type_keys(&mut editor, ":set nois\r/th");
+        assert_eq!(cursor(&editor), (0, 0));
+    }
+
+    #[test]
+    fn test_hlsearch_until_nohlsearch() {
+        let mut editor = editor_with("a x\nx\n");
+        type_keys(&mut editor, "/x\r");
+        assert_eq!(editor.highlighted_pattern().unwrap().as_str(), "x");
+        assert_eq!(editor.search_count.unwrap().to_string(), "[1/2]");
+        type_keys(&mut editor, ":noh\r");
+        assert!(editor.highlighted_pattern().is_none());
+        // The next search shows matches again.
+        type_keys(&mut editor, "n");
+        assert_eq!(editor.highlighted_pattern().unwrap().as_str(), "x");
+        assert_eq!(editor.search_count.unwrap().to_string(), "[2/2]");
+        type_keys(&mut editor, ":set nohls\r");
+        assert!(editor.highlighted_pattern().is_none());
+    }
+
+    #[test]
+    fn test_star_and_hash_search_the_word_under_the_cursor() {
+        let cursor_after = |text: &str, keys: &str| {
+            let mut editor = editor_with(text);
+            type_keys(&mut editor, keys);
+            let cursor = &editor.get_current_buffer().cursor;
+            (cursor.row, cursor.col, editor.message.clone())
+        };
+        let text = "foo foobar foo\n";
+        assert_eq!(cursor_after(text, "*"), (0, 11, None));
+        assert_eq!(cursor_after(text, "g*"), (0, 4, None));
+        assert_eq!(cursor_after(text, "2*").1, 0);
+        assert_eq!(cursor_after(text, "#").1, 11);
+        // From a blank, the next keyword is searched for.
+        assert_eq!(cursor_after("a b.c b\n", "l*").1, 6);
+        assert_eq!(cursor_after("x .. ..\n", "l*").1, 5);
+        assert_eq!(
+            cursor_after("\nx\n", "*").2.as_deref(),
+            Some("E348: No string under cursor")
+        );
+    }
+
+    #[test]
+    fn test_search_offsets_are_repeated_by_n() {
+        let mut editor = editor_with("foo bar\nfoo baz\n");
+        type_keys(&mut editor, "/foo/e+2\r");
+        let cursor = |editor: &Editor| {
+            let cursor = &editor.get_current_buffer().cursor;
+            (cursor.row, cursor.col)
+        };
+        assert_eq!(cursor(&editor), (1, 4));
+        type_keys(&mut editor, "n");
+        assert_eq!(cursor(&editor), (0, 4));
+        type_keys(&mut editor, "gg/bar/-\r");
+        assert_eq!(cursor(&editor), (0, 0));
+        // `N` goes against the direction of the search, `?` included.
+        let mut editor = editor_with("x\nx\nx\n");
+        type_keys(&mut editor, "j?x\r");
+        assert_eq!(cursor(&editor), (0, 0));
+        type_keys(&mut editor, "N");
+        assert_eq!(cursor(&editor), (1, 0));
+    }
+
+    #[test]
+    fn test_tabs_open_move_and_close() {
+        let mut editor = editor_with("a\n");
+        let names = |editor: &Editor| -> Vec<String> {
+            editor
+                .tab_labels()
+                .into_iter()
+                .map(|label| format!("{}{}", label.name, if label.modified { "+" } else { "" }))
+                .collect()
+        };
+        editor.get_current_buffer_mut().file_path = Some("dir/one.txt".to_string());
+        editor.execute_colon_command("tabnew").unwrap();
+        editor.execute_colon_command("tabnew").unwrap();
+        assert_eq!(editor.current_tab(), 2);
+        type_keys(&mut editor, "ix\u{1b}");
+        assert_eq!(names(&editor), ["one.txt", "[No Name]", "[No Name]+"]);
+        // Each tab page keeps its own buffer.
+        type_keys(&mut editor, "1gt");
+        assert_eq!(text_of(&editor), "a\n");
+        type_keys(&mut editor, "gTgT");
+        assert_eq!(editor.current_tab(), 1);
+        editor.execute_colon_command("tabmove 0").unwrap();
+        assert_eq!(names(&editor), ["[No Name]", "one.txt", "[No Name]+"]);
+        editor.execute_colon_command("tabmove").unwrap();
+        assert_eq!(editor.current_tab(), 2);
+        editor.execute_colon_command("tabmove -2").unwrap();
+        assert_eq!(editor.current_tab(), 0);
+        assert!(editor.execute_colon_command("tabmove +3").is_err());
+        assert!(editor
+            .execute_colon_command("tabmove +18446744073709551615")
+            .is_err());
+        editor.execute_colon_command("tabclose").unwrap();
+        editor.execute_colon_command("tabclose").unwrap();
+        assert_eq!(names(&editor), ["[No Name]+"]);
+        assert_eq!(
+            editor
+                .execute_colon_command("tabclose")
+                .unwrap_err()
+                .to_string(),
+            "E784: Cannot close last tab page"
+        );
+    }
+
+    #[test]
+    fn test_windows_belong_to_their_tab_page() {
+        let mut editor = editor_with("a\n");
+        editor.execute_colon_command("split").unwrap();
+        editor.execute_colon_command("tabnew").unwrap();
+        assert_eq!(editor.window_count(), 1);
+        editor.execute_colon_command("tabprevious").unwrap();
+        assert_eq!(editor.window_count(), 2);
+        editor.execute_colon_command("tabnext 2").unwrap();
+        assert_eq!(editor.current_tab(), 1);
     }
 }
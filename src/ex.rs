@@ -0,0 +1,289 @@
+use crate::search;
+use crate::text;
+use ropey::Rope;
+use std::collections::HashMap;
+
+/// Lines an Ex command acts on, 0-based and inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+/// What the line addresses in front of an Ex command refer to.
+pub struct Addresses<'a> {
+    pub content: &'a Rope,
+    pub cursor_row: usize,
+    /// First and last row of the last Visual selection, for `'<` and `'>`.
+    pub visual: Option<(usize, usize)>,
+    pub marks: &'a HashMap<char, (usize, usize)>,
+    /// Pattern `//` and `??` search for again.
+    pub last_pattern: Option<&'a str>,
+    pub ignorecase: bool,
+    pub smartcase: bool,
+}
+impl Addresses<'_> {
+    /// Splits a range such as `%`, `.,$`, `'<,'>`, `/foo/,+2` or `3;+2` off
+    /// the front of `command`, returning it (`None` when there is none) and
+    /// the rest.
+    pub fn parse_range<'c>(
+        &self,
+        command: &'c str,
+    ) -> Result<(Option<LineRange>, &'c str), String> {
+        let command = command.trim_start();
+        if let Some(rest) = command.strip_prefix('%') {
+            let last = text::line_count(self.content) - 1;
+            return Ok((
+                Some(LineRange {
+                    start: 0,
+                    end: last,
+                }),
+                rest,
+            ));
+        }
+        let mut current = self.cursor_row + 1;
+        let (first, mut rest) = self.parse_address(command, current)?;
+        // A range starting with `,` or `;` starts at the cursor line.
+        let first = first.or_else(|| rest.starts_with([',', ';']).then_some(current));
+        let Some(mut start) = first else {
+            return Ok((None, command));
+        };
+        let mut end = start;
+        while let Some(separator) = rest.chars().next().filter(|c| matches!(c, ',' | ';')) {
+            if separator == ';' {
+                current = end;
+            }
+            let (next, after) = self.parse_address(&rest[1..], current)?;
+            start = end;
+            end = next.unwrap_or(current);
+            rest = after;
+        }
+        let lines = text::line_count(self.content);
+        if rest.trim().is_empty() {
+            // `:999` alone goes to the last line rather than failing.
+            start = start.min(lines);
+            end = end.min(lines);
+        } else if start > lines || end > lines {
+            return Err("E16: Invalid range".to_string());
+        }
+        if start > end {
+            std::mem::swap(&mut start, &mut end);
+        }
+        let range = LineRange {
+            start: start.max(1) - 1,
+            end: end.max(1) - 1,
+        };
+        Ok((Some(range), rest))
+    }
+    /// Parses one address such as `42`, `.`, `$`, `'a`, `/foo/` or `.+3` at
+    /// the front of `text`, as a 1-based line number. Offsets alone count
+    /// from `current`. Line 0 is allowed, meaning before the first line.
+    pub fn parse_address<'c>(
+        &self,
+        text: &'c str,
+        current: usize,
+    ) -> Result<(Option<usize>, &'c str), String> {
+        let text = text.trim_start();
+        let (base, mut rest) = match text.chars().next() {
+            Some('.') => (Some(current), &text[1..]),
+            Some('$') => (Some(text::line_count(self.content)), &text[1..]),
+            Some('\'') => {
+                let mark = text[1..]
+                    .chars()
+                    .next()
+                    .ok_or_else(|| "E20: Mark not set".to_string())?;
+                let row = match (mark, self.visual) {
+                    ('<', Some((first, _))) => Some(first),
+                    ('>', Some((_, last))) => Some(last),
+                    _ => self.marks.get(&mark).map(|&(row, _)| row),
+                };
+                let row = row.ok_or_else(|| "E20: Mark not set".to_string())?;
+                (Some(row + 1), &text[1 + mark.len_utf8()..])
+            }
+            Some(delimiter @ ('/' | '?')) => {
+                let (line, rest) = self.search_address(&text[1..], delimiter, current)?;
+                (Some(line), rest)
+            }
+            Some(c) if c.is_ascii_digit() => split_number(text)?,
+            _ => (None, text),
+        };
+        let mut line = base;
+        while let Some(sign) = rest.chars().next().filter(|c| matches!(c, '+' | '-')) {
+            let (amount, after) = split_number(&rest[1..])?;
+            let amount = amount.unwrap_or(1);
+            let from = line.unwrap_or(current);
+            line = Some(if sign == '+' {
+                from.checked_add(amount)
+                    .ok_or_else(|| "E16: Invalid range".to_string())?
+            } else {
+                from.checked_sub(amount)
+                    .ok_or_else(|| "E16: Invalid range".to_string())?
+            });
+            rest = after;
+        }
+        Ok((line, rest))
+    }
+    /// `/pattern/` finds the next line after `current` that matches, and
+    /// `?pattern?` the one before it, wrapping around the buffer.
+    fn search_address<'c>(
+        &self,
+        text: &'c str,
+        delimiter: char,
+        current: usize,
+    ) -> Result<(usize, &'c str), String> {
+        let (pattern, rest) = split_pattern(text, delimiter);
+        let pattern = match (pattern, self.last_pattern) {
+            ("", Some(last)) => last,
+            ("", None) => return Err("E35: No previous regular expression".to_string()),
+            (pattern, _) => pattern,
+        };
+        let regex = search::compile(pattern, self.ignorecase, self.smartcase)?;
+        let row = current.clamp(1, text::line_count(self.content)) - 1;
+        // From the end of the current line forward, or its start backward,
+        // so that a match on the current line itself is not found.
+        let from = if delimiter == '/' {
+            self.content.line_to_char(row) + text::line_len(self.content, row)
+        } else {
+            self.content.line_to_char(row)
+        };
+        let found = search::find(self.content, &regex, from, delimiter == '/')
+            .ok_or_else(|| format!("E486: Pattern not found: {}", pattern))?;
+        Ok((self.content.char_to_line(found.range.start) + 1, rest))
+    }
+}
+/// Splits leading digits off `text`, `None` when there are none. A number
+/// too big for a line number is an error.
+fn split_number(text: &str) -> Result<(Option<usize>, &str), String> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 {
+        return Ok((None, text));
+    }
+    let number = text[..digits]
+        .parse()
+        .map_err(|_| "E16: Invalid range".to_string())?;
+    Ok((Some(number), &text[digits..]))
+}
+/// Splits a pattern off `text` at the next unescaped `delimiter`, which may
+/// be missing at the end.
+fn split_pattern(text: &str, delimiter: char) -> (&str, &str) {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delimiter {
+            return (&text[..i], &text[i + c.len_utf8()..]);
+        }
+    }
+    (text, "")
+}
+/// A parsed `:g/pattern/command`, `:g!/pattern/command` or
+/// `:v/pattern/command`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalCommand {
+    pub pattern: String,
+    /// Run on the lines that do not match, for `:g!` and `:v`.
+    pub invert: bool,
+    /// Ex command run on each line; empty to list the lines.
+    pub command: String,
+}
+impl GlobalCommand {
+    /// Parses what follows `:g` or `:v`. The pattern can be delimited like
+    /// a `:s` pattern.
+    pub fn parse(args: &str, invert: bool) -> Result<Self, String> {
+        let Some(delimiter) = args.chars().next().filter(|c| {
+            !c.is_alphanumeric() && !c.is_whitespace() && !matches!(c, '"' | '|' | '\\')
+        }) else {
+            return Err("E35: No previous regular expression".to_string());
+        };
+        let (pattern, command) = split_pattern(&args[delimiter.len_utf8()..], delimiter);
+        Ok(GlobalCommand {
+            pattern: pattern.to_string(),
+            invert,
+            command: command.trim_start().to_string(),
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(command: &str) -> Result<(Option<(usize, usize)>, String), String> {
+        let content = Rope::from_str("one\ntwo\nthree\nfour\nfive\nsix\n");
+        let marks = HashMap::from([('a', (4, 0))]);
+        let addresses = Addresses {
+            content: &content,
+            cursor_row: 1,
+            visual: Some((2, 3)),
+            marks: &marks,
+            last_pattern: Some("f"),
+            ignorecase: false,
+            smartcase: false,
+        };
+        let (range, rest) = addresses.parse_range(command)?;
+        Ok((
+            range.map(|range| (range.start, range.end)),
+            rest.to_string(),
+        ))
+    }
+
+    #[test]
+    fn test_parse_range_whole_buffer_and_numbers() {
+        assert_eq!(range("%d"), Ok((Some((0, 5)), "d".to_string())));
+        assert_eq!(range("d"), Ok((None, "d".to_string())));
+        assert_eq!(range("2,4d"), Ok((Some((1, 3)), "d".to_string())));
+        assert_eq!(range("4,2d"), Ok((Some((1, 3)), "d".to_string())));
+        assert_eq!(range(".,$y"), Ok((Some((1, 5)), "y".to_string())));
+        assert_eq!(range("999"), Ok((Some((5, 5)), String::new())));
+        assert_eq!(range("1,999d"), Err("E16: Invalid range".to_string()));
+    }
+
+    #[test]
+    fn test_parse_range_marks_and_visual() {
+        assert_eq!(range("'<,'>s"), Ok((Some((2, 3)), "s".to_string())));
+        assert_eq!(range("'a,$d"), Ok((Some((4, 5)), "d".to_string())));
+        assert_eq!(range("'bd"), Err("E20: Mark not set".to_string()));
+    }
+
+    #[test]
+    fn test_parse_range_offsets_and_semicolon() {
+        assert_eq!(range(".+1,+2d"), Ok((Some((2, 3)), "d".to_string())));
+        assert_eq!(range("$-2,$d"), Ok((Some((3, 5)), "d".to_string())));
+        assert_eq!(range("-,.d"), Ok((Some((0, 1)), "d".to_string())));
+        // After `;` offsets count from the first address rather than the
+        // cursor.
+        assert_eq!(range("4;+1d"), Ok((Some((3, 4)), "d".to_string())));
+        assert_eq!(range("4,+1d"), Ok((Some((2, 3)), "d".to_string())));
+        assert_eq!(range("1-2d"), Err("E16: Invalid range".to_string()));
+    }
+
+    #[test]
+    fn test_parse_range_leading_separator() {
+        assert_eq!(range(",5d"), Ok((Some((1, 4)), "d".to_string())));
+        assert_eq!(range(";+2d"), Ok((Some((1, 3)), "d".to_string())));
+        assert_eq!(range(",d"), Ok((Some((1, 1)), "d".to_string())));
+    }
+
+    #[test]
+    fn test_parse_range_overflow_is_an_error() {
+        let invalid = Err("E16: Invalid range".to_string());
+        assert_eq!(range(".+99999999999999999999"), invalid);
+        assert_eq!(range(&format!(".+{}", usize::MAX)), invalid);
+        assert_eq!(range("99999999999999999999d"), invalid);
+        assert_eq!(range("$-99999999999999999999"), invalid);
+    }
+
+    #[test]
+    fn test_parse_range_patterns() {
+        assert_eq!(range("/f/d"), Ok((Some((3, 3)), "d".to_string())));
+        assert_eq!(range("/f/+1d"), Ok((Some((4, 4)), "d".to_string())));
+        assert_eq!(range("/t/;/f/d"), Ok((Some((2, 3)), "d".to_string())));
+        // `//` searches for the last pattern again, `?` backwards.
+        assert_eq!(range("//,$d"), Ok((Some((3, 5)), "d".to_string())));
+        assert_eq!(range("?o?d"), Ok((Some((0, 0)), "d".to_string())));
+        assert_eq!(
+            range("/nope/d"),
+            Err("E486: Pattern not found: nope".to_string())
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// Most entries kept, like vim's default `history` setting.
+const HISTORY_SIZE: usize = 50;
+
+/// Lines entered on the command line, oldest first, saved to `path` after
+/// every change so they survive restarts.
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+impl History {
+    /// Loads the history saved at `path`, starting empty if there is none.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let entries = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        Self { entries, path }
+    }
+    /// Adds `entry` as the newest, dropping an older copy of it.
+    pub fn add(&mut self, entry: &str) {
+        if entry.is_empty() || entry.contains('\n') {
+            return;
+        }
+        self.entries.retain(|existing| existing != entry);
+        self.entries.push(entry.to_string());
+        if self.entries.len() > HISTORY_SIZE {
+            self.entries.remove(0);
+        }
+        self.save();
+    }
+    /// Index of the newest entry older than `from` (or than all of them
+    /// when `from` is `None`) that starts with `prefix`.
+    pub fn older(&self, from: Option<usize>, prefix: &str) -> Option<usize> {
+        let end = from.unwrap_or(self.entries.len());
+        (0..end)
+            .rev()
+            .find(|&i| self.entries[i].starts_with(prefix))
+    }
+    /// Index of the oldest entry newer than `from` that starts with
+    /// `prefix`, or `None` when past the newest.
+    pub fn newer(&self, from: usize, prefix: &str) -> Option<usize> {
+        (from + 1..self.entries.len()).find(|&i| self.entries[i].starts_with(prefix))
+    }
+    pub fn get(&self, index: usize) -> &str {
+        &self.entries[index]
+    }
+    /// Best effort: a history that cannot be written is only lost at exit.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = fs::write(path, self.entries.join("\n") + "\n");
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(entries: &[&str]) -> History {
+        let mut history = History::load(None);
+        for entry in entries {
+            history.add(entry);
+        }
+        history
+    }
+
+    #[test]
+    fn test_add_moves_repeats_to_the_end() {
+        let history = history(&["a", "b", "a", "", "x\ny"]);
+        assert_eq!(history.entries, ["b", "a"]);
+        let mut history = History::load(None);
+        for i in 0..60 {
+            history.add(&i.to_string());
+        }
+        assert_eq!(history.entries.len(), HISTORY_SIZE);
+        assert_eq!(history.get(0), "10");
+    }
+
+    #[test]
+    fn test_older_and_newer_match_the_prefix() {
+        let history = history(&["s/a/b", "w", "s/c/d", "q"]);
+        assert_eq!(history.older(None, ""), Some(3));
+        assert_eq!(history.older(None, "s"), Some(2));
+        assert_eq!(history.older(Some(2), "s"), Some(0));
+        assert_eq!(history.older(Some(0), "s"), None);
+        assert_eq!(history.newer(0, "s"), Some(2));
+        assert_eq!(history.newer(2, "s"), None);
+    }
+
+    #[test]
+    fn test_saved_history_loads_again() {
+        let path = std::env::temp_dir()
+            .join(format!("history-test-{}", std::process::id()))
+            .join("search_history");
+        let mut history = History::load(Some(path.clone()));
+        history.add("foo");
+        history.add("bar");
+        assert_eq!(History::load(Some(path.clone())).entries, ["foo", "bar"]);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
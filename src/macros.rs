@@ -0,0 +1,88 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Special keys without a char of their own are stored in registers as
+/// private-use code points, so a recorded macro is plain editable text.
+const SPECIAL_KEYS: [(KeyCode, char); 10] = [
+    (KeyCode::Up, '\u{E000}'),
+    (KeyCode::Down, '\u{E001}'),
+    (KeyCode::Left, '\u{E002}'),
+    (KeyCode::Right, '\u{E003}'),
+    (KeyCode::Home, '\u{E004}'),
+    (KeyCode::End, '\u{E005}'),
+    (KeyCode::PageUp, '\u{E006}'),
+    (KeyCode::PageDown, '\u{E007}'),
+    (KeyCode::Delete, '\u{E008}'),
+    (KeyCode::BackTab, '\u{E009}'),
+];
+pub fn encode_key(key: KeyEvent) -> Option<char> {
+    match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            let byte = c.to_ascii_lowercase() as u32;
+            ('a' as u32..='z' as u32)
+                .contains(&byte)
+                .then(|| char::from_u32(byte - 'a' as u32 + 1))
+                .flatten()
+        }
+        KeyCode::Char(c) => Some(c),
+        KeyCode::Esc => Some('\u{1b}'),
+        KeyCode::Enter => Some('\r'),
+        KeyCode::Tab => Some('\t'),
+        KeyCode::Backspace => Some('\u{8}'),
+        code => SPECIAL_KEYS
+            .iter()
+            .find(|(special, _)| *special == code)
+            .map(|(_, c)| *c),
+    }
+}
+pub fn decode_key(c: char) -> KeyEvent {
+    let code = match c {
+        '\u{1b}' => KeyCode::Esc,
+        '\r' | '\n' => KeyCode::Enter,
+        '\t' => KeyCode::Tab,
+        '\u{8}' | '\u{7f}' => KeyCode::Backspace,
+        '\u{1}'..='\u{1a}' => {
+            let letter = char::from_u32(c as u32 - 1 + 'a' as u32).unwrap_or('a');
+            return KeyEvent::new(KeyCode::Char(letter), KeyModifiers::CONTROL);
+        }
+        _ => SPECIAL_KEYS
+            .iter()
+            .find(|(_, special)| *special == c)
+            .map_or(KeyCode::Char(c), |(code, _)| *code),
+    };
+    KeyEvent::new(code, KeyModifiers::NONE)
+}
+pub fn decode_keys(text: &str) -> Vec<KeyEvent> {
+    text.chars().map(decode_key).collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_round_trip_through_register_text() {
+        let keys = [
+            KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL),
+            KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Up, KeyModifiers::NONE),
+            KeyEvent::new(KeyCode::Delete, KeyModifiers::NONE),
+        ];
+        let text: String = keys.iter().filter_map(|key| encode_key(*key)).collect();
+        assert_eq!(text.chars().next(), Some('a'));
+        assert_eq!(text.chars().nth(1), Some('\u{17}'));
+        assert_eq!(decode_keys(&text), keys);
+    }
+
+    #[test]
+    fn test_keys_without_a_char_are_not_recorded() {
+        assert_eq!(encode_key(KeyEvent::from(KeyCode::F(1))), None);
+        assert_eq!(
+            encode_key(KeyEvent::new(KeyCode::Char('1'), KeyModifiers::CONTROL)),
+            None
+        );
+        // Typed text with a line break plays back as Enter.
+        assert_eq!(decode_key('\n').code, KeyCode::Enter);
+    }
+}
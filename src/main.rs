@@ -1,10 +1,27 @@
 mod buffer;
+mod clipboard;
+mod cmdline;
 mod commands;
 mod cursor;
 mod editor;
+mod ex;
+mod history;
+mod macros;
 mod modes;
+mod motion;
+mod normal;
+mod options;
+mod registers;
+mod search;
+mod substitute;
 mod syntax;
+mod text;
+mod textobject;
 mod ui;
+mod undo;
+mod visual;
+mod window;
+mod wrap;
 use anyhow::Result;
 use clap::Parser;
 #[derive(Parser)]
@@ -1,8 +1,10 @@
+use crate::visual::VisualMode;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum EditorMode {
     Normal,
     Insert,
-    Visual,
+    Visual(VisualMode),
     Command,
 }
 impl EditorMode {
@@ -10,7 +12,9 @@ impl EditorMode {
         match self {
             EditorMode::Normal => "NORMAL",
             EditorMode::Insert => "INSERT",
-            EditorMode::Visual => "VISUAL",
+            EditorMode::Visual(VisualMode::Char) => "VISUAL",
+            EditorMode::Visual(VisualMode::Line) => "VISUAL LINE",
+            EditorMode::Visual(VisualMode::Block) => "VISUAL BLOCK",
             EditorMode::Command => "COMMAND",
         }
     }
@@ -19,7 +23,7 @@ impl EditorMode {
         match self {
             EditorMode::Normal => CursorStyle::Block,
             EditorMode::Insert => CursorStyle::Line,
-            EditorMode::Visual => CursorStyle::Block,
+            EditorMode::Visual(_) => CursorStyle::Block,
             EditorMode::Command => CursorStyle::Line,
         }
     }
@@ -0,0 +1,207 @@
+use ropey::Rope;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    BigWordForward,
+    BigWordBackward,
+    BigWordEnd,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    CurrentLine,
+    FileStart,
+    FileEnd,
+    ParagraphForward,
+    ParagraphBackward,
+    FindChar {
+        ch: char,
+        forward: bool,
+        till: bool,
+    },
+    MatchBracket,
+    /// `gj`/`gk`: screen rows rather than lines when lines wrap.
+    DisplayDown,
+    DisplayUp,
+    /// `g0`/`g$`: the first and last char of the screen row.
+    DisplayLineStart,
+    DisplayLineEnd,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotionKind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+impl Motion {
+    pub fn kind(&self) -> MotionKind {
+        match self {
+            Motion::Up
+            | Motion::Down
+            | Motion::CurrentLine
+            | Motion::FileStart
+            | Motion::FileEnd => MotionKind::Linewise,
+            Motion::WordEnd
+            | Motion::BigWordEnd
+            | Motion::MatchBracket
+            | Motion::DisplayLineEnd => MotionKind::Inclusive,
+            Motion::FindChar { forward, .. } if *forward => MotionKind::Inclusive,
+            _ => MotionKind::Exclusive,
+        }
+    }
+}
+/// Text covered by an operator: `start..end` in chars. Linewise ranges
+/// always span whole lines including the final line ending.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionRange {
+    pub start: usize,
+    pub end: usize,
+    pub linewise: bool,
+}
+impl MotionRange {
+    pub fn lines(content: &Rope, first: usize, last: usize) -> Self {
+        let last = last.min(content.len_lines().saturating_sub(1));
+        let end = if last + 1 < content.len_lines() {
+            content.line_to_char(last + 1)
+        } else {
+            content.len_chars()
+        };
+        Self {
+            start: content.line_to_char(first.min(last)),
+            end,
+            linewise: true,
+        }
+    }
+    pub fn first_line(&self, content: &Rope) -> usize {
+        content.char_to_line(self.start)
+    }
+    /// Last line touched by the range, not counting a trailing line ending.
+    pub fn last_line(&self, content: &Rope) -> usize {
+        if self.end > self.start && content.char(self.end - 1) == '\n' {
+            content.char_to_line(self.end - 1)
+        } else {
+            content.char_to_line(self.end)
+        }
+    }
+}
+/// Classifies chars for word motions: 0 for blanks, 1 for keyword chars and
+/// 2 for punctuation. WORD motions treat everything non-blank as one class.
+pub fn char_class(c: char, big_word: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big_word || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+fn is_line_start(content: &Rope, pos: usize) -> bool {
+    pos == 0 || content.char(pos - 1) == '\n'
+}
+fn is_empty_line_at(content: &Rope, pos: usize) -> bool {
+    is_line_start(content, pos) && (pos == content.len_chars() || content.char(pos) == '\n')
+}
+pub fn word_forward(content: &Rope, pos: usize, big_word: bool) -> usize {
+    let len = content.len_chars();
+    let mut i = pos;
+    if i >= len {
+        return len;
+    }
+    let class = char_class(content.char(i), big_word);
+    if class != 0 {
+        while i < len && char_class(content.char(i), big_word) == class {
+            i += 1;
+        }
+    }
+    while i < len && content.char(i).is_whitespace() {
+        if i != pos && is_empty_line_at(content, i) {
+            break;
+        }
+        i += 1;
+    }
+    i
+}
+pub fn word_backward(content: &Rope, pos: usize, big_word: bool) -> usize {
+    if pos == 0 {
+        return 0;
+    }
+    let mut i = pos - 1;
+    while i > 0 && content.char(i).is_whitespace() && !is_empty_line_at(content, i) {
+        i -= 1;
+    }
+    let class = char_class(content.char(i), big_word);
+    if class != 0 {
+        while i > 0 && char_class(content.char(i - 1), big_word) == class {
+            i -= 1;
+        }
+    }
+    i
+}
+pub fn word_end(content: &Rope, pos: usize, big_word: bool) -> usize {
+    let len = content.len_chars();
+    let mut i = pos + 1;
+    while i < len && content.char(i).is_whitespace() {
+        i += 1;
+    }
+    if i >= len {
+        return len.saturating_sub(1).max(pos);
+    }
+    let class = char_class(content.char(i), big_word);
+    while i + 1 < len && char_class(content.char(i + 1), big_word) == class {
+        i += 1;
+    }
+    i
+}
+/// Position of the bracket matching the first bracket at or after `pos` on
+/// its line, searching across lines.
+pub fn match_bracket(content: &Rope, pos: usize) -> Option<usize> {
+    let line = content.char_to_line(pos);
+    let line_end = if line + 1 < content.len_lines() {
+        content.line_to_char(line + 1)
+    } else {
+        content.len_chars()
+    };
+    let start = (pos..line_end).find(|&i| "()[]{}".contains(content.char(i)))?;
+    let open = content.char(start);
+    let (target, forward) = match open {
+        '(' => (')', true),
+        '[' => (']', true),
+        '{' => ('}', true),
+        ')' => ('(', false),
+        ']' => ('[', false),
+        _ => ('{', false),
+    };
+    let mut depth = 0usize;
+    if forward {
+        for i in start..content.len_chars() {
+            let c = content.char(i);
+            if c == open {
+                depth += 1;
+            } else if c == target {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+        }
+    } else {
+        for i in (0..=start).rev() {
+            let c = content.char(i);
+            if c == open {
+                depth += 1;
+            } else if c == target {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+        }
+    }
+    None
+}
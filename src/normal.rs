@@ -0,0 +1,536 @@
+use crate::motion::Motion;
+use crate::textobject::TextObject;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+    Lowercase,
+    Uppercase,
+    ToggleCase,
+    Reindent,
+    Join,
+    Format,
+    Replace(char),
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Motion(Motion),
+    TextObject(TextObject),
+    /// The operator key repeated (`dd`, `>>`, `gUU`): `count` whole lines.
+    Lines,
+    /// The Visual mode selection. `linewise` widens it to whole lines, or to
+    /// the line ends in block mode, for keys like `D` and `Y`.
+    Selection {
+        linewise: bool,
+    },
+}
+/// A complete normal-mode command parsed from
+/// `[count]["x]operator[count]motion`, a bare motion, or any other key.
+#[derive(Debug, Clone)]
+pub enum NormalCommand {
+    Move {
+        motion: Motion,
+        count: Option<usize>,
+    },
+    Operate {
+        operator: Operator,
+        target: Target,
+        count: Option<usize>,
+        register: Option<char>,
+    },
+    Action {
+        prefix: Option<char>,
+        key: KeyEvent,
+        count: Option<usize>,
+        register: Option<char>,
+        /// The char typed after keys that take one, like `q{reg}`.
+        arg: Option<char>,
+    },
+}
+impl NormalCommand {
+    /// Whether `.` should repeat this command when it does not enter Insert
+    /// mode; commands that do are always recorded with their inserted keys.
+    pub fn is_change(&self) -> bool {
+        match self {
+            NormalCommand::Move { .. } => false,
+            NormalCommand::Operate {
+                operator, target, ..
+            } => *operator != Operator::Yank && !matches!(target, Target::Selection { .. }),
+            NormalCommand::Action { prefix, key, .. } => {
+                prefix.is_none()
+                    && matches!(
+                        key.code,
+                        KeyCode::Char('p') | KeyCode::Char('P') | KeyCode::Delete
+                    )
+            }
+        }
+    }
+    /// The command to run for `[count].` and how many times to run it.
+    pub fn with_repeat_count(&self, count: Option<usize>) -> (NormalCommand, usize) {
+        match self.clone() {
+            NormalCommand::Operate {
+                operator,
+                target,
+                count: old,
+                register,
+            } => (
+                NormalCommand::Operate {
+                    operator,
+                    target,
+                    count: count.or(old),
+                    register,
+                },
+                1,
+            ),
+            NormalCommand::Action {
+                prefix,
+                key,
+                count: old,
+                register,
+                arg,
+            } if matches!(key.code, KeyCode::Char('p') | KeyCode::Char('P')) => (
+                NormalCommand::Action {
+                    prefix,
+                    key,
+                    count: count.or(old),
+                    register,
+                    arg,
+                },
+                1,
+            ),
+            command => (command, count.unwrap_or(1)),
+        }
+    }
+}
+/// The last buffer change, replayed by `.`: the command that started it and
+/// the keys typed in the Insert mode session that followed, if any.
+#[derive(Debug, Clone)]
+pub struct RepeatableChange {
+    pub command: NormalCommand,
+    pub inserted: Vec<KeyEvent>,
+}
+pub enum ParseResult {
+    Pending,
+    Complete(NormalCommand),
+    Invalid,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Awaiting {
+    Register,
+    Prefix(char),
+    ActionArg(char),
+    Replace,
+    TextObject { inner: bool },
+    FindChar { forward: bool, till: bool },
+}
+/// Counts stop growing here, as in vim, so that adding them to a row
+/// number cannot overflow.
+const MAX_COUNT: usize = 999_999_999;
+/// Accumulates normal-mode keys until they form a command.
+#[derive(Default)]
+pub struct NormalParser {
+    count: Option<usize>,
+    register: Option<char>,
+    operator: Option<(Operator, char)>,
+    motion_count: Option<usize>,
+    awaiting: Option<Awaiting>,
+    /// Parsing in Visual mode, where operators act on the selection at once.
+    visual: bool,
+}
+impl NormalParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_pending(&self) -> bool {
+        self.count.is_some()
+            || self.register.is_some()
+            || self.operator.is_some()
+            || self.awaiting.is_some()
+    }
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+    pub fn feed(&mut self, key: KeyEvent, visual: bool) -> ParseResult {
+        self.visual = visual;
+        if key.code == KeyCode::Esc {
+            let was_pending = self.is_pending();
+            self.reset();
+            return if was_pending {
+                ParseResult::Invalid
+            } else {
+                self.action(None, key)
+            };
+        }
+        let result = self.feed_key(key);
+        if !matches!(result, ParseResult::Pending) {
+            self.reset();
+        }
+        result
+    }
+    fn feed_key(&mut self, key: KeyEvent) -> ParseResult {
+        let ch = match key.code {
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => Some(c),
+            _ => None,
+        };
+        match self.awaiting.take() {
+            Some(Awaiting::Register) => {
+                return match ch {
+                    Some(c) => {
+                        self.register = Some(c);
+                        ParseResult::Pending
+                    }
+                    None => ParseResult::Invalid,
+                };
+            }
+            Some(Awaiting::FindChar { forward, till }) => {
+                return match ch {
+                    Some(c) => self.motion(Motion::FindChar {
+                        ch: c,
+                        forward,
+                        till,
+                    }),
+                    None => ParseResult::Invalid,
+                };
+            }
+            Some(Awaiting::TextObject { inner }) => {
+                return match (
+                    ch.and_then(|c| TextObject::from_char(c, inner)),
+                    self.operator,
+                ) {
+                    (Some(object), Some((operator, _))) => {
+                        self.operate(operator, Target::TextObject(object))
+                    }
+                    _ => ParseResult::Invalid,
+                };
+            }
+            Some(Awaiting::ActionArg(action)) => {
+                return match ch {
+                    Some(c) => ParseResult::Complete(NormalCommand::Action {
+                        prefix: None,
+                        key: KeyEvent::new(KeyCode::Char(action), KeyModifiers::NONE),
+                        count: self.count,
+                        register: self.register,
+                        arg: Some(c),
+                    }),
+                    None => ParseResult::Invalid,
+                };
+            }
+            Some(Awaiting::Replace) => {
+                return match ch {
+                    Some(c) => self.operate(Operator::Replace(c), self.single_char_target()),
+                    None => ParseResult::Invalid,
+                };
+            }
+            Some(Awaiting::Prefix(prefix)) => return self.prefixed(prefix, key, ch),
+            None => {}
+        }
+        if let Some(digit) = ch.and_then(|c| c.to_digit(10)) {
+            let slot = if self.operator.is_some() {
+                &mut self.motion_count
+            } else {
+                &mut self.count
+            };
+            if digit != 0 || slot.is_some() {
+                *slot = Some(
+                    slot.unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(digit as usize)
+                        .min(MAX_COUNT),
+                );
+                return ParseResult::Pending;
+            }
+        }
+        let Some(c) = ch else {
+            // `Ctrl-w` starts a window command, kept as prefix `w`.
+            if key.code == KeyCode::Char('w') && self.operator.is_none() && !self.visual {
+                self.awaiting = Some(Awaiting::Prefix('w'));
+                return ParseResult::Pending;
+            }
+            if key.modifiers.contains(KeyModifiers::CONTROL) {
+                return self.action(None, key);
+            }
+            return match key_motion(key.code) {
+                Some(motion) => self.motion(motion),
+                None => self.action(None, key),
+            };
+        };
+        if let Some(motion) = key_motion(key.code) {
+            return self.motion(motion);
+        }
+        match c {
+            '"' if self.operator.is_none() => {
+                self.awaiting = Some(Awaiting::Register);
+                ParseResult::Pending
+            }
+            'g' | 'z' => {
+                self.awaiting = Some(Awaiting::Prefix(c));
+                ParseResult::Pending
+            }
+            'q' | '@' | 'm' | '\'' | '`' if self.operator.is_none() => {
+                self.awaiting = Some(Awaiting::ActionArg(c));
+                ParseResult::Pending
+            }
+            'r' if self.operator.is_none() => {
+                self.awaiting = Some(Awaiting::Replace);
+                ParseResult::Pending
+            }
+            'J' if self.operator.is_none() => {
+                let target = if self.visual {
+                    Target::Selection { linewise: false }
+                } else {
+                    Target::Lines
+                };
+                self.operate(Operator::Join, target)
+            }
+            'i' | 'a' if self.operator.is_some() => {
+                self.awaiting = Some(Awaiting::TextObject { inner: c == 'i' });
+                ParseResult::Pending
+            }
+            'f' | 'F' | 't' | 'T' => {
+                self.awaiting = Some(Awaiting::FindChar {
+                    forward: c == 'f' || c == 't',
+                    till: c == 't' || c == 'T',
+                });
+                ParseResult::Pending
+            }
+            _ if self.visual => match operator_for(c)
+                .map(|operator| (operator, Target::Selection { linewise: false }))
+                .or_else(|| visual_shorthand(c))
+            {
+                Some((operator, target)) => self.operate(operator, target),
+                None => self.action(None, key),
+            },
+            _ => {
+                if let Some((operator, key_char)) = self.operator {
+                    return if key_char == c {
+                        self.operate(operator, Target::Lines)
+                    } else {
+                        ParseResult::Invalid
+                    };
+                }
+                match operator_for(c) {
+                    Some(operator) => {
+                        self.operator = Some((operator, c));
+                        ParseResult::Pending
+                    }
+                    None => match shorthand(c) {
+                        Some((operator, target)) => self.operate(operator, target),
+                        None => self.action(None, key),
+                    },
+                }
+            }
+        }
+    }
+    fn prefixed(&mut self, prefix: char, key: KeyEvent, ch: Option<char>) -> ParseResult {
+        if prefix == 'g' {
+            if let Some(c) = ch {
+                if let Some((operator, key_char)) = self.operator {
+                    // `gUU` and `gUgU` both operate on lines.
+                    if key_char == c || operator_for_g(c) == Some(operator) {
+                        return self.operate(operator, Target::Lines);
+                    }
+                }
+                if let Some(motion) = g_motion(c) {
+                    return self.motion(motion);
+                }
+                if let Some(operator) = operator_for_g(c).filter(|_| self.visual) {
+                    return self.operate(operator, Target::Selection { linewise: false });
+                }
+                if let Some(operator) = operator_for_g(c) {
+                    if self.operator.is_some() {
+                        return ParseResult::Invalid;
+                    }
+                    self.operator = Some((operator, c));
+                    return ParseResult::Pending;
+                }
+            }
+        }
+        if self.operator.is_some() {
+            return ParseResult::Invalid;
+        }
+        self.action(Some(prefix), key)
+    }
+    /// What `r` replaces: the selection, or `count` chars from the cursor.
+    fn single_char_target(&self) -> Target {
+        if self.visual {
+            Target::Selection { linewise: false }
+        } else {
+            Target::Motion(Motion::Right)
+        }
+    }
+    fn motion(&mut self, motion: Motion) -> ParseResult {
+        match self.operator {
+            Some((operator, _)) => self.operate(operator, Target::Motion(motion)),
+            None => ParseResult::Complete(NormalCommand::Move {
+                motion,
+                count: self.count,
+            }),
+        }
+    }
+    fn operate(&mut self, operator: Operator, target: Target) -> ParseResult {
+        let count = match (self.count, self.motion_count) {
+            (None, None) => None,
+            (count, motion_count) => {
+                let count = count.unwrap_or(1).saturating_mul(motion_count.unwrap_or(1));
+                Some(count.min(MAX_COUNT))
+            }
+        };
+        ParseResult::Complete(NormalCommand::Operate {
+            operator,
+            target,
+            count,
+            register: self.register,
+        })
+    }
+    fn action(&mut self, prefix: Option<char>, key: KeyEvent) -> ParseResult {
+        if self.operator.is_some() {
+            return ParseResult::Invalid;
+        }
+        ParseResult::Complete(NormalCommand::Action {
+            prefix,
+            key,
+            count: self.count,
+            register: self.register,
+            arg: None,
+        })
+    }
+}
+fn key_motion(code: KeyCode) -> Option<Motion> {
+    let motion = match code {
+        KeyCode::Left | KeyCode::Char('h') | KeyCode::Backspace => Motion::Left,
+        KeyCode::Right | KeyCode::Char('l') | KeyCode::Char(' ') => Motion::Right,
+        KeyCode::Up | KeyCode::Char('k') => Motion::Up,
+        KeyCode::Down | KeyCode::Char('j') => Motion::Down,
+        KeyCode::Home | KeyCode::Char('0') => Motion::LineStart,
+        KeyCode::End | KeyCode::Char('$') => Motion::LineEnd,
+        KeyCode::Char('w') => Motion::WordForward,
+        KeyCode::Char('b') => Motion::WordBackward,
+        KeyCode::Char('e') => Motion::WordEnd,
+        KeyCode::Char('W') => Motion::BigWordForward,
+        KeyCode::Char('B') => Motion::BigWordBackward,
+        KeyCode::Char('E') => Motion::BigWordEnd,
+        KeyCode::Char('^') => Motion::FirstNonBlank,
+        KeyCode::Char('_') => Motion::CurrentLine,
+        KeyCode::Char('G') => Motion::FileEnd,
+        KeyCode::Char('}') => Motion::ParagraphForward,
+        KeyCode::Char('{') => Motion::ParagraphBackward,
+        KeyCode::Char('%') => Motion::MatchBracket,
+        _ => return None,
+    };
+    Some(motion)
+}
+fn g_motion(c: char) -> Option<Motion> {
+    match c {
+        'g' => Some(Motion::FileStart),
+        'j' => Some(Motion::DisplayDown),
+        'k' => Some(Motion::DisplayUp),
+        '0' => Some(Motion::DisplayLineStart),
+        '$' => Some(Motion::DisplayLineEnd),
+        _ => None,
+    }
+}
+fn operator_for(c: char) -> Option<Operator> {
+    match c {
+        'd' => Some(Operator::Delete),
+        'c' => Some(Operator::Change),
+        'y' => Some(Operator::Yank),
+        '>' => Some(Operator::ShiftRight),
+        '<' => Some(Operator::ShiftLeft),
+        '=' => Some(Operator::Reindent),
+        _ => None,
+    }
+}
+fn operator_for_g(c: char) -> Option<Operator> {
+    match c {
+        'u' => Some(Operator::Lowercase),
+        'U' => Some(Operator::Uppercase),
+        '~' => Some(Operator::ToggleCase),
+        'q' => Some(Operator::Format),
+        _ => None,
+    }
+}
+/// Single-key commands that are shorthands for an operator and a motion.
+fn shorthand(c: char) -> Option<(Operator, Target)> {
+    let command = match c {
+        'x' => (Operator::Delete, Target::Motion(Motion::Right)),
+        'X' => (Operator::Delete, Target::Motion(Motion::Left)),
+        'D' => (Operator::Delete, Target::Motion(Motion::LineEnd)),
+        'C' => (Operator::Change, Target::Motion(Motion::LineEnd)),
+        's' => (Operator::Change, Target::Motion(Motion::Right)),
+        'S' => (Operator::Change, Target::Lines),
+        'Y' => (Operator::Yank, Target::Lines),
+        _ => return None,
+    };
+    Some(command)
+}
+/// Visual mode keys that apply an operator to the selection.
+fn visual_shorthand(c: char) -> Option<(Operator, Target)> {
+    let selection = Target::Selection { linewise: false };
+    let lines = Target::Selection { linewise: true };
+    let command = match c {
+        'x' => (Operator::Delete, selection),
+        'X' | 'D' => (Operator::Delete, lines),
+        's' => (Operator::Change, selection),
+        'S' | 'C' | 'R' => (Operator::Change, lines),
+        'Y' => (Operator::Yank, lines),
+        'u' => (Operator::Lowercase, selection),
+        'U' => (Operator::Uppercase, selection),
+        '~' => (Operator::ToggleCase, selection),
+        _ => return None,
+    };
+    Some(command)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(keys: &str) -> ParseResult {
+        let mut parser = NormalParser::new();
+        let mut result = ParseResult::Pending;
+        for c in keys.chars() {
+            result = parser.feed(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE), false);
+        }
+        result
+    }
+
+    #[test]
+    fn test_huge_counts_saturate() {
+        let keys = "9".repeat(40) + "x";
+        assert!(matches!(
+            parse(&keys),
+            ParseResult::Complete(NormalCommand::Operate {
+                count: Some(MAX_COUNT),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("99999999999d99999999999w"),
+            ParseResult::Complete(NormalCommand::Operate {
+                count: Some(MAX_COUNT),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("2d3w"),
+            ParseResult::Complete(NormalCommand::Operate { count: Some(6), .. })
+        ));
+    }
+
+    #[test]
+    fn test_capital_y_yanks_lines() {
+        assert!(matches!(
+            parse("3Y"),
+            ParseResult::Complete(NormalCommand::Operate {
+                operator: Operator::Yank,
+                target: Target::Lines,
+                count: Some(3),
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::undo::default_undo_dir;
+use crate::wrap::WrapOptions;
+use std::path::PathBuf;
+
+/// Names `:set` completes, besides the `no` forms of `BOOLEAN_OPTIONS`.
+pub const OPTION_NAMES: &[&str] = &[
+    "breakindent",
+    "clipboard",
+    "clipcopy",
+    "clippaste",
+    "hlsearch",
+    "ignorecase",
+    "incsearch",
+    "linebreak",
+    "showbreak",
+    "sidescrolloff",
+    "smartcase",
+    "undodir",
+    "undofile",
+    "wrap",
+];
+/// Options that are switched off by prefixing `no`.
+pub const BOOLEAN_OPTIONS: &[&str] = &[
+    "breakindent",
+    "hlsearch",
+    "ignorecase",
+    "incsearch",
+    "linebreak",
+    "smartcase",
+    "undofile",
+    "wrap",
+];
+/// Global settings changed through `:set`. Buffer-local settings such as
+/// `number` live on `Buffer` instead.
+pub struct Options {
+    pub undofile: bool,
+    pub undodir: PathBuf,
+    /// `unnamed` or `unnamedplus`: send unnamed yanks and deletes to `"*`
+    /// or `"+`.
+    pub clipboard: String,
+    /// External commands used for `"+`/`"*` besides OSC 52, e.g.
+    /// `xclip -selection clipboard` and `xclip -o -selection clipboard`.
+    pub clipcopy: Option<String>,
+    pub clippaste: Option<String>,
+    /// Searches ignore case, unless `smartcase` is set and the pattern has
+    /// an uppercase letter.
+    pub ignorecase: bool,
+    pub smartcase: bool,
+    /// Jump to the match while a pattern is typed.
+    pub incsearch: bool,
+    /// Highlight every match of the last search pattern.
+    pub hlsearch: bool,
+    /// Lines longer than the window continue on the next rows instead of
+    /// scrolling sideways.
+    pub wrap: bool,
+    /// Wrap at a word boundary rather than the last column that fits.
+    pub linebreak: bool,
+    /// Shown at the start of each continuation row.
+    pub showbreak: String,
+    /// Indent continuation rows like the start of their line.
+    pub breakindent: bool,
+    /// Columns kept left and right of the cursor when scrolling sideways.
+    pub sidescrolloff: usize,
+}
+impl Options {
+    pub fn new() -> Self {
+        Self {
+            undofile: false,
+            undodir: default_undo_dir(),
+            clipboard: String::new(),
+            clipcopy: None,
+            clippaste: None,
+            ignorecase: false,
+            smartcase: false,
+            incsearch: true,
+            hlsearch: true,
+            wrap: true,
+            linebreak: false,
+            showbreak: String::new(),
+            breakindent: false,
+            sidescrolloff: 0,
+        }
+    }
+    /// Applies one `:set` argument such as `undofile` or `undodir=~/.undo`,
+    /// returning the confirmation message.
+    pub fn set(&mut self, arg: &str) -> Result<String, String> {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        match (name, value) {
+            ("undofile" | "udf", None) => {
+                self.undofile = true;
+                Ok("Persistent undo enabled".to_string())
+            }
+            ("noundofile" | "noudf", None) => {
+                self.undofile = false;
+                Ok("Persistent undo disabled".to_string())
+            }
+            ("undodir" | "udir", Some(dir)) => {
+                self.undodir = expand_home(dir);
+                Ok(format!("undodir={}", self.undodir.display()))
+            }
+            ("undodir" | "udir", None) => Ok(format!("undodir={}", self.undodir.display())),
+            ("clipboard" | "cb", Some(value)) => {
+                if let Some(bad) = value
+                    .split(',')
+                    .find(|item| !item.is_empty() && *item != "unnamed" && *item != "unnamedplus")
+                {
+                    return Err(format!("Invalid argument: clipboard={}", bad));
+                }
+                self.clipboard = value.to_string();
+                Ok(format!("clipboard={}", self.clipboard))
+            }
+            ("clipboard" | "cb", None) => Ok(format!("clipboard={}", self.clipboard)),
+            ("clipcopy", Some(command)) => {
+                self.clipcopy = (!command.is_empty()).then(|| command.to_string());
+                Ok(format!("clipcopy={}", command))
+            }
+            ("clippaste", Some(command)) => {
+                self.clippaste = (!command.is_empty()).then(|| command.to_string());
+                Ok(format!("clippaste={}", command))
+            }
+            ("clipcopy", None) => Ok(format!(
+                "clipcopy={}",
+                self.clipcopy.as_deref().unwrap_or("")
+            )),
+            ("clippaste", None) => Ok(format!(
+                "clippaste={}",
+                self.clippaste.as_deref().unwrap_or("")
+            )),
+            ("ignorecase" | "ic", None) => {
+                self.ignorecase = true;
+                Ok("ignorecase".to_string())
+            }
+            ("noignorecase" | "noic", None) => {
+                self.ignorecase = false;
+                Ok("noignorecase".to_string())
+            }
+            ("smartcase" | "scs", None) => {
+                self.smartcase = true;
+                Ok("smartcase".to_string())
+            }
+            ("nosmartcase" | "noscs", None) => {
+                self.smartcase = false;
+                Ok("nosmartcase".to_string())
+            }
+            ("incsearch" | "is", None) => {
+                self.incsearch = true;
+                Ok("incsearch".to_string())
+            }
+            ("noincsearch" | "nois", None) => {
+                self.incsearch = false;
+                Ok("noincsearch".to_string())
+            }
+            ("hlsearch" | "hls", None) => {
+                self.hlsearch = true;
+                Ok("hlsearch".to_string())
+            }
+            ("nohlsearch" | "nohls", None) => {
+                self.hlsearch = false;
+                Ok("nohlsearch".to_string())
+            }
+            ("wrap", None) => {
+                self.wrap = true;
+                Ok("wrap".to_string())
+            }
+            ("nowrap", None) => {
+                self.wrap = false;
+                Ok("nowrap".to_string())
+            }
+            ("linebreak" | "lbr", None) => {
+                self.linebreak = true;
+                Ok("linebreak".to_string())
+            }
+            ("nolinebreak" | "nolbr", None) => {
+                self.linebreak = false;
+                Ok("nolinebreak".to_string())
+            }
+            ("breakindent" | "bri", None) => {
+                self.breakindent = true;
+                Ok("breakindent".to_string())
+            }
+            ("nobreakindent" | "nobri", None) => {
+                self.breakindent = false;
+                Ok("nobreakindent".to_string())
+            }
+            ("showbreak" | "sbr", Some(value)) => {
+                // As in vim, `\ ` stands for a space the command line would trim.
+                self.showbreak = value.replace("\\ ", " ");
+                Ok(format!("showbreak={}", self.showbreak))
+            }
+            ("showbreak" | "sbr", None) => Ok(format!("showbreak={}", self.showbreak)),
+            ("sidescrolloff" | "siso", Some(value)) => {
+                self.sidescrolloff = value
+                    .parse()
+                    .map_err(|_| format!("E521: Number required after =: {}", arg))?;
+                Ok(format!("sidescrolloff={}", self.sidescrolloff))
+            }
+            ("sidescrolloff" | "siso", None) => Ok(format!("sidescrolloff={}", self.sidescrolloff)),
+            _ => Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    pub fn wrap_options(&self) -> WrapOptions {
+        WrapOptions {
+            wrap: self.wrap,
+            linebreak: self.linebreak,
+            showbreak: self.showbreak.clone(),
+            breakindent: self.breakindent,
+            sidescrolloff: self.sidescrolloff,
+        }
+    }
+    pub fn undo_dir(&self) -> Option<PathBuf> {
+        self.undofile.then(|| self.undodir.clone())
+    }
+    /// Register used when a yank, delete or put names none.
+    pub fn default_register(&self) -> Option<char> {
+        let items: Vec<&str> = self.clipboard.split(',').collect();
+        if items.contains(&"unnamedplus") {
+            Some('+')
+        } else if items.contains(&"unnamed") {
+            Some('*')
+        } else {
+            None
+        }
+    }
+}
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
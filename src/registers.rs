@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterKind {
+    Charwise,
+    Linewise,
+    /// A rectangle of text, one line of the block per line of `text`.
+    Blockwise,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Register {
+    pub text: String,
+    pub kind: RegisterKind,
+}
+impl Register {
+    pub fn new(text: String, kind: RegisterKind) -> Self {
+        Self { text, kind }
+    }
+    pub fn charwise(text: String) -> Self {
+        Self::new(text, RegisterKind::Charwise)
+    }
+    /// Letter shown in the Type column of `:registers`.
+    pub fn type_char(&self) -> char {
+        match self.kind {
+            RegisterKind::Charwise => 'c',
+            RegisterKind::Linewise => 'l',
+            RegisterKind::Blockwise => 'b',
+        }
+    }
+    /// Appends `other` like `"A` does: joining a linewise register with
+    /// anything else keeps the result linewise.
+    fn append(&mut self, other: Register) {
+        if self.kind == RegisterKind::Linewise || other.kind == RegisterKind::Linewise {
+            if !self.text.is_empty() && !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+            self.text.push_str(&other.text);
+            if !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+            self.kind = RegisterKind::Linewise;
+        } else {
+            if self.kind == RegisterKind::Blockwise || other.kind == RegisterKind::Blockwise {
+                self.text.push('\n');
+            }
+            self.text.push_str(&other.text);
+        }
+    }
+}
+/// All registers except `"%`, whose value comes from the current buffer.
+///
+/// `""` holds whatever was last yanked or deleted, `"0` the last yank,
+/// `"1`-`"9` the history of deletes that span lines and `"-` the last delete
+/// within a line. `".`, `":` and `"/` are read-only and `"_` discards text.
+pub struct Registers {
+    unnamed: Option<Register>,
+    named: HashMap<char, Register>,
+    numbered: [Option<Register>; 10],
+    small_delete: Option<Register>,
+    last_inserted: Option<String>,
+    last_command: Option<String>,
+    last_search: Option<String>,
+}
+impl Registers {
+    pub fn new() -> Self {
+        Self {
+            unnamed: None,
+            named: HashMap::new(),
+            numbered: Default::default(),
+            small_delete: None,
+            last_inserted: None,
+            last_command: None,
+            last_search: None,
+        }
+    }
+    pub fn is_named(name: char) -> bool {
+        name.is_ascii_alphabetic()
+    }
+    /// Whether yanks and deletes can go to `name`: not `".`, `"%`, `":`,
+    /// `"/` or an unknown name.
+    pub fn is_writable(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '_' | '+' | '*')
+    }
+    pub fn get(&self, name: char) -> Option<Register> {
+        match name {
+            '"' => self.unnamed.clone(),
+            'a'..='z' | 'A'..='Z' => self.named.get(&name.to_ascii_lowercase()).cloned(),
+            '0'..='9' => self.numbered[name as usize - '0' as usize].clone(),
+            '-' => self.small_delete.clone(),
+            '.' => self.last_inserted.clone().map(Register::charwise),
+            ':' => self.last_command.clone().map(Register::charwise),
+            '/' => self.last_search.clone().map(Register::charwise),
+            _ => None,
+        }
+    }
+    /// Stores yanked text in `name`, or in `"0` when no register was given.
+    pub fn yank(&mut self, name: Option<char>, register: Register) {
+        match name {
+            Some(name) if name == '_' || !Self::is_writable(name) => return,
+            Some(name) if name != '"' => self.write(name, register.clone()),
+            _ => self.numbered[0] = Some(register.clone()),
+        }
+        self.unnamed = Some(register);
+    }
+    /// Stores deleted text in `name`, or when no register was given, shifts
+    /// it into `"1` if it spans lines (or `numbered` is set) and into `"-`
+    /// otherwise.
+    pub fn delete(&mut self, name: Option<char>, register: Register, numbered: bool) {
+        match name {
+            Some(name) if name == '_' || !Self::is_writable(name) => return,
+            Some(name) if name != '"' => self.write(name, register.clone()),
+            _ if numbered
+                || register.kind != RegisterKind::Charwise
+                || register.text.contains('\n') =>
+            {
+                self.numbered[1..].rotate_right(1);
+                self.numbered[1] = Some(register.clone());
+            }
+            _ => self.small_delete = Some(register.clone()),
+        }
+        self.unnamed = Some(register);
+    }
+    /// Stores a recorded macro. Like vim, recording leaves `""` alone.
+    pub fn record(&mut self, name: char, keys: String) {
+        self.write(name, Register::charwise(keys));
+    }
+    fn write(&mut self, name: char, register: Register) {
+        match name {
+            'a'..='z' => {
+                self.named.insert(name, register);
+            }
+            'A'..='Z' => match self.named.get_mut(&name.to_ascii_lowercase()) {
+                Some(existing) => existing.append(register),
+                None => {
+                    self.named.insert(name.to_ascii_lowercase(), register);
+                }
+            },
+            '0'..='9' => self.numbered[name as usize - '0' as usize] = Some(register),
+            '-' => self.small_delete = Some(register),
+            '"' => self.unnamed = Some(register),
+            _ => {}
+        }
+    }
+    pub fn set_last_inserted(&mut self, text: String) {
+        self.last_inserted = Some(text);
+    }
+    pub fn set_last_command(&mut self, text: String) {
+        self.last_command = Some(text);
+    }
+    pub fn set_last_search(&mut self, text: String) {
+        self.last_search = Some(text);
+    }
+    /// Non-empty registers in the order `:registers` lists them.
+    pub fn entries(&self) -> Vec<(char, Register)> {
+        std::iter::once('"')
+            .chain('0'..='9')
+            .chain('a'..='z')
+            .chain(['-', '.', ':', '/'])
+            .filter_map(|name| self.get(name).map(|register| (name, register)))
+            .collect()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(registers: &Registers, name: char) -> Option<String> {
+        registers.get(name).map(|register| register.text)
+    }
+
+    #[test]
+    fn test_yank_fills_unnamed_and_zero() {
+        let mut registers = Registers::new();
+        registers.yank(None, Register::charwise("one".to_string()));
+        registers.yank(Some('a'), Register::charwise("two".to_string()));
+        assert_eq!(text(&registers, '0').as_deref(), Some("one"));
+        assert_eq!(text(&registers, 'a').as_deref(), Some("two"));
+        assert_eq!(text(&registers, '"').as_deref(), Some("two"));
+        registers.yank(Some('A'), Register::charwise("!".to_string()));
+        assert_eq!(text(&registers, 'a').as_deref(), Some("two!"));
+    }
+
+    #[test]
+    fn test_deletes_shift_numbered_registers() {
+        let mut registers = Registers::new();
+        registers.delete(
+            None,
+            Register::new("a\n".to_string(), RegisterKind::Linewise),
+            false,
+        );
+        registers.delete(
+            None,
+            Register::new("b\n".to_string(), RegisterKind::Linewise),
+            false,
+        );
+        registers.delete(None, Register::charwise("w".to_string()), false);
+        assert_eq!(text(&registers, '1').as_deref(), Some("b\n"));
+        assert_eq!(text(&registers, '2').as_deref(), Some("a\n"));
+        assert_eq!(text(&registers, '-').as_deref(), Some("w"));
+        registers.delete(Some('_'), Register::charwise("gone".to_string()), false);
+        assert_eq!(text(&registers, '"').as_deref(), Some("w"));
+    }
+
+    #[test]
+    fn test_read_only_registers_are_not_written() {
+        let mut registers = Registers::new();
+        registers.set_last_inserted("typed".to_string());
+        registers.yank(None, Register::charwise("kept".to_string()));
+        for name in ['.', '%', ':', '/'] {
+            assert!(!Registers::is_writable(name));
+            registers.yank(Some(name), Register::charwise("lost".to_string()));
+            registers.delete(Some(name), Register::charwise("lost".to_string()), true);
+        }
+        assert_eq!(text(&registers, '"').as_deref(), Some("kept"));
+        assert_eq!(text(&registers, '.').as_deref(), Some("typed"));
+        assert_eq!(text(&registers, '1'), None);
+    }
+}
@@ -0,0 +1,611 @@
+use crate::text;
+use regex::{Regex, RegexBuilder};
+use ropey::Rope;
+use std::fmt;
+use std::ops::Range;
+
+/// How many chars lose their special meaning without a backslash, set in
+/// a pattern by `\v`, `\m`, `\M` and `\V`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum MagicLevel {
+    VeryNomagic,
+    Nomagic,
+    Magic,
+    VeryMagic,
+}
+/// A match found by `find`. `wrapped` is set when the search went past the
+/// end (or, backwards, the start) of the buffer to find it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub range: Range<usize>,
+    pub wrapped: bool,
+}
+/// The last search, repeated by `n` and `N`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastSearch {
+    pub pattern: String,
+    pub forward: bool,
+    pub offset: SearchOffset,
+}
+/// Where the cursor goes relative to a match: `/foo/+2` lands two lines
+/// below it, `/foo/e+1` one char past its end and `/foo/s-1` one char
+/// before its start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchOffset {
+    Lines(isize),
+    Start(isize),
+    End(isize),
+}
+impl SearchOffset {
+    fn parse(text: &str) -> Result<Self, String> {
+        let (kind, amount) = match text.chars().next() {
+            Some(c @ ('e' | 's' | 'b')) => (Some(c), &text[1..]),
+            _ => (None, text),
+        };
+        let amount = match amount {
+            "" if kind.is_some() => 0,
+            "" => return Ok(SearchOffset::Start(0)),
+            "+" => 1,
+            "-" => -1,
+            _ => amount
+                .parse()
+                .map_err(|_| format!("E488: Trailing characters: {}", text))?,
+        };
+        Ok(match kind {
+            Some('e') => SearchOffset::End(amount),
+            Some(_) => SearchOffset::Start(amount),
+            None => SearchOffset::Lines(amount),
+        })
+    }
+    /// Char index the cursor moves to for a match covering `range`.
+    pub fn apply(&self, content: &Rope, range: &Range<usize>) -> usize {
+        match *self {
+            SearchOffset::Lines(lines) => {
+                let row = (content.char_to_line(range.start) as isize).saturating_add(lines);
+                let row = row.clamp(0, text::line_count(content).saturating_sub(1) as isize);
+                content.line_to_char(row as usize)
+            }
+            SearchOffset::Start(chars) => step_chars(content, range.start, chars),
+            SearchOffset::End(chars) => {
+                step_chars(content, range.end.saturating_sub(1).max(range.start), chars)
+            }
+        }
+    }
+}
+/// Moves `chars` cursor positions from `pos`, crossing line ends like `l`
+/// with `whichwrap` would and stopping at either end of the buffer.
+fn step_chars(content: &Rope, mut pos: usize, chars: isize) -> usize {
+    let len = content.len_chars();
+    // Line endings are not cursor positions, except on empty lines.
+    let is_position = |pos: usize| {
+        pos < len && (content.char(pos) != '\n' || pos == 0 || content.char(pos - 1) == '\n')
+    };
+    for _ in 0..chars.unsigned_abs() {
+        let next = if chars > 0 {
+            (pos + 1..len).find(|&next| is_position(next))
+        } else {
+            (0..pos).rev().find(|&next| is_position(next))
+        };
+        match next {
+            Some(next) => pos = next,
+            None => break,
+        }
+    }
+    pos
+}
+/// Splits what was typed after `/` (or `?`, the `delimiter`) into the
+/// pattern and the offset after a second unescaped delimiter.
+pub fn parse_search(text: &str, delimiter: char) -> Result<(&str, SearchOffset), String> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delimiter {
+            let offset = SearchOffset::parse(&text[i + 1..])?;
+            return Ok((&text[..i], offset));
+        }
+    }
+    Ok((text, SearchOffset::Start(0)))
+}
+/// Escapes `text` so that it matches literally, as `*` does with the word
+/// under the cursor.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '\\' | '/' | '.' | '*' | '$' | '^' | '~' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+/// Counting matches stops past this many, like vim's `[>99/>99]`.
+pub const MAX_COUNT: usize = 99;
+/// Position of the match at char `pos` among all `total` matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchCount {
+    pub pos: usize,
+    pub index: usize,
+    pub total: usize,
+}
+impl fmt::Display for MatchCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |n: usize| {
+            if n > MAX_COUNT {
+                format!(">{}", MAX_COUNT)
+            } else {
+                n.to_string()
+            }
+        };
+        write!(f, "[{}/{}]", show(self.index), show(self.total))
+    }
+}
+/// Compiles a vim search pattern. `ignorecase` and `smartcase` follow the
+/// options of the same name; `\c` and `\C` in the pattern override both.
+pub fn compile(pattern: &str, ignorecase: bool, smartcase: bool) -> Result<Regex, String> {
+    let (translated, case) = translate(pattern)?;
+    let ignore_case = case.unwrap_or(ignorecase && !(smartcase && has_uppercase(pattern)));
+    RegexBuilder::new(&translated)
+        .case_insensitive(ignore_case)
+        .multi_line(true)
+        .crlf(true)
+        .build()
+        .map_err(|_| format!("E383: Invalid search string: {}", pattern))
+}
+/// Translates vim pattern syntax to `regex` syntax, returning the pattern
+/// and whether `\c` (`Some(true)`) or `\C` (`Some(false)`) was given.
+fn translate(pattern: &str) -> Result<(String, Option<bool>), String> {
+    let mut out = String::new();
+    let mut case = None;
+    let mut magic = MagicLevel::Magic;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let (c, escaped) = match c {
+            '\\' => match chars.next() {
+                Some(next) => (next, true),
+                None => ('\\', false),
+            },
+            _ => (c, false),
+        };
+        let special = special_without_backslash(c, magic);
+        if escaped && !special_with_backslash(c) {
+            match c {
+                'c' => case = Some(true),
+                'C' => case = Some(false),
+                'v' => magic = MagicLevel::VeryMagic,
+                'm' => magic = MagicLevel::Magic,
+                'M' => magic = MagicLevel::Nomagic,
+                'V' => magic = MagicLevel::VeryNomagic,
+                'd' | 'D' | 'w' | 'W' | 's' | 'S' | 'n' | 't' | 'r' => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                'e' => out.push_str(r"\x1b"),
+                'a' => out.push_str("[A-Za-z]"),
+                'A' => out.push_str("[^A-Za-z]"),
+                'l' => out.push_str("[a-z]"),
+                'L' => out.push_str("[^a-z]"),
+                'u' => out.push_str("[A-Z]"),
+                'U' => out.push_str("[^A-Z]"),
+                'x' => out.push_str("[0-9A-Fa-f]"),
+                'X' => out.push_str("[^0-9A-Fa-f]"),
+                'o' => out.push_str("[0-7]"),
+                'O' => out.push_str("[^0-7]"),
+                'h' => out.push_str("[A-Za-z_]"),
+                'H' => out.push_str("[^A-Za-z_]"),
+                'z' | '1'..='9' => {
+                    return Err(format!("E383: Invalid search string: {}", pattern));
+                }
+                _ => out.push_str(&regex::escape(&c.to_string())),
+            }
+            continue;
+        }
+        // A special char is written with a backslash exactly when it is
+        // not special without one at this magic level.
+        let is_special = if special_with_backslash(c) {
+            escaped != special
+        } else {
+            special
+        };
+        if !is_special {
+            out.push_str(&regex::escape(&c.to_string()));
+            continue;
+        }
+        match c {
+            '^' | '$' | '.' | '*' | '(' | ')' | '|' | '+' => out.push(c),
+            '?' | '=' => out.push('?'),
+            '<' => out.push_str(r"\b{start}"),
+            '>' => out.push_str(r"\b{end}"),
+            '%' if chars.peek() == Some(&'(') => {
+                chars.next();
+                out.push_str("(?:");
+            }
+            '[' => match bracket_expression(&mut chars) {
+                Some(class) => out.push_str(&class),
+                None => out.push_str(r"\["),
+            },
+            '{' => out.push_str(&repeat_count(&mut chars, pattern)?),
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    Ok((out, case))
+}
+/// Chars that are special with no backslash before them at `magic`.
+fn special_without_backslash(c: char, magic: MagicLevel) -> bool {
+    match c {
+        '^' | '$' => true,
+        '.' | '*' | '[' | '~' => magic >= MagicLevel::Magic,
+        '(' | ')' | '|' | '+' | '?' | '=' | '{' | '@' | '%' | '<' | '>' => {
+            magic == MagicLevel::VeryMagic
+        }
+        _ => false,
+    }
+}
+/// Chars whose meaning flips with a backslash, rather than naming a class
+/// such as `\d` or a flag such as `\c`.
+fn special_with_backslash(c: char) -> bool {
+    matches!(
+        c,
+        '^' | '$'
+            | '.'
+            | '*'
+            | '['
+            | '~'
+            | '('
+            | ')'
+            | '|'
+            | '+'
+            | '?'
+            | '='
+            | '{'
+            | '@'
+            | '%'
+            | '<'
+            | '>'
+    )
+}
+/// Copies a `[...]` collection after its `[`, escaping what `regex` treats
+/// specially inside a class. `None` when there is no closing `]`, in which
+/// case vim matches a literal `[`.
+fn bracket_expression(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    let rest: String = chars.clone().collect();
+    let mut class = String::from("[");
+    let mut iter = rest.chars().peekable();
+    let mut consumed = 0;
+    if iter.peek() == Some(&'^') {
+        class.push('^');
+        iter.next();
+        consumed += 1;
+    }
+    // A `]` right after `[` or `[^` is a literal.
+    if iter.peek() == Some(&']') {
+        class.push_str(r"\]");
+        iter.next();
+        consumed += 1;
+    }
+    loop {
+        let c = iter.next()?;
+        consumed += 1;
+        match c {
+            ']' => break,
+            '\\' => {
+                let next = iter.next()?;
+                consumed += 1;
+                match next {
+                    'n' | 't' | 'r' | 'd' | 's' | 'w' => {
+                        class.push('\\');
+                        class.push(next);
+                    }
+                    'e' => class.push_str(r"\x1b"),
+                    _ => class.push_str(&regex::escape(&next.to_string())),
+                }
+            }
+            '[' | '&' | '~' => {
+                class.push('\\');
+                class.push(c);
+            }
+            _ => class.push(c),
+        }
+    }
+    class.push(']');
+    for _ in 0..consumed {
+        chars.next();
+    }
+    Some(class)
+}
+/// Translates the rest of a `\{n,m}` count after its `{`: `\{-n,m}` is the
+/// lazy form and a missing count means any number.
+fn repeat_count(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    pattern: &str,
+) -> Result<String, String> {
+    let mut body = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some('\\') if chars.peek() == Some(&'}') => {
+                chars.next();
+                break;
+            }
+            Some(c) if c.is_ascii_digit() || c == ',' || c == '-' => body.push(c),
+            _ => return Err(format!("E554: Syntax error in {}", pattern)),
+        }
+    }
+    let (lazy, body) = match body.strip_prefix('-') {
+        Some(body) => (true, body),
+        None => (false, body.as_str()),
+    };
+    let count = match body.split_once(',') {
+        None if body.is_empty() => "*".to_string(),
+        None => format!("{{{}}}", body),
+        Some(("", "")) => "*".to_string(),
+        Some(("", max)) => format!("{{0,{}}}", max),
+        Some((min, max)) => format!("{{{},{}}}", min, max),
+    };
+    Ok(if lazy { count + "?" } else { count })
+}
+/// Whether `pattern` has an uppercase letter for `smartcase`, skipping
+/// escapes such as `\S`.
+fn has_uppercase(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+/// Finds the first match after char `from` (before it when `forward` is
+/// unset), wrapping around the buffer. Lines are searched one at a time so
+/// the rope is never copied whole; a match cannot span lines.
+pub fn find(content: &Rope, regex: &Regex, from: usize, forward: bool) -> Option<Match> {
+    let lines = text::line_count(content);
+    if lines == 0 {
+        return None;
+    }
+    let from = from.min(content.len_chars());
+    let row = content.char_to_line(from).min(lines - 1);
+    let col = from - content.line_to_char(row).min(from);
+    let mut line = String::new();
+    for step in 0..=lines {
+        let current = if forward {
+            (row + step) % lines
+        } else {
+            (row + lines - step % lines) % lines
+        };
+        read_line(content, current, &mut line);
+        let matches = line_matches(regex, &line);
+        let found = match (step, forward) {
+            (0, true) => matches.into_iter().find(|range| range.start > col),
+            (0, false) => matches.into_iter().rev().find(|range| range.start < col),
+            (_, true) if step == lines => matches.into_iter().find(|range| range.start <= col),
+            (_, false) if step == lines => {
+                matches.into_iter().rev().find(|range| range.start >= col)
+            }
+            (_, true) => matches.into_iter().next(),
+            (_, false) => matches.into_iter().next_back(),
+        };
+        if let Some(range) = found {
+            let start = content.line_to_char(current);
+            return Some(Match {
+                range: start + range.start..start + range.end,
+                wrapped: if forward {
+                    current <= row && step > 0
+                } else {
+                    current >= row && step > 0
+                },
+            });
+        }
+    }
+    None
+}
+/// Counts the matches of `regex` up to `MAX_COUNT`, and which of them
+/// starts at char `pos`, for the `[3/17]` shown after a search.
+pub fn count(content: &Rope, regex: &Regex, pos: usize) -> MatchCount {
+    let mut index = 0;
+    let mut total = 0;
+    let mut line = String::new();
+    for row in 0..text::line_count(content) {
+        let start = content.line_to_char(row);
+        read_line(content, row, &mut line);
+        for range in line_matches(regex, &line) {
+            total += 1;
+            if start + range.start <= pos {
+                index = total;
+            }
+            if total > MAX_COUNT {
+                // The match at `pos` comes later still.
+                let index = if index == 0 { total } else { index };
+                return MatchCount { pos, index, total };
+            }
+        }
+    }
+    MatchCount { pos, index, total }
+}
+/// Copies `row`, line ending included, into `line` chunk by chunk.
+fn read_line(content: &Rope, row: usize, line: &mut String) {
+    line.clear();
+    for chunk in content.line(row).chunks() {
+        line.push_str(chunk);
+    }
+}
+/// Char ranges of the matches in `line`, left to right without overlaps,
+/// as `hlsearch` shows them. Chars are counted on from the previous match
+/// so that a long line is only walked once.
+pub fn line_matches(regex: &Regex, line: &str) -> Vec<Range<usize>> {
+    let mut matches = Vec::new();
+    let (mut byte, mut col) = (0, 0);
+    for found in regex.find_iter(line) {
+        col += line[byte..found.start()].chars().count();
+        let end = col + found.as_str().chars().count();
+        matches.push(col..end);
+        (byte, col) = (found.end(), end);
+    }
+    matches
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, line: &str) -> Vec<String> {
+        let regex = compile(pattern, false, false).unwrap();
+        line_matches(&regex, line)
+            .into_iter()
+            .map(|range| line.chars().skip(range.start).take(range.len()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_translate_magic_levels() {
+        assert_eq!(translate("a.b*").unwrap().0, "a.b*");
+        assert_eq!(translate(r"\va(b|c)+").unwrap().0, "a(b|c)+");
+        assert_eq!(translate(r"a\(b\|c\)\+").unwrap().0, "a(b|c)+");
+        assert_eq!(translate(r"\Ma.b\.").unwrap().0, r"a\.b.");
+        assert_eq!(translate(r"\V$a.*^").unwrap().0, r"$a\.\*^");
+        assert_eq!(translate(r"a\{2,3}").unwrap().0, "a{2,3}");
+        assert_eq!(translate(r"a\{-}").unwrap().0, "a*?");
+        assert!(translate(r"a\{x}").is_err());
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        assert_eq!(translate(r"\<a\>").unwrap().0, r"\b{start}a\b{end}");
+        assert_eq!(translate(r"\v<a>").unwrap().0, r"\b{start}a\b{end}");
+        assert_eq!(matches(r"\<in", "in pin inn"), ["in", "in"]);
+        assert_eq!(matches(r"in\>", "in pin inn"), ["in", "in"]);
+        assert_eq!(matches(r"\<in\>", "in pin inn"), ["in"]);
+        // `\>` ends a word, so it never matches before one.
+        assert!(matches(r"\>x", "a x").is_empty());
+    }
+
+    #[test]
+    fn test_case_flags_and_smartcase() {
+        assert_eq!(translate(r"a\cb").unwrap(), ("ab".to_string(), Some(true)));
+        assert_eq!(translate(r"a\Cb").unwrap(), ("ab".to_string(), Some(false)));
+        assert!(compile("foo", true, false).unwrap().is_match("FOO"));
+        assert!(!compile("foo", false, false).unwrap().is_match("FOO"));
+        assert!(compile("foo", true, true).unwrap().is_match("FOO"));
+        assert!(!compile("Foo", true, true).unwrap().is_match("FOO"));
+        // An escape such as `\S` is not an uppercase letter.
+        assert!(compile(r"f\S", true, true).unwrap().is_match("FO"));
+        assert!(compile(r"Foo\c", false, true).unwrap().is_match("FOO"));
+        assert!(!compile(r"foo\C", true, false).unwrap().is_match("FOO"));
+    }
+
+    #[test]
+    fn test_line_matches_do_not_overlap() {
+        let regex = compile("aa", false, false).unwrap();
+        assert_eq!(line_matches(&regex, "aaaaa"), [0..2, 2..4]);
+        let regex = compile("é.", false, false).unwrap();
+        assert_eq!(line_matches(&regex, "日éxéy"), [1..3, 3..5]);
+        let regex = compile("x*", false, false).unwrap();
+        assert_eq!(line_matches(&regex, "ab"), [0..0, 1..1, 2..2]);
+        let line = "ab".repeat(100_000);
+        let regex = compile("b", false, false).unwrap();
+        let matches = line_matches(&regex, &line);
+        assert_eq!(
+            (matches.len(), matches.last()),
+            (100_000, Some(&(199_999..200_000)))
+        );
+    }
+
+    #[test]
+    fn test_find_wraps_around() {
+        let content = Rope::from_str("foo\nbar\nfoo bar\n");
+        let regex = compile("foo", false, false).unwrap();
+        let found = find(&content, &regex, 0, true).unwrap();
+        assert_eq!((found.range, found.wrapped), (8..11, false));
+        let found = find(&content, &regex, 8, true).unwrap();
+        assert_eq!((found.range, found.wrapped), (0..3, true));
+        let found = find(&content, &regex, 8, false).unwrap();
+        assert_eq!((found.range, found.wrapped), (0..3, false));
+        let found = find(&content, &regex, 0, false).unwrap();
+        assert_eq!((found.range, found.wrapped), (8..11, true));
+        // The only match is found again after a full circle.
+        let regex = compile("^bar", false, false).unwrap();
+        let found = find(&content, &regex, 4, true).unwrap();
+        assert_eq!((found.range, found.wrapped), (4..7, true));
+        let regex = compile("baz", false, false).unwrap();
+        assert!(find(&content, &regex, 0, true).is_none());
+    }
+
+    #[test]
+    fn test_match_count_stops_past_the_limit() {
+        let content = Rope::from_str("x x\nx\n");
+        let regex = compile("x", false, false).unwrap();
+        assert_eq!(count(&content, &regex, 2).to_string(), "[2/3]");
+        assert_eq!(count(&content, &regex, 4).to_string(), "[3/3]");
+        let content = Rope::from_str(&"x\n".repeat(150));
+        assert_eq!(count(&content, &regex, 20).to_string(), "[11/>99]");
+        assert_eq!(count(&content, &regex, 280).to_string(), "[>99/>99]");
+    }
+
+    #[test]
+    fn test_parse_search_offsets() {
+        assert_eq!(
+            parse_search("foo", '/'),
+            Ok(("foo", SearchOffset::Start(0)))
+        );
+        assert_eq!(
+            parse_search("foo/", '/'),
+            Ok(("foo", SearchOffset::Start(0)))
+        );
+        assert_eq!(
+            parse_search("foo/+2", '/'),
+            Ok(("foo", SearchOffset::Lines(2)))
+        );
+        assert_eq!(
+            parse_search("foo/-", '/'),
+            Ok(("foo", SearchOffset::Lines(-1)))
+        );
+        assert_eq!(
+            parse_search("foo/e", '/'),
+            Ok(("foo", SearchOffset::End(0)))
+        );
+        assert_eq!(
+            parse_search("foo/e+1", '/'),
+            Ok(("foo", SearchOffset::End(1)))
+        );
+        assert_eq!(
+            parse_search("foo/b-2", '/'),
+            Ok(("foo", SearchOffset::Start(-2)))
+        );
+        // An escaped delimiter is part of the pattern, the other one is not.
+        assert_eq!(
+            parse_search(r"a\/b/s", '/'),
+            Ok((r"a\/b", SearchOffset::Start(0)))
+        );
+        assert_eq!(
+            parse_search("a/b?e", '?'),
+            Ok(("a/b", SearchOffset::End(0)))
+        );
+        assert!(parse_search("foo/x", '/').is_err());
+    }
+
+    #[test]
+    fn test_offsets_move_from_the_match() {
+        let content = Rope::from_str("ab\nfoo bar\n\nend\n");
+        let found = 3..6;
+        assert_eq!(SearchOffset::Lines(1).apply(&content, &found), 11);
+        assert_eq!(SearchOffset::Lines(-5).apply(&content, &found), 0);
+        assert_eq!(SearchOffset::Lines(isize::MAX).apply(&content, &found), 12);
+        assert_eq!(SearchOffset::Start(-1).apply(&content, &found), 1);
+        assert_eq!(SearchOffset::End(0).apply(&content, &found), 5);
+        // Steps skip line endings but stop on an empty line.
+        assert_eq!(SearchOffset::End(4).apply(&content, &found), 9);
+        assert_eq!(SearchOffset::End(5).apply(&content, &found), 11);
+        assert_eq!(SearchOffset::End(isize::MAX).apply(&content, &found), 14);
+    }
+
+    #[test]
+    fn test_escaped_text_matches_literally() {
+        let word = r"a.b*[c]$\/~^";
+        let regex = compile(&escape(word), false, false).unwrap();
+        assert_eq!(matches(&escape(word), &format!("x{}x", word)), [word]);
+        assert!(!regex.is_match("axb"));
+    }
+}
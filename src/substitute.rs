@@ -0,0 +1,501 @@
+use crate::buffer::Buffer;
+use crate::text;
+use regex::{Captures, Regex};
+use ropey::Rope;
+use std::ops::Range;
+
+/// Flags after `:s/pattern/replacement/`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Flags {
+    /// `g`: every match in the line, not just the first.
+    pub global: bool,
+    /// `c`: ask before each substitution.
+    pub confirm: bool,
+    /// `i` or `I`: ignore or match case whatever `ignorecase` says.
+    pub ignore_case: Option<bool>,
+    /// `e`: no error when the pattern is not found.
+    pub no_error: bool,
+}
+/// A parsed `:s{delim}pattern{delim}replacement{delim}flags`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubstituteCommand {
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: Flags,
+}
+/// Parses what follows `:s` or `:substitute`, starting with a delimiter
+/// that can be any char but a letter, a digit, a space, `"`, `|` or `\`.
+pub fn parse(args: &str) -> Result<SubstituteCommand, String> {
+    let delimiter = args
+        .chars()
+        .next()
+        .filter(|c| !c.is_alphanumeric() && !c.is_whitespace() && !matches!(c, '"' | '|' | '\\'))
+        .ok_or_else(|| "E35: No previous regular expression".to_string())?;
+    let rest = &args[delimiter.len_utf8()..];
+    let (pattern, rest) = split_unescaped(rest, delimiter);
+    let (replacement, flags) = match rest {
+        Some(rest) => split_unescaped(rest, delimiter),
+        None => ("", None),
+    };
+    let mut parsed = Flags::default();
+    for flag in flags.unwrap_or("").trim().chars() {
+        match flag {
+            'g' => parsed.global = !parsed.global,
+            'c' => parsed.confirm = true,
+            'i' => parsed.ignore_case = Some(true),
+            'I' => parsed.ignore_case = Some(false),
+            'e' => parsed.no_error = true,
+            _ => return Err(format!("E488: Trailing characters: {}", flag)),
+        }
+    }
+    Ok(SubstituteCommand {
+        pattern: pattern.to_string(),
+        replacement: replacement.to_string(),
+        flags: parsed,
+    })
+}
+/// Splits `text` at the first `delimiter` without a backslash before it.
+/// An escaped delimiter keeps its backslash: patterns and replacements
+/// both read `\/` as a plain `/`.
+fn split_unescaped(text: &str, delimiter: char) -> (&str, Option<&str>) {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delimiter {
+            return (&text[..i], Some(&text[i + c.len_utf8()..]));
+        }
+    }
+    (text, None)
+}
+/// Replaces each `~` in a replacement string with the `previous` one, as
+/// the result is remembered for the next `~`.
+pub fn expand_tilde(text: &str, previous: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '~' => out.push_str(previous),
+            '\\' => {
+                out.push(c);
+                out.extend(chars.next());
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Case {
+    Upper,
+    Lower,
+}
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Group(usize),
+    /// `\u` and `\l`: the next char only.
+    NextChar(Case),
+    /// `\U` and `\L`: everything up to `\E` or `\e`.
+    Until(Option<Case>),
+}
+/// A replacement string with `&`, `\1`, `\u` and the like resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replacement {
+    pieces: Vec<Piece>,
+}
+impl Replacement {
+    /// Parses `text`, where `\r` or `\n` stands for a line break. `~` must
+    /// already be replaced with `expand_tilde`.
+    pub fn parse(text: &str) -> Self {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            let piece = match c {
+                '&' => Piece::Group(0),
+                '\\' => match chars.next() {
+                    Some(digit @ '0'..='9') => Piece::Group(digit as usize - '0' as usize),
+                    Some('u') => Piece::NextChar(Case::Upper),
+                    Some('l') => Piece::NextChar(Case::Lower),
+                    Some('U') => Piece::Until(Some(Case::Upper)),
+                    Some('L') => Piece::Until(Some(Case::Lower)),
+                    Some('E' | 'e') => Piece::Until(None),
+                    Some('r' | 'n') => {
+                        literal.push('\n');
+                        continue;
+                    }
+                    Some('t') => {
+                        literal.push('\t');
+                        continue;
+                    }
+                    Some(other) => {
+                        literal.push(other);
+                        continue;
+                    }
+                    None => {
+                        literal.push('\\');
+                        continue;
+                    }
+                },
+                _ => {
+                    literal.push(c);
+                    continue;
+                }
+            };
+            if !literal.is_empty() {
+                pieces.push(Piece::Text(std::mem::take(&mut literal)));
+            }
+            pieces.push(piece);
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Text(literal));
+        }
+        Self { pieces }
+    }
+    /// The text to put in place of one match, filled in from `captures`.
+    pub fn expand(&self, captures: &Captures) -> String {
+        let mut out = String::new();
+        let mut next_char = None;
+        let mut until = None;
+        for piece in &self.pieces {
+            let text = match piece {
+                Piece::Text(text) => text.as_str(),
+                Piece::Group(group) => captures.get(*group).map_or("", |m| m.as_str()),
+                Piece::NextChar(case) => {
+                    next_char = Some(*case);
+                    continue;
+                }
+                Piece::Until(case) => {
+                    until = *case;
+                    continue;
+                }
+            };
+            for c in text.chars() {
+                match next_char.take().or(until) {
+                    Some(Case::Upper) => out.extend(c.to_uppercase()),
+                    Some(Case::Lower) => out.extend(c.to_lowercase()),
+                    None => out.push(c),
+                }
+            }
+        }
+        out
+    }
+}
+/// A match found by `Substitution::next_match` and the text to put in its
+/// place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Found {
+    pub row: usize,
+    /// Chars of the line the match covers.
+    pub cols: Range<usize>,
+    /// Bytes the match covers in the line as it was before the
+    /// substitution changed it.
+    bytes: Range<usize>,
+    pub replacement: String,
+}
+impl Found {
+    /// Char range of the match in the buffer.
+    pub fn range(&self, content: &Rope) -> Range<usize> {
+        let start = content.line_to_char(self.row);
+        start + self.cols.start..start + self.cols.end
+    }
+}
+/// A `:substitute` in progress over the lines `row..=end_row`. Matches
+/// never span lines; replacements can add line breaks, which moves the
+/// later lines down.
+pub struct Substitution {
+    regex: Regex,
+    replacement: Replacement,
+    pub flags: Flags,
+    pub pattern: String,
+    /// The line being searched as it was before any replacement in it,
+    /// read once when the search reaches it. Like vim, later matches are
+    /// found in this text, which past `byte` is what the buffer still has.
+    line: Option<String>,
+    /// Byte of `line` the search goes on from, which is at `(row, col)`
+    /// in the buffer.
+    byte: usize,
+    row: usize,
+    col: usize,
+    end_row: usize,
+    /// An empty match at this byte of `line`, right where the previous
+    /// match ended, is skipped.
+    skip_empty_at: Option<usize>,
+    /// Row the search continued on after the last substitution.
+    changed_row: Option<usize>,
+    pub substitutions: usize,
+    pub lines: usize,
+    /// Row of the last substitution, where the cursor ends up.
+    pub last_row: Option<usize>,
+}
+impl Substitution {
+    pub fn new(
+        regex: Regex,
+        replacement: Replacement,
+        flags: Flags,
+        pattern: String,
+        rows: (usize, usize),
+    ) -> Self {
+        Self {
+            regex,
+            replacement,
+            flags,
+            pattern,
+            line: None,
+            byte: 0,
+            row: rows.0,
+            col: 0,
+            end_row: rows.1,
+            skip_empty_at: None,
+            changed_row: None,
+            substitutions: 0,
+            lines: 0,
+            last_row: None,
+        }
+    }
+    pub fn next_match(&mut self, content: &Rope) -> Option<Found> {
+        let lines = text::line_count(content);
+        while self.row <= self.end_row && self.row < lines {
+            let line = self
+                .line
+                .get_or_insert_with(|| text::line_text(content, self.row));
+            while self.byte <= line.len() {
+                let Some(captures) = self.regex.captures_at(line, self.byte) else {
+                    break;
+                };
+                let found = captures.get(0).expect("group 0 is the whole match");
+                let start = self.col + line[self.byte..found.start()].chars().count();
+                if found.is_empty() && self.skip_empty_at == Some(found.start()) {
+                    self.col = start + 1;
+                    self.byte = found.start()
+                        + line[found.start()..]
+                            .chars()
+                            .next()
+                            .map_or(1, char::len_utf8);
+                    continue;
+                }
+                return Some(Found {
+                    row: self.row,
+                    cols: start..start + found.as_str().chars().count(),
+                    bytes: found.range(),
+                    replacement: self.replacement.expand(&captures),
+                });
+            }
+            self.next_line(self.row);
+        }
+        None
+    }
+    /// Replaces `found` in `buffer` and moves past the new text.
+    pub fn replace(&mut self, buffer: &mut Buffer, found: &Found) {
+        let range = found.range(&buffer.content);
+        buffer.remove_text(range.clone());
+        buffer.insert_text(range.start, &found.replacement);
+        self.substitutions += 1;
+        self.last_row = Some(found.row);
+        if self.changed_row != Some(found.row) {
+            self.lines += 1;
+        }
+        let breaks = found.replacement.matches('\n').count();
+        self.end_row += breaks;
+        // The rest of the line follows the replacement, on the row its
+        // last line break starts.
+        let (row, col) = match found.replacement.rsplit_once('\n') {
+            Some((_, tail)) => (found.row + breaks, tail.chars().count()),
+            None => (
+                found.row,
+                found.cols.start + found.replacement.chars().count(),
+            ),
+        };
+        self.changed_row = Some(row);
+        self.continue_at(row, col, found.bytes.end);
+    }
+    /// Leaves `found` as it is and moves past it.
+    pub fn skip(&mut self, found: &Found) {
+        self.continue_at(found.row, found.cols.end, found.bytes.end);
+    }
+    /// Goes on at `byte` of the line, now at `(row, col)`, or stops after
+    /// the line, as `:s` without `g` does.
+    fn continue_at(&mut self, row: usize, col: usize, byte: usize) {
+        if self.flags.global {
+            (self.row, self.col, self.byte) = (row, col, byte);
+            self.skip_empty_at = Some(byte);
+        } else {
+            self.next_line(row);
+        }
+    }
+    /// Moves the search to the start of the line after `row`.
+    fn next_line(&mut self, row: usize) {
+        (self.row, self.col, self.byte) = (row + 1, 0, 0);
+        self.line = None;
+        self.skip_empty_at = None;
+    }
+    /// `N substitutions on M lines`.
+    pub fn report(&self) -> String {
+        report(self.substitutions, self.lines)
+    }
+}
+/// `N substitutions on M lines`, also for the sum over a `:global`.
+pub fn report(substitutions: usize, lines: usize) -> String {
+    format!(
+        "{} substitution{} on {} line{}",
+        substitutions,
+        if substitutions == 1 { "" } else { "s" },
+        lines,
+        if lines == 1 { "" } else { "s" },
+    )
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(replacement: &str, pattern: &str, text: &str) -> String {
+        let regex = Regex::new(pattern).unwrap();
+        Replacement::parse(replacement).expand(&regex.captures(text).unwrap())
+    }
+
+    /// Substitutes over every line of `text`, returning the result and the
+    /// counts `report` shows.
+    fn substitute(
+        text: &str,
+        pattern: &str,
+        replacement: &str,
+        global: bool,
+    ) -> (String, usize, usize) {
+        let mut buffer = Buffer::new();
+        buffer.content = Rope::from_str(text);
+        let flags = Flags {
+            global,
+            ..Flags::default()
+        };
+        let last = text::line_count(&buffer.content) - 1;
+        let mut substitution = Substitution::new(
+            Regex::new(pattern).unwrap(),
+            Replacement::parse(replacement),
+            flags,
+            pattern.to_string(),
+            (0, last),
+        );
+        while let Some(found) = substitution.next_match(&buffer.content) {
+            substitution.replace(&mut buffer, &found);
+        }
+        (
+            buffer.content.to_string(),
+            substitution.substitutions,
+            substitution.lines,
+        )
+    }
+
+    #[test]
+    fn test_parse_delimiters() {
+        let parsed = parse("/a/b/gc").unwrap();
+        assert_eq!(
+            (parsed.pattern.as_str(), parsed.replacement.as_str()),
+            ("a", "b")
+        );
+        assert!(parsed.flags.global && parsed.flags.confirm);
+        let parsed = parse("#a/b#c/d#").unwrap();
+        assert_eq!(
+            (parsed.pattern.as_str(), parsed.replacement.as_str()),
+            ("a/b", "c/d")
+        );
+        // An escaped delimiter keeps its backslash.
+        let parsed = parse(r"/a\/b/c\/d/").unwrap();
+        assert_eq!(
+            (parsed.pattern.as_str(), parsed.replacement.as_str()),
+            (r"a\/b", r"c\/d")
+        );
+        let parsed = parse("/a").unwrap();
+        assert_eq!(
+            (parsed.pattern.as_str(), parsed.replacement.as_str()),
+            ("a", "")
+        );
+        assert!(!parse("/a/b/gg").unwrap().flags.global);
+        assert_eq!(parse("/a/b/I").unwrap().flags.ignore_case, Some(false));
+        assert!(parse("xaxbx").is_err());
+        assert!(parse("/a/b/z").is_err());
+    }
+
+    #[test]
+    fn test_replacement_expand() {
+        assert_eq!(expand("<&>", "b+", "abbc"), "<bb>");
+        assert_eq!(expand(r"\2-\1", r"(\w+) (\w+)", "one two"), "two-one");
+        assert_eq!(expand(r"\u&", r"\w+", "word"), "Word");
+        assert_eq!(expand(r"\U&\E!", r"\w+", "wow"), "WOW!");
+        assert_eq!(expand(r"\U\1\e \2", r"(\w+) (\w+)", "ab cd"), "AB cd");
+        assert_eq!(expand(r"\L&", "[A-Z]+", "ABC"), "abc");
+        assert_eq!(expand(r"a\&b\\", "x", "x"), r"a&b\");
+        assert_eq!(expand_tilde(r"~ \~", "prev"), r"prev \~");
+    }
+
+    #[test]
+    fn test_substitution_global_flag() {
+        assert_eq!(
+            substitute("aaa\nba\n", "a", "x", false),
+            ("xaa\nbx\n".to_string(), 2, 2)
+        );
+        assert_eq!(
+            substitute("aaa\nba\n", "a", "x", true),
+            ("xxx\nbx\n".to_string(), 4, 2)
+        );
+    }
+
+    #[test]
+    fn test_substitution_empty_matches() {
+        // An empty match right after a replaced one is skipped.
+        assert_eq!(substitute("abc\n", "x*", "-", true).0, "-a-b-c-\n");
+        assert_eq!(substitute("axc\n", "x*", "-", true).0, "-a-c-\n");
+        assert_eq!(substitute("ab\n", "^", "> ", false).0, "> ab\n");
+    }
+
+    #[test]
+    fn test_substitution_over_multibyte_and_long_lines() {
+        assert_eq!(substitute("éaéa\n", "a", "日", true).0, "é日é日\n");
+        assert_eq!(substitute("日x日\n", "日", "ab", true).0, "abxab\n");
+        let line = "x,".repeat(10_000) + "\n";
+        let (text, substitutions, lines) = substitute(&line, ",", ";", true);
+        assert_eq!(text, "x;".repeat(10_000) + "\n");
+        assert_eq!((substitutions, lines), (10_000, 1));
+    }
+
+    #[test]
+    fn test_skipped_matches_stay() {
+        let mut buffer = Buffer::new();
+        buffer.content = Rope::from_str("aéa aéa\n");
+        let flags = Flags {
+            global: true,
+            ..Flags::default()
+        };
+        let mut substitution = Substitution::new(
+            Regex::new("a").unwrap(),
+            Replacement::parse("<&&>"),
+            flags,
+            "a".to_string(),
+            (0, 0),
+        );
+        let mut replace = false;
+        while let Some(found) = substitution.next_match(&buffer.content) {
+            if replace {
+                substitution.replace(&mut buffer, &found);
+            } else {
+                substitution.skip(&found);
+            }
+            replace = !replace;
+        }
+        assert_eq!(buffer.content.to_string(), "aé<aa> aé<aa>\n");
+        assert_eq!(substitution.substitutions, 2);
+    }
+
+    #[test]
+    fn test_substitution_splits_lines() {
+        let (text, substitutions, lines) = substitute("a,b,c\nd,e\n", ",", r"\r", true);
+        assert_eq!(text, "a\nb\nc\nd\ne\n");
+        assert_eq!((substitutions, lines), (3, 2));
+        // The rows added by a split are still searched.
+        let (text, _, lines) = substitute("a,b\nc,d\n", ",", r"\r,", false);
+        assert_eq!(text, "a\n,b\nc\n,d\n");
+        assert_eq!(lines, 2);
+    }
+}
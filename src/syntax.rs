@@ -1,8 +1,10 @@
-use syntect::easy::HighlightLines;
-use syntect::highlighting::{Style as SyntectStyle, ThemeSet};
-use syntect::parsing::{SyntaxReference, SyntaxSet};
+use ropey::Rope;
+use std::path::Path;
+use syntect::highlighting::{
+    HighlightIterator, HighlightState, Highlighter, Style as SyntectStyle, ThemeSet,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet};
 
-#[allow(dead_code)]
 pub struct SyntaxHighlighter {
     syntax_set: SyntaxSet,
     theme_set: ThemeSet,
@@ -30,22 +32,76 @@ impl SyntaxHighlighter {
     pub fn get_syntax_by_name(&self, name: &str) -> Option<&SyntaxReference> {
         self.syntax_set.find_syntax_by_name(name)
     }
-    pub fn highlight_line<'a>(
+    pub fn detect_syntax(
+        &self,
+        content: &Rope,
+        file_path: Option<&str>,
+    ) -> Option<&SyntaxReference> {
+        let by_extension = file_path
+            .and_then(|path| Path::new(path).extension())
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.get_syntax_by_extension(ext));
+        by_extension.or_else(|| {
+            let first_line = content.lines().next()?.to_string();
+            self.syntax_set.find_syntax_by_first_line(&first_line)
+        })
+    }
+    pub fn highlight_lines(
         &self,
-        line: &'a str,
-        syntax: &SyntaxReference,
-    ) -> Vec<(SyntectStyle, &'a str)> {
+        content: &Rope,
+        file_path: Option<&str>,
+        cache: &mut HighlightCache,
+        start_line: usize,
+        end_line: usize,
+    ) -> Option<Vec<HighlightedLine>> {
+        if cache.file_path.as_deref() != file_path || cache.theme != self.current_theme {
+            cache.reset();
+            cache.file_path = file_path.map(str::to_string);
+            cache.theme = self.current_theme.clone();
+            cache.syntax = self
+                .detect_syntax(content, file_path)
+                .map(|syntax| syntax.name.clone());
+        }
+        let syntax = self.get_syntax_by_name(cache.syntax.as_deref()?)?;
         let theme = &self.theme_set.themes[&self.current_theme];
-        let mut highlighter = HighlightLines::new(syntax, theme);
-        match highlighter.highlight_line(line, &self.syntax_set) {
-            Ok(highlights) => highlights,
-            Err(_) => vec![(SyntectStyle::default(), line)],
+        let highlighter = Highlighter::new(theme);
+        let end_line = end_line.min(content.len_lines());
+        if cache.states.is_empty() {
+            cache.states.push((
+                ParseState::new(syntax),
+                HighlightState::new(&highlighter, ScopeStack::new()),
+            ));
+        }
+        while cache.lines.len() < end_line {
+            let line_num = cache.lines.len();
+            let line = content.line(line_num).to_string();
+            let (mut parse_state, mut highlight_state) = cache.states[line_num].clone();
+            let segments = match parse_state.parse_line(&line, &self.syntax_set) {
+                Ok(ops) => HighlightIterator::new(&mut highlight_state, &ops, &line, &highlighter)
+                    .map(|(style, text)| HighlightedSegment {
+                        text: text.trim_end_matches(['\n', '\r']).to_string(),
+                        style: style.into(),
+                    })
+                    .filter(|segment| !segment.text.is_empty())
+                    .collect(),
+                Err(_) => vec![HighlightedSegment {
+                    text: line.trim_end_matches(['\n', '\r']).to_string(),
+                    style: TextStyle::default(),
+                }],
+            };
+            cache.lines.push(HighlightedLine { segments });
+            cache.states.push((parse_state, highlight_state));
         }
+        let start_line = start_line.min(end_line);
+        Some(cache.lines[start_line..end_line].to_vec())
     }
-    pub fn set_theme(&mut self, theme_name: &str) {
-        if self.theme_set.themes.contains_key(theme_name) {
+    /// Switches to `theme_name`, returning false if there is no such theme.
+    pub fn set_theme(&mut self, theme_name: &str) -> bool {
+        let found = self.theme_set.themes.contains_key(theme_name);
+        if found {
             self.current_theme = theme_name.to_string();
         }
+        found
     }
     pub fn get_available_themes(&self) -> Vec<&String> {
         self.theme_set.themes.keys().collect()
@@ -54,13 +110,32 @@ impl SyntaxHighlighter {
         &self.current_theme
     }
 }
+#[derive(Default)]
+pub struct HighlightCache {
+    file_path: Option<String>,
+    theme: String,
+    syntax: Option<String>,
+    states: Vec<(ParseState, HighlightState)>,
+    lines: Vec<HighlightedLine>,
+}
+impl HighlightCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn invalidate_from(&mut self, line: usize) {
+        self.lines.truncate(line);
+        self.states.truncate(line + 1);
+    }
+    fn reset(&mut self) {
+        self.states.clear();
+        self.lines.clear();
+    }
+}
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct HighlightedLine {
     pub segments: Vec<HighlightedSegment>,
 }
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct HighlightedSegment {
     pub text: String,
     pub style: TextStyle,
@@ -102,3 +177,91 @@ impl From<SyntectStyle> for TextStyle {
         }
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::Buffer;
+
+    fn foregrounds(lines: &[HighlightedLine]) -> Vec<Vec<(u8, u8, u8)>> {
+        lines
+            .iter()
+            .map(|line| line.segments.iter().map(|s| s.style.foreground).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_lines_are_highlighted_up_to_the_last_one_asked_for() {
+        let highlighter = SyntaxHighlighter::new();
+        let content = Rope::from_str("fn a() {}\nlet b = 1;\nlet c = 2;\nlet d = 3;\n");
+        let mut cache = HighlightCache::new();
+        let lines = highlighter
+            .highlight_lines(&content, Some("a.rs"), &mut cache, 1, 3)
+            .unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(cache.lines.len(), 3);
+        assert_eq!(cache.states.len(), 4);
+        // No syntax for the file, no highlighting.
+        let mut cache = HighlightCache::new();
+        assert!(highlighter
+            .highlight_lines(&content, Some("a.unknown"), &mut cache, 0, 3)
+            .is_none());
+    }
+
+    #[test]
+    fn test_invalidated_lines_are_parsed_again_from_the_saved_state() {
+        let highlighter = SyntaxHighlighter::new();
+        let mut content = Rope::from_str("let a = 1;\nlet b = 2;\nlet c = 3;\n");
+        let mut cache = HighlightCache::new();
+        let before = highlighter
+            .highlight_lines(&content, Some("a.rs"), &mut cache, 0, 3)
+            .unwrap();
+        // Opening a block comment on line 2 changes how line 3 looks.
+        content.insert(content.line_to_char(1), "/*\n");
+        cache.invalidate_from(1);
+        assert_eq!((cache.lines.len(), cache.states.len()), (1, 2));
+        let after = highlighter
+            .highlight_lines(&content, Some("a.rs"), &mut cache, 0, 4)
+            .unwrap();
+        assert_eq!(foregrounds(&after[..1]), foregrounds(&before[..1]));
+        assert_eq!(after[2].segments.len(), 1);
+        assert_ne!(foregrounds(&after[2..3]), foregrounds(&before[1..2]));
+    }
+
+    #[test]
+    fn test_cache_resets_for_another_file() {
+        let highlighter = SyntaxHighlighter::new();
+        let content = Rope::from_str("# title\ntext\n");
+        let mut cache = HighlightCache::new();
+        highlighter.highlight_lines(&content, Some("a.rs"), &mut cache, 0, 2);
+        let rust = foregrounds(&cache.lines);
+        highlighter.highlight_lines(&content, Some("a.md"), &mut cache, 0, 2);
+        assert_eq!(cache.syntax.as_deref(), Some("Markdown"));
+        assert_ne!(foregrounds(&cache.lines), rust);
+    }
+
+    #[test]
+    fn test_buffer_edits_invalidate_from_their_line() {
+        let highlighter = SyntaxHighlighter::new();
+        let mut buffer = Buffer::new();
+        buffer.content = Rope::from_str("let a = 1;\nlet b = 2;\nlet c = 3;\n");
+        let highlight = |buffer: &mut Buffer| {
+            highlighter.highlight_lines(
+                &buffer.content,
+                Some("a.rs"),
+                &mut buffer.highlight_cache,
+                0,
+                3,
+            );
+            buffer.highlight_cache.lines.len()
+        };
+        assert_eq!(highlight(&mut buffer), 3);
+        buffer.insert_text(buffer.content.line_to_char(2), "x");
+        assert_eq!(buffer.highlight_cache.lines.len(), 2);
+        assert_eq!(highlight(&mut buffer), 3);
+        buffer.remove_text(buffer.content.line_to_char(1)..buffer.content.line_to_char(2));
+        assert_eq!(buffer.highlight_cache.lines.len(), 1);
+        assert_eq!(highlight(&mut buffer), 3);
+        buffer.undo();
+        assert!(buffer.highlight_cache.lines.is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+use ropey::Rope;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
+
+pub const TAB_WIDTH: usize = 8;
+pub const SHIFT_WIDTH: usize = 4;
+/// Line width `gq` wraps text to.
+pub const TEXT_WIDTH: usize = 79;
+
+/// Text of `line` without its line ending.
+pub fn line_text(content: &Rope, line: usize) -> String {
+    if line >= content.len_lines() {
+        return String::new();
+    }
+    let text = content.line(line).to_string();
+    text.trim_end_matches(['\n', '\r']).to_string()
+}
+/// Number of lines, not counting the empty one after a final newline.
+pub fn line_count(content: &Rope) -> usize {
+    let lines = content.len_lines();
+    if lines > 1 && content.line(lines - 1).len_chars() == 0 {
+        lines - 1
+    } else {
+        lines
+    }
+}
+/// Number of chars on `line`, not counting the line ending.
+pub fn line_len(content: &Rope, line: usize) -> usize {
+    if line >= content.len_lines() {
+        return 0;
+    }
+    let slice = content.line(line);
+    let mut len = slice.len_chars();
+    if len > 0 && slice.char(len - 1) == '\n' {
+        len -= 1;
+        if len > 0 && slice.char(len - 1) == '\r' {
+            len -= 1;
+        }
+    }
+    len
+}
+/// Char index of the grapheme boundary after `col`.
+pub fn next_grapheme(text: &str, col: usize) -> usize {
+    let mut offset = 0;
+    for grapheme in text.graphemes(true) {
+        offset += grapheme.chars().count();
+        if offset > col {
+            return offset;
+        }
+    }
+    offset.max(col)
+}
+/// Char index of the grapheme boundary before `col`.
+pub fn prev_grapheme(text: &str, col: usize) -> usize {
+    let mut offset = 0;
+    let mut prev = 0;
+    for grapheme in text.graphemes(true) {
+        if offset >= col {
+            break;
+        }
+        prev = offset;
+        offset += grapheme.chars().count();
+    }
+    prev
+}
+/// Start of the grapheme containing char index `col`.
+pub fn grapheme_start(text: &str, col: usize) -> usize {
+    let mut offset = 0;
+    for grapheme in text.graphemes(true) {
+        let next = offset + grapheme.chars().count();
+        if next > col {
+            return offset;
+        }
+        offset = next;
+    }
+    offset
+}
+/// Cells taken by `c` when drawn at display column `display_col`.
+pub fn char_width(c: char, display_col: usize) -> usize {
+    if c == '\t' {
+        TAB_WIDTH - display_col % TAB_WIDTH
+    } else {
+        c.width().unwrap_or(0)
+    }
+}
+/// Display column of char index `col`, expanding tabs and wide characters.
+pub fn display_col(text: &str, col: usize) -> usize {
+    text.chars()
+        .take(col)
+        .fold(0, |display, c| display + char_width(c, display))
+}
+/// Char index whose cell range covers `display`, snapped to a grapheme start.
+pub fn col_for_display(text: &str, display: usize) -> usize {
+    let mut current = 0;
+    for (col, c) in text.chars().enumerate() {
+        let width = char_width(c, current);
+        if current + width > display {
+            return grapheme_start(text, col);
+        }
+        current += width;
+    }
+    text.chars().count()
+}
+/// Replaces tabs with spaces, starting at display column `start`. Returns
+/// the expanded text and the display column after it.
+pub fn expand_tabs(text: &str, start: usize) -> (String, usize) {
+    let mut expanded = String::with_capacity(text.len());
+    let mut display = start;
+    for c in text.chars() {
+        let width = char_width(c, display);
+        if c == '\t' {
+            expanded.push_str(&" ".repeat(width));
+        } else {
+            expanded.push(c);
+        }
+        display += width;
+    }
+    (expanded, display)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `e` with a combining acute accent, one grapheme of two chars.
+    const E_ACUTE: &str = "e\u{301}";
+
+    #[test]
+    fn test_graphemes_keep_combining_marks() {
+        let text = format!("a{}b", E_ACUTE);
+        assert_eq!(next_grapheme(&text, 0), 1);
+        assert_eq!(next_grapheme(&text, 1), 3);
+        assert_eq!(next_grapheme(&text, 3), 4);
+        assert_eq!(prev_grapheme(&text, 3), 1);
+        assert_eq!(prev_grapheme(&text, 1), 0);
+        assert_eq!(grapheme_start(&text, 2), 1);
+        assert_eq!(grapheme_start(&text, 3), 3);
+    }
+
+    #[test]
+    fn test_display_col_of_wide_and_combining_chars() {
+        assert_eq!(display_col("日本語x", 2), 4);
+        assert_eq!(display_col("日本語x", 4), 7);
+        // A combining mark takes no cell of its own.
+        assert_eq!(display_col(&format!("{}x", E_ACUTE), 2), 1);
+        assert_eq!(char_width('日', 0), 2);
+        assert_eq!(char_width('\u{301}', 0), 0);
+    }
+
+    #[test]
+    fn test_tabs_stop_at_tab_width() {
+        assert_eq!(char_width('\t', 0), TAB_WIDTH);
+        assert_eq!(char_width('\t', 3), TAB_WIDTH - 3);
+        assert_eq!(display_col("ab\tc", 3), TAB_WIDTH);
+        assert_eq!(display_col("\t\tc", 2), 2 * TAB_WIDTH);
+        assert_eq!(expand_tabs("a\tb", 0), ("a       b".to_string(), 9));
+        assert_eq!(expand_tabs("\t", 6), ("  ".to_string(), 8));
+    }
+
+    #[test]
+    fn test_col_for_display_snaps_to_char_start() {
+        // Either cell of a wide char maps to the char.
+        assert_eq!(col_for_display("日本語", 2), 1);
+        assert_eq!(col_for_display("日本語", 3), 1);
+        // Any cell of a tab maps to the tab.
+        assert_eq!(col_for_display("a\tb", 5), 1);
+        assert_eq!(col_for_display("a\tb", 8), 2);
+        assert_eq!(col_for_display(&format!("{}x", E_ACUTE), 1), 2);
+        assert_eq!(col_for_display("ab", 10), 2);
+    }
+
+    #[test]
+    fn test_line_len_and_count_ignore_line_endings() {
+        let content = Rope::from_str("日本\r\nx\n");
+        assert_eq!(line_count(&content), 2);
+        assert_eq!(line_len(&content, 0), 2);
+        assert_eq!(line_text(&content, 0), "日本");
+        assert_eq!(line_len(&content, 5), 0);
+    }
+}
@@ -0,0 +1,401 @@
+use crate::motion::{char_class, MotionRange};
+use crate::text;
+use regex::Regex;
+use ropey::Rope;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectKind {
+    Word,
+    BigWord,
+    Sentence,
+    Paragraph,
+    Bracket(char, char),
+    Quote(char),
+    Tag,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextObject {
+    pub kind: ObjectKind,
+    pub inner: bool,
+}
+impl TextObject {
+    /// Parses the char typed after `i` or `a`, e.g. `w`, `(`, `b`, `"`, `t`.
+    pub fn from_char(c: char, inner: bool) -> Option<Self> {
+        let kind = match c {
+            'w' => ObjectKind::Word,
+            'W' => ObjectKind::BigWord,
+            's' => ObjectKind::Sentence,
+            'p' => ObjectKind::Paragraph,
+            '(' | ')' | 'b' => ObjectKind::Bracket('(', ')'),
+            '[' | ']' => ObjectKind::Bracket('[', ']'),
+            '{' | '}' | 'B' => ObjectKind::Bracket('{', '}'),
+            '<' | '>' => ObjectKind::Bracket('<', '>'),
+            '"' | '\'' | '`' => ObjectKind::Quote(c),
+            't' => ObjectKind::Tag,
+            _ => return None,
+        };
+        Some(Self { kind, inner })
+    }
+    /// Range of the object around char index `pos`, `count` levels or
+    /// repetitions deep.
+    pub fn select(&self, content: &Rope, pos: usize, count: usize) -> Option<MotionRange> {
+        let count = count.max(1);
+        let (start, end) = match self.kind {
+            ObjectKind::Word => select_word(content, pos, count, false, self.inner)?,
+            ObjectKind::BigWord => select_word(content, pos, count, true, self.inner)?,
+            ObjectKind::Sentence => select_sentence(content, pos, self.inner)?,
+            ObjectKind::Paragraph => return select_paragraph(content, pos, count, self.inner),
+            ObjectKind::Bracket(open, close) => {
+                select_bracket(content, pos, count, open, close, self.inner)?
+            }
+            ObjectKind::Quote(quote) => select_quote(content, pos, quote, self.inner)?,
+            ObjectKind::Tag => select_tag(content, pos, count, self.inner)?,
+        };
+        Some(MotionRange {
+            start,
+            end,
+            linewise: false,
+        })
+    }
+}
+fn line_bounds(content: &Rope, pos: usize) -> (usize, usize) {
+    let row = content.char_to_line(pos);
+    let start = content.line_to_char(row);
+    (start, start + text::line_len(content, row))
+}
+fn select_word(
+    content: &Rope,
+    pos: usize,
+    count: usize,
+    big_word: bool,
+    inner: bool,
+) -> Option<(usize, usize)> {
+    let (line_start, line_end) = line_bounds(content, pos);
+    if line_start == line_end {
+        return None;
+    }
+    let pos = pos.min(line_end - 1);
+    let class_at = |i: usize| char_class(content.char(i), big_word);
+    let mut start = pos;
+    while start > line_start && class_at(start - 1) == class_at(pos) {
+        start -= 1;
+    }
+    let mut end = pos;
+    let mut started_on_blank = class_at(pos) == 0;
+    for n in 0..count {
+        if n > 0 {
+            if end >= line_end {
+                break;
+            }
+            started_on_blank = class_at(end) == 0;
+        }
+        let class = class_at(end);
+        while end < line_end && class_at(end) == class {
+            end += 1;
+        }
+        // `aw` also takes the blanks after a word, or the word after blanks.
+        if !inner && end < line_end {
+            let next = class_at(end);
+            if (class == 0) != (next == 0) {
+                while end < line_end && class_at(end) == next {
+                    end += 1;
+                }
+            }
+        }
+    }
+    if !inner && !started_on_blank && (end == line_end || class_at(end - 1) != 0) {
+        // No trailing blanks: include the blanks before the word instead.
+        while start > line_start && class_at(start - 1) == 0 {
+            start -= 1;
+        }
+    }
+    Some((start, end))
+}
+fn is_blank_line(content: &Rope, row: usize) -> bool {
+    text::line_text(content, row).trim().is_empty()
+}
+fn select_paragraph(content: &Rope, pos: usize, count: usize, inner: bool) -> Option<MotionRange> {
+    let last = content.len_lines().saturating_sub(1);
+    let row = content.char_to_line(pos);
+    let mut first = row;
+    let blank = is_blank_line(content, row);
+    while first > 0 && is_blank_line(content, first - 1) == blank {
+        first -= 1;
+    }
+    let mut end_row = row;
+    for n in 0..count {
+        if n > 0 {
+            if end_row >= last {
+                break;
+            }
+            end_row += 1;
+        }
+        let blank = is_blank_line(content, end_row);
+        while end_row < last && is_blank_line(content, end_row + 1) == blank {
+            end_row += 1;
+        }
+        if !inner && end_row < last {
+            end_row += 1;
+            let next_blank = is_blank_line(content, end_row);
+            while end_row < last && is_blank_line(content, end_row + 1) == next_blank {
+                end_row += 1;
+            }
+        }
+    }
+    if !inner && !blank && (end_row == last && !is_blank_line(content, end_row)) {
+        while first > 0 && is_blank_line(content, first - 1) {
+            first -= 1;
+        }
+    }
+    Some(MotionRange::lines(content, first, end_row))
+}
+fn is_sentence_end(content: &Rope, i: usize) -> bool {
+    matches!(content.char(i), '.' | '!' | '?')
+        && (i + 1 >= content.len_chars() || content.char(i + 1).is_whitespace())
+}
+fn select_sentence(content: &Rope, pos: usize, inner: bool) -> Option<(usize, usize)> {
+    let len = content.len_chars();
+    if len == 0 {
+        return None;
+    }
+    let pos = pos.min(len - 1);
+    let paragraph_break =
+        |i: usize| content.char(i) == '\n' && i > 0 && content.char(i - 1) == '\n';
+    let mut start = pos;
+    while start > 0 && !is_sentence_end(content, start - 1) && !paragraph_break(start - 1) {
+        start -= 1;
+    }
+    while start < pos && content.char(start).is_whitespace() {
+        start += 1;
+    }
+    let mut end = pos;
+    while end < len && !is_sentence_end(content, end) && !paragraph_break(end) {
+        end += 1;
+    }
+    if end < len && is_sentence_end(content, end) {
+        end += 1;
+    }
+    if !inner {
+        let trailing = end;
+        while end < len && content.char(end).is_whitespace() && !paragraph_break(end) {
+            end += 1;
+        }
+        if end == trailing {
+            while start > 0 && content.char(start - 1) == ' ' {
+                start -= 1;
+            }
+        }
+    }
+    Some((start, end))
+}
+/// Finds the `count`th unmatched `open` before `pos` and its matching
+/// `close`, scanning the rope across lines.
+fn select_bracket(
+    content: &Rope,
+    pos: usize,
+    count: usize,
+    open: char,
+    close: char,
+    inner: bool,
+) -> Option<(usize, usize)> {
+    let len = content.len_chars();
+    if pos >= len {
+        return None;
+    }
+    let mut open_pos = None;
+    let mut depth = 0usize;
+    let mut remaining = count;
+    let mut i = if content.char(pos) == close {
+        pos
+    } else {
+        pos + 1
+    };
+    while i > 0 {
+        i -= 1;
+        let c = content.char(i);
+        if c == close {
+            depth += 1;
+        } else if c == open {
+            if depth == 0 {
+                remaining -= 1;
+                if remaining == 0 {
+                    open_pos = Some(i);
+                    break;
+                }
+            } else {
+                depth -= 1;
+            }
+        }
+    }
+    let open_pos = open_pos?;
+    let mut depth = 0usize;
+    let close_pos = (open_pos + 1..len).find(|&i| {
+        let c = content.char(i);
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            if depth == 0 {
+                return true;
+            }
+            depth -= 1;
+        }
+        false
+    })?;
+    if !inner {
+        return Some((open_pos, close_pos + 1));
+    }
+    let mut start = open_pos + 1;
+    let mut end = close_pos;
+    // A block whose brackets sit on their own lines selects whole inner lines.
+    if start < len && content.char(start) == '\n' {
+        start += 1;
+        let close_line_start = content.line_to_char(content.char_to_line(close_pos));
+        if (close_line_start..close_pos).all(|i| content.char(i).is_whitespace()) {
+            end = close_line_start.max(start);
+        }
+    }
+    Some((start, end))
+}
+fn select_quote(content: &Rope, pos: usize, quote: char, inner: bool) -> Option<(usize, usize)> {
+    let (line_start, line_end) = line_bounds(content, pos);
+    let chars: Vec<char> = content.slice(line_start..line_end).chars().collect();
+    let col = pos - line_start;
+    let quotes: Vec<usize> = (0..chars.len())
+        .filter(|&i| chars[i] == quote && (i == 0 || chars[i - 1] != '\\'))
+        .collect();
+    let pair = quotes
+        .chunks_exact(2)
+        .find(|pair| pair[0] <= col && col <= pair[1])
+        .or_else(|| quotes.chunks_exact(2).find(|pair| pair[0] > col))?;
+    let (open, close) = (pair[0], pair[1]);
+    if inner {
+        return Some((line_start + open + 1, line_start + close));
+    }
+    let mut start = open;
+    let mut end = close + 1;
+    if end < chars.len() && chars[end].is_whitespace() {
+        while end < chars.len() && chars[end].is_whitespace() {
+            end += 1;
+        }
+    } else {
+        while start > 0 && chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+    }
+    Some((line_start + start, line_start + end))
+}
+/// Chars either side of the cursor searched for tags at first. The span
+/// doubles until the enclosing tags are found or it covers the buffer.
+const TAG_SPAN: usize = 4096;
+/// `<name ...>`, `</name>` and `<name/>`.
+fn tag_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"<(/?)([A-Za-z][\w:.-]*)[^<>]*?(/?)>").expect("tag pattern is valid")
+    })
+}
+fn select_tag(content: &Rope, pos: usize, count: usize, inner: bool) -> Option<(usize, usize)> {
+    let len = content.len_chars();
+    let mut span = TAG_SPAN;
+    let (open_start, open_end, close_start, close_end) = loop {
+        let range = pos.saturating_sub(span)..(pos + span).min(len);
+        let whole = range == (0..len);
+        match enclosing_tags(content, range, pos, count) {
+            Some(pair) => break pair,
+            None if whole => return None,
+            None => span *= 2,
+        }
+    };
+    if inner {
+        Some((open_end, close_start))
+    } else {
+        Some((open_start, close_end))
+    }
+}
+/// The `count`th smallest pair of tags in `range` around `pos`, as the
+/// opening tag's start and end and the closing tag's start and end. A tag
+/// cut off by the ends of `range` does not match the pattern, so the pairs
+/// found inside it are pairs in the whole buffer too.
+fn enclosing_tags(
+    content: &Rope,
+    range: Range<usize>,
+    pos: usize,
+    count: usize,
+) -> Option<(usize, usize, usize, usize)> {
+    let slice = content.slice(range.clone());
+    let source = slice.to_string();
+    let to_char = |byte: usize| range.start + slice.byte_to_char(byte);
+    let mut stack: Vec<(&str, usize, usize)> = Vec::new();
+    let mut enclosing = Vec::new();
+    for capture in tag_pattern().captures_iter(&source) {
+        let whole = capture.get(0)?;
+        if !capture[3].is_empty() {
+            continue;
+        }
+        let name = capture.get(2)?.as_str();
+        let (start, end) = (to_char(whole.start()), to_char(whole.end()));
+        if capture[1].is_empty() {
+            stack.push((name, start, end));
+        } else if let Some(index) = stack.iter().rposition(|(open, _, _)| *open == name) {
+            let (_, open_start, open_end) = stack[index];
+            stack.truncate(index);
+            // Pairs around the cursor close innermost first.
+            if open_start <= pos && pos < end {
+                enclosing.push((open_start, open_end, start, end));
+                if enclosing.len() == count {
+                    break;
+                }
+            }
+        }
+    }
+    enclosing.get(count - 1).copied()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(text: &str, pos: usize, count: usize, inner: bool) -> Option<String> {
+        let content = Rope::from_str(text);
+        let object = TextObject::from_char('t', inner)?;
+        let range = object.select(&content, pos, count)?;
+        Some(content.slice(range.start..range.end).to_string())
+    }
+
+    #[test]
+    fn test_inner_and_outer_tag() {
+        let text = "<p>a <b>bold</b> c</p>";
+        assert_eq!(tag(text, 9, 1, true).as_deref(), Some("bold"));
+        assert_eq!(tag(text, 9, 1, false).as_deref(), Some("<b>bold</b>"));
+        assert_eq!(tag(text, 9, 2, true).as_deref(), Some("a <b>bold</b> c"));
+        assert_eq!(tag(text, 9, 3, true), None);
+    }
+
+    #[test]
+    fn test_tag_skips_siblings_and_void_tags() {
+        let text = "<div><span>x</span><br>y<img/></div>";
+        assert_eq!(
+            tag(text, 23, 1, true).as_deref(),
+            Some("<span>x</span><br>y<img/>")
+        );
+    }
+
+    #[test]
+    fn test_nested_tags_of_one_name() {
+        let text = "<div>a<div>b</div>c</div>";
+        assert_eq!(tag(text, 5, 1, true).as_deref(), Some("a<div>b</div>c"));
+        assert_eq!(tag(text, 11, 1, true).as_deref(), Some("b"));
+        assert_eq!(tag(text, 11, 2, true).as_deref(), Some("a<div>b</div>c"));
+    }
+
+    #[test]
+    fn test_tag_wider_than_first_span() {
+        let filler = "x".repeat(TAG_SPAN * 3);
+        let text = format!("<body>{}<i>y</i></body>", filler);
+        let range = TextObject::from_char('t', true)
+            .and_then(|object| object.select(&Rope::from_str(&text), 10, 1))
+            .expect("body encloses the cursor");
+        assert_eq!((range.start, range.end), (6, text.len() - 7));
+    }
+}
@@ -1,5 +1,13 @@
 use crate::buffer::Buffer;
+use crate::cmdline::CommandLine;
+use crate::cursor::Cursor;
 use crate::modes::EditorMode;
+use crate::search::{self, MatchCount};
+use crate::syntax::{SyntaxHighlighter, TextStyle};
+use crate::text;
+use crate::visual::Selection;
+use crate::window::{Window, WindowLayout};
+use crate::wrap::{WrapOptions, Wrapping};
 use anyhow::Result;
 use crossterm::{cursor, execute, terminal};
 use ratatui::{
@@ -7,25 +15,59 @@ use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color as RatatuiColor, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Paragraph},
+    widgets::{Block, Borders, Clear, Paragraph},
     Terminal,
 };
+use regex::Regex;
+use ropey::Rope;
 use std::io::{self, stdout, Stdout};
+use std::ops::Range;
+/// What is drawn over the text besides syntax colours.
+pub struct Highlights<'a> {
+    pub selection: Option<&'a Selection>,
+    /// Pattern whose visible matches are highlighted (`hlsearch`).
+    pub search: Option<&'a Regex>,
+    /// Match the cursor jumped to while typing a pattern (`incsearch`).
+    pub current_match: Option<Range<usize>>,
+}
+/// What is drawn below the windows, and the mode shown in the current
+/// window's status line.
+pub struct Prompt<'a> {
+    pub mode: &'a EditorMode,
+    pub command_line: &'a CommandLine,
+    pub message: &'a Option<String>,
+    pub search_count: Option<MatchCount>,
+}
+/// A tab page as shown in the tabline.
+pub struct TabLabel {
+    pub name: String,
+    /// A buffer in one of its windows has unsaved changes.
+    pub modified: bool,
+}
+/// The row of tab pages drawn above the windows while there are several.
+pub struct TabLine {
+    pub labels: Vec<TabLabel>,
+    pub current: usize,
+}
+/// Narrowest screen the editor draws on, leaving room for the line number
+/// gutter and some text.
+const MIN_SCREEN_WIDTH: u16 = 12;
+/// Fewest digits line numbers take, so the text starts in the same
+/// column for any short file.
+const MIN_NUMBER_WIDTH: usize = 4;
+/// A style drawn over display columns `start..end` of a line.
+type Overlay = (usize, usize, fn(Style) -> Style);
 pub struct UI {
     terminal: Terminal<CrosstermBackend<Stdout>>,
-    viewport_start: usize,
-    viewport_height: usize,
+    syntax_highlighter: SyntaxHighlighter,
 }
 impl UI {
     pub fn new() -> Result<Self> {
         let backend = CrosstermBackend::new(stdout());
         let terminal = Terminal::new(backend)?;
-        let size = terminal.size()?;
-        let viewport_height = size.height as usize - 2;
         Ok(Self {
             terminal,
-            viewport_start: 0,
-            viewport_height,
+            syntax_highlighter: SyntaxHighlighter::new(),
         })
     }
     pub fn enter_alternate_screen(&mut self) -> Result<()> {
@@ -38,87 +80,514 @@ impl UI {
         terminal::disable_raw_mode()?;
         Ok(())
     }
+    /// Draws every window of `layout`, the current one with `highlights`,
+    /// and the command line, wrapping long lines as `wrap` says.
     pub fn render(
         &mut self,
-        buffer: &Buffer,
-        mode: &EditorMode,
-        command_line: &str,
-        message: &Option<String>,
+        buffers: &mut [Buffer],
+        layout: &mut WindowLayout,
+        highlights: &Highlights,
+        prompt: &Prompt,
+        tabline: &TabLine,
+        wrap: &WrapOptions,
     ) -> Result<()> {
-        self.update_viewport(buffer);
-        let viewport_start = self.viewport_start;
-        let viewport_height = self.viewport_height;
+        let syntax_highlighter = &self.syntax_highlighter;
+        let mode = prompt.mode;
+        let command_height = match (mode, prompt.message) {
+            (EditorMode::Command, _) | (_, None) => 1,
+            (_, Some(msg)) => msg.lines().count().max(1) as u16,
+        };
+        let current = layout.current().id;
+        let tabline_height = u16::from(tabline.labels.len() > 1);
+        // Other windows still show matches of the last pattern.
+        let other_highlights = Highlights {
+            selection: None,
+            search: highlights.search,
+            current_match: None,
+        };
+        let (min_width, min_height) = layout.min_size();
         self.terminal.draw(|f| {
+            let size = f.size();
+            let windows_height = min_height + tabline_height;
+            if size.width < min_width.max(MIN_SCREEN_WIDTH) || size.height < windows_height + 1 {
+                render_too_small(f, size);
+                return;
+            }
+            // A long message never squeezes the windows below their minimum.
+            let command_height = command_height
+                .min(size.height / 2)
+                .min(size.height - windows_height)
+                .max(1);
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .constraints(
                     [
+                        Constraint::Length(tabline_height),
                         Constraint::Min(1),
-                        Constraint::Length(1),
-                        Constraint::Length(1),
+                        Constraint::Length(command_height),
                     ]
                     .as_ref(),
                 )
                 .split(f.size());
-            render_editor_content(f, chunks[0], buffer, viewport_start, viewport_height);
-            render_status_line(f, chunks[1], buffer, mode);
-            render_command_line(f, chunks[2], mode, command_line, message);
+            if tabline_height > 0 {
+                render_tabline(f, chunks[0], tabline);
+            }
+            let (areas, separators) = layout.arrange(chunks[1]);
+            for area in areas {
+                let active = area.id == current;
+                let Some(window) = layout
+                    .windows_mut()
+                    .iter_mut()
+                    .find(|window| window.id == area.id)
+                else {
+                    continue;
+                };
+                let buffer = &mut buffers[window.buffer];
+                window.cursor.clamp_to_buffer(&buffer.content);
+                let mut wrapping = Wrapping {
+                    width: (area.text.width as usize).saturating_sub(gutter_width(buffer)),
+                    left_col: window.left_col,
+                    options: wrap.clone(),
+                };
+                scroll_to_cursor(
+                    window,
+                    &buffer.content,
+                    &wrapping,
+                    area.text.height as usize,
+                );
+                wrapping.left_col = window.left_col;
+                let highlights = if active {
+                    highlights
+                } else {
+                    &other_highlights
+                };
+                let view = View {
+                    window,
+                    wrapping: &wrapping,
+                    active,
+                };
+                render_editor_content(f, area.text, buffer, syntax_highlighter, &view, highlights);
+                let status = StatusLine {
+                    mode: active.then_some(mode),
+                    search_count: prompt.search_count.filter(|_| active),
+                };
+                render_status_line(f, area.status, buffer, &window.cursor, &status);
+            }
+            for separator in separators {
+                let bar = vec![Line::from("│"); separator.height as usize];
+                let style = Style::default().fg(RatatuiColor::DarkGray);
+                f.render_widget(Paragraph::new(bar).style(style), separator);
+            }
+            render_command_line(f, chunks[2], mode, prompt.command_line, prompt.message);
+            if *mode == EditorMode::Command {
+                render_wildmenu(f, chunks[2], prompt.command_line);
+            }
         })?;
         Ok(())
     }
-    fn update_viewport(&mut self, buffer: &Buffer) {
-        let cursor_row = buffer.cursor.row;
-        if cursor_row < self.viewport_start {
-            self.viewport_start = cursor_row;
-        } else if cursor_row >= self.viewport_start + self.viewport_height {
-            self.viewport_start = cursor_row - self.viewport_height + 1;
+    /// Fits the screen to a resized terminal before the next draw.
+    pub fn resize(&mut self) -> Result<()> {
+        self.terminal.autoresize()?;
+        Ok(())
+    }
+    /// Names of the colour schemes, sorted.
+    pub fn themes(&self) -> Vec<String> {
+        let mut themes: Vec<String> = self
+            .syntax_highlighter
+            .get_available_themes()
+            .into_iter()
+            .cloned()
+            .collect();
+        themes.sort();
+        themes
+    }
+    pub fn current_theme(&self) -> &str {
+        self.syntax_highlighter.get_current_theme()
+    }
+    /// Switches the colour scheme, returning false if there is no such one.
+    pub fn set_theme(&mut self, name: &str) -> bool {
+        self.syntax_highlighter.set_theme(name)
+    }
+}
+/// Scrolls `window` so that the whole cursor line is among the `height`
+/// rows shown, or as much of it as fits, and while lines do not wrap so
+/// that the cursor column is shown with `sidescrolloff` columns around it.
+fn scroll_to_cursor(window: &mut Window, content: &Rope, wrapping: &Wrapping, height: usize) {
+    let height = height.max(1);
+    let cursor = &window.cursor;
+    if wrapping.wraps() {
+        window.left_col = 0;
+    } else if wrapping.width > 0 {
+        let line = text::line_text(content, cursor.row);
+        let display = text::display_col(&line, cursor.col);
+        let margin = wrapping.side_margin();
+        if display < window.left_col + margin {
+            window.left_col = display.saturating_sub(margin);
+        } else if display + margin >= window.left_col + wrapping.width {
+            window.left_col = display + margin + 1 - wrapping.width;
         }
     }
+    let rows = |row: usize| wrapping.rows(&text::line_text(content, row)).len();
+    if cursor.row < window.viewport_start {
+        window.viewport_start = cursor.row;
+        return;
+    }
+    // Every line takes a row at least, so only this many can come before.
+    if cursor.row < window.viewport_start + height
+        && (window.viewport_start..=cursor.row)
+            .map(rows)
+            .sum::<usize>()
+            <= height
+    {
+        return;
+    }
+    let mut start = cursor.row;
+    let mut used = rows(start);
+    while start > window.viewport_start && used + rows(start - 1) <= height {
+        start -= 1;
+        used += rows(start);
+    }
+    window.viewport_start = start;
+}
+/// A window being drawn and how its lines fall into rows.
+struct View<'a> {
+    window: &'a Window,
+    wrapping: &'a Wrapping,
+    /// The window has the cursor.
+    active: bool,
 }
 fn render_editor_content(
     f: &mut ratatui::Frame,
     area: Rect,
-    buffer: &Buffer,
-    viewport_start: usize,
-    _viewport_height: usize,
+    buffer: &mut Buffer,
+    syntax_highlighter: &SyntaxHighlighter,
+    view: &View,
+    highlights: &Highlights,
 ) {
+    let (window, wrapping) = (view.window, view.wrapping);
+    let viewport_start = window.viewport_start;
+    let height = area.height as usize;
+    let end_line = (viewport_start + height).min(buffer.line_count());
+    let highlighted = if buffer.syntax_highlighting {
+        syntax_highlighter.highlight_lines(
+            &buffer.content,
+            buffer.file_path.as_deref(),
+            &mut buffer.highlight_cache,
+            viewport_start,
+            end_line,
+        )
+    } else {
+        None
+    };
+    let cursor_line = text::line_text(&buffer.content, window.cursor.row);
+    let cursor_display = text::display_col(&cursor_line, window.cursor.col);
+    let cursor_rows = wrapping.rows(&cursor_line);
+    let cursor_index = Wrapping::row_of(&cursor_rows, cursor_display);
+    // A line taller than the window is shown from the row that keeps the
+    // cursor at the bottom.
+    let skip = if window.cursor.row == viewport_start {
+        (cursor_index + 1).saturating_sub(height)
+    } else {
+        0
+    };
+    let gutter = gutter_width(buffer);
+    let gutter_style = Style::default().fg(RatatuiColor::DarkGray);
+    let showbreak_width = text::display_col(
+        &wrapping.options.showbreak,
+        wrapping.options.showbreak.chars().count(),
+    );
     let mut lines = Vec::new();
-    let end_line = (viewport_start + area.height as usize).min(buffer.line_count());
+    let mut cursor_y = None;
     for line_num in viewport_start..end_line {
-        let line_content = buffer.get_line(line_num).unwrap_or_default();
-        let line_number = format!("{:4} ", line_num + 1);
-        let mut spans = vec![Span::styled(
-            line_number,
-            Style::default().fg(RatatuiColor::DarkGray),
-        )];
-        let content = line_content.trim_end_matches('\n').to_string();
-        spans.push(Span::raw(content));
-        lines.push(Line::from(spans));
+        if lines.len() >= height + skip {
+            break;
+        }
+        let mut spans = Vec::new();
+        let overlays = line_overlays(&buffer.content, line_num, highlights);
+        let mut display_col = 0;
+        match highlighted
+            .as_ref()
+            .and_then(|lines| lines.get(line_num - viewport_start))
+        {
+            Some(highlighted_line) => {
+                for segment in &highlighted_line.segments {
+                    let (content, next_col) = text::expand_tabs(&segment.text, display_col);
+                    let style = to_ratatui_style(&segment.style);
+                    push_highlighted(&mut spans, content, style, display_col, &overlays);
+                    display_col = next_col;
+                }
+            }
+            None => {
+                let line_content = buffer.get_line(line_num).unwrap_or_default();
+                let line_content = line_content.trim_end_matches(['\n', '\r']);
+                let (content, next_col) = text::expand_tabs(line_content, 0);
+                push_highlighted(&mut spans, content, Style::default(), 0, &overlays);
+                display_col = next_col;
+            }
+        }
+        if let Some(&(_, end, overlay)) = overlays.iter().find(|&&(_, end, _)| end > display_col) {
+            // The selection or a match covers the line ending.
+            let padding = " ".repeat(end - display_col);
+            spans.push(Span::styled(padding, overlay(Style::default())));
+        }
+        if line_num == window.cursor.row {
+            cursor_y = Some(lines.len() + cursor_index);
+        }
+        let rows = wrapping.rows(&text::line_text(&buffer.content, line_num));
+        let last = rows.len() - 1;
+        for (index, row) in rows.into_iter().enumerate() {
+            let number = if index == 0 {
+                line_number(buffer, line_num, window.cursor.row, gutter)
+            } else {
+                " ".repeat(gutter)
+            };
+            let mut row_spans = vec![Span::styled(number, gutter_style)];
+            if row.indent > 0 {
+                row_spans.push(Span::raw(" ".repeat(row.indent - showbreak_width)));
+                row_spans.push(Span::styled(
+                    wrapping.options.showbreak.clone(),
+                    gutter_style,
+                ));
+            }
+            let (start, end) = if !wrapping.wraps() {
+                (wrapping.left_col, wrapping.left_col + wrapping.width)
+            } else if index == last {
+                (row.start, usize::MAX)
+            } else {
+                (row.start, row.end)
+            };
+            row_spans.extend(slice_spans(&spans, start, end));
+            lines.push(Line::from(row_spans));
+        }
     }
+    let lines: Vec<Line> = lines.into_iter().skip(skip).collect();
     let paragraph = Paragraph::new(lines).block(Block::default().borders(Borders::NONE));
     f.render_widget(paragraph, area);
-    let cursor_x = 5 + buffer.cursor.col;
-    let cursor_y = buffer.cursor.row.saturating_sub(viewport_start);
-    if cursor_y < area.height as usize {
+    if !view.active {
+        return;
+    }
+    let row = cursor_rows[cursor_index];
+    let cursor_x = if wrapping.wraps() {
+        gutter + row.indent + cursor_display - row.start
+    } else if cursor_display >= wrapping.left_col {
+        gutter + cursor_display - wrapping.left_col
+    } else {
+        return;
+    };
+    let Some(cursor_y) = cursor_y.and_then(|y| y.checked_sub(skip)) else {
+        return;
+    };
+    if cursor_y < height && cursor_x < area.width as usize {
         f.set_cursor(area.x + cursor_x as u16, area.y + cursor_y as u16);
     }
 }
-fn render_status_line(f: &mut ratatui::Frame, area: Rect, buffer: &Buffer, mode: &EditorMode) {
+/// Columns taken by the line numbers left of the text, the space after
+/// them included; none with `nonumber` and `norelativenumber`.
+pub fn gutter_width(buffer: &Buffer) -> usize {
+    if !buffer.line_numbers && !buffer.relative_numbers {
+        return 0;
+    }
+    buffer.line_count().to_string().len().max(MIN_NUMBER_WIDTH) + 1
+}
+/// The number shown `width` columns wide beside `line`. With
+/// `relativenumber` it is the distance from `cursor_row`, and in hybrid
+/// mode the cursor line shows its own number, aligned left.
+fn line_number(buffer: &Buffer, line: usize, cursor_row: usize, width: usize) -> String {
+    let digits = width.saturating_sub(1);
+    match (buffer.line_numbers, buffer.relative_numbers) {
+        (false, false) => String::new(),
+        (true, true) if line == cursor_row => format!("{:<digits$} ", line + 1),
+        (_, true) => format!("{:>digits$} ", line.abs_diff(cursor_row)),
+        (true, false) => format!("{:>digits$} ", line + 1),
+    }
+}
+/// The part of `spans`, drawn from display column 0 with tabs expanded,
+/// that falls in display columns `start..end`.
+fn slice_spans(spans: &[Span<'static>], start: usize, end: usize) -> Vec<Span<'static>> {
+    let mut sliced = Vec::new();
+    let mut display = 0;
+    for span in spans {
+        let mut content = String::new();
+        for c in span.content.chars() {
+            let width = text::char_width(c, display);
+            if display >= start && display + width <= end {
+                content.push(c);
+            }
+            display += width;
+        }
+        if !content.is_empty() {
+            sliced.push(Span::styled(content, span.style));
+        }
+        if display >= end {
+            break;
+        }
+    }
+    sliced
+}
+/// Styles drawn over `row`, in order of precedence: the selection, the
+/// match found while typing, then every other match. Only visible lines
+/// are passed here, so `hlsearch` never scans the whole buffer.
+fn line_overlays(content: &Rope, row: usize, highlights: &Highlights) -> Vec<Overlay> {
+    let mut overlays = Vec::new();
+    if let Some((start, end)) = highlights
+        .selection
+        .and_then(|selection| selection.highlight(content, row))
+    {
+        overlays.push((start, end, selection_style as fn(Style) -> Style));
+    }
+    let line_start = content.line_to_char(row);
+    let line = content.line(row).to_string();
+    let len = text::line_len(content, row);
+    let text = text::line_text(content, row);
+    // Char columns to display columns, a line ending taking one cell.
+    let to_display = |col: usize| {
+        let width = text::display_col(&text, col.min(len));
+        width + col.saturating_sub(len).min(1)
+    };
+    let line_end = line_start + line.chars().count();
+    if let Some(range) = highlights
+        .current_match
+        .as_ref()
+        .filter(|range| range.start < line_end && range.end > line_start)
+    {
+        let start = range.start.max(line_start) - line_start;
+        let end = range.end.min(line_end) - line_start;
+        overlays.push((to_display(start), to_display(end), current_match_style));
+    }
+    if let Some(regex) = highlights.search {
+        for range in search::line_matches(regex, &line) {
+            overlays.push((to_display(range.start), to_display(range.end), search_style));
+        }
+    }
+    overlays
+}
+/// Pushes `content`, drawn from display column `start` with tabs already
+/// expanded, styling each cell by the first overlay covering it.
+fn push_highlighted(
+    spans: &mut Vec<Span<'static>>,
+    content: String,
+    style: Style,
+    start: usize,
+    overlays: &[Overlay],
+) {
+    if overlays.is_empty() {
+        spans.push(Span::styled(content, style));
+        return;
+    }
+    let mut current: Option<(String, Style)> = None;
+    let mut display = start;
+    for c in content.chars() {
+        let cell_style = overlays
+            .iter()
+            .find(|&&(start, end, _)| display >= start && display < end)
+            .map_or(style, |&(_, _, overlay)| overlay(style));
+        match &mut current {
+            Some((text, style)) if *style == cell_style => text.push(c),
+            _ => {
+                if let Some((text, style)) = current.take() {
+                    spans.push(Span::styled(text, style));
+                }
+                current = Some((c.to_string(), cell_style));
+            }
+        }
+        display += text::char_width(c, display);
+    }
+    if let Some((text, style)) = current {
+        spans.push(Span::styled(text, style));
+    }
+}
+fn selection_style(style: Style) -> Style {
+    style.add_modifier(Modifier::REVERSED)
+}
+fn search_style(style: Style) -> Style {
+    style.fg(RatatuiColor::Black).bg(RatatuiColor::Yellow)
+}
+fn current_match_style(style: Style) -> Style {
+    style.add_modifier(Modifier::REVERSED | Modifier::BOLD)
+}
+fn to_ratatui_style(style: &TextStyle) -> Style {
+    let (r, g, b) = style.foreground;
+    let mut ratatui_style = Style::default().fg(RatatuiColor::Rgb(r, g, b));
+    if style.bold {
+        ratatui_style = ratatui_style.add_modifier(Modifier::BOLD);
+    }
+    if style.italic {
+        ratatui_style = ratatui_style.add_modifier(Modifier::ITALIC);
+    }
+    if style.underline {
+        ratatui_style = ratatui_style.add_modifier(Modifier::UNDERLINED);
+    }
+    ratatui_style
+}
+/// Shown instead of the windows when the terminal cannot fit them.
+fn render_too_small(f: &mut ratatui::Frame, area: Rect) {
+    let text = Paragraph::new("Terminal too small").style(
+        Style::default()
+            .fg(RatatuiColor::White)
+            .add_modifier(Modifier::BOLD),
+    );
+    f.render_widget(text, area);
+}
+/// Each tab page's number and name, `+` marking unsaved changes, with the
+/// current one highlighted.
+fn render_tabline(f: &mut ratatui::Frame, area: Rect, tabline: &TabLine) {
+    let spans: Vec<Span> = tabline
+        .labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| {
+            let modified = if label.modified { " +" } else { "" };
+            let text = format!(" {} {}{} ", i + 1, label.name, modified);
+            let style = if i == tabline.current {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+                    .bg(RatatuiColor::DarkGray)
+                    .fg(RatatuiColor::White)
+            };
+            Span::styled(text, style)
+        })
+        .collect();
+    let style = Style::default().bg(RatatuiColor::DarkGray);
+    f.render_widget(Paragraph::new(Line::from(spans)).style(style), area);
+}
+/// What a window's status line shows besides its buffer and cursor. Only
+/// the current window shows the mode.
+struct StatusLine<'a> {
+    mode: Option<&'a EditorMode>,
+    search_count: Option<MatchCount>,
+}
+fn render_status_line(
+    f: &mut ratatui::Frame,
+    area: Rect,
+    buffer: &Buffer,
+    cursor: &Cursor,
+    status: &StatusLine,
+) {
     let file_name = buffer.file_path.as_deref().unwrap_or("[No Name]");
     let modified_indicator = if buffer.modified { " [+]" } else { "" };
-    let position = format!("{}:{}", buffer.cursor.row + 1, buffer.cursor.col + 1);
+    let position = format!("{}:{}", cursor.row + 1, cursor.col + 1);
     let line_count = buffer.line_count();
-    let status_content = format!(
-        " {} {} | {} | {} lines | {}",
-        mode.to_string(),
+    let mode = status.mode.map(|mode| format!(" {}", mode.to_string()));
+    let mut status_content = format!(
+        "{} {} | {} | {} lines | {}",
+        mode.as_deref().unwrap_or(""),
         file_name,
         modified_indicator,
         line_count,
         position
     );
+    if let Some(count) = status.search_count {
+        status_content.push_str(&format!(" | {}", count));
+    }
+    let background = if status.mode.is_some() {
+        RatatuiColor::Blue
+    } else {
+        RatatuiColor::DarkGray
+    };
     let status_line = Paragraph::new(status_content).style(
         Style::default()
-            .bg(RatatuiColor::Blue)
+            .bg(background)
             .fg(RatatuiColor::White)
             .add_modifier(Modifier::BOLD),
     );
@@ -128,11 +597,16 @@ fn render_command_line(
     f: &mut ratatui::Frame,
     area: Rect,
     mode: &EditorMode,
-    command_line: &str,
+    command_line: &CommandLine,
     message: &Option<String>,
 ) {
     let content = match mode {
-        EditorMode::Command => command_line.to_string(),
+        EditorMode::Command => {
+            let before = &command_line.text[..command_line.cursor];
+            let col = text::display_col(before, before.chars().count());
+            f.set_cursor(area.x + col.min(area.width as usize) as u16, area.y);
+            command_line.text.clone()
+        }
         _ => {
             if let Some(msg) = message {
                 msg.clone()
@@ -144,6 +618,54 @@ fn render_command_line(
     let command_widget = Paragraph::new(content);
     f.render_widget(command_widget, area);
 }
+/// Most completions the wildmenu shows at once.
+const WILDMENU_HEIGHT: usize = 10;
+/// Completions of the word being typed on the command line, in a popup
+/// above it that starts at the word's column and scrolls to keep the
+/// selected one in view.
+fn render_wildmenu(f: &mut ratatui::Frame, area: Rect, command_line: &CommandLine) {
+    let Some(menu) = &command_line.wildmenu else {
+        return;
+    };
+    let height = menu.items.len().min(WILDMENU_HEIGHT).min(area.y as usize);
+    if height == 0 {
+        return;
+    }
+    let selected = menu.selected.unwrap_or(0);
+    let first = (selected + 1).saturating_sub(height);
+    let width = menu
+        .items
+        .iter()
+        .map(|item| text::display_col(item, item.chars().count()))
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let width = width.min(area.width as usize) as u16;
+    let before = &command_line.text[..menu.start];
+    let col = text::display_col(before, before.chars().count()) as u16;
+    let x = area.x + col.min(area.width - width);
+    let popup = Rect::new(x, area.y - height as u16, width, height as u16);
+    let lines: Vec<Line> = menu
+        .items
+        .iter()
+        .enumerate()
+        .skip(first)
+        .take(height)
+        .map(|(i, item)| {
+            let style = if menu.selected == Some(i) {
+                Style::default().add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            Line::from(Span::styled(format!(" {} ", item), style))
+        })
+        .collect();
+    let style = Style::default()
+        .bg(RatatuiColor::DarkGray)
+        .fg(RatatuiColor::White);
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(lines).style(style), popup);
+}
 impl UI {
     #[allow(dead_code)]
     pub fn get_terminal_size(&self) -> Result<(u16, u16)> {
@@ -151,3 +673,42 @@ impl UI {
         Ok((size.width, size.height))
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    fn buffer_with_lines(count: usize, number: bool, relative: bool) -> Buffer {
+        let mut buffer = Buffer::new();
+        buffer.content = Rope::from_str(&"x\n".repeat(count - 1));
+        buffer.line_numbers = number;
+        buffer.relative_numbers = relative;
+        buffer
+    }
+
+    #[test]
+    fn test_gutter_fits_the_last_line_number() {
+        assert_eq!(gutter_width(&buffer_with_lines(3, false, false)), 0);
+        assert_eq!(gutter_width(&buffer_with_lines(3, true, false)), 5);
+        assert_eq!(gutter_width(&buffer_with_lines(3, false, true)), 5);
+        assert_eq!(gutter_width(&buffer_with_lines(9_999, true, false)), 5);
+        assert_eq!(gutter_width(&buffer_with_lines(10_000, true, false)), 6);
+    }
+
+    #[test]
+    fn test_absolute_relative_and_hybrid_numbers() {
+        let buffer = buffer_with_lines(20, true, false);
+        assert_eq!(line_number(&buffer, 0, 5, 5), "   1 ");
+        assert_eq!(line_number(&buffer, 11, 5, 5), "  12 ");
+        let buffer = buffer_with_lines(20, false, true);
+        assert_eq!(line_number(&buffer, 2, 5, 5), "   3 ");
+        assert_eq!(line_number(&buffer, 9, 5, 5), "   4 ");
+        assert_eq!(line_number(&buffer, 5, 5, 5), "   0 ");
+        // Hybrid: the cursor line shows its own number, aligned left.
+        let buffer = buffer_with_lines(20, true, true);
+        assert_eq!(line_number(&buffer, 5, 5, 5), "6    ");
+        assert_eq!(line_number(&buffer, 7, 5, 5), "   2 ");
+        let buffer = buffer_with_lines(20, false, false);
+        assert_eq!(line_number(&buffer, 5, 5, 0), "");
+    }
+}
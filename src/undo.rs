@@ -0,0 +1,543 @@
+use anyhow::Result;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const UNDO_FILE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EditOp {
+    Insert { pos: usize, text: String },
+    Delete { pos: usize, text: String },
+}
+impl EditOp {
+    pub fn pos(&self) -> usize {
+        match self {
+            EditOp::Insert { pos, .. } | EditOp::Delete { pos, .. } => *pos,
+        }
+    }
+    fn apply(&self, content: &mut Rope) {
+        match self {
+            EditOp::Insert { pos, text } => content.insert(*pos, text),
+            EditOp::Delete { pos, text } => content.remove(*pos..*pos + text.chars().count()),
+        }
+    }
+    fn revert(&self, content: &mut Rope) {
+        match self {
+            EditOp::Insert { pos, text } => content.remove(*pos..*pos + text.chars().count()),
+            EditOp::Delete { pos, text } => content.insert(*pos, text),
+        }
+    }
+    fn merge(&mut self, next: &EditOp) -> bool {
+        match (self, next) {
+            (
+                EditOp::Insert { pos, text },
+                EditOp::Insert {
+                    pos: next_pos,
+                    text: next_text,
+                },
+            ) if *pos + text.chars().count() == *next_pos => {
+                text.push_str(next_text);
+                true
+            }
+            (
+                EditOp::Delete { pos, text },
+                EditOp::Delete {
+                    pos: next_pos,
+                    text: next_text,
+                },
+            ) if *next_pos == *pos => {
+                text.push_str(next_text);
+                true
+            }
+            (
+                EditOp::Delete { pos, text },
+                EditOp::Delete {
+                    pos: next_pos,
+                    text: next_text,
+                },
+            ) if *next_pos + next_text.chars().count() == *pos => {
+                text.insert_str(0, next_text);
+                *pos = *next_pos;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UndoNode {
+    pub parent: Option<usize>,
+    pub last_child: Option<usize>,
+    pub ops: Vec<EditOp>,
+    pub time: SystemTime,
+}
+/// A tree of changes where every node is one logical undo step. Node indices
+/// double as change numbers, so `g-`/`g+` simply walk to the previous or next
+/// index, crossing branches when needed.
+#[derive(Serialize, Deserialize)]
+pub struct UndoTree {
+    nodes: Vec<UndoNode>,
+    current: usize,
+    #[serde(skip)]
+    pending: Vec<EditOp>,
+    #[serde(skip, default = "SystemTime::now")]
+    pending_time: SystemTime,
+    #[serde(skip)]
+    group_depth: usize,
+}
+impl UndoTree {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![UndoNode {
+                parent: None,
+                last_child: None,
+                ops: Vec::new(),
+                time: SystemTime::now(),
+            }],
+            current: 0,
+            pending: Vec::new(),
+            pending_time: SystemTime::now(),
+            group_depth: 0,
+        }
+    }
+    pub fn record(&mut self, op: EditOp) {
+        if self.pending.is_empty() {
+            self.pending_time = SystemTime::now();
+        }
+        if let Some(last) = self.pending.last_mut() {
+            if last.merge(&op) {
+                return;
+            }
+        }
+        self.pending.push(op);
+    }
+    /// Closes the change in progress unless an undo group is open.
+    pub fn checkpoint(&mut self) {
+        if self.group_depth == 0 {
+            self.commit();
+        }
+    }
+    pub fn begin_group(&mut self) {
+        if self.group_depth == 0 {
+            self.commit();
+        }
+        self.group_depth += 1;
+    }
+    pub fn end_group(&mut self) {
+        self.group_depth = self.group_depth.saturating_sub(1);
+        if self.group_depth == 0 {
+            self.commit();
+        }
+    }
+    fn commit(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let index = self.nodes.len();
+        self.nodes.push(UndoNode {
+            parent: Some(self.current),
+            last_child: None,
+            ops: std::mem::take(&mut self.pending),
+            time: self.pending_time,
+        });
+        self.nodes[self.current].last_child = Some(index);
+        self.current = index;
+    }
+    pub fn current(&self) -> usize {
+        self.current
+    }
+    /// Change number the text is at, a change in progress counting as the
+    /// node it will be committed to.
+    pub fn change_number(&self) -> usize {
+        if self.pending.is_empty() {
+            self.current
+        } else {
+            self.nodes.len()
+        }
+    }
+    pub fn undo(&mut self, content: &mut Rope) -> Option<usize> {
+        self.commit();
+        let parent = self.nodes[self.current].parent?;
+        let pos = self.revert_node(self.current, content);
+        self.nodes[parent].last_child = Some(self.current);
+        self.current = parent;
+        Some(pos)
+    }
+    pub fn redo(&mut self, content: &mut Rope) -> Option<usize> {
+        self.commit();
+        let child = self.nodes[self.current].last_child?;
+        let pos = self.apply_node(child, content);
+        self.current = child;
+        Some(pos)
+    }
+    /// Moves to change number `target`, undoing up to the common ancestor and
+    /// redoing down the target's branch.
+    pub fn goto(&mut self, target: usize, content: &mut Rope) -> Option<usize> {
+        self.commit();
+        if target >= self.nodes.len() || target == self.current {
+            return None;
+        }
+        let mut target_path = vec![target];
+        while let Some(parent) = self.nodes[*target_path.last().unwrap()].parent {
+            target_path.push(parent);
+        }
+        let mut pos = None;
+        while !target_path.contains(&self.current) {
+            pos = Some(self.revert_node(self.current, content));
+            let parent = self.nodes[self.current].parent.unwrap_or(0);
+            self.nodes[parent].last_child = Some(self.current);
+            self.current = parent;
+        }
+        let ancestor = target_path.iter().position(|&n| n == self.current)?;
+        for &node in target_path[..ancestor].iter().rev() {
+            pos = Some(self.apply_node(node, content));
+            self.nodes[self.current].last_child = Some(node);
+            self.current = node;
+        }
+        pos
+    }
+    pub fn earlier(&mut self, travel: UndoTravel, content: &mut Rope) -> Option<usize> {
+        self.commit();
+        let target = match travel {
+            UndoTravel::Steps(steps) => self.current.saturating_sub(steps),
+            UndoTravel::Time(duration) => {
+                let time = self.nodes[self.current]
+                    .time
+                    .checked_sub(duration)
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                self.last_change_before(time)
+            }
+        };
+        self.goto(target, content)
+    }
+    pub fn later(&mut self, travel: UndoTravel, content: &mut Rope) -> Option<usize> {
+        self.commit();
+        let target = match travel {
+            UndoTravel::Steps(steps) => {
+                self.current.saturating_add(steps).min(self.nodes.len() - 1)
+            }
+            UndoTravel::Time(duration) => match self.nodes[self.current].time.checked_add(duration)
+            {
+                Some(time) => self.last_change_before(time).max(self.current),
+                None => self.nodes.len() - 1,
+            },
+        };
+        self.goto(target, content)
+    }
+    fn last_change_before(&self, time: SystemTime) -> usize {
+        self.nodes
+            .iter()
+            .rposition(|node| node.time <= time)
+            .unwrap_or(0)
+    }
+    /// Returns `(change number, depth, time)` for every leaf of the tree.
+    pub fn leaves(&self) -> Vec<(usize, usize, SystemTime)> {
+        (1..self.nodes.len())
+            .filter(|&index| !self.nodes.iter().any(|node| node.parent == Some(index)))
+            .map(|index| (index, self.depth(index), self.nodes[index].time))
+            .collect()
+    }
+    fn depth(&self, mut index: usize) -> usize {
+        let mut depth = 0;
+        while let Some(parent) = self.nodes[index].parent {
+            depth += 1;
+            index = parent;
+        }
+        depth
+    }
+    fn apply_node(&self, index: usize, content: &mut Rope) -> usize {
+        let ops = &self.nodes[index].ops;
+        for op in ops {
+            op.apply(content);
+        }
+        ops.first().map_or(0, EditOp::pos)
+    }
+    fn revert_node(&self, index: usize, content: &mut Rope) -> usize {
+        let ops = &self.nodes[index].ops;
+        for op in ops.iter().rev() {
+            op.revert(content);
+        }
+        ops.first().map_or(0, EditOp::pos)
+    }
+}
+#[derive(Serialize, Deserialize)]
+struct UndoFile {
+    version: u32,
+    hash: u64,
+    tree: UndoTree,
+}
+impl UndoTree {
+    /// Writes the committed history to `path`, tagged with the hash of the
+    /// text it applies to.
+    pub fn write_to(&mut self, path: &Path, content: &Rope) -> Result<()> {
+        self.commit();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let undo_file = UndoFile {
+            version: UNDO_FILE_VERSION,
+            hash: content_hash(content),
+            tree: std::mem::replace(self, UndoTree::new()),
+        };
+        let result = serde_json::to_string(&undo_file);
+        *self = undo_file.tree;
+        fs::write(path, result?)?;
+        Ok(())
+    }
+    /// Reads a history written by `write_to`. Returns `None` when the file is
+    /// missing, unreadable, or was written for different text.
+    pub fn read_from(path: &Path, content: &Rope) -> Option<Self> {
+        let data = fs::read_to_string(path).ok()?;
+        let undo_file: UndoFile = serde_json::from_str(&data).ok()?;
+        if undo_file.version != UNDO_FILE_VERSION || undo_file.hash != content_hash(content) {
+            return None;
+        }
+        let tree = undo_file.tree;
+        let valid = tree.current < tree.nodes.len()
+            && tree.nodes.iter().all(|node| {
+                node.parent.iter().all(|&p| p < tree.nodes.len())
+                    && node.last_child.iter().all(|&c| c < tree.nodes.len())
+            });
+        valid.then_some(tree)
+    }
+}
+/// Location of the undo file for `file_path` inside `undo_dir`, named after
+/// the absolute path with separators replaced by `%` like vim does.
+pub fn undo_file_path(undo_dir: &Path, file_path: &str) -> PathBuf {
+    let absolute = fs::canonicalize(file_path).unwrap_or_else(|_| PathBuf::from(file_path));
+    let name = absolute
+        .to_string_lossy()
+        .replace(std::path::MAIN_SEPARATOR, "%");
+    undo_dir.join(name)
+}
+pub fn default_undo_dir() -> PathBuf {
+    data_dir().join("undo")
+}
+/// Where state kept between sessions lives, `~/.local/share/vimrust` unless
+/// `XDG_DATA_HOME` says otherwise.
+pub fn data_dir() -> PathBuf {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .unwrap_or_else(std::env::temp_dir);
+    data_home.join("vimrust")
+}
+/// FNV-1a over the text, stable across builds so undo files stay valid.
+fn content_hash(content: &Rope) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for chunk in content.chunks() {
+        for byte in chunk.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+#[derive(Debug, Clone, Copy)]
+pub enum UndoTravel {
+    Steps(usize),
+    Time(Duration),
+}
+impl UndoTravel {
+    pub fn parse(arg: &str) -> Option<Self> {
+        let arg = arg.trim();
+        if arg.is_empty() {
+            return Some(UndoTravel::Steps(1));
+        }
+        let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+        let count: u64 = arg[..split].parse().ok()?;
+        let seconds = match &arg[split..] {
+            "" => return Some(UndoTravel::Steps(count as usize)),
+            "s" => count,
+            // A huge count goes back to the first change rather than
+            // overflowing.
+            "m" => count.saturating_mul(60),
+            "h" => count.saturating_mul(60 * 60),
+            "d" => count.saturating_mul(60 * 60 * 24),
+            _ => return None,
+        };
+        Some(UndoTravel::Time(Duration::from_secs(seconds)))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes `op` on `content` and records it as one change.
+    fn change(tree: &mut UndoTree, content: &mut Rope, op: EditOp) {
+        op.apply(content);
+        tree.record(op);
+        tree.checkpoint();
+    }
+    fn insert(pos: usize, text: &str) -> EditOp {
+        EditOp::Insert {
+            pos,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_undo_redo_across_branch() {
+        let mut tree = UndoTree::new();
+        let mut content = Rope::from_str("abc");
+        change(&mut tree, &mut content, insert(3, "x"));
+        assert_eq!(tree.undo(&mut content), Some(3));
+        assert_eq!(content.to_string(), "abc");
+        change(&mut tree, &mut content, insert(0, "y"));
+        assert_eq!(content.to_string(), "yabc");
+        tree.undo(&mut content);
+        assert_eq!(content.to_string(), "abc");
+        // Redo follows the branch made last.
+        tree.redo(&mut content);
+        assert_eq!(content.to_string(), "yabc");
+        assert_eq!(tree.current(), 2);
+        assert_eq!(tree.undo(&mut content), Some(0));
+        assert_eq!(tree.undo(&mut content), None);
+        assert_eq!(content.to_string(), "abc");
+    }
+
+    #[test]
+    fn test_goto_crosses_branches() {
+        let mut tree = UndoTree::new();
+        let mut content = Rope::from_str("abc");
+        change(&mut tree, &mut content, insert(3, "x"));
+        change(&mut tree, &mut content, insert(4, "z"));
+        tree.undo(&mut content);
+        tree.undo(&mut content);
+        change(&mut tree, &mut content, insert(0, "y"));
+        assert_eq!(content.to_string(), "yabc");
+        tree.goto(2, &mut content);
+        assert_eq!(content.to_string(), "abcxz");
+        tree.goto(3, &mut content);
+        assert_eq!(content.to_string(), "yabc");
+        tree.goto(0, &mut content);
+        assert_eq!(content.to_string(), "abc");
+        assert_eq!(tree.goto(0, &mut content), None);
+        assert_eq!(tree.goto(9, &mut content), None);
+        let leaves: Vec<usize> = tree.leaves().iter().map(|leaf| leaf.0).collect();
+        assert_eq!(leaves, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_earlier_and_later_by_steps() {
+        let mut tree = UndoTree::new();
+        let mut content = Rope::new();
+        for (pos, text) in ["a", "b", "c"].iter().enumerate() {
+            change(&mut tree, &mut content, insert(pos, text));
+        }
+        tree.earlier(UndoTravel::Steps(2), &mut content);
+        assert_eq!(content.to_string(), "a");
+        tree.later(UndoTravel::Steps(1), &mut content);
+        assert_eq!(content.to_string(), "ab");
+        tree.later(UndoTravel::Steps(10), &mut content);
+        assert_eq!(content.to_string(), "abc");
+        tree.earlier(UndoTravel::Time(Duration::from_secs(3600)), &mut content);
+        assert_eq!(content.to_string(), "");
+    }
+
+    #[test]
+    fn test_typed_inserts_are_one_change() {
+        let mut tree = UndoTree::new();
+        let mut content = Rope::new();
+        for (pos, text) in ["h", "i", "!"].iter().enumerate() {
+            let op = insert(pos, text);
+            op.apply(&mut content);
+            tree.record(op);
+        }
+        assert_eq!(tree.change_number(), 1);
+        tree.checkpoint();
+        assert_eq!(tree.current(), 1);
+        tree.undo(&mut content);
+        assert_eq!(content.to_string(), "");
+    }
+
+    #[test]
+    fn test_group_is_one_change() {
+        let mut tree = UndoTree::new();
+        let mut content = Rope::from_str("one two");
+        tree.begin_group();
+        change(
+            &mut tree,
+            &mut content,
+            EditOp::Delete {
+                pos: 0,
+                text: "one".to_string(),
+            },
+        );
+        change(&mut tree, &mut content, insert(0, "1"));
+        tree.end_group();
+        assert_eq!(content.to_string(), "1 two");
+        tree.undo(&mut content);
+        assert_eq!(content.to_string(), "one two");
+    }
+
+    #[test]
+    fn test_undo_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("vimrust-undo-test-{}", std::process::id()));
+        let path = dir.join("file");
+        let mut tree = UndoTree::new();
+        let mut content = Rope::from_str("abc");
+        change(&mut tree, &mut content, insert(3, "d"));
+        change(&mut tree, &mut content, insert(4, "e"));
+        tree.write_to(&path, &content).unwrap();
+
+        let mut read = UndoTree::read_from(&path, &content).expect("history for the same text");
+        assert_eq!(read.current(), 2);
+        read.undo(&mut content);
+        read.undo(&mut content);
+        assert_eq!(content.to_string(), "abc");
+        read.redo(&mut content);
+        assert_eq!(content.to_string(), "abcd");
+
+        // The file was written for "abcde".
+        assert!(UndoTree::read_from(&path, &Rope::from_str("abcd")).is_none());
+        assert!(UndoTree::read_from(&dir.join("missing"), &content).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_undo_travel() {
+        assert!(matches!(UndoTravel::parse(""), Some(UndoTravel::Steps(1))));
+        assert!(matches!(UndoTravel::parse("3"), Some(UndoTravel::Steps(3))));
+        assert!(matches!(UndoTravel::parse("10s"), Some(UndoTravel::Time(d)) if d.as_secs() == 10));
+        assert!(matches!(UndoTravel::parse("2m"), Some(UndoTravel::Time(d)) if d.as_secs() == 120));
+        assert!(
+            matches!(UndoTravel::parse("1h"), Some(UndoTravel::Time(d)) if d.as_secs() == 3600)
+        );
+        assert!(UndoTravel::parse("5x").is_none());
+        assert!(UndoTravel::parse("m").is_none());
+    }
+
+    #[test]
+    fn test_huge_travel_saturates() {
+        let huge = "999999999999999999";
+        for unit in ["m", "h", "d"] {
+            let parsed = UndoTravel::parse(&format!("{}{}", huge, unit));
+            assert!(matches!(parsed, Some(UndoTravel::Time(d)) if d.as_secs() == u64::MAX));
+        }
+        assert!(UndoTravel::parse("99999999999999999999999").is_none());
+        let mut tree = UndoTree::new();
+        let mut content = Rope::from_str("abc");
+        change(&mut tree, &mut content, insert(0, "x"));
+        change(&mut tree, &mut content, insert(0, "y"));
+        let Some(UndoTravel::Time(forever)) = UndoTravel::parse(&format!("{}d", huge)) else {
+            unreachable!();
+        };
+        tree.earlier(UndoTravel::Time(forever), &mut content);
+        assert_eq!(
+            (tree.current(), content.to_string()),
+            (0, "abc".to_string())
+        );
+        tree.later(UndoTravel::Steps(usize::MAX), &mut content);
+        assert_eq!(
+            (tree.current(), content.to_string()),
+            (2, "yxabc".to_string())
+        );
+        tree.earlier(UndoTravel::Steps(usize::MAX), &mut content);
+        tree.later(UndoTravel::Time(forever), &mut content);
+        assert_eq!(tree.current(), 2);
+    }
+}
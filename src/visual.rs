@@ -0,0 +1,191 @@
+use crate::motion::MotionRange;
+use crate::text;
+use ropey::Rope;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VisualMode {
+    Char,
+    Line,
+    Block,
+}
+/// A visual selection from `anchor`, where it was started, to `cursor`.
+/// Both are `(row, col)` with `col` a char index into the line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selection {
+    pub mode: VisualMode,
+    pub anchor: (usize, usize),
+    pub cursor: (usize, usize),
+    /// Set by `$` in block mode: every line is selected to its own end.
+    pub to_eol: bool,
+}
+/// Rectangle covered by a block selection, in display columns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub first_row: usize,
+    pub last_row: usize,
+    pub left: usize,
+    /// Exclusive right edge, or `None` when extended to the line ends.
+    pub right: Option<usize>,
+}
+impl Selection {
+    pub fn new(mode: VisualMode, row: usize, col: usize) -> Self {
+        Self {
+            mode,
+            anchor: (row, col),
+            cursor: (row, col),
+            to_eol: false,
+        }
+    }
+    pub fn first_row(&self) -> usize {
+        self.anchor.0.min(self.cursor.0)
+    }
+    pub fn last_row(&self) -> usize {
+        self.anchor.0.max(self.cursor.0)
+    }
+    /// Text selected in char or line mode. Char selections include the char
+    /// under the cursor, and the line ending when it sits past the text.
+    pub fn range(&self, content: &Rope) -> MotionRange {
+        if self.mode == VisualMode::Line {
+            return MotionRange::lines(content, self.first_row(), self.last_row());
+        }
+        let index = |(row, col): (usize, usize)| {
+            (content.line_to_char(row) + col.min(text::line_len(content, row)))
+                .min(content.len_chars())
+        };
+        let (anchor, cursor) = (index(self.anchor), index(self.cursor));
+        MotionRange {
+            start: anchor.min(cursor),
+            end: (anchor.max(cursor) + 1).min(content.len_chars()),
+            linewise: false,
+        }
+    }
+    pub fn block(&self, content: &Rope) -> Block {
+        let edges = |(row, col): (usize, usize)| {
+            let line = text::line_text(content, row);
+            let start = text::display_col(&line, col);
+            let width = line
+                .chars()
+                .nth(col)
+                .map_or(1, |c| text::char_width(c, start).max(1));
+            (start, start + width)
+        };
+        let (anchor_left, anchor_right) = edges(self.anchor);
+        let (cursor_left, cursor_right) = edges(self.cursor);
+        Block {
+            first_row: self.first_row(),
+            last_row: self.last_row(),
+            left: anchor_left.min(cursor_left),
+            right: (!self.to_eol).then_some(anchor_right.max(cursor_right)),
+        }
+    }
+    /// Display columns `start..end` to highlight on `row`. The range may run
+    /// one cell past the text to show a selected line ending.
+    pub fn highlight(&self, content: &Rope, row: usize) -> Option<(usize, usize)> {
+        if row < self.first_row() || row > self.last_row() {
+            return None;
+        }
+        let line = text::line_text(content, row);
+        let len = line.chars().count();
+        let width = text::display_col(&line, len);
+        match self.mode {
+            VisualMode::Line => Some((0, width.max(1))),
+            VisualMode::Block => {
+                let block = self.block(content);
+                let right = block.right.unwrap_or(width).min(width);
+                Some((block.left.min(right), right))
+            }
+            VisualMode::Char => {
+                let (start, end) = if self.anchor <= self.cursor {
+                    (self.anchor, self.cursor)
+                } else {
+                    (self.cursor, self.anchor)
+                };
+                let first = if row == start.0 { start.1 } else { 0 };
+                let last = if row == end.0 { end.1 } else { len };
+                let left = text::display_col(&line, first.min(len));
+                let right = if last >= len {
+                    width + 1
+                } else {
+                    let next = text::next_grapheme(&line, last);
+                    text::display_col(&line, next)
+                };
+                Some((left, right))
+            }
+        }
+    }
+}
+impl Block {
+    /// Char columns `start..end` of the block on `row`, covering every char
+    /// that overlaps it and clipped to the line.
+    pub fn cols(&self, content: &Rope, row: usize) -> (usize, usize) {
+        let line = text::line_text(content, row);
+        let mut display = 0;
+        let mut cols = None::<(usize, usize)>;
+        for (col, c) in line.chars().enumerate() {
+            let width = text::char_width(c, display);
+            let overlaps = display + width.max(1) > self.left
+                && self.right.iter().all(|&right| display < right);
+            if overlaps {
+                cols = Some((cols.map_or(col, |(start, _)| start), col + 1));
+            }
+            display += width;
+        }
+        cols.unwrap_or_else(|| {
+            let col = text::col_for_display(&line, self.left);
+            (col, col)
+        })
+    }
+}
+/// A block `I` or `A` in progress: text typed on `row` from `col` is
+/// copied to the other lines of the block when Insert mode ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockInsert {
+    pub row: usize,
+    pub col: usize,
+    pub line_len: usize,
+    pub other_rows: std::ops::RangeInclusive<usize>,
+    /// Display column to insert at on the other lines, `None` for line ends.
+    pub display: Option<usize>,
+    pub pad: bool,
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_selection_covers_whole_lines() {
+        let content = Rope::from_str("ab\ncd\nef\n");
+        let mut selection = Selection::new(VisualMode::Line, 1, 1);
+        selection.cursor = (0, 0);
+        let range = selection.range(&content);
+        assert_eq!((range.start, range.end, range.linewise), (0, 6, true));
+        assert_eq!(selection.highlight(&content, 1), Some((0, 2)));
+        assert_eq!(selection.highlight(&content, 2), None);
+    }
+
+    #[test]
+    fn test_block_uses_display_columns() {
+        let content = Rope::from_str("日本語\nabcdef\n");
+        // The cursor on `本` covers cells 2..4 of the first line.
+        let mut selection = Selection::new(VisualMode::Block, 0, 1);
+        selection.cursor = (1, 3);
+        let block = selection.block(&content);
+        assert_eq!((block.left, block.right), (2, Some(4)));
+        assert_eq!(block.cols(&content, 0), (1, 2));
+        assert_eq!(block.cols(&content, 1), (2, 4));
+        assert_eq!(selection.highlight(&content, 1), Some((2, 4)));
+        selection.to_eol = true;
+        assert_eq!(selection.block(&content).right, None);
+        assert_eq!(selection.highlight(&content, 1), Some((2, 6)));
+    }
+
+    #[test]
+    fn test_block_past_a_short_line_is_empty() {
+        let content = Rope::from_str("abcdef\nx\n");
+        let mut selection = Selection::new(VisualMode::Block, 0, 3);
+        selection.cursor = (1, 4);
+        let block = selection.block(&content);
+        assert_eq!(block.cols(&content, 1), (1, 1));
+        assert_eq!(selection.highlight(&content, 1), Some((1, 1)));
+    }
+}
@@ -0,0 +1,604 @@
+use crate::cursor::Cursor;
+use ratatui::layout::Rect;
+
+/// Fewest rows a window takes: one line of text and its status line.
+const MIN_HEIGHT: u16 = 2;
+/// Fewest columns a window takes, besides the separator on its right.
+const MIN_WIDTH: u16 = 1;
+
+/// A view onto a buffer with its own cursor and scroll position. Several
+/// windows can show the same buffer, each seeing the others' edits.
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub id: usize,
+    pub buffer: usize,
+    pub cursor: Cursor,
+    /// First buffer line shown.
+    pub viewport_start: usize,
+    /// First display column shown while lines do not wrap.
+    pub left_col: usize,
+}
+/// How a split arranges the windows in it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Split {
+    /// Stacked above each other, as by `:split`.
+    Horizontal,
+    /// Side by side, as by `:vsplit`.
+    Vertical,
+}
+#[derive(Debug, Clone)]
+enum Node {
+    Window(usize),
+    /// Children with the rows (or columns, separator included) each took
+    /// at the last layout. New sizes are shared out in the same proportion.
+    Split(Split, Vec<(Node, u16)>),
+}
+/// Where a window was drawn at the last layout.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowArea {
+    pub id: usize,
+    pub text: Rect,
+    pub status: Rect,
+}
+/// Windows on screen, arranged by splits nested to any depth.
+#[derive(Debug, Clone)]
+pub struct WindowLayout {
+    root: Node,
+    windows: Vec<Window>,
+    /// Id of the window with the cursor.
+    current: usize,
+    next_id: usize,
+    /// Areas from the last `arrange`, for moving between windows by
+    /// direction and checking there is room to split.
+    areas: Vec<WindowArea>,
+}
+impl WindowLayout {
+    /// A single window showing `buffer`.
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            root: Node::Window(0),
+            windows: vec![Window {
+                id: 0,
+                buffer,
+                cursor: Cursor::new(),
+                viewport_start: 0,
+                left_col: 0,
+            }],
+            current: 0,
+            next_id: 1,
+            areas: Vec::new(),
+        }
+    }
+    pub fn current(&self) -> &Window {
+        self.window(self.current)
+    }
+    pub fn current_mut(&mut self) -> &mut Window {
+        let id = self.current;
+        self.windows
+            .iter_mut()
+            .find(|window| window.id == id)
+            .expect("current window exists")
+    }
+    pub fn window(&self, id: usize) -> &Window {
+        self.windows
+            .iter()
+            .find(|window| window.id == id)
+            .expect("window exists")
+    }
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+    pub fn windows_mut(&mut self) -> &mut [Window] {
+        &mut self.windows
+    }
+    pub fn count(&self) -> usize {
+        self.windows.len()
+    }
+    /// Window ids from top left to bottom right.
+    pub fn order(&self) -> Vec<usize> {
+        let mut ids = Vec::new();
+        self.root.collect(&mut ids);
+        ids
+    }
+    pub fn focus(&mut self, id: usize) {
+        self.current = id;
+    }
+    /// Splits the current window in two, the new half (above or to the
+    /// left) showing the same place and becoming current.
+    pub fn split(&mut self, split: Split) -> Result<(), String> {
+        if let Some(area) = self.areas.iter().find(|area| area.id == self.current) {
+            let room = match split {
+                Split::Horizontal => area.text.height + area.status.height >= 2 * MIN_HEIGHT,
+                Split::Vertical => area.status.width > 2 * MIN_WIDTH,
+            };
+            if !room {
+                return Err("E36: Not enough room".to_string());
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let window = Window {
+            id,
+            ..self.current().clone()
+        };
+        self.windows.push(window);
+        self.root.split(self.current, id, split);
+        self.current = id;
+        Ok(())
+    }
+    /// Closes the current window, giving its room to a neighbour, which
+    /// becomes current.
+    pub fn close(&mut self) -> Result<(), String> {
+        if self.windows.len() == 1 {
+            return Err("E444: Cannot close last window".to_string());
+        }
+        let id = self.current;
+        let next = self.root.remove(id).expect("window is in the layout");
+        self.windows.retain(|window| window.id != id);
+        self.current = next;
+        Ok(())
+    }
+    /// Closes every window but the current one.
+    pub fn only(&mut self) {
+        self.root = Node::Window(self.current);
+        let id = self.current;
+        self.windows.retain(|window| window.id == id);
+    }
+    /// Makes every window in each split the same size.
+    pub fn equalize(&mut self) {
+        self.root.equalize();
+    }
+    /// Grows (or shrinks) the current window by `delta` rows or columns,
+    /// taking them from the window after it, or before it if it is last.
+    pub fn resize(&mut self, split: Split, delta: i32) {
+        self.root.resize(self.current, split, delta);
+    }
+    /// The `count`th window, or the one after (or before) the current one,
+    /// wrapping around.
+    pub fn cycle(&mut self, forward: bool, count: Option<usize>) {
+        let order = self.order();
+        let index = match count {
+            Some(n) => n.clamp(1, order.len()) - 1,
+            None => {
+                let index = order.iter().position(|&id| id == self.current).unwrap_or(0);
+                if forward {
+                    (index + 1) % order.len()
+                } else {
+                    (index + order.len() - 1) % order.len()
+                }
+            }
+        };
+        self.current = order[index];
+    }
+    /// The nearest window left, below, above or right of the current one
+    /// (`h`, `j`, `k`, `l`), preferring the one beside the cursor row.
+    pub fn neighbor(&self, direction: char) -> Option<usize> {
+        let current = self.areas.iter().find(|area| area.id == self.current)?;
+        let cursor_row = self
+            .current()
+            .cursor
+            .row
+            .saturating_sub(self.current().viewport_start);
+        let row = current.text.y + (cursor_row as u16).min(current.text.height.saturating_sub(1));
+        let col = current.text.x;
+        let whole = |area: &WindowArea| area.text.union(area.status);
+        let here = whole(current);
+        self.areas
+            .iter()
+            .filter(|area| area.id != self.current)
+            .filter_map(|area| {
+                let there = whole(area);
+                let overlap_rows = there.y < here.bottom() && there.bottom() > here.y;
+                let overlap_cols = there.x < here.right() && there.right() > here.x;
+                let distance = match direction {
+                    'h' if overlap_rows && there.right() <= here.x => here.x - there.right(),
+                    'l' if overlap_rows && there.x >= here.right() => there.x - here.right(),
+                    'k' if overlap_cols && there.bottom() <= here.y => here.y - there.bottom(),
+                    'j' if overlap_cols && there.y >= here.bottom() => there.y - here.bottom(),
+                    _ => return None,
+                };
+                let beside = match direction {
+                    'h' | 'l' => there.y <= row && row < there.bottom(),
+                    _ => there.x <= col && col < there.right(),
+                };
+                Some((distance, !beside, there.y, there.x, area.id))
+            })
+            .min()
+            .map(|(.., id)| id)
+    }
+    /// Where the current window was drawn, if the layout was drawn yet.
+    pub fn current_area(&self) -> Option<&WindowArea> {
+        self.areas.iter().find(|area| area.id == self.current)
+    }
+    /// Columns and rows the windows need at the least.
+    pub fn min_size(&self) -> (u16, u16) {
+        self.root.min_size()
+    }
+    /// Shares `area` out among the windows, remembering the sizes, and
+    /// returns each window's area and the columns separating side by side
+    /// windows.
+    pub fn arrange(&mut self, area: Rect) -> (Vec<WindowArea>, Vec<Rect>) {
+        let mut areas = Vec::new();
+        let mut separators = Vec::new();
+        self.root.arrange(area, &mut areas, &mut separators);
+        self.areas = areas.clone();
+        (areas, separators)
+    }
+    /// After buffer `removed` is deleted, windows showing it show
+    /// `replacement` instead and the others keep their buffer.
+    pub fn buffer_removed(&mut self, removed: usize, replacement: usize) {
+        for window in &mut self.windows {
+            if window.buffer == removed {
+                window.buffer = replacement;
+                window.cursor = Cursor::new();
+                window.viewport_start = 0;
+                window.left_col = 0;
+            } else if window.buffer > removed {
+                window.buffer -= 1;
+            }
+        }
+    }
+}
+impl Node {
+    fn collect(&self, ids: &mut Vec<usize>) {
+        match self {
+            Node::Window(id) => ids.push(*id),
+            Node::Split(_, children) => {
+                for (child, _) in children {
+                    child.collect(ids);
+                }
+            }
+        }
+    }
+    fn first_window(&self) -> usize {
+        match self {
+            Node::Window(id) => *id,
+            Node::Split(_, children) => children[0].0.first_window(),
+        }
+    }
+    fn contains(&self, id: usize) -> bool {
+        match self {
+            Node::Window(window) => *window == id,
+            Node::Split(_, children) => children.iter().any(|(child, _)| child.contains(id)),
+        }
+    }
+    /// Puts window `new` before window `id`, halving its room. A split the
+    /// same way as its parent joins the parent's windows.
+    fn split(&mut self, id: usize, new: usize, split: Split) -> bool {
+        match self {
+            Node::Window(window) if *window == id => {
+                *self = Node::Split(split, vec![(Node::Window(new), 1), (Node::Window(id), 1)]);
+                true
+            }
+            Node::Window(_) => false,
+            Node::Split(direction, children) => {
+                if *direction == split {
+                    if let Some(i) = children
+                        .iter()
+                        .position(|(child, _)| matches!(child, Node::Window(w) if *w == id))
+                    {
+                        let size = children[i].1;
+                        children[i].1 = size - size / 2;
+                        children.insert(i, (Node::Window(new), (size / 2).max(1)));
+                        return true;
+                    }
+                }
+                children
+                    .iter_mut()
+                    .any(|(child, _)| child.split(id, new, split))
+            }
+        }
+    }
+    /// Removes window `id`, returning the window that gets its room.
+    fn remove(&mut self, id: usize) -> Option<usize> {
+        let Node::Split(_, children) = self else {
+            return None;
+        };
+        let Some(i) = children
+            .iter()
+            .position(|(child, _)| matches!(child, Node::Window(w) if *w == id))
+        else {
+            return children.iter_mut().find_map(|(child, _)| child.remove(id));
+        };
+        let (_, size) = children.remove(i);
+        let receiver = i.saturating_sub(1).min(children.len() - 1);
+        children[receiver].1 += size;
+        let next = children[receiver].0.first_window();
+        if children.len() == 1 {
+            let (only, _) = children.remove(0);
+            *self = only;
+        }
+        Some(next)
+    }
+    fn equalize(&mut self) {
+        if let Node::Split(_, children) = self {
+            let total: u16 = children.iter().map(|(_, size)| size).sum();
+            let each = (total / children.len() as u16).max(1);
+            let last = children.len() - 1;
+            for (i, (child, size)) in children.iter_mut().enumerate() {
+                *size = if i == last {
+                    total.saturating_sub(each * last as u16).max(1)
+                } else {
+                    each
+                };
+                child.equalize();
+            }
+        }
+    }
+    /// Resizes the child holding window `id` in the innermost split going
+    /// the `split` way. Returns whether some split took the change.
+    fn resize(&mut self, id: usize, split: Split, delta: i32) -> bool {
+        let Node::Split(direction, children) = self else {
+            return false;
+        };
+        let Some(i) = children.iter().position(|(child, _)| child.contains(id)) else {
+            return false;
+        };
+        if children[i].0.resize(id, split, delta) {
+            return true;
+        }
+        if *direction != split || children.len() < 2 {
+            return false;
+        }
+        let other = if i + 1 < children.len() { i + 1 } else { i - 1 };
+        let min = match split {
+            Split::Horizontal => MIN_HEIGHT,
+            Split::Vertical => MIN_WIDTH + 1,
+        } as i32;
+        let (size, other_size) = (children[i].1 as i32, children[other].1 as i32);
+        let delta = delta.clamp((min - size).min(0), (other_size - min).max(0));
+        children[i].1 = (size + delta) as u16;
+        children[other].1 = (other_size - delta) as u16;
+        true
+    }
+    /// Columns and rows needed to show every window in this node.
+    fn min_size(&self) -> (u16, u16) {
+        match self {
+            Node::Window(_) => (MIN_WIDTH, MIN_HEIGHT),
+            Node::Split(split, children) => {
+                let sizes = children.iter().map(|(child, _)| child.min_size());
+                match split {
+                    Split::Horizontal => {
+                        sizes.fold((0, 0), |(width, height), (w, h)| (width.max(w), height + h))
+                    }
+                    // One separator column between each pair.
+                    Split::Vertical => {
+                        let (width, height) = sizes.fold((0, 0), |(width, height), (w, h)| {
+                            (width + w + 1, height.max(h))
+                        });
+                        (width - 1, height)
+                    }
+                }
+            }
+        }
+    }
+    fn arrange(&mut self, area: Rect, areas: &mut Vec<WindowArea>, separators: &mut Vec<Rect>) {
+        let (split, children) = match self {
+            Node::Window(id) => {
+                let text_height = area.height.saturating_sub(1);
+                areas.push(WindowArea {
+                    id: *id,
+                    text: Rect::new(area.x, area.y, area.width, text_height),
+                    status: Rect::new(area.x, area.y + text_height, area.width, area.height.min(1)),
+                });
+                return;
+            }
+            Node::Split(split, children) => (*split, children),
+        };
+        let total = match split {
+            Split::Horizontal => area.height,
+            Split::Vertical => area.width,
+        };
+        share(children, split, total);
+        let mut offset = 0;
+        let last = children.len() - 1;
+        for (i, (child, size)) in children.iter_mut().enumerate() {
+            let child_area = match split {
+                Split::Horizontal => Rect::new(area.x, area.y + offset, area.width, *size),
+                Split::Vertical if i < last && *size > 0 => {
+                    let width = *size - 1;
+                    separators.push(Rect::new(area.x + offset + width, area.y, 1, area.height));
+                    Rect::new(area.x + offset, area.y, width, area.height)
+                }
+                Split::Vertical => Rect::new(area.x + offset, area.y, *size, area.height),
+            };
+            offset += *size;
+            child.arrange(child_area, areas, separators);
+        }
+    }
+}
+/// Scales the children's sizes to add up to `total`, keeping their
+/// proportions, then grows any below its minimum at the expense of those
+/// with the most room to spare.
+fn share(children: &mut [(Node, u16)], split: Split, total: u16) {
+    let last = children.len() - 1;
+    let mins: Vec<u16> = children
+        .iter()
+        .enumerate()
+        .map(|(i, (child, _))| {
+            let (width, height) = child.min_size();
+            match split {
+                Split::Horizontal => height,
+                Split::Vertical if i < last => width + 1,
+                Split::Vertical => width,
+            }
+        })
+        .collect();
+    let sum: u32 = children.iter().map(|&(_, size)| size as u32).sum();
+    if sum != total as u32 {
+        let mut left = total;
+        for (i, (_, size)) in children.iter_mut().enumerate() {
+            *size = if i == last {
+                left
+            } else {
+                let scaled = (*size as u32 * total as u32 / sum.max(1)) as u16;
+                scaled.min(left)
+            };
+            left -= *size;
+        }
+    }
+    for i in 0..children.len() {
+        while children[i].1 < mins[i] {
+            let Some(donor) = (0..children.len())
+                .filter(|&j| children[j].1 > mins[j])
+                .max_by_key(|&j| children[j].1 - mins[j])
+            else {
+                return;
+            };
+            children[donor].1 -= 1;
+            children[i].1 += 1;
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each window's id and whole area, status line included, as
+    /// `(x, y, width, height)` from top left to bottom right.
+    fn layout_of(layout: &mut WindowLayout, width: u16, height: u16) -> Vec<(usize, [u16; 4])> {
+        let (areas, _) = layout.arrange(Rect::new(0, 0, width, height));
+        areas
+            .iter()
+            .map(|area| {
+                let whole = area.text.union(area.status);
+                (area.id, [whole.x, whole.y, whole.width, whole.height])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_splits_share_the_room_and_leave_a_separator() {
+        let mut layout = WindowLayout::new(0);
+        layout.split(Split::Horizontal).unwrap();
+        assert_eq!(
+            layout_of(&mut layout, 80, 20),
+            [(1, [0, 0, 80, 10]), (0, [0, 10, 80, 10])]
+        );
+        layout.split(Split::Vertical).unwrap();
+        assert_eq!(
+            layout_of(&mut layout, 80, 20),
+            [
+                (2, [0, 0, 39, 10]),
+                (1, [40, 0, 40, 10]),
+                (0, [0, 10, 80, 10])
+            ]
+        );
+        let (_, separators) = layout.arrange(Rect::new(0, 0, 80, 20));
+        assert_eq!(separators, [Rect::new(39, 0, 1, 10)]);
+        assert_eq!(layout.order(), [2, 1, 0]);
+        assert_eq!(layout.current().id, 2);
+    }
+
+    #[test]
+    fn test_neighbors_and_cycling() {
+        let mut layout = WindowLayout::new(0);
+        layout.split(Split::Horizontal).unwrap();
+        layout.split(Split::Vertical).unwrap();
+        layout_of(&mut layout, 80, 20);
+        assert_eq!(layout.neighbor('l'), Some(1));
+        assert_eq!(layout.neighbor('j'), Some(0));
+        assert_eq!(layout.neighbor('h'), None);
+        // Going up, the window above the cursor column wins.
+        layout.focus(0);
+        assert_eq!(layout.neighbor('k'), Some(2));
+        layout.cycle(true, None);
+        assert_eq!(layout.current().id, 2);
+        layout.cycle(false, None);
+        assert_eq!(layout.current().id, 0);
+        layout.cycle(true, Some(2));
+        assert_eq!(layout.current().id, 1);
+        layout.cycle(true, Some(99));
+        assert_eq!(layout.current().id, 0);
+    }
+
+    #[test]
+    fn test_closing_gives_the_room_to_a_neighbor() {
+        let mut layout = WindowLayout::new(0);
+        layout.split(Split::Horizontal).unwrap();
+        layout.split(Split::Vertical).unwrap();
+        layout_of(&mut layout, 80, 20);
+        layout.close().unwrap();
+        assert_eq!(layout.current().id, 1);
+        assert_eq!(
+            layout_of(&mut layout, 80, 20),
+            [(1, [0, 0, 80, 10]), (0, [0, 10, 80, 10])]
+        );
+        layout.only();
+        assert_eq!(layout_of(&mut layout, 80, 20), [(1, [0, 0, 80, 20])]);
+        assert_eq!(
+            layout.close(),
+            Err("E444: Cannot close last window".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resize_stops_at_the_minimum() {
+        let mut layout = WindowLayout::new(0);
+        layout.split(Split::Horizontal).unwrap();
+        layout_of(&mut layout, 80, 20);
+        layout.resize(Split::Horizontal, 3);
+        assert_eq!(
+            layout_of(&mut layout, 80, 20),
+            [(1, [0, 0, 80, 13]), (0, [0, 13, 80, 7])]
+        );
+        layout.resize(Split::Horizontal, -100);
+        assert_eq!(layout_of(&mut layout, 80, 20)[0].1[3], MIN_HEIGHT);
+        // A resize the other way has no split to act on.
+        layout.resize(Split::Vertical, 5);
+        assert_eq!(layout_of(&mut layout, 80, 20)[0].1[2], 80);
+        layout.equalize();
+        assert_eq!(layout_of(&mut layout, 80, 20)[0].1[3], 10);
+    }
+
+    #[test]
+    fn test_terminal_resize_keeps_proportions() {
+        let mut layout = WindowLayout::new(0);
+        layout.split(Split::Horizontal).unwrap();
+        layout_of(&mut layout, 80, 20);
+        layout.resize(Split::Horizontal, 3);
+        assert_eq!(
+            layout_of(&mut layout, 80, 40),
+            [(1, [0, 0, 80, 26]), (0, [0, 26, 80, 14])]
+        );
+        assert_eq!(
+            layout_of(&mut layout, 80, 20),
+            [(1, [0, 0, 80, 13]), (0, [0, 13, 80, 7])]
+        );
+    }
+
+    #[test]
+    fn test_small_terminal_keeps_every_window_at_its_minimum() {
+        let mut layout = WindowLayout::new(0);
+        layout.split(Split::Horizontal).unwrap();
+        layout.split(Split::Horizontal).unwrap();
+        layout.split(Split::Vertical).unwrap();
+        assert_eq!(layout.min_size(), (3, 3 * MIN_HEIGHT));
+        layout_of(&mut layout, 80, 20);
+        let heights: Vec<u16> = layout_of(&mut layout, 80, 6)
+            .iter()
+            .map(|(_, [.., height])| *height)
+            .collect();
+        assert_eq!(heights, [2, 2, 2, 2]);
+        let widths: Vec<u16> = layout_of(&mut layout, 3, 6)[..2]
+            .iter()
+            .map(|(_, [_, _, width, _])| *width)
+            .collect();
+        assert_eq!(widths, [1, 1]);
+    }
+
+    #[test]
+    fn test_no_split_without_room() {
+        let mut layout = WindowLayout::new(0);
+        layout_of(&mut layout, 80, 3);
+        assert_eq!(
+            layout.split(Split::Horizontal),
+            Err("E36: Not enough room".to_string())
+        );
+        layout_of(&mut layout, 2, 20);
+        assert!(layout.split(Split::Vertical).is_err());
+        layout_of(&mut layout, 3, 4);
+        layout.split(Split::Vertical).unwrap();
+        assert_eq!(layout.count(), 2);
+    }
+}
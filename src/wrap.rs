@@ -0,0 +1,215 @@
+use crate::text;
+
+/// Chars `linebreak` may break a line after, vim's default `breakat`.
+const BREAKAT: &str = " \t!@*-+;:,./?";
+
+/// The `:set` options saying how lines longer than the window are shown.
+#[derive(Debug, Clone)]
+pub struct WrapOptions {
+    pub wrap: bool,
+    /// Wrap after a `BREAKAT` char rather than in the middle of a word.
+    pub linebreak: bool,
+    /// Text shown at the start of each continuation row.
+    pub showbreak: String,
+    /// Indent continuation rows as much as the line's own indent.
+    pub breakindent: bool,
+    /// Columns kept in view left and right of the cursor when scrolling
+    /// sideways.
+    pub sidescrolloff: usize,
+}
+/// How lines fall into screen rows in a window `width` columns wide
+/// (besides its line numbers), scrolled sideways to `left_col` when lines
+/// do not wrap.
+#[derive(Debug, Clone)]
+pub struct Wrapping {
+    pub width: usize,
+    pub left_col: usize,
+    pub options: WrapOptions,
+}
+/// One screen row of a line: display columns `start..end`, drawn after
+/// `indent` columns of `breakindent` and `showbreak`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Row {
+    pub start: usize,
+    pub end: usize,
+    pub indent: usize,
+}
+impl Wrapping {
+    /// Whether long lines continue on the next rows. False while the
+    /// window's width is not known yet.
+    pub fn wraps(&self) -> bool {
+        self.options.wrap && self.width > 0
+    }
+    /// The screen rows `line` takes; one when lines do not wrap.
+    pub fn rows(&self, line: &str) -> Vec<Row> {
+        let mut cells = Vec::new();
+        let mut display = 0;
+        for c in line.chars() {
+            let width = text::char_width(c, display);
+            cells.push((c, display + width));
+            display += width;
+        }
+        if !self.wraps() {
+            return vec![Row {
+                start: 0,
+                end: display,
+                indent: 0,
+            }];
+        }
+        let mut indent = text::display_col(
+            &self.options.showbreak,
+            self.options.showbreak.chars().count(),
+        );
+        if self.options.breakindent {
+            indent += self.indent(line);
+        }
+        if indent >= self.width {
+            indent = 0;
+        }
+        let mut rows: Vec<Row> = Vec::new();
+        let (mut start, mut i) = (0, 0);
+        loop {
+            let room = if rows.is_empty() {
+                self.width
+            } else {
+                self.width - indent
+            };
+            let mut j = i;
+            while j < cells.len() && cells[j].1 - start <= room {
+                j += 1;
+            }
+            // A char wider than the whole row still gets a row of its own.
+            if j == i && j < cells.len() {
+                j += 1;
+            }
+            if self.options.linebreak && j < cells.len() {
+                if let Some(k) = (i..j).rev().find(|&k| BREAKAT.contains(cells[k].0)) {
+                    j = k + 1;
+                }
+            }
+            let end = if j > i { cells[j - 1].1 } else { start };
+            rows.push(Row {
+                start,
+                end,
+                indent: if rows.is_empty() { 0 } else { indent },
+            });
+            if j >= cells.len() {
+                return rows;
+            }
+            (start, i) = (end, j);
+        }
+    }
+    /// Index of the row in `rows` showing display column `col`.
+    pub fn row_of(rows: &[Row], col: usize) -> usize {
+        rows.iter().rposition(|row| row.start <= col).unwrap_or(0)
+    }
+    /// Display width of the blanks at the start of `line`.
+    fn indent(&self, line: &str) -> usize {
+        let blanks = line.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+        text::display_col(line, blanks)
+    }
+    /// Columns kept between the cursor and the window edge when scrolling
+    /// sideways, at most half the window.
+    pub fn side_margin(&self) -> usize {
+        self.options
+            .sidescrolloff
+            .min(self.width.saturating_sub(1) / 2)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapping(width: usize) -> Wrapping {
+        Wrapping {
+            width,
+            left_col: 0,
+            options: WrapOptions {
+                wrap: true,
+                linebreak: false,
+                showbreak: String::new(),
+                breakindent: false,
+                sidescrolloff: 0,
+            },
+        }
+    }
+    /// Each row as `(start, end, indent)`.
+    fn rows(wrapping: &Wrapping, line: &str) -> Vec<(usize, usize, usize)> {
+        wrapping
+            .rows(line)
+            .iter()
+            .map(|row| (row.start, row.end, row.indent))
+            .collect()
+    }
+
+    #[test]
+    fn test_long_lines_fill_rows() {
+        let mut wrapping = wrapping(4);
+        assert_eq!(
+            rows(&wrapping, "abcdefghij"),
+            [(0, 4, 0), (4, 8, 0), (8, 10, 0)]
+        );
+        assert_eq!(rows(&wrapping, ""), [(0, 0, 0)]);
+        assert_eq!(rows(&wrapping, "\tab"), [(0, 8, 0), (8, 10, 0)]);
+        wrapping.options.wrap = false;
+        assert_eq!(rows(&wrapping, "abcdefghij"), [(0, 10, 0)]);
+        // Until the window is drawn its width is not known.
+        let wrapping = self::wrapping(0);
+        assert!(!wrapping.wraps());
+        assert_eq!(rows(&wrapping, "abcdefghij"), [(0, 10, 0)]);
+    }
+
+    #[test]
+    fn test_wide_chars_are_never_split() {
+        assert_eq!(
+            rows(&wrapping(3), "日本語"),
+            [(0, 2, 0), (2, 4, 0), (4, 6, 0)]
+        );
+        assert_eq!(rows(&wrapping(5), "a日本語"), [(0, 5, 0), (5, 7, 0)]);
+        // Wider than the window, a char still gets a row of its own.
+        assert_eq!(rows(&wrapping(1), "日x"), [(0, 2, 0), (2, 3, 0)]);
+    }
+
+    #[test]
+    fn test_linebreak_wraps_after_breakat_chars() {
+        let mut wrapping = wrapping(6);
+        wrapping.options.linebreak = true;
+        assert_eq!(
+            rows(&wrapping, "one two three"),
+            [(0, 4, 0), (4, 8, 0), (8, 13, 0)]
+        );
+        // A word longer than a row is broken anyway.
+        assert_eq!(rows(&wrapping, "abcdefgh"), [(0, 6, 0), (6, 8, 0)]);
+        assert_eq!(rows(&wrapping, "a,bcdefg"), [(0, 2, 0), (2, 8, 0)]);
+    }
+
+    #[test]
+    fn test_showbreak_and_breakindent_narrow_continuation_rows() {
+        let mut wrapping = wrapping(6);
+        wrapping.options.showbreak = "> ".to_string();
+        assert_eq!(rows(&wrapping, "abcdefghij"), [(0, 6, 0), (6, 10, 2)]);
+        wrapping.options.breakindent = true;
+        assert_eq!(
+            rows(&wrapping, "  abcdefgh"),
+            [(0, 6, 0), (6, 8, 4), (8, 10, 4)]
+        );
+        // Indent that leaves no room is dropped.
+        wrapping.options.showbreak = "......".to_string();
+        assert_eq!(rows(&wrapping, "abcdefgh"), [(0, 6, 0), (6, 8, 0)]);
+    }
+
+    #[test]
+    fn test_row_of_and_side_margin() {
+        let wrapping = wrapping(4);
+        let rows = wrapping.rows("abcdefghij");
+        assert_eq!(Wrapping::row_of(&rows, 0), 0);
+        assert_eq!(Wrapping::row_of(&rows, 5), 1);
+        assert_eq!(Wrapping::row_of(&rows, 8), 2);
+        assert_eq!(Wrapping::row_of(&rows, 10), 2);
+        let mut wrapping = self::wrapping(7);
+        wrapping.options.sidescrolloff = 5;
+        assert_eq!(wrapping.side_margin(), 3);
+        wrapping.width = 0;
+        assert_eq!(wrapping.side_margin(), 0);
+    }
+}